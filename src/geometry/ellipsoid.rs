@@ -104,12 +104,29 @@ impl Ellipsoid {
     }
 
     /// Copmute the isomitrized distance of a point in world coordinate, to the center of the ellipsoid
+    /// distances are normalized by the axes so ordering honours the anisotropy of the ellipsoid
     pub fn iso_distance(&self, point: &Point3<f32>) -> f32 {
-        let point = self.coordinate_system.world_to_local.transform_point(point);
+        self.normalized_distance(point)
+    }
+
+    /// Compute the distance of a point in world coordinates to the center of the ellipsoid
+    /// normalized by the axes of the ellipsoid (1 on the surface of the ellipsoid)
+    #[inline(always)]
+    pub fn normalized_distance(&self, point: &Point3<f32>) -> f32 {
+        let mut point = self.coordinate_system.world_to_local.transform_point(point);
+        point
+            .coords
+            .component_div_assign(&Vector3::new(self.a, self.b, self.c));
 
         point.coords.norm()
     }
 
+    /// Length of the longest axis of the ellipsoid
+    #[inline(always)]
+    pub fn max_axis(&self) -> f32 {
+        self.a.max(self.b).max(self.c)
+    }
+
     // Compute the indices of the points to include in each octant for kriging
     //  NOT USED
     pub fn octant_points(
@@ -117,7 +134,7 @@ impl Ellipsoid {
         points: &[Point3<f32>],
         parameters: &KrigingParameters,
     ) -> Vec<usize> {
-        let init_size = parameters.min_octant_data;
+        let init_size = parameters.max_octant_data;
         let mut octant_points = vec![Vec::with_capacity(init_size); 8];
         let mut octant_flag = vec![Vec::with_capacity(points.len()); 8];
 
@@ -283,4 +300,25 @@ mod tests {
 
         assert!(ellipse.contains(&point));
     }
+
+    #[test]
+    fn test_ellipse_normalized_distance() {
+        let coordinate_system = CoordinateSystem::new(
+            Translation3::new(1f32, 0f32, 0f32),
+            UnitQuaternion::from_euler_angles(0.0, 0.0, std::f32::consts::PI / 2f32),
+        );
+
+        let ellipse = Ellipsoid::new(4f32, 2f32, 1f32, coordinate_system);
+
+        //major axis is rotated onto the y axis
+        let point = Point3::new(1f32, 4f32, 0f32);
+        assert_relative_eq!(ellipse.normalized_distance(&point), 1f32, epsilon = 0.0001);
+
+        let point = Point3::new(2f32, 0f32, 0f32);
+        assert_relative_eq!(
+            ellipse.normalized_distance(&point),
+            0.5f32,
+            epsilon = 0.0001
+        );
+    }
 }
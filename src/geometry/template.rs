@@ -9,6 +9,11 @@ impl Template {
         Self { offsets }
     }
 
+    /// Offsets of the template nodes from the centre node
+    pub fn offsets(&self) -> &[[isize; 3]] {
+        &self.offsets
+    }
+
     #[inline(always)]
     pub fn get_ind(ind: &[usize; 3], offset: [isize; 3], bounds: [usize; 3]) -> Option<[usize; 3]> {
         let ind = [
@@ -4,8 +4,13 @@ use indicatif::ParallelProgressIterator;
 use nalgebra::Point3;
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 
-use crate::{spatial_database::SpatialQueryable, variography::model_variograms::VariogramModel};
+use crate::{
+    spatial_database::{search::SearchStatus, SpatialQueryable},
+    variography::model_variograms::VariogramModel,
+};
 
+pub mod inequality;
+pub mod multi_pass;
 pub mod simple_kriging;
 
 pub trait KrigingSystem: Clone {
@@ -23,10 +28,22 @@ pub trait KrigingSystem: Clone {
     fn variance(&self) -> f32;
 }
 
+/// Treatment of the mean in kriging systems
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KrigingType {
+    /// Known (zero) mean, data must be normalized
+    #[default]
+    Simple,
+    /// Unknown mean re-estimated from the data of each system
+    Ordinary,
+}
+
+/// Kriging system parameters
+/// minimums and sectors of the search are set by the search specification of the conditioning data
+/// (`SearchSpecification`), they are honoured through `SpatialQueryable::search_status`
+/// # Members
+/// * `max_octant_data` - Maximum number of data per octant returned by the conditioning data search
 pub struct KrigingParameters {
-    pub max_cond_data: usize,
-    pub min_cond_data: usize,
-    pub min_octant_data: usize,
     pub max_octant_data: usize,
 }
 
@@ -71,7 +88,22 @@ where
     }
 
     /// Perform simple kriging at all kriging points
+    /// points skipped by the search specification of the conditioning data are set to NaN
     pub fn krig(&self, kriging_points: &[Point3<f32>]) -> Vec<f32> {
+        self.krig_with_status(kriging_points)
+            .0
+            .into_iter()
+            .map(|value| value.unwrap_or(f32::NAN))
+            .collect()
+    }
+
+    /// Perform kriging at all kriging points honouring the search specification
+    /// # Returns
+    /// The estimate (None if skipped) and the search status of each kriging point
+    pub fn krig_with_status(
+        &self,
+        kriging_points: &[Point3<f32>],
+    ) -> (Vec<Option<f32>>, Vec<SearchStatus>) {
         //construct kriging system
         //let kriging_system = SimpleKrigingSystem::new(self.kriging_parameters.max_octant_data * 8);
 
@@ -85,16 +117,83 @@ where
                 //get nearest points and values
                 let (cond_values, cond_points) = self.conditioning_data.query(kriging_point);
 
-                //build kriging system for point
-                local_system.build_system(
-                    &cond_points,
-                    cond_values.as_slice(),
-                    kriging_point,
-                    &self.variogram_model,
-                );
+                //check search minimums
+                let status = self
+                    .conditioning_data
+                    .search_status(kriging_point, &cond_points);
+
+                let value = status.resolve(|| {
+                    //build kriging system for point
+                    local_system.build_system(
+                        &cond_points,
+                        cond_values.as_slice(),
+                        kriging_point,
+                        &self.variogram_model,
+                    );
 
-                local_system.estimate()
+                    local_system.estimate()
+                });
+
+                (value, status)
             })
-            .collect::<Vec<f32>>()
+            .unzip()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion, Vector3};
+    use ndarray::Array3;
+
+    use crate::{
+        geometry::ellipsoid::Ellipsoid,
+        spatial_database::{
+            coordinate_system::{CoordinateSystem, GridSpacing},
+            gridded_databases::{
+                gridded_data_base_query_engine::GriddedDataBaseOctantQueryEngine,
+                incomplete_grid::InCompleteGriddedDataBase,
+            },
+            search::{InsufficientDataRule, SearchSpecification, Sectors},
+        },
+        variography::model_variograms::spherical::SphericalVariogram,
+    };
+
+    use super::{simple_kriging::SimpleKrigingSystem, *};
+
+    #[test]
+    fn kriging_honours_search_minimums() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let mut grid = Array3::from_elem((10, 10, 1), None);
+        grid[[2, 2, 0]] = Some(1.0);
+        grid[[3, 2, 0]] = Some(0.5);
+        let gdb = InCompleteGriddedDataBase::new(
+            grid,
+            GridSpacing {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+            cs,
+        );
+
+        let kriging = |min_total: usize| {
+            let query_engine =
+                GriddedDataBaseOctantQueryEngine::new(Ellipsoid::new(4.0, 4.0, 4.0, cs), &gdb, 4)
+                    .with_search(
+                        SearchSpecification::new(Sectors::One)
+                            .with_minimums(min_total, 1, 0)
+                            .with_insufficient_data_rule(InsufficientDataRule::Skip),
+                    );
+            Kriging::<_, _, _, SimpleKrigingSystem>::new(
+                query_engine,
+                SphericalVariogram::new(Vector3::new(5.0, 5.0, 5.0), 1.0, 0.0, cs),
+                KrigingParameters { max_octant_data: 4 },
+            )
+            .krig_with_status(&[Point3::new(2.0, 3.0, 0.0)])
+            .0
+        };
+
+        assert!(kriging(2)[0].is_some());
+        assert_eq!(kriging(3), vec![None]);
     }
 }
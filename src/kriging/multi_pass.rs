@@ -0,0 +1,243 @@
+use itertools::Itertools;
+use nalgebra::Point3;
+
+use crate::{
+    geometry::ellipsoid::Ellipsoid,
+    kriging::{simple_kriging::SimpleKriging, Kriging, KrigingSystem},
+    spatial_database::{
+        qbvh::point_set::ConditioningParams, search::SearchStatus, ConditioningProvider,
+        SpatialQueryable,
+    },
+    variography::model_variograms::VariogramModel,
+};
+
+/// Estimator reporting the search status of each target
+pub trait StatusEstimator {
+    /// Estimate all targets
+    /// # Returns
+    /// The estimate (None if skipped) and the search status of each target
+    fn estimate_with_status(&self, points: &[Point3<f32>])
+        -> (Vec<Option<f32>>, Vec<SearchStatus>);
+}
+
+impl<S, V> StatusEstimator for SimpleKriging<S, V>
+where
+    S: ConditioningProvider<Ellipsoid, f32, ConditioningParams> + Sync + std::marker::Send,
+    V: VariogramModel + Sync + std::marker::Send,
+{
+    fn estimate_with_status(
+        &self,
+        points: &[Point3<f32>],
+    ) -> (Vec<Option<f32>>, Vec<SearchStatus>) {
+        self.krig_with_status(points)
+    }
+}
+
+/// Estimator reporting the search status of each target using the parameters of its domain
+pub trait DomainStatusEstimator {
+    /// Estimate all targets in their domain
+    /// # Arguments
+    /// * `points` - The targets
+    /// * `domains` - The domain of each target
+    /// # Returns
+    /// The estimate (None if skipped) and the search status of each target
+    fn estimate_in_domains_with_status(
+        &self,
+        points: &[Point3<f32>],
+        domains: &[u32],
+    ) -> (Vec<Option<f32>>, Vec<SearchStatus>);
+}
+
+impl<S, V> DomainStatusEstimator for SimpleKriging<S, V>
+where
+    S: ConditioningProvider<Ellipsoid, f32, ConditioningParams> + Sync + std::marker::Send,
+    V: VariogramModel + Sync + std::marker::Send,
+{
+    fn estimate_in_domains_with_status(
+        &self,
+        points: &[Point3<f32>],
+        domains: &[u32],
+    ) -> (Vec<Option<f32>>, Vec<SearchStatus>) {
+        self.krig_in_domains_with_status(points, domains)
+    }
+}
+
+impl<S, V, G, KS> StatusEstimator for Kriging<S, V, G, KS>
+where
+    S: SpatialQueryable<f32, G> + Sync,
+    V: VariogramModel + Sync,
+    KS: KrigingSystem + Send + Sync,
+{
+    fn estimate_with_status(
+        &self,
+        points: &[Point3<f32>],
+    ) -> (Vec<Option<f32>>, Vec<SearchStatus>) {
+        self.krig_with_status(points)
+    }
+}
+
+/// Result of a multi-pass estimation
+/// # Members
+/// * `values` - Estimate of each target (None if no pass estimated the target)
+/// * `passes` - Pass (starting at 1) which estimated each target
+pub struct MultiPassEstimate {
+    pub values: Vec<Option<f32>>,
+    pub passes: Vec<Option<usize>>,
+}
+
+impl MultiPassEstimate {
+    /// Estimates with unestimated targets set to NaN
+    pub fn values_or_nan(&self) -> Vec<f32> {
+        self.values
+            .iter()
+            .map(|value| value.unwrap_or(f32::NAN))
+            .collect()
+    }
+}
+
+/// Multi-pass estimation driver
+/// each pass only estimates the targets left unestimated by the previous passes
+/// a target is filled by a pass if its search meets the minimums of that pass,
+/// the last pass also fills targets according to its insufficient data rule
+/// * earlier passes should use `InsufficientDataRule::Skip` to avoid solving systems which are discarded
+pub struct MultiPass<E> {
+    passes: Vec<E>,
+}
+
+impl<E> MultiPass<E> {
+    /// Create a new multi-pass driver
+    /// # Arguments
+    /// * `passes` - Estimators of each pass, usually with increasing search ellipsoids and relaxed minimums
+    pub fn new(passes: Vec<E>) -> Self {
+        assert!(!passes.is_empty(), "at least one pass is required");
+        Self { passes }
+    }
+
+    /// Run the passes over the targets left by the previous passes
+    /// # Arguments
+    /// * `n_points` - Number of targets
+    /// * `estimate_pass` - Estimates and search statuses of a pass for the given targets
+    fn run<F>(&self, n_points: usize, estimate_pass: F) -> MultiPassEstimate
+    where
+        F: Fn(&E, &[usize]) -> (Vec<Option<f32>>, Vec<SearchStatus>),
+    {
+        let mut values = vec![None; n_points];
+        let mut passes = vec![None; n_points];
+        let mut remaining = (0..n_points).collect_vec();
+
+        for (pass_ind, pass) in self.passes.iter().enumerate() {
+            if remaining.is_empty() {
+                break;
+            }
+            let last_pass = pass_ind == self.passes.len() - 1;
+
+            let (pass_values, statuses) = estimate_pass(pass, &remaining);
+
+            let mut unfilled = Vec::new();
+            for ((ind, value), status) in remaining.iter().zip(pass_values).zip(statuses) {
+                let accepted = last_pass || status == SearchStatus::Satisfied;
+                match value {
+                    Some(value) if accepted => {
+                        values[*ind] = Some(value);
+                        passes[*ind] = Some(pass_ind + 1);
+                    }
+                    _ => unfilled.push(*ind),
+                }
+            }
+            remaining = unfilled;
+        }
+
+        MultiPassEstimate { values, passes }
+    }
+}
+
+impl<E> MultiPass<E>
+where
+    E: StatusEstimator,
+{
+    /// Estimate all targets
+    pub fn estimate(&self, points: &[Point3<f32>]) -> MultiPassEstimate {
+        self.run(points.len(), |pass, inds| {
+            let pass_points = inds.iter().map(|ind| points[*ind]).collect_vec();
+            pass.estimate_with_status(&pass_points)
+        })
+    }
+}
+
+impl<E> MultiPass<E>
+where
+    E: DomainStatusEstimator,
+{
+    /// Estimate all targets using the parameters of their domain in each pass
+    /// # Arguments
+    /// * `points` - The targets
+    /// * `domains` - The domain of each target
+    pub fn estimate_in_domains(
+        &self,
+        points: &[Point3<f32>],
+        domains: &[u32],
+    ) -> MultiPassEstimate {
+        assert_eq!(points.len(), domains.len(), "each target requires a domain");
+        self.run(points.len(), |pass, inds| {
+            let pass_points = inds.iter().map(|ind| points[*ind]).collect_vec();
+            let pass_domains = inds.iter().map(|ind| domains[*ind]).collect_vec();
+            pass.estimate_in_domains_with_status(&pass_points, &pass_domains)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion, Vector3};
+
+    use crate::{
+        spatial_database::{
+            coordinate_system::CoordinateSystem,
+            qbvh::point_set::PointSet,
+            search::{InsufficientDataRule, SearchSpecification, Sectors},
+        },
+        variography::model_variograms::spherical::SphericalVariogram,
+    };
+
+    use super::*;
+
+    #[test]
+    fn passes_fill_remaining_targets() {
+        let coordinate_system = CoordinateSystem::new(
+            Translation3::new(0.0, 0.0, 0.0),
+            UnitQuaternion::from_euler_angles(0.0, 0.0, 0.0),
+        );
+        let point_set = PointSet::new(vec![Point3::new(0f32, 0f32, 0f32)], vec![1f32]);
+
+        let pass = |radius: f32| {
+            let vgram = SphericalVariogram::new(
+                Vector3::new(20f32, 20f32, 20f32),
+                1f32,
+                0f32,
+                coordinate_system,
+            );
+            let ellipsoid = Ellipsoid::new(radius, radius, radius, coordinate_system);
+            let search = SearchSpecification::new(Sectors::Eight)
+                .with_minimums(1, 1, 1)
+                .with_insufficient_data_rule(InsufficientDataRule::Skip);
+            SimpleKriging::new(
+                &point_set,
+                vgram,
+                ellipsoid,
+                ConditioningParams::new(4).with_search(search),
+            )
+        };
+
+        let multi_pass = MultiPass::new(vec![pass(2.0), pass(10.0)]);
+        let targets = [
+            Point3::new(1f32, 0f32, 0f32),
+            Point3::new(5f32, 0f32, 0f32),
+            Point3::new(50f32, 0f32, 0f32),
+        ];
+        let estimate = multi_pass.estimate(&targets);
+
+        assert_eq!(estimate.passes, vec![Some(1), Some(2), None]);
+        assert!(estimate.values[0].unwrap() > estimate.values[1].unwrap());
+        assert!(estimate.values_or_nan()[2].is_nan());
+    }
+}
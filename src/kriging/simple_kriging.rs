@@ -1,10 +1,11 @@
-use std::marker::PhantomData;
+use std::{collections::HashMap, marker::PhantomData};
 
 use crate::{
     geometry::{ellipsoid::Ellipsoid, Geometry},
     kriging::KrigingParameters,
     spatial_database::{
-        qbvh::point_set::ConditioningParams, ConditioningProvider, SpatialQueryable,
+        qbvh::point_set::ConditioningParams, search::SearchStatus, ConditioningProvider,
+        SpatialQueryable,
     },
     variography::model_variograms::VariogramModel,
 };
@@ -378,6 +379,57 @@ impl SimpleKrigingSystem {
             cov_vec: self.krig_point_cov_vec.clone(),
         }
     }
+
+    /// Build the system for OK and compute the weights
+    /// the mean is re-estimated from the conditioning data, the weights sum to one
+    /// # Arguments
+    /// * `cond_points` - The conditioning points for the kriging point
+    /// * `kriging_point` - The kriging point
+    /// * 'vgram' - The variogram model
+    #[inline(always)]
+    pub fn build_mini_ok_system<V>(
+        &mut self,
+        cond_points: &[Point3<f32>],
+        kriging_point: &Point3<f32>,
+        vgram: &V,
+    ) -> MiniSKSystem
+    where
+        V: VariogramModel,
+    {
+        let sk_system = self.build_mini_system(cond_points, kriging_point, vgram);
+
+        //without conditioning data the mean cannot be estimated, fall back to SK
+        let n = cond_points.len();
+        if n == 0 {
+            return sk_system;
+        }
+
+        //lambda_e = C^-1 @ e using the cholesky factor computed for the SK weights
+        let mut lambda_e = Mat::zeros(n, 1);
+        let mut cholesky_solve_stack = DynStack::new(&mut self.cholesky_solve_mem);
+        faer_cholesky::llt::solve::solve_with_conj(
+            lambda_e.as_mut(),
+            self.cond_cov_mat.as_ref(),
+            Conj::No,
+            Mat::<f32>::from_fn(n, 1, |_, _| 1.0).as_ref(),
+            Parallelism::None,
+            cholesky_solve_stack.rb_mut(),
+        );
+
+        //lagrange multiplier enforcing unbiasedness
+        let denom = (0..n).map(|i| lambda_e.read(i, 0)).sum::<f32>();
+        let sk_sum = (0..n).map(|i| self.weights.read(i, 0)).sum::<f32>();
+        let mu = (sk_sum - 1.0) / denom;
+
+        //OK variance is c_0 - lambda^T @ c - mu
+        MiniSKSystem {
+            c_0: self.c_0 - mu,
+            weights: Mat::from_fn(n, 1, |i, _| {
+                self.weights.read(i, 0) - mu * lambda_e.read(i, 0)
+            }),
+            cov_vec: self.krig_point_cov_vec.clone(),
+        }
+    }
 }
 
 pub struct MiniSKSystem {
@@ -445,11 +497,37 @@ impl KrigingSystem for SimpleKrigingSystem {
     }
 }
 
+/// Estimation parameters for a single domain
+/// # Members
+/// * `variogram_model` - The variogram model of the domain
+/// * `search_ellipsoid` - The search ellipsoid of the domain
+/// * `query_params` - The conditioning parameters of the domain
+pub struct DomainParameters<V> {
+    pub variogram_model: V,
+    pub search_ellipsoid: Ellipsoid,
+    pub query_params: ConditioningParams,
+}
+
+impl<V> DomainParameters<V> {
+    pub fn new(
+        variogram_model: V,
+        search_ellipsoid: Ellipsoid,
+        query_params: ConditioningParams,
+    ) -> Self {
+        Self {
+            variogram_model,
+            search_ellipsoid,
+            query_params,
+        }
+    }
+}
+
 pub struct SimpleKriging<S, V> {
     conditioning_data: S,
     variogram_model: V,
     search_ellipsoid: Ellipsoid,
     query_params: ConditioningParams,
+    domain_parameters: HashMap<u32, DomainParameters<V>>,
 }
 
 // impl<S, V, G> SimpleKriging<S, V, G>
@@ -530,11 +608,34 @@ where
             variogram_model,
             search_ellipsoid,
             query_params,
+            domain_parameters: HashMap::new(),
         }
     }
 
+    /// Use a separate variogram and search for a domain
+    /// domains without their own parameters use the parameters of the estimator
+    pub fn with_domain_parameters(mut self, domain: u32, params: DomainParameters<V>) -> Self {
+        self.domain_parameters.insert(domain, params);
+        self
+    }
+
     /// Perform simple kriging at all kriging points
+    /// points skipped by the search specification are set to NaN
     pub fn krig(&self, kriging_points: &[Point3<f32>]) -> Vec<f32> {
+        self.krig_with_status(kriging_points)
+            .0
+            .into_iter()
+            .map(|value| value.unwrap_or(f32::NAN))
+            .collect()
+    }
+
+    /// Perform simple kriging at all kriging points honouring the search specification
+    /// # Returns
+    /// The estimate (None if skipped) and the search status of each kriging point
+    pub fn krig_with_status(
+        &self,
+        kriging_points: &[Point3<f32>],
+    ) -> (Vec<Option<f32>>, Vec<SearchStatus>) {
         //construct kriging system
         let kriging_system = SimpleKrigingSystem::new(self.query_params.max_n_cond * 8);
 
@@ -551,23 +652,129 @@ where
                         self.conditioning_data
                             .query(kriging_point, &ellipsoid, &self.query_params);
 
-                    // if cond_points.len() > 0 {
-                    //     println!("cond_points: {:?}", cond_points);
-                    //     println!("search ellipsoid: {:?}", ellipsoid);
-                    // }
+                    //check search minimums
+                    let status = self.conditioning_data.search_status(
+                        kriging_point,
+                        &cond_points,
+                        &ellipsoid,
+                        &self.query_params,
+                    );
+
+                    let value = status.resolve(|| {
+                        //build kriging system for point
+                        local_system.build_system(
+                            &cond_points,
+                            cond_values.as_slice(),
+                            kriging_point,
+                            &self.variogram_model,
+                        );
+
+                        local_system.estimate()
+                    });
 
+                    (value, status)
+                },
+            )
+            .unzip()
+    }
+
+    /// Perform simple kriging at all kriging points using the parameters of their domain
+    /// points skipped by the search specification of their domain are set to NaN
+    /// # Arguments
+    /// * `kriging_points` - The points to estimate
+    /// * `domains` - The domain of each kriging point
+    pub fn krig_in_domains(&self, kriging_points: &[Point3<f32>], domains: &[u32]) -> Vec<f32> {
+        self.krig_in_domains_with_status(kriging_points, domains)
+            .0
+            .into_iter()
+            .map(|value| value.unwrap_or(f32::NAN))
+            .collect()
+    }
+
+    /// Perform simple kriging at all kriging points using the parameters of their domain, honouring
+    /// the search specification of the domain
+    /// # Arguments
+    /// * `kriging_points` - The points to estimate
+    /// * `domains` - The domain of each kriging point
+    /// # Returns
+    /// The estimate (None if skipped) and the search status of each kriging point
+    pub fn krig_in_domains_with_status(
+        &self,
+        kriging_points: &[Point3<f32>],
+        domains: &[u32],
+    ) -> (Vec<Option<f32>>, Vec<SearchStatus>) {
+        assert_eq!(
+            kriging_points.len(),
+            domains.len(),
+            "each kriging point requires a domain"
+        );
+        assert!(
+            self.conditioning_data.has_domains(),
+            "domain kriging requires conditioning data with domain codes"
+        );
+
+        //construct kriging system large enough for every domain
+        let max_n_cond = self
+            .domain_parameters
+            .values()
+            .map(|params| params.query_params.max_n_cond)
+            .fold(self.query_params.max_n_cond, usize::max);
+        let kriging_system = SimpleKrigingSystem::new(max_n_cond * 8);
+
+        kriging_points
+            .par_iter()
+            .zip(domains.par_iter())
+            .progress()
+            .map_with(kriging_system, |local_system, (kriging_point, domain)| {
+                let (variogram_model, search_ellipsoid, query_params) =
+                    match self.domain_parameters.get(domain) {
+                        Some(params) => (
+                            &params.variogram_model,
+                            &params.search_ellipsoid,
+                            &params.query_params,
+                        ),
+                        None => (
+                            &self.variogram_model,
+                            &self.search_ellipsoid,
+                            &self.query_params,
+                        ),
+                    };
+
+                //translate search ellipsoid to kriging point
+                let mut ellipsoid = search_ellipsoid.clone();
+                ellipsoid.translate_to(kriging_point);
+
+                //get nearest points and values permitted by the domain boundaries
+                let (_, cond_values, cond_points) = self.conditioning_data.query_in_domain(
+                    kriging_point,
+                    *domain,
+                    &ellipsoid,
+                    query_params,
+                );
+
+                //check search minimums of the domain
+                let status = self.conditioning_data.search_status(
+                    kriging_point,
+                    &cond_points,
+                    &ellipsoid,
+                    query_params,
+                );
+
+                let value = status.resolve(|| {
                     //build kriging system for point
                     local_system.build_system(
                         &cond_points,
                         cond_values.as_slice(),
                         kriging_point,
-                        &self.variogram_model,
+                        variogram_model,
                     );
 
                     local_system.estimate()
-                },
-            )
-            .collect::<Vec<f32>>()
+                });
+
+                (value, status)
+            })
+            .unzip()
     }
 }
 
@@ -585,12 +792,14 @@ mod tests {
         geometry::ellipsoid::Ellipsoid,
         spatial_database::{
             coordinate_system::{CoordinateSystem, GridSpacing},
+            domain_boundaries::DomainBoundaries,
             gridded_databases::{
                 gridded_data_base_query_engine::GriddedDataBaseOctantQueryEngine,
                 incomplete_grid::InCompleteGriddedDataBase, GriddedDataBaseInterface,
             },
             normalized::Normalize,
             qbvh::point_set::PointSet,
+            search::{InsufficientDataRule, SearchSpecification, Sectors},
             SpatialDataBase,
         },
         variography::model_variograms::spherical::SphericalVariogram,
@@ -598,6 +807,173 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn krig_with_hard_boundaries() {
+        let coordinate_system = CoordinateSystem::new(
+            Translation3::new(0.0, 0.0, 0.0),
+            UnitQuaternion::from_euler_angles(0.0, 0.0, 0.0),
+        );
+        let vgram = || {
+            SphericalVariogram::new(
+                Vector3::new(10f32, 10f32, 10f32),
+                1f32,
+                0.1f32,
+                coordinate_system.clone(),
+            )
+        };
+        let ellipsoid = Ellipsoid::new(10.0, 10.0, 10.0, coordinate_system.clone());
+
+        let points = vec![
+            Point3::new(0f32, 0f32, 0f32),
+            Point3::new(1f32, 1f32, 0f32),
+            Point3::new(3f32, 0f32, 0f32),
+        ];
+        let domain_set =
+            PointSet::new(points.clone(), vec![1f32, 0.5, -1.0]).with_domains(vec![1, 1, 2]);
+        let same_domain_set = PointSet::new(points[0..2].to_vec(), vec![1f32, 0.5]);
+
+        let kriging_point = Point3::new(2f32, 0f32, 0f32);
+
+        let domain_kriging = SimpleKriging::new(
+            domain_set,
+            vgram(),
+            ellipsoid.clone(),
+            ConditioningParams::new(4).with_domain_boundaries(DomainBoundaries::Hard),
+        );
+        let same_domain_kriging = SimpleKriging::new(
+            same_domain_set,
+            vgram(),
+            ellipsoid,
+            ConditioningParams::new(4),
+        );
+
+        let domain_estimate = domain_kriging.krig_in_domains(&[kriging_point], &[1]);
+        let same_domain_estimate = same_domain_kriging.krig(&[kriging_point]);
+
+        assert_eq!(domain_estimate, same_domain_estimate);
+    }
+
+    #[test]
+    fn krig_in_domains_with_insufficient_data() {
+        let coordinate_system = CoordinateSystem::new(
+            Translation3::new(0.0, 0.0, 0.0),
+            UnitQuaternion::from_euler_angles(0.0, 0.0, 0.0),
+        );
+        let vgram = || {
+            SphericalVariogram::new(
+                Vector3::new(10f32, 10f32, 10f32),
+                1f32,
+                0.1f32,
+                coordinate_system.clone(),
+            )
+        };
+        let ellipsoid = Ellipsoid::new(10.0, 10.0, 10.0, coordinate_system.clone());
+        let point_set = PointSet::new(
+            vec![
+                Point3::new(-1f32, 0f32, 0f32),
+                Point3::new(0f32, 1f32, 0f32),
+            ],
+            vec![1f32, 0.5],
+        )
+        .with_domains(vec![1, 1]);
+
+        //domain 2 requires more samples than the data hold
+        let search = SearchSpecification::new(Sectors::Eight)
+            .with_minimums(3, 1, 3)
+            .with_insufficient_data_rule(InsufficientDataRule::Skip);
+        let kriging = SimpleKriging::new(
+            point_set,
+            vgram(),
+            ellipsoid.clone(),
+            ConditioningParams::new(4),
+        )
+        .with_domain_parameters(
+            2,
+            DomainParameters::new(
+                vgram(),
+                ellipsoid,
+                ConditioningParams::new(4).with_search(search),
+            ),
+        );
+
+        let kriging_points = [Point3::new(0f32, 0f32, 0f32); 2];
+        let (values, statuses) = kriging.krig_in_domains_with_status(&kriging_points, &[1, 2]);
+        assert!(values[0].is_some());
+        assert_eq!(values[1], None);
+        assert_eq!(statuses[0], SearchStatus::Satisfied);
+        assert_eq!(
+            statuses[1],
+            SearchStatus::Insufficient(InsufficientDataRule::Skip)
+        );
+
+        let estimates = kriging.krig_in_domains(&kriging_points, &[1, 2]);
+        assert!(estimates[0].is_finite());
+        assert!(estimates[1].is_nan());
+    }
+
+    #[test]
+    fn krig_with_insufficient_data() {
+        let coordinate_system = CoordinateSystem::new(
+            Translation3::new(0.0, 0.0, 0.0),
+            UnitQuaternion::from_euler_angles(0.0, 0.0, 0.0),
+        );
+        let vgram = || {
+            SphericalVariogram::new(
+                Vector3::new(10f32, 10f32, 10f32),
+                1f32,
+                0.1f32,
+                coordinate_system.clone(),
+            )
+        };
+        let ellipsoid = Ellipsoid::new(10.0, 10.0, 10.0, coordinate_system.clone());
+        let point_set = || {
+            PointSet::new(
+                vec![
+                    Point3::new(-1f32, 0f32, 0f32),
+                    Point3::new(0f32, 1f32, 0f32),
+                ],
+                vec![1f32, 0.5],
+            )
+        };
+        let kriging_points = [
+            Point3::new(0f32, 0f32, 0f32),
+            Point3::new(50f32, 0f32, 0f32),
+        ];
+
+        //the first point finds both samples, the second none
+        let search = SearchSpecification::new(Sectors::Eight).with_minimums(2, 1, 2);
+
+        let skip = SimpleKriging::new(
+            point_set(),
+            vgram(),
+            ellipsoid.clone(),
+            ConditioningParams::new(4).with_search(
+                search
+                    .clone()
+                    .with_insufficient_data_rule(InsufficientDataRule::Skip),
+            ),
+        );
+        let (values, statuses) = skip.krig_with_status(&kriging_points);
+        assert!(values[0].is_some());
+        assert_eq!(values[1], None);
+        assert_eq!(statuses[0], SearchStatus::Satisfied);
+        assert_eq!(
+            statuses[1],
+            SearchStatus::Insufficient(InsufficientDataRule::Skip)
+        );
+        assert!(skip.krig(&kriging_points)[1].is_nan());
+
+        let fallback = SimpleKriging::new(
+            point_set(),
+            vgram(),
+            ellipsoid,
+            ConditioningParams::new(4).with_search(
+                search.with_insufficient_data_rule(InsufficientDataRule::Fallback(-1.0)),
+            ),
+        );
+        assert_eq!(fallback.krig(&kriging_points)[1], -1.0);
+    }
+
     #[test]
     fn test_simple_kriging() {
         let cond_points = vec![
@@ -740,4 +1116,55 @@ mod tests {
                 .write_all(format!("{} {} {} {}\n", point.x, point.y, point.z, value).as_bytes());
         }
     }
+
+    #[test]
+    fn mini_ok_vs_dense() {
+        let vgram = SphericalVariogram::new(
+            Vector3::new(4.0, 4.0, 4.0),
+            1.0,
+            0.1,
+            CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity()),
+        );
+        let cond_points = vec![
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(0.0, 2.0, 0.0),
+            Point3::new(1.5, 1.5, 0.5),
+        ];
+        let values = Mat::<f32>::from_fn(4, 1, |i, _| [0.5, 1.0, 2.0, -1.0][i]);
+        let kriging_point = Point3::new(0.5, 0.5, 1.0);
+
+        let mut system = SimpleKrigingSystem::new(cond_points.len());
+        let mini = system.build_mini_ok_system(&cond_points, &kriging_point, &vgram);
+
+        //dense ordinary kriging system [[C, e], [e^T, 0]] @ [lambda, mu] = [c, 1]
+        let n = cond_points.len();
+        let cov = |p1: &Point3<f32>, p2: &Point3<f32>| vgram.covariogram(p1 - p2) as f64;
+        let mut ok_mat = nalgebra::DMatrix::<f64>::zeros(n + 1, n + 1);
+        let mut rhs = nalgebra::DVector::<f64>::from_element(n + 1, 1.0);
+        for i in 0..n {
+            for j in 0..n {
+                ok_mat[(i, j)] = cov(&cond_points[i], &cond_points[j]);
+            }
+            ok_mat[(i, n)] = 1.0;
+            ok_mat[(n, i)] = 1.0;
+            rhs[i] = cov(&cond_points[i], &kriging_point);
+        }
+        let solution = ok_mat.lu().solve(&rhs).unwrap();
+
+        let estimate = (0..n)
+            .map(|i| solution[i] * values.read(i, 0) as f64)
+            .sum::<f64>();
+        let variance =
+            vgram.c_0() as f64 - (0..n).map(|i| solution[i] * rhs[i]).sum::<f64>() - solution[n];
+
+        assert!((mini.estimate(values.as_ref()) as f64 - estimate).abs() < 1e-4);
+        assert!((mini.variance() as f64 - variance).abs() < 1e-4);
+
+        //a constant shift of the data shifts the estimate by the same amount
+        let shifted = Mat::<f32>::from_fn(4, 1, |i, _| values.read(i, 0) + 10.0);
+        assert!(
+            (mini.estimate(shifted.as_ref()) - mini.estimate(values.as_ref()) - 10.0).abs() < 1e-3
+        );
+    }
 }
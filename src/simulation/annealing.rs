@@ -0,0 +1,581 @@
+use ndarray::Array3;
+use rand::{rngs::StdRng, Rng};
+
+use crate::spatial_database::gridded_databases::{ind_at_offset, GriddedDataBaseInterface};
+
+/// Cooling schedule of simulated annealing
+/// the temperature is lowered once a number of perturbations was attempted or accepted at the current
+/// temperature, annealing stops when the objective is low enough, when no perturbation was accepted
+/// at a temperature or after the last temperature
+#[derive(Clone, Debug)]
+pub struct CoolingSchedule {
+    /// Temperature of the first step
+    pub initial_temperature: f64,
+    /// Factor applied to the temperature after each step
+    pub reduction: f64,
+    /// Attempted perturbations per free node before lowering the temperature
+    pub attempts_per_node: usize,
+    /// Accepted perturbations per free node before lowering the temperature
+    pub accepted_per_node: usize,
+    /// Maximum number of temperatures
+    pub max_temperatures: usize,
+    /// Objective (relative to the initial objective) below which annealing stops
+    pub min_objective: f64,
+}
+
+impl Default for CoolingSchedule {
+    fn default() -> Self {
+        Self {
+            initial_temperature: 1.0,
+            reduction: 0.1,
+            attempts_per_node: 100,
+            accepted_per_node: 10,
+            max_temperatures: 10,
+            min_objective: 1e-4,
+        }
+    }
+}
+
+/// Target cdf at thresholds and the values new node values are drawn from
+struct HistogramTarget {
+    values: Vec<f32>,
+    thresholds: Vec<f32>,
+    cdf: Vec<f64>,
+    weight: f64,
+}
+
+/// Target variogram at grid offsets
+struct VariogramTarget {
+    lags: Vec<[isize; 3]>,
+    gamma: Vec<f64>,
+    weight: f64,
+}
+
+/// Target correlation with a secondary grid
+struct CorrelationTarget {
+    secondary: Array3<f32>,
+    sum_y: f64,
+    sum_yy: f64,
+    correlation: f64,
+    weight: f64,
+}
+
+/// Statistics of the realization updated as nodes change
+struct Statistics {
+    //number of nodes at or below each histogram threshold
+    below: Vec<usize>,
+    //number of pairs and sum of squared differences of each lag
+    lag_pairs: Vec<usize>,
+    lag_sums: Vec<f64>,
+    //sums of the primary, its square and its product with the secondary
+    sum_x: f64,
+    sum_xx: f64,
+    sum_xy: f64,
+}
+
+/// Simulated annealing post-processing of realizations
+/// free nodes are perturbed to lower an objective combining the mismatch of the histogram, of the
+/// variogram at a set of lags and of the correlation with a secondary grid, each component is scaled
+/// by its initial value, perturbations draw new values from the target histogram when it is set and
+/// swap the values of two nodes otherwise
+pub struct SimulatedAnnealing {
+    histogram: Option<HistogramTarget>,
+    variogram: Option<VariogramTarget>,
+    correlation: Option<CorrelationTarget>,
+    assigned_data: Option<Array3<Option<f32>>>,
+    schedule: CoolingSchedule,
+}
+
+impl Default for SimulatedAnnealing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimulatedAnnealing {
+    /// Create a new annealing without targets
+    pub fn new() -> Self {
+        Self {
+            histogram: None,
+            variogram: None,
+            correlation: None,
+            assigned_data: None,
+            schedule: CoolingSchedule::default(),
+        }
+    }
+
+    /// Match the histogram of reference values
+    /// repeated quantiles (e.g. of category codes) are merged into a single threshold
+    /// # Arguments
+    /// * `values` - Reference values (e.g. declustered data or category codes in their proportions)
+    /// * `n_quantiles` - Number of quantiles of the reference values used as thresholds
+    /// * `weight` - Weight of the component in the objective
+    pub fn with_histogram(mut self, values: &[f32], n_quantiles: usize, weight: f64) -> Self {
+        assert!(!values.is_empty(), "reference values are required");
+        let mut sorted = values.to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+
+        let mut thresholds = (1..=n_quantiles)
+            .map(|q| {
+                sorted[((q * sorted.len() + n_quantiles) / (n_quantiles + 1)).saturating_sub(1)]
+            })
+            .collect::<Vec<_>>();
+        thresholds.dedup();
+        let cdf = thresholds
+            .iter()
+            .map(|t| sorted.partition_point(|v| v <= t) as f64 / sorted.len() as f64)
+            .collect();
+
+        self.histogram = Some(HistogramTarget {
+            values: sorted,
+            thresholds,
+            cdf,
+            weight,
+        });
+        self
+    }
+
+    /// Match a variogram at a set of lags
+    /// # Arguments
+    /// * `lags` - Lags as offsets between grid nodes
+    /// * `gamma` - Target variogram of each lag
+    /// * `weight` - Weight of the component in the objective
+    pub fn with_variogram(mut self, lags: Vec<[isize; 3]>, gamma: &[f32], weight: f64) -> Self {
+        assert_eq!(lags.len(), gamma.len(), "a target is required for each lag");
+        assert!(
+            lags.iter().all(|lag| *lag != [0, 0, 0]),
+            "lags must not be zero"
+        );
+        self.variogram = Some(VariogramTarget {
+            lags,
+            gamma: gamma.iter().map(|g| *g as f64).collect(),
+            weight,
+        });
+        self
+    }
+
+    /// Match the correlation coefficient with a secondary variable
+    /// # Arguments
+    /// * `secondary` - Secondary value of each node, must match the shape of the annealed grid
+    /// * `correlation` - Target correlation coefficient
+    /// * `weight` - Weight of the component in the objective
+    pub fn with_correlation(
+        mut self,
+        secondary: Array3<f32>,
+        correlation: f32,
+        weight: f64,
+    ) -> Self {
+        self.correlation = Some(CorrelationTarget {
+            sum_y: secondary.iter().map(|y| *y as f64).sum(),
+            sum_yy: secondary.iter().map(|y| (*y as f64).powi(2)).sum(),
+            secondary,
+            correlation: correlation as f64,
+            weight,
+        });
+        self
+    }
+
+    /// Freeze the nodes holding conditioning data
+    /// frozen nodes keep their value and are never perturbed
+    /// # Arguments
+    /// * `assigned_data` - Value of each frozen node (see `DataAssignment`), must match the shape of the annealed grid
+    pub fn with_assigned_data(mut self, assigned_data: Array3<Option<f32>>) -> Self {
+        self.assigned_data = Some(assigned_data);
+        self
+    }
+
+    /// Cooling schedule of the annealing
+    pub fn with_schedule(mut self, schedule: CoolingSchedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Perturb the realization stored in a grid
+    /// # Arguments
+    /// * `grid` - Grid holding a value at every node
+    /// * `rng` - The random number generator
+    /// # Returns
+    /// Objective of the annealed realization relative to the initial objective
+    pub fn anneal<GDB>(&self, grid: &mut GDB, rng: &mut StdRng) -> f64
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        let shape = grid.shape();
+        let mut values = Array3::from_shape_fn(shape, |(i, j, k)| {
+            grid.data_at_ind(&[i, j, k])
+                .expect("annealing requires a value at every node")
+        });
+
+        let mut free = Vec::new();
+        if let Some(assigned_data) = &self.assigned_data {
+            assert_eq!(
+                assigned_data.shape(),
+                shape.as_slice(),
+                "assigned data must match the shape of the grid"
+            );
+        }
+        if let Some(correlation) = &self.correlation {
+            assert_eq!(
+                correlation.secondary.shape(),
+                shape.as_slice(),
+                "secondary values must match the shape of the grid"
+            );
+        }
+        for ((i, j, k), value) in values.indexed_iter_mut() {
+            match self.assigned_data.as_ref().and_then(|a| a[[i, j, k]]) {
+                Some(datum) => *value = datum,
+                None => free.push([i, j, k]),
+            }
+        }
+
+        let mut statistics = self.statistics(&values);
+        let scales = self.components(&statistics, values.len());
+        let scales = scales.map(|c| if c > 0.0 { c } else { 1.0 });
+        let mut objective = self.objective(&statistics, values.len(), &scales);
+        let initial_objective = objective;
+
+        let n_free = free.len();
+        let mut temperature = self.schedule.initial_temperature;
+        'cooling: for _ in 0..self.schedule.max_temperatures {
+            if n_free < 2 || initial_objective <= 0.0 {
+                break;
+            }
+
+            let mut accepted = 0;
+            for _ in 0..self.schedule.attempts_per_node * n_free {
+                if objective <= self.schedule.min_objective * initial_objective {
+                    break 'cooling;
+                }
+
+                //changes as (node, new value), a single change is repeated (the repeat is a no-op)
+                let changes = match &self.histogram {
+                    Some(histogram) => {
+                        let ind = free[rng.gen_range(0..n_free)];
+                        let value = histogram.values[rng.gen_range(0..histogram.values.len())];
+                        [(ind, value), (ind, value)]
+                    }
+                    None => {
+                        let first = free[rng.gen_range(0..n_free)];
+                        let second = free[rng.gen_range(0..n_free)];
+                        [(first, values[second]), (second, values[first])]
+                    }
+                };
+
+                //previous values are replayed in reverse order when rejected
+                let mut previous = changes;
+                for ((ind, value), previous) in changes.iter().zip(previous.iter_mut()) {
+                    *previous = (*ind, values[*ind]);
+                    self.change(&mut statistics, &mut values, *ind, *value);
+                }
+
+                let new_objective = self.objective(&statistics, values.len(), &scales);
+                let delta = new_objective - objective;
+                if delta <= 0.0 || rng.gen::<f64>() < (-delta / temperature).exp() {
+                    objective = new_objective;
+                    accepted += 1;
+                    if accepted >= self.schedule.accepted_per_node * n_free {
+                        break;
+                    }
+                } else {
+                    for (ind, value) in previous.iter().rev() {
+                        self.change(&mut statistics, &mut values, *ind, *value);
+                    }
+                }
+            }
+
+            //no perturbation is accepted anymore
+            if accepted == 0 {
+                break;
+            }
+            temperature *= self.schedule.reduction;
+        }
+
+        for ((i, j, k), value) in values.indexed_iter() {
+            grid.set_data_at_ind(&[i, j, k], *value);
+        }
+
+        if initial_objective > 0.0 {
+            objective / initial_objective
+        } else {
+            0.0
+        }
+    }
+
+    /// Statistics of a realization
+    fn statistics(&self, values: &Array3<f32>) -> Statistics {
+        let shape = values.dim();
+        let shape = [shape.0, shape.1, shape.2];
+
+        let below = self
+            .histogram
+            .as_ref()
+            .map(|histogram| {
+                histogram
+                    .thresholds
+                    .iter()
+                    .map(|t| values.iter().filter(|v| *v <= t).count())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let (mut lag_pairs, mut lag_sums) = (Vec::new(), Vec::new());
+        if let Some(variogram) = &self.variogram {
+            for lag in &variogram.lags {
+                let (mut pairs, mut sum) = (0, 0.0);
+                for ((i, j, k), value) in values.indexed_iter() {
+                    if let Some(other) =
+                        ind_at_offset(shape, [i as isize, j as isize, k as isize], *lag)
+                    {
+                        pairs += 1;
+                        sum += (*value as f64 - values[other] as f64).powi(2);
+                    }
+                }
+                lag_pairs.push(pairs);
+                lag_sums.push(sum);
+            }
+        }
+
+        let (mut sum_x, mut sum_xx, mut sum_xy) = (0.0, 0.0, 0.0);
+        if let Some(correlation) = &self.correlation {
+            for (value, secondary) in values.iter().zip(correlation.secondary.iter()) {
+                let (x, y) = (*value as f64, *secondary as f64);
+                sum_x += x;
+                sum_xx += x * x;
+                sum_xy += x * y;
+            }
+        }
+
+        Statistics {
+            below,
+            lag_pairs,
+            lag_sums,
+            sum_x,
+            sum_xx,
+            sum_xy,
+        }
+    }
+
+    /// Update the statistics and set the value of a node
+    fn change(
+        &self,
+        statistics: &mut Statistics,
+        values: &mut Array3<f32>,
+        ind: [usize; 3],
+        value: f32,
+    ) {
+        let old = values[ind];
+        if old == value {
+            return;
+        }
+
+        if let Some(histogram) = &self.histogram {
+            for (count, t) in statistics.below.iter_mut().zip(histogram.thresholds.iter()) {
+                match (old <= *t, value <= *t) {
+                    (true, false) => *count -= 1,
+                    (false, true) => *count += 1,
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some(variogram) = &self.variogram {
+            let shape = values.dim();
+            let shape = [shape.0, shape.1, shape.2];
+            for (sum, lag) in statistics.lag_sums.iter_mut().zip(variogram.lags.iter()) {
+                //the node is the tail of one pair and the head of another
+                for offset in [*lag, lag.map(|l| -l)] {
+                    if let Some(other) = ind_at_offset(shape, ind.map(|i| i as isize), offset) {
+                        let other = values[other] as f64;
+                        *sum += (value as f64 - other).powi(2) - (old as f64 - other).powi(2);
+                    }
+                }
+            }
+        }
+
+        if let Some(correlation) = &self.correlation {
+            let (x_old, x_new) = (old as f64, value as f64);
+            let y = correlation.secondary[ind] as f64;
+            statistics.sum_x += x_new - x_old;
+            statistics.sum_xx += x_new * x_new - x_old * x_old;
+            statistics.sum_xy += (x_new - x_old) * y;
+        }
+
+        values[ind] = value;
+    }
+
+    /// Mismatch of the histogram, variogram and correlation
+    fn components(&self, statistics: &Statistics, n: usize) -> [f64; 3] {
+        let n = n as f64;
+
+        let histogram = self.histogram.as_ref().map_or(0.0, |histogram| {
+            statistics
+                .below
+                .iter()
+                .zip(histogram.cdf.iter())
+                .map(|(count, cdf)| (*count as f64 / n - cdf).powi(2))
+                .sum()
+        });
+
+        let variogram = self.variogram.as_ref().map_or(0.0, |variogram| {
+            statistics
+                .lag_pairs
+                .iter()
+                .zip(statistics.lag_sums.iter())
+                .zip(variogram.gamma.iter())
+                .filter(|((pairs, _), _)| **pairs > 0)
+                .map(|((pairs, sum), target)| {
+                    let gamma = sum / (2.0 * *pairs as f64);
+                    let scale = if *target > 0.0 { target * target } else { 1.0 };
+                    (gamma - target).powi(2) / scale
+                })
+                .sum()
+        });
+
+        let correlation = self.correlation.as_ref().map_or(0.0, |correlation| {
+            let var_x = n * statistics.sum_xx - statistics.sum_x.powi(2);
+            let var_y = n * correlation.sum_yy - correlation.sum_y.powi(2);
+            let rho = if var_x > 0.0 && var_y > 0.0 {
+                (n * statistics.sum_xy - statistics.sum_x * correlation.sum_y)
+                    / (var_x * var_y).sqrt()
+            } else {
+                0.0
+            };
+            (rho - correlation.correlation).powi(2)
+        });
+
+        [histogram, variogram, correlation]
+    }
+
+    /// Weighted sum of the scaled components
+    fn objective(&self, statistics: &Statistics, n: usize, scales: &[f64; 3]) -> f64 {
+        let weights = [
+            self.histogram.as_ref().map_or(0.0, |h| h.weight),
+            self.variogram.as_ref().map_or(0.0, |v| v.weight),
+            self.correlation.as_ref().map_or(0.0, |c| c.weight),
+        ];
+        self.components(statistics, n)
+            .iter()
+            .zip(weights.iter())
+            .zip(scales.iter())
+            .map(|((component, weight), scale)| weight * component / scale)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion};
+    use rand::SeedableRng;
+
+    use crate::spatial_database::{
+        coordinate_system::{CoordinateSystem, GridSpacing},
+        gridded_databases::incomplete_grid::InCompleteGriddedDataBase,
+    };
+
+    use super::*;
+
+    fn grid(values: Array3<f32>) -> InCompleteGriddedDataBase<f32> {
+        InCompleteGriddedDataBase::new(
+            values.map(|v| Some(*v)),
+            GridSpacing {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+            CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity()),
+        )
+    }
+
+    fn schedule() -> CoolingSchedule {
+        CoolingSchedule {
+            initial_temperature: 1e-3,
+            attempts_per_node: 50,
+            accepted_per_node: 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn incremental_statistics() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut values = Array3::from_shape_fn((6, 5, 2), |_| rng.gen::<f32>());
+        let annealing = SimulatedAnnealing::new()
+            .with_histogram(&[0.1, 0.4, 0.5, 0.9], 3, 1.0)
+            .with_variogram(vec![[1, 0, 0], [0, 2, 1]], &[0.1, 0.2], 1.0)
+            .with_correlation(values.map(|v| v * 2.0 + 1.0), 0.5, 1.0);
+
+        let mut statistics = annealing.statistics(&values);
+        annealing.change(&mut statistics, &mut values, [2, 3, 1], 0.45);
+        annealing.change(&mut statistics, &mut values, [0, 0, 0], -1.0);
+
+        let expected = annealing.statistics(&values);
+        assert_eq!(statistics.below, expected.below);
+        for (sum, expected_sum) in statistics.lag_sums.iter().zip(expected.lag_sums.iter()) {
+            assert!((sum - expected_sum).abs() < 1e-9);
+        }
+        assert!((statistics.sum_xy - expected.sum_xy).abs() < 1e-9);
+    }
+
+    #[test]
+    fn anneal_variogram_with_frozen_nodes() {
+        //white noise is smoothed towards a continuous structure
+        let mut rng = StdRng::seed_from_u64(1);
+        let values = Array3::from_shape_fn((20, 20, 1), |_| rng.gen::<f32>());
+        let mut assigned = Array3::from_elem((20, 20, 1), None);
+        assigned[[5, 5, 0]] = Some(0.25);
+        assigned[[15, 2, 0]] = Some(0.75);
+
+        let lags = vec![[1, 0, 0], [0, 1, 0], [2, 0, 0], [0, 2, 0]];
+        let annealing = SimulatedAnnealing::new()
+            .with_variogram(lags.clone(), &[0.01, 0.01, 0.03, 0.03], 1.0)
+            .with_assigned_data(assigned)
+            .with_schedule(schedule());
+
+        let mut grid = grid(values.clone());
+        let objective = annealing.anneal(&mut grid, &mut rng);
+        assert!(objective < 0.3);
+
+        assert_eq!(grid.data_at_ind(&[5, 5, 0]), Some(0.25));
+        assert_eq!(grid.data_at_ind(&[15, 2, 0]), Some(0.75));
+
+        //swapping keeps the histogram of the free nodes
+        let mut before = values
+            .indexed_iter()
+            .filter(|((i, j, _), _)| (*i, *j) != (5, 5) && (*i, *j) != (15, 2))
+            .map(|(_, v)| *v)
+            .collect::<Vec<_>>();
+        let mut after = grid
+            .raw_grid
+            .grid
+            .indexed_iter()
+            .filter(|((i, j, _), _)| (*i, *j) != (5, 5) && (*i, *j) != (15, 2))
+            .map(|(_, v)| v.unwrap())
+            .collect::<Vec<_>>();
+        before.sort_by(|a, b| a.total_cmp(b));
+        after.sort_by(|a, b| a.total_cmp(b));
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn anneal_histogram_and_correlation() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let secondary = Array3::from_shape_fn((16, 16, 1), |(i, _, _)| i as f32);
+        let annealing = SimulatedAnnealing::new()
+            .with_histogram(&[0.0, 1.0, 2.0, 3.0], 3, 1.0)
+            .with_correlation(secondary, 0.8, 1.0)
+            .with_schedule(schedule());
+
+        let mut grid = grid(Array3::zeros((16, 16, 1)));
+        annealing.anneal(&mut grid, &mut rng);
+
+        let values = grid.raw_grid.grid.map(|v| v.unwrap());
+        for code in [0.0, 1.0, 2.0, 3.0] {
+            let proportion = values.iter().filter(|v| **v == code).count() as f32 / 256.0;
+            assert!((proportion - 0.25).abs() < 0.05);
+        }
+
+        let statistics = annealing.statistics(&values);
+        let [_, _, correlation] = annealing.components(&statistics, values.len());
+        assert!(correlation.sqrt() < 0.1);
+    }
+}
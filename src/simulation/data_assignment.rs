@@ -0,0 +1,154 @@
+use nalgebra::{distance, Point3};
+use ndarray::Array3;
+use ordered_float::OrderedFloat;
+
+use crate::spatial_database::gridded_databases::{ind_at_offset, GriddedDataBaseInterface};
+
+/// Value of a node when several data are assigned to it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MultipleDataRule {
+    /// Datum closest to the node
+    #[default]
+    Closest,
+    /// Average of the data
+    Average,
+    /// First datum in input order
+    First,
+}
+
+impl MultipleDataRule {
+    /// Combine the data assigned to a node
+    /// # Arguments
+    /// * `data` - Distance to the node and value of each datum (in input order)
+    fn combine(&self, data: &[(f32, f32)]) -> Option<f32> {
+        if data.is_empty() {
+            return None;
+        }
+
+        match self {
+            MultipleDataRule::Closest => data
+                .iter()
+                .min_by_key(|(dist, _)| OrderedFloat(*dist))
+                .map(|(_, value)| *value),
+            MultipleDataRule::Average => {
+                Some(data.iter().map(|(_, value)| value).sum::<f32>() / data.len() as f32)
+            }
+            MultipleDataRule::First => data.first().map(|(_, value)| *value),
+        }
+    }
+}
+
+/// Migration of conditioning data to the nearest node of a simulation grid
+/// nodes holding data are frozen: they are skipped on the path and reproduce the data exactly
+/// # Members
+/// * `tolerance` - Maximum distance between a datum and its node
+/// * `rule` - Value of a node when several data are assigned to it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DataAssignment {
+    pub tolerance: f32,
+    pub rule: MultipleDataRule,
+}
+
+impl DataAssignment {
+    /// Create a data assignment
+    /// # Arguments
+    /// * `tolerance` - Maximum distance between a datum and its node
+    pub fn new(tolerance: f32) -> Self {
+        Self {
+            tolerance,
+            rule: MultipleDataRule::default(),
+        }
+    }
+
+    /// Set the value of a node when several data are assigned to it
+    pub fn with_rule(mut self, rule: MultipleDataRule) -> Self {
+        self.rule = rule;
+        self
+    }
+
+    /// Assign data to the nodes of a grid
+    /// # Arguments
+    /// * `grid` - The simulation grid
+    /// * `points` - Location of the data
+    /// * `values` - Values of the data (must be normalized like the conditioning data)
+    /// # Returns
+    /// Value of each frozen node (None for nodes to simulate)
+    pub fn assign<GDB>(
+        &self,
+        grid: &GDB,
+        points: &[Point3<f32>],
+        values: &[f32],
+    ) -> Array3<Option<f32>>
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        let shape = grid.shape();
+        let mut node_data = Array3::from_elem(shape, Vec::new());
+
+        for (point, value) in points.iter().zip(values.iter()) {
+            //data outside of the grid are not assigned
+            let Some(ind) = ind_at_offset(shape, grid.coord_to_nearest_ind(point), [0, 0, 0])
+            else {
+                continue;
+            };
+
+            let dist = distance(point, &grid.ind_to_point(&ind.map(|i| i as isize)));
+            if dist <= self.tolerance {
+                node_data[ind].push((dist, *value));
+            }
+        }
+
+        node_data.map(|data| self.rule.combine(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion};
+
+    use crate::spatial_database::{
+        coordinate_system::{CoordinateSystem, GridSpacing},
+        gridded_databases::incomplete_grid::InCompleteGriddedDataBase,
+    };
+
+    use super::*;
+
+    #[test]
+    fn assign_to_nearest_node() {
+        let grid = InCompleteGriddedDataBase::<f32>::new(
+            Array3::from_elem((4, 4, 1), None),
+            GridSpacing {
+                x: 2.0,
+                y: 2.0,
+                z: 2.0,
+            },
+            CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity()),
+        );
+
+        let points = vec![
+            Point3::new(2.3, 0.0, 0.0),
+            Point3::new(1.9, 0.0, 0.0),
+            Point3::new(4.0, 4.5, 0.0),
+            Point3::new(5.1, 6.0, 0.0),
+            Point3::new(-3.0, 0.0, 0.0),
+        ];
+        let values = vec![1.0, 2.0, 3.0, 4.0, 5.0];
+
+        let assignment = DataAssignment::new(0.6);
+        let assigned = assignment.assign(&grid, &points, &values);
+        assert_eq!(assigned[[1, 0, 0]], Some(2.0));
+        assert_eq!(assigned[[2, 2, 0]], Some(3.0));
+        //beyond the tolerance or outside of the grid
+        assert_eq!(assigned[[3, 3, 0]], None);
+        assert_eq!(assigned.iter().filter(|v| v.is_some()).count(), 2);
+
+        let average = assignment.with_rule(MultipleDataRule::Average);
+        assert_eq!(
+            average.assign(&grid, &points, &values)[[1, 0, 0]],
+            Some(1.5)
+        );
+
+        let first = assignment.with_rule(MultipleDataRule::First);
+        assert_eq!(first.assign(&grid, &points, &values)[[1, 0, 0]], Some(1.0));
+    }
+}
@@ -0,0 +1,511 @@
+use nalgebra::{DMatrix, DVector, Point3, Vector3};
+use rand::{rngs::StdRng, Rng};
+use rand_distr::StandardNormal;
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    geometry::Geometry,
+    spatial_database::{gridded_databases::GriddedDataBaseInterface, SpatialQueryable},
+    variography::model_variograms::VariogramModel,
+};
+
+use super::{
+    multigrid::node_level,
+    path::{level_offsets, level_query_engines, random_path},
+    realizations::Simulation,
+    seeding::SeedSequence,
+};
+
+/// Diagonal regularization of singular block systems, relative to the sill
+const BLOCK_SYSTEM_JITTER: f32 = 1e-4;
+
+/// Direct block simulation parameters
+/// the point support neighbourhood is set by the search of the conditioning data
+/// # Members
+/// * `max_octant_sim_data` - Maximum number of previously simulated blocks per octant
+pub struct DBSIMParameters {
+    pub max_octant_sim_data: usize,
+}
+
+/// Path and block systems of a simulation
+pub struct DBSIMPlan {
+    sequential_data: Vec<BlockStep>,
+}
+
+/// Block, conditioning values, simulated neighbouring blocks and the system of the points of the block
+struct BlockStep {
+    ind: [usize; 3],
+    cond_values: Vec<f32>,
+    sim_inds: Vec<[usize; 3]>,
+    /// Kriging weights of the data (rows) for every discretization point (columns)
+    weights: DMatrix<f32>,
+    /// Square root of the conditional covariance of the discretization points
+    factor: DMatrix<f32>,
+}
+
+/// Direct block sequential simulation
+/// every node of the grid is the centre of a block the size of the grid spacing, the points
+/// discretizing a block are simulated jointly from the point data and the previously simulated
+/// blocks, the block value is the average of its points
+/// * point to block and block to block covariances are averages of the point covariances over the
+///   discretization points
+/// * values are averaged in the simulated (normal score) space
+pub struct DBSIM<S, V, G>
+where
+    S: SpatialQueryable<f32, G>,
+{
+    conditioning_data: S,
+    variogram_model: V,
+    dbsim_parameters: DBSIMParameters,
+    discretization: [usize; 3],
+    multigrid_levels: usize,
+    phantom: std::marker::PhantomData<G>,
+}
+
+impl<S, V, G> DBSIM<S, V, G>
+where
+    S: SpatialQueryable<f32, G> + Sync,
+    V: VariogramModel + Sync,
+    G: Geometry + Sync + Clone,
+{
+    /// Create a new direct block simulation
+    /// # Arguments
+    /// * `conditioning_data` - Point support data to condition the blocks on (must be normalized)
+    /// * `variogram_model` - Point support variogram model
+    /// * `dbsim_parameters` - The DBSIM parameters to use
+    pub fn new(
+        conditioning_data: S,
+        variogram_model: V,
+        dbsim_parameters: DBSIMParameters,
+    ) -> Self {
+        Self {
+            conditioning_data,
+            variogram_model,
+            dbsim_parameters,
+            discretization: [2, 2, 2],
+            multigrid_levels: 1,
+            phantom: std::marker::PhantomData,
+        }
+    }
+
+    /// Number of points discretizing a block along each axis of the grid (2 x 2 x 2 by default)
+    pub fn with_discretization(mut self, discretization: [usize; 3]) -> Self {
+        assert!(
+            discretization.iter().all(|n| *n > 0),
+            "at least one discretization point per axis is required"
+        );
+        self.discretization = discretization;
+        self
+    }
+
+    /// Visit coarse sub-grids of blocks before the full grid (see `SGS::with_multigrid`)
+    pub fn with_multigrid(mut self, levels: usize) -> Self {
+        assert!(levels > 0, "at least one multigrid level is required");
+        self.multigrid_levels = levels;
+        self
+    }
+
+    /// Offsets of the points discretizing a block from its centre
+    pub fn discretization_offsets<GDB>(&self, grid: &GDB) -> Vec<Vector3<f32>>
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        let spacing = grid.grid_spacing();
+        let rotation = grid.coordinate_system().rotation;
+        let [nx, ny, nz] = self.discretization;
+
+        //points sit at the centres of a regular subdivision of the block
+        let offset = |i: usize, n: usize, size: f32| ((i as f32 + 0.5) / n as f32 - 0.5) * size;
+        (0..nx)
+            .flat_map(|i| (0..ny).flat_map(move |j| (0..nz).map(move |k| [i, j, k])))
+            .map(|[i, j, k]| {
+                rotation
+                    * Vector3::new(
+                        offset(i, nx, spacing.x),
+                        offset(j, ny, spacing.y),
+                        offset(k, nz, spacing.z),
+                    )
+            })
+            .collect()
+    }
+
+    /// Average covariance between a point and the points of a block
+    #[inline(always)]
+    fn point_block_covariance(
+        &self,
+        point: &Point3<f32>,
+        block: &Point3<f32>,
+        offsets: &[Vector3<f32>],
+    ) -> f32 {
+        offsets
+            .iter()
+            .map(|offset| self.variogram_model.covariogram(block + offset - point))
+            .sum::<f32>()
+            / offsets.len() as f32
+    }
+
+    /// Average covariance between the points of two blocks
+    #[inline(always)]
+    fn block_block_covariance(
+        &self,
+        block: &Point3<f32>,
+        other_block: &Point3<f32>,
+        offsets: &[Vector3<f32>],
+    ) -> f32 {
+        offsets
+            .iter()
+            .map(|offset| self.point_block_covariance(&(block + offset), other_block, offsets))
+            .sum::<f32>()
+            / offsets.len() as f32
+    }
+
+    /// Shuffle the path and solve the system of every block
+    fn build_plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> DBSIMPlan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        let (path, simulation_order) = random_path(grid, None, self.multigrid_levels, rng);
+        let level_offsets = level_offsets(
+            self.conditioning_data.geometry(),
+            grid,
+            self.dbsim_parameters.max_octant_sim_data,
+            self.multigrid_levels,
+        );
+        let sim_qes = level_query_engines(
+            self.conditioning_data.geometry(),
+            grid,
+            self.dbsim_parameters.max_octant_sim_data,
+            &level_offsets,
+        );
+        let offsets = self.discretization_offsets(grid);
+
+        let sequential_data = path
+            .par_iter()
+            .map(|ind| {
+                let ind = [ind.0, ind.1, ind.2];
+                let block = grid.ind_to_point(&ind.map(|i| i as isize));
+                let sim_qe = &sim_qes[node_level(ind, self.multigrid_levels)];
+
+                //point support data and previously simulated blocks
+                let (cond_values, cond_points) = self.conditioning_data.query(&block);
+                let (sim_inds, sim_points) = sim_qe
+                    .nearest_inds_and_points_masked(&block, |neighbor_ind| {
+                        simulation_order[neighbor_ind] < simulation_order[ind]
+                    });
+
+                let (weights, factor) =
+                    self.block_system(&block, &cond_points, &sim_points, &offsets);
+
+                BlockStep {
+                    ind,
+                    cond_values,
+                    sim_inds,
+                    weights,
+                    factor,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        DBSIMPlan { sequential_data }
+    }
+
+    /// Solve the kriging system of the discretization points of a block
+    /// # Arguments
+    /// * `block` - Centre of the block
+    /// * `cond_points` - Point support data
+    /// * `sim_points` - Centres of the previously simulated blocks
+    /// * `offsets` - Discretization offsets of a block
+    /// # Returns
+    /// The kriging weights and the square root of the conditional covariance of the points
+    fn block_system(
+        &self,
+        block: &Point3<f32>,
+        cond_points: &[Point3<f32>],
+        sim_points: &[Point3<f32>],
+        offsets: &[Vector3<f32>],
+    ) -> (DMatrix<f32>, DMatrix<f32>) {
+        let n_cond = cond_points.len();
+        let n = n_cond + sim_points.len();
+        let m = offsets.len();
+
+        //covariance between data of point and block support
+        let cov_mat = DMatrix::from_fn(n, n, |i, j| match (i < n_cond, j < n_cond) {
+            (true, true) => self
+                .variogram_model
+                .covariogram(cond_points[j] - cond_points[i]),
+            (true, false) => {
+                self.point_block_covariance(&cond_points[i], &sim_points[j - n_cond], offsets)
+            }
+            (false, true) => {
+                self.point_block_covariance(&cond_points[j], &sim_points[i - n_cond], offsets)
+            }
+            (false, false) => self.block_block_covariance(
+                &sim_points[i - n_cond],
+                &sim_points[j - n_cond],
+                offsets,
+            ),
+        });
+
+        //covariance between the data and the discretization points
+        let targets = offsets
+            .iter()
+            .map(|offset| block + offset)
+            .collect::<Vec<_>>();
+        let cov_rhs = DMatrix::from_fn(n, m, |i, k| {
+            if i < n_cond {
+                self.variogram_model
+                    .covariogram(targets[k] - cond_points[i])
+            } else {
+                self.point_block_covariance(&targets[k], &sim_points[i - n_cond], offsets)
+            }
+        });
+
+        //duplicate or nearly collocated data make the covariance singular, the diagonal is
+        //regularized and the neighbours are dropped if it still cannot be factorized
+        let weights = if n == 0 {
+            DMatrix::zeros(0, m)
+        } else {
+            let jitter = BLOCK_SYSTEM_JITTER * self.variogram_model.c_0();
+            cov_mat
+                .clone()
+                .cholesky()
+                .or_else(|| (cov_mat + DMatrix::from_diagonal_element(n, n, jitter)).cholesky())
+                .map_or_else(|| DMatrix::zeros(n, m), |cholesky| cholesky.solve(&cov_rhs))
+        };
+
+        //conditional covariance of the discretization points
+        let point_cov = DMatrix::from_fn(m, m, |k, l| {
+            self.variogram_model.covariogram(targets[l] - targets[k])
+        });
+        let cond_cov = point_cov - cov_rhs.tr_mul(&weights);
+
+        //symmetric square root, negative eigenvalues are round off
+        let eigen = cond_cov.symmetric_eigen();
+        let sqrt_values = eigen.eigenvalues.map(|value| value.max(0.0).sqrt());
+        let factor = &eigen.eigenvectors * DMatrix::from_diagonal(&sqrt_values);
+
+        (weights, factor)
+    }
+
+    /// Simulate the blocks of a plan in path order
+    fn apply_plan<GDB>(&self, plan: &DBSIMPlan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        for (path_ind, step) in plan.sequential_data.iter().enumerate() {
+            let values = step
+                .cond_values
+                .iter()
+                .copied()
+                .chain(
+                    step.sim_inds
+                        .iter()
+                        .map(|ind| grid.data_at_ind(ind).expect("No value at ind")),
+                )
+                .collect::<Vec<_>>();
+
+            //points of the block are drawn jointly from their conditional distribution
+            let mut rng = seeds.stream(path_ind as u64);
+            let noise = DVector::from_fn(step.factor.ncols(), |_, _| {
+                rng.sample::<f32, _>(StandardNormal)
+            });
+            let points = step.weights.tr_mul(&DVector::from_vec(values)) + &step.factor * noise;
+
+            grid.set_data_at_ind(&step.ind, points.mean());
+        }
+    }
+}
+
+impl<S, V, G> Simulation for DBSIM<S, V, G>
+where
+    S: SpatialQueryable<f32, G> + Sync,
+    V: VariogramModel + Sync,
+    G: Geometry + Sync + Clone,
+{
+    type Plan = DBSIMPlan;
+
+    fn plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> Self::Plan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.build_plan(grid, rng)
+    }
+
+    fn realize<GDB>(&self, plan: &Self::Plan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.apply_plan(plan, grid, seeds);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion};
+    use ndarray::Array3;
+
+    use crate::{
+        geometry::ellipsoid::Ellipsoid,
+        simulation::realizations::simulate_on_threads,
+        spatial_database::{
+            coordinate_system::{CoordinateSystem, GridSpacing},
+            gridded_databases::{
+                gridded_data_base_query_engine::GriddedDataBaseOctantQueryEngine,
+                incomplete_grid::InCompleteGriddedDataBase,
+            },
+        },
+        variography::model_variograms::spherical::SphericalVariogram,
+    };
+
+    use super::*;
+
+    #[test]
+    fn block_covariances_by_discretization() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 4.0,
+            y: 4.0,
+            z: 1.0,
+        };
+        let cond_db =
+            InCompleteGriddedDataBase::new(Array3::from_elem((4, 4, 1), None), spacing, cs);
+        let vgram = SphericalVariogram::new(Vector3::new(10.0, 10.0, 10.0), 1.0, 0.0, cs);
+
+        let dbsim = |discretization: [usize; 3]| {
+            DBSIM::new(
+                GriddedDataBaseOctantQueryEngine::new(
+                    Ellipsoid::new(10.0, 10.0, 10.0, cs),
+                    &cond_db,
+                    4,
+                ),
+                &vgram,
+                DBSIMParameters {
+                    max_octant_sim_data: 4,
+                },
+            )
+            .with_discretization(discretization)
+        };
+
+        let block = Point3::new(0.0, 0.0, 0.0);
+        let other_block = Point3::new(4.0, 0.0, 0.0);
+
+        //a single point per block reduces to point covariances
+        let point_dbsim = dbsim([1, 1, 1]);
+        let offsets = point_dbsim.discretization_offsets(&cond_db);
+        assert_eq!(offsets, vec![Vector3::zeros()]);
+        assert_eq!(
+            point_dbsim.block_block_covariance(&block, &other_block, &offsets),
+            vgram.covariogram(other_block - block)
+        );
+
+        //block covariances are averages over the discretization points
+        let block_dbsim = dbsim([4, 4, 1]);
+        let offsets = block_dbsim.discretization_offsets(&cond_db);
+        assert_eq!(offsets.len(), 16);
+        let mut expected = 0.0;
+        for offset in offsets.iter() {
+            for other_offset in offsets.iter() {
+                expected += vgram.covariogram(other_block + other_offset - block - offset);
+            }
+        }
+        expected /= 256.0;
+        let covariance = block_dbsim.block_block_covariance(&block, &other_block, &offsets);
+        assert!((covariance - expected).abs() < 1e-5);
+
+        //block variance is lower than the point variance
+        let variance = block_dbsim.block_block_covariance(&block, &block, &offsets);
+        assert!(variance < vgram.c_0() && variance > 0.5);
+    }
+
+    #[test]
+    fn block_system_with_duplicate_data() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 2.0,
+            y: 2.0,
+            z: 1.0,
+        };
+        let cond_db =
+            InCompleteGriddedDataBase::new(Array3::from_elem((4, 4, 1), None), spacing, cs);
+        let vgram = SphericalVariogram::new(Vector3::new(10.0, 10.0, 10.0), 1.0, 0.0, cs);
+        let dbsim = DBSIM::new(
+            GriddedDataBaseOctantQueryEngine::new(
+                Ellipsoid::new(10.0, 10.0, 10.0, cs),
+                &cond_db,
+                4,
+            ),
+            &vgram,
+            DBSIMParameters {
+                max_octant_sim_data: 4,
+            },
+        );
+        let offsets = dbsim.discretization_offsets(&cond_db);
+        let block = Point3::new(0.0, 0.0, 0.0);
+        let datum = Point3::new(1.0, 2.0, 0.0);
+
+        //collocated samples share the weight of a single sample
+        let (weights, factor) = dbsim.block_system(&block, &[datum, datum], &[], &offsets);
+        let (single_weights, _) = dbsim.block_system(&block, &[datum], &[], &offsets);
+        assert!(weights.iter().chain(factor.iter()).all(|v| v.is_finite()));
+        for k in 0..offsets.len() {
+            let shared = weights[(0, k)] + weights[(1, k)];
+            assert!((shared - single_weights[(0, k)]).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn dbsim_block_variance() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 3.0,
+            y: 3.0,
+            z: 1.0,
+        };
+
+        let mut cond_grid = Array3::<Option<f32>>::from_elem((10, 10, 1), None);
+        cond_grid[[2, 3, 0]] = Some(1.5);
+        cond_grid[[7, 6, 0]] = Some(-1.0);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid, spacing, cs);
+
+        let vgram = SphericalVariogram::new(Vector3::new(8.0, 8.0, 8.0), 1.0, 0.0, cs);
+        let dbsim = DBSIM::new(
+            GriddedDataBaseOctantQueryEngine::new(
+                Ellipsoid::new(12.0, 12.0, 12.0, cs),
+                &cond_db,
+                4,
+            ),
+            vgram,
+            DBSIMParameters {
+                max_octant_sim_data: 4,
+            },
+        )
+        .with_discretization([3, 3, 1]);
+
+        let realization = |threads: usize, seed: u64| {
+            let mut sim_db =
+                InCompleteGriddedDataBase::new(Array3::from_elem((10, 10, 1), None), spacing, cs);
+            simulate_on_threads(&dbsim, &mut sim_db, threads, seed);
+            sim_db.raw_grid.grid.map(|value| value.unwrap())
+        };
+
+        assert_eq!(realization(1, 5), realization(4, 5));
+
+        //blocks far from the data have the block variance of the variogram
+        let values = (0..40)
+            .flat_map(|seed| {
+                let grid = realization(4, seed);
+                [grid[[0, 9, 0]], grid[[9, 0, 0]]]
+            })
+            .collect::<Vec<_>>();
+        assert!(values.iter().all(|value| value.is_finite()));
+        let n = values.len() as f32;
+        let mean = values.iter().sum::<f32>() / n;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n;
+
+        let offsets = dbsim.discretization_offsets(&cond_db);
+        let origin = Point3::origin();
+        let block_variance = dbsim.block_block_covariance(&origin, &origin, &offsets);
+        assert!(block_variance < 0.9);
+        assert!((variance - block_variance).abs() < 0.25);
+    }
+}
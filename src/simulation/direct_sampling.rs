@@ -0,0 +1,557 @@
+use ndarray::Array3;
+use rand::{rngs::StdRng, Rng};
+
+use crate::{
+    geometry::{template::Template, Geometry},
+    spatial_database::gridded_databases::{
+        complete_grid::CompleteGriddedDataBase,
+        gridded_data_base_query_engine_mut::GriddedDataBaseOctantQueryEngineMut,
+        incomplete_grid::InCompleteGriddedDataBase, GriddedDataBaseInterface,
+    },
+};
+
+use super::{path::random_path, realizations::Simulation, seeding::SeedSequence};
+
+pub struct DSParameters {
+    /// Maximum number of data event nodes per octant
+    pub max_octant_size: usize,
+    /// Distance below which a training image location is accepted, in [0, 1]
+    pub threshold: f32,
+    /// Maximum fraction of the training image scanned for a node, in (0, 1]
+    pub scan_fraction: f32,
+}
+
+/// Values of a training image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DSVariable {
+    Continuous,
+    Categorical,
+}
+
+/// Training image of a variable and its weight in the data event distance
+struct TrainingVariable {
+    training_image: CompleteGriddedDataBase<f32>,
+    variable: DSVariable,
+    weight: f32,
+    range: f32,
+}
+
+impl TrainingVariable {
+    fn new(
+        training_image: CompleteGriddedDataBase<f32>,
+        variable: DSVariable,
+        weight: f32,
+    ) -> Self {
+        let (min, max) = training_image
+            .raw_grid
+            .grid
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(min, max), v| {
+                (min.min(*v), max.max(*v))
+            });
+        Self {
+            training_image,
+            variable,
+            weight,
+            range: if max > min { max - min } else { 1.0 },
+        }
+    }
+
+    /// Mismatch in [0, 1] between a data event value and a training image value
+    #[inline(always)]
+    fn mismatch(&self, value: f32, ti_value: f32) -> f32 {
+        match self.variable {
+            DSVariable::Continuous => ((value - ti_value).abs() / self.range).min(1.0),
+            DSVariable::Categorical => {
+                if value == ti_value {
+                    0.0
+                } else {
+                    1.0
+                }
+            }
+        }
+    }
+}
+
+/// Path of a simulation over all nodes of a grid
+pub struct DSPlan {
+    path: Vec<[usize; 3]>,
+}
+
+/// Direct sampling multiple-point simulation
+/// the training image is scanned from a random location for a data event close to the data event of
+/// the simulated node, the value of the first location below the distance threshold (or of the closest
+/// location once the scan fraction is exhausted) is pasted
+/// * nodes informed before the simulation are hard data
+/// * covariables are simulated jointly, their values at the accepted location are pasted with the
+///   primary value, covariable data at a node are part of its data event
+/// * as a `Simulation` only the primary variable is kept, covariables are simulated in temporary grids
+pub struct DirectSampling<G> {
+    variables: Vec<TrainingVariable>,
+    geometry: G,
+    ds_parameters: DSParameters,
+}
+
+impl<G> DirectSampling<G>
+where
+    G: Geometry + Clone,
+{
+    /// Create a new direct sampling simulation
+    /// # Arguments
+    /// * `training_image` - Training image of the simulated variable
+    /// * `variable` - Continuous or categorical values
+    /// * `geometry` - Search geometry of the data events
+    /// * `ds_parameters` - The direct sampling parameters to use
+    pub fn new(
+        training_image: CompleteGriddedDataBase<f32>,
+        variable: DSVariable,
+        geometry: G,
+        ds_parameters: DSParameters,
+    ) -> Self {
+        assert!(
+            ds_parameters.scan_fraction > 0.0 && ds_parameters.scan_fraction <= 1.0,
+            "scan fraction must be in (0, 1]"
+        );
+        Self {
+            variables: vec![TrainingVariable::new(training_image, variable, 1.0)],
+            geometry,
+            ds_parameters,
+        }
+    }
+
+    /// Simulate a covariable jointly with the simulated variable
+    /// # Arguments
+    /// * `training_image` - Training image of the covariable, with the shape of the primary training image
+    /// * `variable` - Continuous or categorical values
+    /// * `weight` - Weight of the covariable in the data event distance (the primary weight is 1)
+    pub fn with_covariable(
+        mut self,
+        training_image: CompleteGriddedDataBase<f32>,
+        variable: DSVariable,
+        weight: f32,
+    ) -> Self {
+        assert_eq!(
+            training_image.shape(),
+            self.variables[0].training_image.shape(),
+            "training images must have the same shape"
+        );
+        self.variables
+            .push(TrainingVariable::new(training_image, variable, weight));
+        self
+    }
+
+    /// Simulate the uninformed nodes of the grids of the variable and covariables
+    /// # Arguments
+    /// * `grids` - Grid of the simulated variable followed by a grid per covariable
+    /// * `rng` - The random number generator
+    pub fn simulate_grids<GDB>(&self, grids: &mut [GDB], rng: &mut StdRng)
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        assert_eq!(
+            grids.len(),
+            self.variables.len(),
+            "one grid per variable is required"
+        );
+        let seeds = SeedSequence::from_rng(rng);
+
+        let (primary, covariables) = grids.split_first_mut().expect("one grid per variable");
+        let shape = primary.shape();
+        assert!(
+            covariables.iter().all(|grid| grid.shape() == shape),
+            "grids must have the same shape"
+        );
+
+        let plan = self.build_plan(&*primary, rng);
+        self.apply_plan(&plan, primary, covariables, seeds);
+    }
+
+    /// Shuffle the path over all nodes of the grid
+    fn build_plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> DSPlan
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        let (path, _) = random_path(grid, None, 1, rng);
+        DSPlan {
+            path: path.into_iter().map(|ind| [ind.0, ind.1, ind.2]).collect(),
+        }
+    }
+
+    /// Simulate the nodes of a plan in path order
+    /// nodes of the primary grid informed before the simulation are hard data and are skipped
+    fn apply_plan<GDB, CDB>(
+        &self,
+        plan: &DSPlan,
+        primary: &mut GDB,
+        covariables: &mut [CDB],
+        seeds: SeedSequence,
+    ) where
+        GDB: GriddedDataBaseInterface<f32>,
+        CDB: GriddedDataBaseInterface<f32>,
+    {
+        let n_ti = self.variables[0].training_image.raw_grid.grid.len();
+        let n_scan =
+            ((self.ds_parameters.scan_fraction * n_ti as f32).ceil() as usize).clamp(1, n_ti);
+
+        let mut query_engine = GriddedDataBaseOctantQueryEngineMut::new(
+            self.geometry.clone(),
+            primary,
+            self.ds_parameters.max_octant_size,
+        );
+
+        for (path_ind, &ind) in plan.path.iter().enumerate() {
+            if query_engine.db.data_at_ind(&ind).is_some() {
+                continue;
+            }
+
+            //data event of the primary variable and collocated covariables
+            let (neighbor_inds, values) = query_engine.nearest_inds_and_values_to_ind(&ind);
+            let mut lags = neighbor_inds
+                .iter()
+                .map(|neighbor| [0, 1, 2].map(|axis| neighbor[axis] as isize - ind[axis] as isize))
+                .collect::<Vec<_>>();
+            let mut data_event = vec![values.into_iter().map(Some).collect::<Vec<_>>()];
+            data_event.extend(covariables.iter().map(|grid| {
+                neighbor_inds
+                    .iter()
+                    .map(|neighbor| grid.data_at_ind(neighbor))
+                    .collect::<Vec<_>>()
+            }));
+
+            //covariable data at the node condition the pasted values
+            if covariables
+                .iter()
+                .any(|grid| grid.data_at_ind(&ind).is_some())
+            {
+                lags.push([0, 0, 0]);
+                data_event[0].push(None);
+                for (values, grid) in data_event[1..].iter_mut().zip(covariables.iter()) {
+                    values.push(grid.data_at_ind(&ind));
+                }
+            }
+
+            let ti_ind = self.scan(
+                &lags,
+                &data_event,
+                n_scan,
+                &mut seeds.stream(path_ind as u64),
+            );
+
+            query_engine
+                .db
+                .set_data_at_ind(&ind, self.variables[0].training_image.raw_grid.grid[ti_ind]);
+            for (grid, variable) in covariables.iter_mut().zip(self.variables[1..].iter()) {
+                //collocated covariable data are kept
+                if grid.data_at_ind(&ind).is_none() {
+                    grid.set_data_at_ind(&ind, variable.training_image.raw_grid.grid[ti_ind]);
+                }
+            }
+        }
+    }
+
+    /// Scan the training image from a random location for a matching data event
+    /// # Arguments
+    /// * `lags` - Offsets of the data event nodes from the simulated node
+    /// * `data_event` - Values of each variable at the data event nodes
+    /// * `n_scan` - Maximum number of scanned locations
+    /// # Returns
+    /// The accepted training image location
+    fn scan(
+        &self,
+        lags: &[[isize; 3]],
+        data_event: &[Vec<Option<f32>>],
+        n_scan: usize,
+        rng: &mut StdRng,
+    ) -> [usize; 3] {
+        let shape = self.variables[0].training_image.shape();
+        let n_ti = shape.iter().product::<usize>();
+        let start = rng.gen_range(0..n_ti);
+
+        let mut best = (f32::MAX, [0; 3]);
+        for scanned in 0..n_scan {
+            let flat_ind = (start + scanned) % n_ti;
+            let ti_ind = [
+                flat_ind / (shape[1] * shape[2]),
+                flat_ind / shape[2] % shape[1],
+                flat_ind % shape[2],
+            ];
+
+            let distance = self.distance(ti_ind, lags, data_event);
+            if distance < best.0 {
+                best = (distance, ti_ind);
+            }
+            if distance <= self.ds_parameters.threshold {
+                break;
+            }
+        }
+
+        best.1
+    }
+
+    /// Weighted mean mismatch between a data event and the training images around a location
+    /// data event nodes falling outside the training images are ignored
+    fn distance(
+        &self,
+        ti_ind: [usize; 3],
+        lags: &[[isize; 3]],
+        data_event: &[Vec<Option<f32>>],
+    ) -> f32 {
+        if lags.is_empty() {
+            return 0.0;
+        }
+
+        let shape = self.variables[0].training_image.shape();
+        let mut distance = 0.0;
+        let mut weights = 0.0;
+        for (variable, values) in self.variables.iter().zip(data_event.iter()) {
+            let mut mismatch = 0.0;
+            let mut n_compared = 0;
+            for (lag, value) in lags.iter().zip(values.iter()) {
+                let Some(value) = value else {
+                    continue;
+                };
+                let Some(neighbor_ind) = Template::get_ind(&ti_ind, *lag, shape) else {
+                    continue;
+                };
+                mismatch +=
+                    variable.mismatch(*value, variable.training_image.raw_grid.grid[neighbor_ind]);
+                n_compared += 1;
+            }
+
+            if n_compared > 0 {
+                distance += variable.weight * mismatch / n_compared as f32;
+                weights += variable.weight;
+            }
+        }
+
+        //no data event node falls in the training image
+        if weights == 0.0 {
+            return 1.0;
+        }
+        distance / weights
+    }
+}
+
+impl<G> Simulation for DirectSampling<G>
+where
+    G: Geometry + Clone,
+{
+    type Plan = DSPlan;
+
+    fn plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> Self::Plan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.build_plan(grid, rng)
+    }
+
+    fn realize<GDB>(&self, plan: &Self::Plan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        let mut covariables = self.variables[1..]
+            .iter()
+            .map(|_| {
+                InCompleteGriddedDataBase::new(
+                    Array3::from_elem(grid.shape(), None),
+                    grid.grid_spacing(),
+                    grid.coordinate_system(),
+                )
+            })
+            .collect::<Vec<_>>();
+        self.apply_plan(plan, grid, &mut covariables, seeds);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion};
+    use rand::SeedableRng;
+
+    use crate::{
+        geometry::ellipsoid::Ellipsoid,
+        simulation::realizations::Realizations,
+        spatial_database::coordinate_system::{CoordinateSystem, GridSpacing},
+    };
+
+    use super::*;
+
+    fn cs() -> CoordinateSystem {
+        CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity())
+    }
+
+    fn training_image<F>(shape: (usize, usize, usize), f: F) -> CompleteGriddedDataBase<f32>
+    where
+        F: FnMut((usize, usize, usize)) -> f32,
+    {
+        CompleteGriddedDataBase::new(
+            Array3::from_shape_fn(shape, f),
+            GridSpacing::new(1.0, 1.0, 1.0),
+            cs(),
+        )
+    }
+
+    fn grid(shape: (usize, usize, usize)) -> InCompleteGriddedDataBase<f32> {
+        InCompleteGriddedDataBase::new(
+            Array3::from_elem(shape, None),
+            GridSpacing::new(1.0, 1.0, 1.0),
+            cs(),
+        )
+    }
+
+    #[test]
+    fn ds_continuous_2d() {
+        let ti = training_image((40, 40, 1), |(i, j, _)| {
+            (i as f32 / 5.0).sin() * (j as f32 / 8.0).cos()
+        });
+        let (min, max) = (-1.0, 1.0);
+        let ds = DirectSampling::new(
+            ti,
+            DSVariable::Continuous,
+            Ellipsoid::new(5.0, 5.0, 1.0, cs()),
+            DSParameters {
+                max_octant_size: 3,
+                threshold: 0.05,
+                scan_fraction: 0.5,
+            },
+        );
+
+        let realization = |seed: u64| {
+            let mut sim_db = grid((20, 20, 1));
+            sim_db.set_data_at_ind(&[4, 6, 0], 0.9);
+            sim_db.set_data_at_ind(&[15, 11, 0], -0.7);
+            ds.simulate_grid(&mut sim_db, &mut StdRng::seed_from_u64(seed));
+            sim_db.raw_grid.grid.map(|value| value.unwrap())
+        };
+
+        let values = realization(3);
+        assert_eq!(values, realization(3));
+        assert_eq!(values[[4, 6, 0]], 0.9);
+        assert_eq!(values[[15, 11, 0]], -0.7);
+        assert!(values.iter().all(|value| *value >= min && *value <= max));
+    }
+
+    #[test]
+    fn ds_categorical_3d() {
+        //horizontal layers two nodes thick
+        let ti = training_image((12, 12, 8), |(_, _, k)| (k / 2 % 2) as f32);
+        let ds = DirectSampling::new(
+            ti,
+            DSVariable::Categorical,
+            Ellipsoid::new(3.0, 3.0, 3.0, cs()),
+            DSParameters {
+                max_octant_size: 2,
+                threshold: 0.0,
+                scan_fraction: 1.0,
+            },
+        );
+
+        let mut sim_db = grid((8, 8, 6));
+        ds.simulate_grid(&mut sim_db, &mut StdRng::seed_from_u64(5));
+        let values = sim_db.raw_grid.grid.map(|value| value.unwrap());
+        assert!(values.iter().all(|value| *value == 0.0 || *value == 1.0));
+
+        //layers are continuous horizontally
+        let pairs = values
+            .indexed_iter()
+            .filter(|((i, _, _), _)| *i > 0)
+            .collect::<Vec<_>>();
+        let continuous = pairs
+            .iter()
+            .filter(|((i, j, k), value)| values[[i - 1, *j, *k]] == **value)
+            .count();
+        assert!(continuous as f32 / pairs.len() as f32 > 0.8);
+    }
+
+    #[test]
+    fn ds_covariable_pasted_jointly() {
+        let primary = training_image((30, 30, 1), |(i, j, _)| ((i + 2 * j) % 7) as f32);
+        let secondary = training_image((30, 30, 1), |(i, j, _)| 2.0 * ((i + 2 * j) % 7) as f32);
+        let ds = DirectSampling::new(
+            primary,
+            DSVariable::Continuous,
+            Ellipsoid::new(3.0, 3.0, 1.0, cs()),
+            DSParameters {
+                max_octant_size: 2,
+                threshold: 0.1,
+                scan_fraction: 0.3,
+            },
+        )
+        .with_covariable(secondary, DSVariable::Continuous, 0.5);
+
+        let mut grids = vec![grid((10, 10, 1)), grid((10, 10, 1))];
+        ds.simulate_grids(&mut grids, &mut StdRng::seed_from_u64(1));
+
+        let primary = grids[0].raw_grid.grid.map(|value| value.unwrap());
+        let secondary = grids[1].raw_grid.grid.map(|value| value.unwrap());
+        assert_eq!(primary.map(|v| 2.0 * v), secondary);
+    }
+
+    #[test]
+    fn ds_collocated_covariable_data() {
+        let primary = training_image((30, 30, 1), |(i, j, _)| ((i + 2 * j) % 7) as f32);
+        let secondary = training_image((30, 30, 1), |(i, j, _)| 2.0 * ((i + 2 * j) % 7) as f32);
+        let ds = DirectSampling::new(
+            primary,
+            DSVariable::Continuous,
+            Ellipsoid::new(3.0, 3.0, 1.0, cs()),
+            DSParameters {
+                max_octant_size: 2,
+                threshold: 0.0,
+                scan_fraction: 1.0,
+            },
+        )
+        .with_covariable(secondary, DSVariable::Continuous, 1.0);
+
+        //a shifted pattern of the training image informs the covariable at every node
+        let mut grids = vec![grid((10, 10, 1)), grid((10, 10, 1))];
+        for i in 0..10 {
+            for j in 0..10 {
+                grids[1].set_data_at_ind(&[i, j, 0], 2.0 * ((i + 2 * j + 3) % 7) as f32);
+            }
+        }
+        ds.simulate_grids(&mut grids, &mut StdRng::seed_from_u64(2));
+
+        let primary = grids[0].raw_grid.grid.map(|value| value.unwrap());
+        let secondary = grids[1].raw_grid.grid.map(|value| value.unwrap());
+        assert_eq!(primary.map(|v| 2.0 * v), secondary);
+    }
+
+    #[test]
+    fn ds_realizations() {
+        let ti = training_image((30, 30, 1), |(i, j, _)| ((i / 3 + j / 5) % 2) as f32);
+        let ds = DirectSampling::new(
+            ti,
+            DSVariable::Categorical,
+            Ellipsoid::new(4.0, 4.0, 1.0, cs()),
+            DSParameters {
+                max_octant_size: 2,
+                threshold: 0.1,
+                scan_fraction: 0.5,
+            },
+        );
+        let new_grid = |_: usize| {
+            let mut sim_db = grid((12, 12, 1));
+            sim_db.set_data_at_ind(&[3, 8, 0], 1.0);
+            sim_db
+        };
+
+        let realizations = Realizations::new(3, 4);
+        let values = realizations.to_array(&ds, new_grid);
+        assert!(values.iter().all(|value| *value == 0.0 || *value == 1.0));
+        assert!(values.outer_iter().all(|r| r[[3, 8, 0]] == 1.0));
+
+        let mut sim_db = new_grid(2);
+        ds.simulate_grid(&mut sim_db, &mut realizations.realization_rng(2));
+        assert_eq!(
+            values.index_axis(ndarray::Axis(0), 2),
+            sim_db.raw_grid.grid.map(|value| value.unwrap())
+        );
+
+        let shared = Realizations::new(3, 4).with_shared_path(true);
+        assert!(shared
+            .to_array(&ds, new_grid)
+            .outer_iter()
+            .all(|r| r[[3, 8, 0]] == 1.0));
+    }
+}
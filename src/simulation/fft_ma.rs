@@ -0,0 +1,449 @@
+use std::f64::consts::PI;
+
+use nalgebra::{Complex, Matrix3};
+use ndarray::{Array3, ArrayViewMut1, Axis};
+use rand::{rngs::StdRng, Rng};
+use rand_distr::StandardNormal;
+use rayon::prelude::{IntoParallelRefMutIterator, ParallelIterator};
+
+use crate::{
+    spatial_database::gridded_databases::GriddedDataBaseInterface,
+    variography::model_variograms::{NestedStructures, VariogramModel},
+};
+
+use super::{realizations::Simulation, seeding::SeedSequence};
+
+/// Fast Fourier transform moving average simulation on regular grids
+/// the covariance is embedded in a periodic padded grid, the square root of its spectrum is the
+/// moving average operator convolved with white noise
+pub struct FftMa<V> {
+    variogram_model: V,
+    padding: Option<[usize; 3]>,
+}
+
+impl<V> FftMa<V>
+where
+    V: VariogramModel + NestedStructures,
+{
+    /// Create an FFT-MA simulation
+    /// # Arguments
+    /// * `variogram_model` - The variogram model, may be anisotropic and rotated
+    pub fn new(variogram_model: V) -> Self {
+        Self {
+            variogram_model,
+            padding: None,
+        }
+    }
+
+    /// Number of nodes added along each axis of the grid
+    /// the covariance must vanish within the padding to avoid periodic artifacts, by default the
+    /// padding covers the support of the variogram structures, bounded by the lags within the grid
+    /// (grid size - 1)
+    pub fn with_padding(mut self, padding: [usize; 3]) -> Self {
+        self.padding = Some(padding);
+        self
+    }
+
+    /// Build the moving average operator of a grid
+    /// the operator only depends on the geometry of the grid and can be reused for any number of realizations
+    /// # Arguments
+    /// * `grid` - Grid defining the simulated nodes (values are not read)
+    pub fn kernel<GDB>(&self, grid: &GDB) -> FftMaKernel
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        let shape = grid.shape();
+        let padding = self.padding.unwrap_or_else(|| self.support_padding(grid));
+        let padded_shape: [usize; 3] =
+            std::array::from_fn(|axis| (shape[axis] + padding[axis]).next_power_of_two());
+
+        //covariance of the periodic lags
+        let origin = grid.ind_to_point(&[0, 0, 0]);
+        let lag = |m: usize, size: usize| {
+            if m <= size / 2 {
+                m as isize
+            } else {
+                m as isize - size as isize
+            }
+        };
+        let mut spectrum = Array3::from_shape_fn(
+            (padded_shape[0], padded_shape[1], padded_shape[2]),
+            |(i, j, k)| {
+                let ind = [
+                    lag(i, padded_shape[0]),
+                    lag(j, padded_shape[1]),
+                    lag(k, padded_shape[2]),
+                ];
+                let h = grid.ind_to_point(&ind) - origin;
+                Complex::new(self.variogram_model.covariogram(h) as f64, 0.0)
+            },
+        );
+        fft_3d(&mut spectrum, false);
+
+        //small negative eigenvalues come from truncating the covariance at the padding
+        let operator = spectrum.map(|value| value.re.max(0.0).sqrt());
+
+        FftMaKernel { shape, operator }
+    }
+
+    /// Nodes along each axis of the grid spanned by the support of the variogram structures
+    /// the support of a structure is an ellipsoid in lag space, its extent along a grid axis is the
+    /// norm of the matching row of the inverse map from its isotropic space to grid indices
+    fn support_padding<GDB>(&self, grid: &GDB) -> [usize; 3]
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        let shape = grid.shape();
+        let origin = grid.ind_to_point(&[0, 0, 0]);
+        let grid_axes = Matrix3::from_columns(
+            &[[1, 0, 0], [0, 1, 0], [0, 0, 1]].map(|ind| grid.ind_to_point(&ind) - origin),
+        );
+
+        let mut extent = [0f32; 3];
+        for structure in self.variogram_model.structures() {
+            let to_structure = structure.rotation.to_rotation_matrix().matrix() * grid_axes;
+            let to_grid = to_structure
+                .try_inverse()
+                .expect("grid axes must be independent")
+                * Matrix3::from_diagonal(&(structure.range * structure.shape.support()));
+            for (axis, extent) in extent.iter_mut().enumerate() {
+                *extent = extent.max(to_grid.row(axis).norm());
+            }
+        }
+
+        std::array::from_fn(|axis| {
+            (extent[axis].ceil() as usize).min(shape[axis].saturating_sub(1))
+        })
+    }
+}
+
+impl<V> Simulation for FftMa<V>
+where
+    V: VariogramModel + NestedStructures,
+{
+    type Plan = FftMaKernel;
+
+    fn plan<GDB>(&self, grid: &GDB, _rng: &mut StdRng) -> Self::Plan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.kernel(grid)
+    }
+
+    fn realize<GDB>(&self, plan: &Self::Plan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        let noise = plan.noise(&mut seeds.stream(0));
+        plan.simulate(&noise, grid);
+    }
+}
+
+/// Moving average operator of a grid in the frequency domain
+pub struct FftMaKernel {
+    shape: [usize; 3],
+    operator: Array3<f64>,
+}
+
+impl FftMaKernel {
+    /// Shape of the padded grid, the shape of the white noise
+    pub fn padded_shape(&self) -> [usize; 3] {
+        let shape = self.operator.shape();
+        [shape[0], shape[1], shape[2]]
+    }
+
+    /// Draw white noise on the padded grid
+    pub fn noise(&self, rng: &mut StdRng) -> Array3<f32> {
+        let shape = self.padded_shape();
+        Array3::from_shape_simple_fn((shape[0], shape[1], shape[2]), || {
+            rng.sample(StandardNormal)
+        })
+    }
+
+    /// Convolve white noise with the operator and write the realization to a grid
+    /// the same noise always gives the same realization, perturbing the noise (e.g. with
+    /// `gradual_deformation`) perturbs the realization continuously
+    /// # Arguments
+    /// * `noise` - White noise on the padded grid
+    /// * `grid` - Grid with the geometry the kernel was built for
+    pub fn simulate<GDB>(&self, noise: &Array3<f32>, grid: &mut GDB)
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        assert_eq!(
+            noise.shape(),
+            self.operator.shape(),
+            "noise must have the padded shape of the kernel"
+        );
+        assert_eq!(grid.shape(), self.shape, "grid must match the kernel");
+
+        let mut field = noise.map(|value| Complex::new(*value as f64, 0.0));
+        fft_3d(&mut field, false);
+        field.zip_mut_with(&self.operator, |value, operator| *value *= *operator);
+        fft_3d(&mut field, true);
+
+        for i in 0..self.shape[0] {
+            for j in 0..self.shape[1] {
+                for k in 0..self.shape[2] {
+                    grid.set_data_at_ind(&[i, j, k], field[[i, j, k]].re as f32);
+                }
+            }
+        }
+    }
+}
+
+/// Combine two independent white noises into a white noise
+/// realizations vary continuously with the angle, 0 gives the first noise and pi / 2 the second
+/// # Arguments
+/// * `noise` - Current noise
+/// * `other_noise` - Independent noise
+/// * `theta` - Deformation angle
+pub fn gradual_deformation(
+    noise: &Array3<f32>,
+    other_noise: &Array3<f32>,
+    theta: f32,
+) -> Array3<f32> {
+    let (sin, cos) = theta.sin_cos();
+    let mut deformed = noise.map(|value| value * cos);
+    deformed.zip_mut_with(other_noise, |value, other| *value += other * sin);
+    deformed
+}
+
+/// In place radix-2 FFT along every axis, the inverse is normalized
+/// lanes are transformed through strided views, slabs across the longest other axis are processed
+/// in parallel
+fn fft_3d(data: &mut Array3<Complex<f64>>, inverse: bool) {
+    for axis in 0..3 {
+        if data.len_of(Axis(axis)) < 2 {
+            continue;
+        }
+
+        let slab_axis = (0..3)
+            .filter(|other| *other != axis)
+            .max_by_key(|other| data.len_of(Axis(*other)))
+            .unwrap();
+        //the lane axis of a slab once the slab axis is removed
+        let lane_axis = if axis < slab_axis { axis } else { axis - 1 };
+
+        let mut slabs = data.axis_iter_mut(Axis(slab_axis)).collect::<Vec<_>>();
+        slabs.par_iter_mut().for_each(|slab| {
+            for lane in slab.lanes_mut(Axis(lane_axis)) {
+                fft(lane, inverse);
+            }
+        });
+    }
+}
+
+/// In place iterative radix-2 FFT of a power of two length sequence
+fn fft(mut data: ArrayViewMut1<Complex<f64>>, inverse: bool) {
+    let n = data.len();
+    assert!(n.is_power_of_two(), "fft length must be a power of two");
+
+    //bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as f64;
+        let root = Complex::new(angle.cos(), angle.sin());
+        for start in (0..n).step_by(len) {
+            let mut twiddle = Complex::new(1.0, 0.0);
+            for k in 0..len / 2 {
+                let even = data[start + k];
+                let odd = data[start + k + len / 2] * twiddle;
+                data[start + k] = even + odd;
+                data[start + k + len / 2] = even - odd;
+                twiddle *= root;
+            }
+        }
+        len <<= 1;
+    }
+
+    if inverse {
+        data.iter_mut().for_each(|value| *value /= n as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion, Vector3};
+    use rand::SeedableRng;
+
+    use crate::{
+        spatial_database::{
+            coordinate_system::{CoordinateSystem, GridSpacing},
+            gridded_databases::complete_grid::CompleteGriddedDataBase,
+        },
+        variography::model_variograms::{
+            basic::BasicVariogram, spherical::SphericalVariogram, StructureShape,
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn fft_round_trip() {
+        let mut impulse = vec![Complex::new(0.0, 0.0); 8];
+        impulse[0] = Complex::new(1.0, 0.0);
+        fft(ArrayViewMut1::from(&mut impulse[..]), false);
+        assert!(impulse
+            .iter()
+            .all(|v| (v - Complex::new(1.0, 0.0)).norm() < 1e-12));
+
+        let values = (0..16)
+            .map(|i| Complex::new((i as f64 * 0.7).sin(), (i as f64).cos()))
+            .collect::<Vec<_>>();
+        let mut transformed = values.clone();
+        fft(ArrayViewMut1::from(&mut transformed[..]), false);
+        //dft definition
+        let k = 3;
+        let expected = values
+            .iter()
+            .enumerate()
+            .map(|(n, v)| v * Complex::from_polar(1.0, -2.0 * PI * (k * n) as f64 / 16.0))
+            .sum::<Complex<f64>>();
+        assert!((transformed[k] - expected).norm() < 1e-9);
+
+        fft(ArrayViewMut1::from(&mut transformed[..]), true);
+        assert!(values
+            .iter()
+            .zip(transformed.iter())
+            .all(|(v, t)| (v - t).norm() < 1e-12));
+    }
+
+    #[test]
+    fn kernel_reproduces_rotated_covariance() {
+        let grid_cs = CoordinateSystem::new(
+            Translation3::identity(),
+            UnitQuaternion::from_euler_angles(0.0, 0.0, 0.3),
+        );
+        let grid = CompleteGriddedDataBase::new(
+            Array3::<f32>::zeros((32, 32, 4)),
+            GridSpacing {
+                x: 1.0,
+                y: 1.0,
+                z: 2.0,
+            },
+            grid_cs,
+        );
+        let vgram_cs = CoordinateSystem::new(
+            Translation3::identity(),
+            UnitQuaternion::from_euler_angles(0.0, 0.0, 45f32.to_radians()),
+        );
+        let vgram = SphericalVariogram::new(Vector3::new(8.0, 3.0, 4.0), 1.0, 0.1, vgram_cs);
+
+        let kernel = FftMa::new(&vgram).kernel(&grid);
+        assert_eq!(kernel.padded_shape(), [64, 64, 8]);
+
+        //operator squared is the spectrum of the embedded covariance
+        let mut covariance = kernel.operator.map(|v| Complex::new(v * v, 0.0));
+        fft_3d(&mut covariance, true);
+
+        let origin = grid.ind_to_point(&[0, 0, 0]);
+        for ind in [[0, 0, 0], [2, 0, 0], [0, 3, 0], [3, 3, 1], [5, 2, 0]] {
+            let h = grid.ind_to_point(&ind.map(|i| i as isize)) - origin;
+            assert!((covariance[ind].re as f32 - vgram.covariogram(h)).abs() < 5e-3);
+        }
+    }
+
+    #[test]
+    fn padding_follows_variogram_range() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let grid = CompleteGriddedDataBase::new(
+            Array3::<f32>::zeros((40, 40, 1)),
+            GridSpacing {
+                x: 2.0,
+                y: 1.0,
+                z: 1.0,
+            },
+            cs,
+        );
+
+        //the support spans 4 nodes along the first axis and 8 along the second
+        let vgram = SphericalVariogram::new(Vector3::new(8.0, 8.0, 8.0), 1.0, 0.0, cs);
+        let fft_ma = FftMa::new(&vgram);
+        assert_eq!(fft_ma.support_padding(&grid), [4, 8, 0]);
+        assert_eq!(fft_ma.kernel(&grid).padded_shape(), [64, 64, 1]);
+
+        let gaussian = BasicVariogram::new(
+            StructureShape::Gaussian,
+            Vector3::new(8.0, 8.0, 8.0),
+            1.0,
+            0.0,
+            cs,
+        );
+        assert_eq!(FftMa::new(gaussian).support_padding(&grid), [6, 12, 0]);
+    }
+
+    #[test]
+    fn fft_ma_realizations() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let mut grid = CompleteGriddedDataBase::new(
+            Array3::<f32>::zeros((64, 64, 1)),
+            GridSpacing {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+            cs,
+        );
+        let vgram = SphericalVariogram::new(Vector3::new(6.0, 6.0, 6.0), 1.0, 0.0, cs);
+        let fft_ma = FftMa::new(vgram);
+        let kernel = fft_ma.kernel(&grid);
+        let mut rng = StdRng::seed_from_u64(8);
+
+        //variance over several realizations
+        let values = (0..10)
+            .flat_map(|_| {
+                kernel.simulate(&kernel.noise(&mut rng), &mut grid);
+                grid.raw_grid.grid.iter().copied().collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let n = values.len() as f32;
+        let mean = values.iter().sum::<f32>() / n;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n;
+        assert!(mean.abs() < 0.1);
+        assert!((variance - 1.0).abs() < 0.1);
+
+        //gradual deformation moves continuously between realizations
+        let noise = kernel.noise(&mut rng);
+        let other_noise = kernel.noise(&mut rng);
+        assert_eq!(gradual_deformation(&noise, &other_noise, 0.0), noise);
+
+        kernel.simulate(&other_noise, &mut grid);
+        let other = grid.raw_grid.grid.clone();
+        kernel.simulate(
+            &gradual_deformation(&noise, &other_noise, std::f32::consts::FRAC_PI_2),
+            &mut grid,
+        );
+        assert!(grid
+            .raw_grid
+            .grid
+            .iter()
+            .zip(other.iter())
+            .all(|(v, o)| (v - o).abs() < 1e-4));
+
+        let seeded = |seed: u64| {
+            let mut grid = CompleteGriddedDataBase::new(
+                Array3::<f32>::zeros((64, 64, 1)),
+                grid.grid_spacing(),
+                cs,
+            );
+            fft_ma.simulate_grid(&mut grid, &mut StdRng::seed_from_u64(seed));
+            grid.raw_grid.grid
+        };
+        assert_eq!(seeded(1), seeded(1));
+    }
+}
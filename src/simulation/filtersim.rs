@@ -0,0 +1,602 @@
+use ndarray::Array3;
+use rand::{rngs::StdRng, Rng};
+use rayon::prelude::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    geometry::template::Template,
+    spatial_database::gridded_databases::{
+        complete_grid::CompleteGriddedDataBase, GriddedDataBaseInterface,
+    },
+};
+
+use super::{
+    path::{random_path, write_assigned_data},
+    realizations::Simulation,
+    seeding::SeedSequence,
+};
+
+/// Maximum number of k-means iterations
+const MAX_ITERATIONS: usize = 20;
+
+/// Number of closest patterns of a class a pattern is drawn from
+const BEST_PATTERNS: usize = 3;
+
+/// Weight of hard data relative to previously pasted nodes in data event distances
+const HARD_DATA_WEIGHT: f32 = 2.0;
+
+pub struct FILTERSIMParameters {
+    /// Half size of the template along each axis of the grid
+    pub template_size: [usize; 3],
+    /// Half size of the pasted patch along each axis, no larger than the template
+    pub patch_size: [usize; 3],
+    /// Number of pattern classes
+    pub n_classes: usize,
+}
+
+/// Values of the training image
+#[derive(Clone, Debug, PartialEq)]
+pub enum PatternVariable {
+    Continuous,
+    /// Facies codes, patterns are compared by their category indicators
+    Categorical {
+        codes: Vec<f32>,
+    },
+}
+
+impl PatternVariable {
+    /// Number of indicator channels of a pattern (one for continuous values)
+    fn n_channels(&self) -> usize {
+        match self {
+            PatternVariable::Continuous => 1,
+            PatternVariable::Categorical { codes } => codes.len(),
+        }
+    }
+
+    /// Value of a channel of a node
+    #[inline(always)]
+    fn channel(&self, channel: usize, value: f32) -> f32 {
+        match self {
+            PatternVariable::Continuous => value,
+            PatternVariable::Categorical { codes } => {
+                if value == codes[channel] {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    /// Mismatch between a data event value and a pattern or prototype node
+    /// # Arguments
+    /// * `value` - Data event value
+    /// * `node` - Channels of the pattern or prototype node
+    #[inline(always)]
+    fn mismatch(&self, value: f32, node: &[f32]) -> f32 {
+        match self {
+            PatternVariable::Continuous => (value - node[0]).powi(2),
+            PatternVariable::Categorical { codes } => {
+                let proportion = codes
+                    .iter()
+                    .position(|code| *code == value)
+                    .map_or(0.0, |channel| node[channel]);
+                1.0 - proportion
+            }
+        }
+    }
+}
+
+/// Path of a simulation
+/// patches pasted along the path depend on the simulated values, only the path is shared
+pub struct FILTERSIMPlan {
+    path: Vec<(usize, usize, usize)>,
+}
+
+/// Filter based pattern simulation
+/// patterns of the training image are summarized by average, gradient and curvature filter scores
+/// along each axis and grouped into classes by k-means, every node visited along the path receives a
+/// patch of a pattern of the class whose prototype best matches its data event
+/// * nodes already informed (hard data or pasted) are never overwritten and are not visited
+pub struct FILTERSIM {
+    variable: PatternVariable,
+    template: Template,
+    patch: Vec<usize>,
+    patterns: Vec<Vec<f32>>,
+    pattern_channels: Vec<Vec<f32>>,
+    classes: Vec<Vec<usize>>,
+    prototypes: Vec<Vec<f32>>,
+    assigned_data: Option<Array3<Option<f32>>>,
+}
+
+impl FILTERSIM {
+    /// Extract and classify the patterns of a training image
+    /// # Arguments
+    /// * `training_image` - Training image, with the grid spacing of the simulated grid
+    /// * `parameters` - Template, patch and number of classes
+    /// * `variable` - Continuous or categorical values
+    pub fn new(
+        training_image: &CompleteGriddedDataBase<f32>,
+        parameters: FILTERSIMParameters,
+        variable: PatternVariable,
+    ) -> Self {
+        let size = parameters.template_size;
+        assert!(
+            parameters
+                .patch_size
+                .iter()
+                .zip(size.iter())
+                .all(|(patch, template)| patch <= template),
+            "patch must fit in the template"
+        );
+        assert!(parameters.n_classes > 0, "at least one class is required");
+
+        let offsets = window(size);
+        let patch = offsets
+            .iter()
+            .enumerate()
+            .filter(|(_, offset)| {
+                (0..3).all(|axis| offset[axis].unsigned_abs() <= parameters.patch_size[axis])
+            })
+            .map(|(position, _)| position)
+            .collect();
+        let template = Template::new(offsets);
+
+        //patterns where the whole template lies in the training image
+        let shape = training_image.shape();
+        let patterns = training_image
+            .raw_grid
+            .grid
+            .indexed_iter()
+            .filter_map(|(ind, _)| {
+                template
+                    .offsets()
+                    .iter()
+                    .map(|offset| {
+                        let ind = Template::get_ind(&[ind.0, ind.1, ind.2], *offset, shape)?;
+                        training_image.data_at_ind(&ind)
+                    })
+                    .collect::<Option<Vec<_>>>()
+            })
+            .collect::<Vec<_>>();
+        assert!(
+            !patterns.is_empty(),
+            "template must fit in the training image"
+        );
+
+        let filters = filter_weights(&template, size);
+        let scores = patterns
+            .par_iter()
+            .map(|pattern| filter_scores(pattern, &filters, &variable))
+            .collect::<Vec<_>>();
+        let classes = k_means(&scores, parameters.n_classes);
+
+        let pattern_channels = patterns
+            .par_iter()
+            .map(|pattern| pattern_channels(pattern, &variable))
+            .collect::<Vec<_>>();
+
+        //mean channels of the patterns of each class
+        let prototypes = classes
+            .iter()
+            .map(|members| {
+                let mut prototype = vec![0.0; template.offsets().len() * variable.n_channels()];
+                for member in members {
+                    for (sum, channel) in prototype.iter_mut().zip(&pattern_channels[*member]) {
+                        *sum += channel;
+                    }
+                }
+                prototype
+                    .iter_mut()
+                    .for_each(|v| *v /= members.len() as f32);
+                prototype
+            })
+            .collect();
+
+        Self {
+            variable,
+            template,
+            patch,
+            patterns,
+            pattern_channels,
+            classes,
+            prototypes,
+            assigned_data: None,
+        }
+    }
+
+    /// Freeze the nodes holding hard data
+    /// frozen nodes are never overwritten by patches and weigh more in the data events
+    pub fn with_assigned_data(mut self, assigned_data: Array3<Option<f32>>) -> Self {
+        self.assigned_data = Some(assigned_data);
+        self
+    }
+
+    /// Number of pattern classes
+    pub fn n_classes(&self) -> usize {
+        self.classes.len()
+    }
+
+    fn build_plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> FILTERSIMPlan
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        let (path, _) = random_path(grid, self.assigned_data.as_ref(), 1, rng);
+        FILTERSIMPlan { path }
+    }
+
+    /// Paste patches along the path of a plan
+    fn apply_plan<GDB>(&self, plan: &FILTERSIMPlan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        write_assigned_data(grid, self.assigned_data.as_ref());
+
+        let shape = grid.shape();
+        let hard = match self.assigned_data.as_ref() {
+            Some(assigned_data) => assigned_data.map(Option::is_some),
+            None => Array3::from_elem(shape, false),
+        };
+        let mut informed = hard.clone();
+
+        for (path_ind, ind) in plan.path.iter().enumerate() {
+            let ind = [ind.0, ind.1, ind.2];
+            if informed[ind] {
+                continue;
+            }
+
+            //template position, value and weight of the informed nodes
+            let data_event = self
+                .template
+                .offsets()
+                .iter()
+                .enumerate()
+                .filter_map(|(position, offset)| {
+                    let neighbor_ind = Template::get_ind(&ind, *offset, shape)?;
+                    if !informed[neighbor_ind] {
+                        return None;
+                    }
+                    let value = grid.data_at_ind(&neighbor_ind).expect("No value at ind");
+                    let weight = if hard[neighbor_ind] {
+                        HARD_DATA_WEIGHT
+                    } else {
+                        1.0
+                    };
+                    Some((position, value, weight))
+                })
+                .collect::<Vec<_>>();
+
+            let pattern = self.select_pattern(&data_event, &mut seeds.stream(path_ind as u64));
+
+            //paste the patch on uninformed nodes
+            for position in self.patch.iter() {
+                let offset = self.template.offsets()[*position];
+                let Some(patch_ind) = Template::get_ind(&ind, offset, shape) else {
+                    continue;
+                };
+                if !informed[patch_ind] {
+                    grid.set_data_at_ind(&patch_ind, self.patterns[pattern][*position]);
+                    informed[patch_ind] = true;
+                }
+            }
+        }
+    }
+
+    /// Draw a pattern for a data event
+    /// the class of the closest prototype is retained and the pattern is drawn among its closest patterns
+    fn select_pattern(&self, data_event: &[(usize, f32, f32)], rng: &mut StdRng) -> usize {
+        if data_event.is_empty() {
+            return rng.gen_range(0..self.patterns.len());
+        }
+
+        //weighted mismatch of the data event with the channels of a prototype or pattern
+        let n_channels = self.variable.n_channels();
+        let distance = |channels: &Vec<f32>| {
+            data_event
+                .iter()
+                .map(|(position, value, weight)| {
+                    let node = &channels[position * n_channels..(position + 1) * n_channels];
+                    weight * self.variable.mismatch(*value, node)
+                })
+                .sum::<f32>()
+        };
+        let class = self
+            .prototypes
+            .iter()
+            .map(distance)
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(class, _)| class)
+            .expect("at least one class is required");
+
+        let mut candidates = self.classes[class]
+            .iter()
+            .map(|pattern| (*pattern, distance(&self.pattern_channels[*pattern])))
+            .collect::<Vec<_>>();
+        candidates.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        let n_best = candidates.len().min(BEST_PATTERNS);
+        candidates[rng.gen_range(0..n_best)].0
+    }
+}
+
+impl Simulation for FILTERSIM {
+    type Plan = FILTERSIMPlan;
+
+    fn plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> Self::Plan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.build_plan(grid, rng)
+    }
+
+    fn realize<GDB>(&self, plan: &Self::Plan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.apply_plan(plan, grid, seeds);
+    }
+}
+
+/// Offsets of a rectangular window
+fn window(size: [usize; 3]) -> Vec<[isize; 3]> {
+    let [sx, sy, sz] = size.map(|s| s as isize);
+    (-sx..=sx)
+        .flat_map(|i| (-sy..=sy).flat_map(move |j| (-sz..=sz).map(move |k| [i, j, k])))
+        .collect()
+}
+
+/// Channels of every node of a pattern, node major
+fn pattern_channels(pattern: &[f32], variable: &PatternVariable) -> Vec<f32> {
+    pattern
+        .iter()
+        .flat_map(|value| {
+            (0..variable.n_channels()).map(|channel| variable.channel(channel, *value))
+        })
+        .collect()
+}
+
+/// Average, gradient and curvature filters along each axis the template extends along
+/// the curvature filter is centred so linear trends have no curvature
+/// # Returns
+/// The weight of every template node for each filter
+fn filter_weights(template: &Template, size: [usize; 3]) -> Vec<Vec<f32>> {
+    (0..3)
+        .filter(|axis| size[*axis] > 0)
+        .flat_map(|axis| {
+            //position along the axis scaled to [-1, 1]
+            let t = template
+                .offsets()
+                .iter()
+                .map(|offset| offset[axis] as f32 / size[axis] as f32)
+                .collect::<Vec<_>>();
+            let curvature_mean =
+                t.iter().map(|t| 2.0 * t.abs() - 1.0).sum::<f32>() / t.len() as f32;
+            [
+                t.iter().map(|t| 1.0 - t.abs()).collect::<Vec<_>>(),
+                t.clone(),
+                t.iter()
+                    .map(|t| 2.0 * t.abs() - 1.0 - curvature_mean)
+                    .collect::<Vec<_>>(),
+            ]
+        })
+        .collect()
+}
+
+/// Normalized filter scores of each channel of a pattern
+fn filter_scores(pattern: &[f32], filters: &[Vec<f32>], variable: &PatternVariable) -> Vec<f32> {
+    (0..variable.n_channels())
+        .flat_map(|channel| {
+            filters.iter().map(move |weights| {
+                let norm = weights
+                    .iter()
+                    .map(|w| w.abs())
+                    .sum::<f32>()
+                    .max(f32::EPSILON);
+                weights
+                    .iter()
+                    .zip(pattern.iter())
+                    .map(|(w, v)| w * variable.channel(channel, *v))
+                    .sum::<f32>()
+                    / norm
+            })
+        })
+        .collect()
+}
+
+/// Group score vectors into classes by k-means
+/// centroids start from patterns evenly spaced in the order of their summed scores so the
+/// classification does not depend on a random draw, empty classes are dropped
+/// # Returns
+/// The members of each class
+fn k_means(scores: &[Vec<f32>], n_classes: usize) -> Vec<Vec<usize>> {
+    let distance = |a: &[f32], b: &[f32]| {
+        a.iter()
+            .zip(b.iter())
+            .map(|(a, b)| (a - b).powi(2))
+            .sum::<f32>()
+    };
+
+    let mut order = (0..scores.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| {
+        let a = scores[*a].iter().sum::<f32>();
+        let b = scores[*b].iter().sum::<f32>();
+        a.total_cmp(&b)
+    });
+    let n_classes = n_classes.min(scores.len());
+    let mut centroids = (0..n_classes)
+        .map(|class| scores[order[class * scores.len() / n_classes]].clone())
+        .collect::<Vec<_>>();
+
+    let mut assignments = vec![usize::MAX; scores.len()];
+    for _ in 0..MAX_ITERATIONS {
+        let new_assignments = (0..scores.len())
+            .into_par_iter()
+            .map(|pattern| {
+                centroids
+                    .iter()
+                    .map(|centroid| distance(&scores[pattern], centroid))
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .map(|(class, _)| class)
+                    .expect("at least one class is required")
+            })
+            .collect::<Vec<_>>();
+        if new_assignments == assignments {
+            break;
+        }
+        assignments = new_assignments;
+
+        //empty classes keep their centroid
+        let mut sums = vec![vec![0.0; scores[0].len()]; n_classes];
+        let mut counts = vec![0usize; n_classes];
+        for (pattern, class) in assignments.iter().enumerate() {
+            counts[*class] += 1;
+            for (sum, score) in sums[*class].iter_mut().zip(scores[pattern].iter()) {
+                *sum += score;
+            }
+        }
+        for ((centroid, sum), count) in centroids.iter_mut().zip(sums).zip(counts) {
+            if count > 0 {
+                *centroid = sum.iter().map(|s| s / count as f32).collect();
+            }
+        }
+    }
+
+    let mut classes = vec![Vec::new(); n_classes];
+    for (pattern, class) in assignments.iter().enumerate() {
+        classes[*class].push(pattern);
+    }
+    classes.retain(|members| !members.is_empty());
+    classes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        simulation::realizations::simulate_on_threads,
+        spatial_database::{
+            coordinate_system::{CoordinateSystem, GridSpacing},
+            gridded_databases::incomplete_grid::InCompleteGriddedDataBase,
+        },
+    };
+    use nalgebra::{Translation3, UnitQuaternion};
+
+    use super::*;
+
+    fn simulate(
+        filtersim: &FILTERSIM,
+        shape: (usize, usize, usize),
+        threads: usize,
+        seed: u64,
+    ) -> Array3<f32> {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let mut sim_db = InCompleteGriddedDataBase::new(
+            Array3::from_elem(shape, None),
+            GridSpacing::new(1.0, 1.0, 1.0),
+            cs,
+        );
+        simulate_on_threads(filtersim, &mut sim_db, threads, seed);
+        sim_db.raw_grid.grid.map(|value| value.unwrap())
+    }
+
+    #[test]
+    fn filters_along_axis() {
+        let template = Template::new(window([1, 0, 0]));
+        let filters = filter_weights(&template, [1, 0, 0]);
+        let expected = [
+            [0.0, 1.0, 0.0],
+            [-1.0, 0.0, 1.0],
+            [2.0 / 3.0, -4.0 / 3.0, 2.0 / 3.0],
+        ];
+        assert_eq!(filters.len(), 3);
+        for (filter, expected) in filters.iter().zip(expected) {
+            assert!(filter
+                .iter()
+                .zip(expected)
+                .all(|(w, e)| (w - e).abs() < 1e-6));
+        }
+
+        //a ramp has a gradient but no curvature
+        let scores = filter_scores(&[1.0, 2.0, 3.0], &filters, &PatternVariable::Continuous);
+        assert_eq!(scores[..2], [2.0, 1.0]);
+        assert!(scores[2].abs() < 1e-6);
+
+        //a valley has curvature but no gradient
+        let scores = filter_scores(&[1.0, 0.0, 1.0], &filters, &PatternVariable::Continuous);
+        assert_eq!(scores[1], 0.0);
+        assert!((scores[2] - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn filtersim_continuous_training_image() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let ti = CompleteGriddedDataBase::new(
+            Array3::from_shape_fn((40, 40, 1), |(i, j, _)| {
+                (i as f32 / 5.0).sin() + 0.5 * (j as f32 / 7.0).cos()
+            }),
+            GridSpacing::new(1.0, 1.0, 1.0),
+            cs,
+        );
+        let (min, max) = ti
+            .raw_grid
+            .grid
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(min, max), v| {
+                (min.min(*v), max.max(*v))
+            });
+
+        let mut assigned = Array3::<Option<f32>>::from_elem((24, 24, 1), None);
+        assigned[[5, 5, 0]] = Some(1.2);
+        assigned[[18, 12, 0]] = Some(-1.0);
+
+        let filtersim = FILTERSIM::new(
+            &ti,
+            FILTERSIMParameters {
+                template_size: [3, 3, 0],
+                patch_size: [1, 1, 0],
+                n_classes: 10,
+            },
+            PatternVariable::Continuous,
+        )
+        .with_assigned_data(assigned);
+        assert!(filtersim.n_classes() > 1 && filtersim.n_classes() <= 10);
+
+        let values = simulate(&filtersim, (24, 24, 1), 1, 2);
+        assert_eq!(values, simulate(&filtersim, (24, 24, 1), 4, 2));
+        assert_eq!(values[[5, 5, 0]], 1.2);
+        assert_eq!(values[[18, 12, 0]], -1.0);
+        assert!(values
+            .iter()
+            .all(|value| (*value >= min && *value <= max) || *value == 1.2 || *value == -1.0));
+    }
+
+    #[test]
+    fn filtersim_categorical_training_image() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let ti = CompleteGriddedDataBase::new(
+            Array3::from_shape_fn((30, 30, 1), |(i, _, _)| (i / 4 % 2) as f32),
+            GridSpacing::new(1.0, 1.0, 1.0),
+            cs,
+        );
+
+        let mut assigned = Array3::<Option<f32>>::from_elem((20, 20, 1), None);
+        assigned[[10, 3, 0]] = Some(1.0);
+
+        let filtersim = FILTERSIM::new(
+            &ti,
+            FILTERSIMParameters {
+                template_size: [2, 2, 0],
+                patch_size: [1, 1, 0],
+                n_classes: 4,
+            },
+            PatternVariable::Categorical {
+                codes: vec![0.0, 1.0],
+            },
+        )
+        .with_assigned_data(assigned);
+
+        let values = simulate(&filtersim, (20, 20, 1), 2, 6);
+        assert_eq!(values[[10, 3, 0]], 1.0);
+        assert!(values.iter().all(|value| *value == 0.0 || *value == 1.0));
+    }
+}
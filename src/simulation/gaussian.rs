@@ -0,0 +1,153 @@
+use rand::{rngs::StdRng, Rng};
+
+/// Cumulative distribution function of the standard normal distribution
+/// complementary error function approximation with a fractional error below 1.2e-7
+pub(crate) fn normal_cdf(x: f64) -> f64 {
+    let z = (x / std::f64::consts::SQRT_2).abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let erfc = t
+        * (-z * z - 1.26551223
+            + t * (1.00002368
+                + t * (0.37409196
+                    + t * (0.09678418
+                        + t * (-0.18628806
+                            + t * (0.27886807
+                                + t * (-1.13520398
+                                    + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+            .exp();
+
+    if x >= 0.0 {
+        1.0 - 0.5 * erfc
+    } else {
+        0.5 * erfc
+    }
+}
+
+/// Quantile of the standard normal distribution
+/// rational approximation with a relative error below 1.2e-9, infinite at 0 and 1
+pub(crate) fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969683028665376e1,
+        2.209460984245205e2,
+        -2.759285104469687e2,
+        1.383577518672690e2,
+        -3.066479806614716e1,
+        2.506628277459239,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e1,
+        1.615858368580409e2,
+        -1.556989798598866e2,
+        6.680131188771972e1,
+        -1.328068155288572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-3,
+        -3.223964580411365e-1,
+        -2.400758277161838,
+        -2.549732539343734,
+        4.374664141464968,
+        2.938163982698783,
+    ];
+    const D: [f64; 4] = [
+        7.784695709041462e-3,
+        3.224671290700398e-1,
+        2.445134137142996,
+        3.754408661907416,
+    ];
+    const P_LOW: f64 = 0.02425;
+
+    if p <= 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if p >= 1.0 {
+        return f64::INFINITY;
+    }
+
+    //tails
+    let tail = |q: f64| {
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    };
+
+    if p < P_LOW {
+        tail((-2.0 * p.ln()).sqrt())
+    } else if p <= 1.0 - P_LOW {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    } else {
+        -tail((-2.0 * (1.0 - p).ln()).sqrt())
+    }
+}
+
+/// Draw from a normal distribution truncated to an interval by inversion
+/// # Arguments
+/// * `mean` - Mean of the untruncated distribution
+/// * `std_dev` - Standard deviation of the untruncated distribution
+/// * `lower` - Lower bound (may be infinite)
+/// * `upper` - Upper bound (may be infinite)
+/// * `rng` - The random number generator
+pub(crate) fn truncated_normal(
+    mean: f64,
+    std_dev: f64,
+    lower: f64,
+    upper: f64,
+    rng: &mut StdRng,
+) -> f64 {
+    //equality data
+    if lower >= upper || std_dev <= 0.0 {
+        return mean.clamp(lower, upper.max(lower));
+    }
+
+    let p_lower = normal_cdf((lower - mean) / std_dev);
+    let p_upper = normal_cdf((upper - mean) / std_dev);
+
+    //interval far in a tail, the bound closest to the mean is the most likely value
+    if p_upper - p_lower < 1e-12 {
+        return mean.clamp(lower, upper);
+    }
+
+    let p = p_lower + rng.gen::<f64>() * (p_upper - p_lower);
+    (mean + std_dev * normal_quantile(p)).clamp(lower, upper)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn normal_cdf_and_quantile() {
+        assert!((normal_cdf(0.0) - 0.5).abs() < 1e-7);
+        assert!((normal_cdf(1.959964) - 0.975).abs() < 1e-6);
+        assert!((normal_cdf(-1.0) - 0.158655).abs() < 1e-6);
+        assert_eq!(normal_cdf(f64::INFINITY), 1.0);
+        assert_eq!(normal_cdf(f64::NEG_INFINITY), 0.0);
+
+        for x in [-4.0, -2.5, -1.0, 0.0, 0.3, 1.7, 3.5] {
+            assert!((normal_quantile(normal_cdf(x)) - x).abs() < 1e-5);
+        }
+        assert_eq!(normal_quantile(0.0), f64::NEG_INFINITY);
+        assert_eq!(normal_quantile(1.0), f64::INFINITY);
+    }
+
+    #[test]
+    fn truncated_normal_within_bounds() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let samples = (0..2000)
+            .map(|_| truncated_normal(0.0, 1.0, 0.0, f64::INFINITY, &mut rng))
+            .collect::<Vec<_>>();
+        assert!(samples.iter().all(|x| *x >= 0.0));
+
+        //mean of the half normal distribution
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        assert!((mean - (2.0 / std::f64::consts::PI).sqrt()).abs() < 0.05);
+
+        assert_eq!(truncated_normal(3.0, 1.0, 0.5, 0.5, &mut rng), 0.5);
+        let x = truncated_normal(0.0, 1.0, -1.0, -0.9, &mut rng);
+        assert!((-1.0..=-0.9).contains(&x));
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+
+use nalgebra::{DMatrix, Point3};
+use rand::rngs::StdRng;
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+
+use crate::variography::model_variograms::VariogramModel;
+
+use super::{gaussian::truncated_normal, seeding::SeedSequence};
+
+/// Gibbs sampler of gaussian values constrained to intervals at data locations
+/// each sweep redraws every value from its normal conditional distribution given all other values,
+/// truncated to the interval of the datum, the conditionals are read from the precision matrix
+/// (inverse covariance) of the data so all data are used as neighbours
+/// * data sharing a location (e.g. repeated censored assays) are merged, their value is drawn once
+///   within the intersection of their intervals
+pub struct GibbsSampler {
+    precision: DMatrix<f64>,
+    std_devs: Vec<f64>,
+    locations: Vec<usize>,
+    sweeps: usize,
+}
+
+impl GibbsSampler {
+    /// Create a new Gibbs sampler
+    /// the covariance of the data is factorized and inverted as a dense matrix, taking O(n^3) time and
+    /// O(n^2) memory for n data, data sets of more than a few thousand values should be split (e.g.
+    /// by domain) before sampling
+    /// # Arguments
+    /// * `points` - Location of the data
+    /// * `variogram_model` - Variogram of the gaussian field (must have a positive definite covariance at the data)
+    pub fn new<V>(points: &[Point3<f32>], variogram_model: &V) -> Self
+    where
+        V: VariogramModel,
+    {
+        //index of the distinct location of each datum
+        let mut location_inds = HashMap::new();
+        let mut unique_points = Vec::new();
+        let locations = points
+            .iter()
+            .map(|point| {
+                *location_inds
+                    .entry(point.coords.map(f32::to_bits))
+                    .or_insert_with(|| {
+                        unique_points.push(*point);
+                        unique_points.len() - 1
+                    })
+            })
+            .collect();
+
+        let n = unique_points.len();
+        let cov_mat = DMatrix::from_fn(n, n, |i, j| {
+            variogram_model.covariogram(unique_points[j] - unique_points[i]) as f64
+        });
+        let std_devs = cov_mat.diagonal().iter().map(|c| c.sqrt()).collect();
+
+        let precision = cov_mat
+            .cholesky()
+            .expect("covariance matrix of the data is not positive definite")
+            .inverse();
+
+        Self {
+            precision,
+            std_devs,
+            locations,
+            sweeps: 100,
+        }
+    }
+
+    /// Number of sweeps through the data (100 by default)
+    pub fn with_sweeps(mut self, sweeps: usize) -> Self {
+        self.sweeps = sweeps;
+        self
+    }
+
+    /// Draw gaussian values within the intervals of the data
+    /// # Arguments
+    /// * `intervals` - Lower and upper bound of each datum (infinite for one sided data, equal for exact data)
+    /// * `rng` - The random number generator
+    /// # Returns
+    /// Value of each datum after the last sweep
+    pub fn sample(&self, intervals: &[[f32; 2]], rng: &mut StdRng) -> Vec<f32> {
+        assert_eq!(
+            intervals.len(),
+            self.locations.len(),
+            "an interval is required for each datum"
+        );
+
+        //data at a shared location are bounded by all of their intervals
+        let mut location_intervals = vec![[f32::NEG_INFINITY, f32::INFINITY]; self.std_devs.len()];
+        for (location, [lower, upper]) in self.locations.iter().zip(intervals.iter()) {
+            let bounds = &mut location_intervals[*location];
+            bounds[0] = bounds[0].max(*lower);
+            bounds[1] = bounds[1].min(*upper);
+            assert!(
+                bounds[0] <= bounds[1],
+                "data at a shared location have disjoint intervals"
+            );
+        }
+        let intervals = location_intervals;
+
+        //independent draws within the intervals start the chain
+        let mut values = intervals
+            .iter()
+            .zip(self.std_devs.iter())
+            .map(|([lower, upper], std_dev)| {
+                truncated_normal(0.0, *std_dev, *lower as f64, *upper as f64, rng)
+            })
+            .collect::<Vec<_>>();
+
+        for _ in 0..self.sweeps {
+            for (i, [lower, upper]) in intervals.iter().enumerate() {
+                let q_ii = self.precision[(i, i)];
+                let weighted_sum = self
+                    .precision
+                    .column(i)
+                    .iter()
+                    .zip(values.iter())
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .map(|(_, (q_ij, value))| q_ij * value)
+                    .sum::<f64>();
+
+                values[i] = truncated_normal(
+                    -weighted_sum / q_ii,
+                    (1.0 / q_ii).sqrt(),
+                    *lower as f64,
+                    *upper as f64,
+                    rng,
+                );
+            }
+        }
+
+        self.locations
+            .iter()
+            .map(|location| values[*location] as f32)
+            .collect()
+    }
+
+    /// Draw conditioning sets from independent chains
+    /// set `s` is drawn from stream `s` of a seed taken from `rng`, sets do not depend on the number
+    /// of threads, each set conditions one realization of a gaussian simulation (e.g. `SGS` or `GSGS`
+    /// with the values assigned to the grid by `DataAssignment`)
+    /// # Arguments
+    /// * `intervals` - Lower and upper bound of each datum
+    /// * `n_sets` - Number of conditioning sets
+    /// * `rng` - The random number generator
+    pub fn sample_sets(
+        &self,
+        intervals: &[[f32; 2]],
+        n_sets: usize,
+        rng: &mut StdRng,
+    ) -> Vec<Vec<f32>> {
+        let seeds = SeedSequence::from_rng(rng);
+        (0..n_sets)
+            .into_par_iter()
+            .map(|set| self.sample(intervals, &mut seeds.stream(set as u64)))
+            .collect()
+    }
+
+    /// Conditional expectation of each datum given all constraints, averaged over conditioning sets
+    /// # Arguments
+    /// * `intervals` - Lower and upper bound of each datum
+    /// * `n_sets` - Number of conditioning sets averaged
+    /// * `rng` - The random number generator
+    pub fn expected_values(
+        &self,
+        intervals: &[[f32; 2]],
+        n_sets: usize,
+        rng: &mut StdRng,
+    ) -> Vec<f32> {
+        assert!(n_sets > 0, "at least one conditioning set is required");
+        let sets = self.sample_sets(intervals, n_sets, rng);
+        (0..intervals.len())
+            .map(|i| sets.iter().map(|set| set[i]).sum::<f32>() / n_sets as f32)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion, Vector3};
+    use ndarray::Array3;
+    use rand::SeedableRng;
+
+    use crate::{
+        geometry::ellipsoid::Ellipsoid,
+        simulation::{
+            data_assignment::DataAssignment,
+            sgs::{SGSParameters, SGS},
+        },
+        spatial_database::{
+            coordinate_system::{CoordinateSystem, GridSpacing},
+            gridded_databases::{
+                gridded_data_base_query_engine::GriddedDataBaseOctantQueryEngine,
+                incomplete_grid::InCompleteGriddedDataBase, GriddedDataBaseInterface,
+            },
+        },
+        variography::model_variograms::spherical::SphericalVariogram,
+    };
+
+    use super::*;
+
+    #[test]
+    fn gibbs_samples_within_intervals() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let vgram = SphericalVariogram::new(Vector3::new(10.0, 10.0, 10.0), 1.0, 0.0, cs);
+        let points = (0..6)
+            .map(|i| Point3::new(i as f32, 0.0, 0.0))
+            .collect::<Vec<_>>();
+        let intervals = [
+            [0.5, f32::INFINITY],
+            [f32::NEG_INFINITY, f32::INFINITY],
+            [-0.2, 0.2],
+            [0.1, 0.1],
+            [f32::NEG_INFINITY, -1.0],
+            [f32::NEG_INFINITY, f32::INFINITY],
+        ];
+
+        let sampler = GibbsSampler::new(&points, &vgram).with_sweeps(20);
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..20 {
+            let values = sampler.sample(&intervals, &mut rng);
+            for (value, [lower, upper]) in values.iter().zip(intervals.iter()) {
+                assert!(value >= lower && value <= upper);
+            }
+            assert_eq!(values[3], 0.1);
+        }
+    }
+
+    #[test]
+    fn gibbs_merges_duplicate_locations() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let vgram = SphericalVariogram::new(Vector3::new(10.0, 10.0, 10.0), 1.0, 0.0, cs);
+
+        //two censored assays of the same sample and a neighbouring sample
+        let points = [
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(2.0, 0.0, 0.0),
+        ];
+        let intervals = [
+            [f32::NEG_INFINITY, 0.5],
+            [-1.0, f32::INFINITY],
+            [f32::NEG_INFINITY, f32::INFINITY],
+        ];
+
+        let sampler = GibbsSampler::new(&points, &vgram).with_sweeps(10);
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..20 {
+            let values = sampler.sample(&intervals, &mut rng);
+            assert_eq!(values[0], values[1]);
+            assert!((-1.0..=0.5).contains(&values[0]));
+            assert!(values[2].is_finite());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "disjoint intervals")]
+    fn gibbs_rejects_conflicting_duplicates() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let vgram = SphericalVariogram::new(Vector3::new(10.0, 10.0, 10.0), 1.0, 0.0, cs);
+        let points = [Point3::new(1.0, 0.0, 0.0); 2];
+        GibbsSampler::new(&points, &vgram).sample(
+            &[[f32::NEG_INFINITY, -1.0], [1.0, f32::INFINITY]],
+            &mut StdRng::seed_from_u64(0),
+        );
+    }
+
+    #[test]
+    fn gibbs_correlated_neighbour() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let vgram = SphericalVariogram::new(Vector3::new(20.0, 20.0, 20.0), 1.0, 0.0, cs);
+        let points = [Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0)];
+
+        //an unconstrained value next to a large exact datum follows it
+        let sampler = GibbsSampler::new(&points, &vgram).with_sweeps(5);
+        let mut rng = StdRng::seed_from_u64(1);
+        let mean = (0..500)
+            .map(|_| sampler.sample(&[[2.0, 2.0], [f32::NEG_INFINITY, f32::INFINITY]], &mut rng)[1])
+            .sum::<f32>()
+            / 500.0;
+        let rho = vgram.covariogram(points[1] - points[0]);
+        assert!((mean - 2.0 * rho).abs() < 0.1);
+    }
+
+    #[test]
+    fn conditioning_sets_feed_sgs() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        let vgram = SphericalVariogram::new(Vector3::new(6.0, 6.0, 6.0), 1.0, 0.05, cs);
+
+        //a censored datum below detection limit, an interval and an exact datum
+        let points = vec![
+            Point3::new(2.0, 2.0, 0.0),
+            Point3::new(5.0, 6.0, 0.0),
+            Point3::new(8.0, 3.0, 0.0),
+        ];
+        let intervals = [[f32::NEG_INFINITY, -0.5], [0.2, 0.8], [1.0, 1.0]];
+
+        let sampler = GibbsSampler::new(&points, &vgram).with_sweeps(10);
+        let sets = sampler.sample_sets(&intervals, 4, &mut StdRng::seed_from_u64(7));
+        assert_eq!(
+            sets,
+            sampler.sample_sets(&intervals, 4, &mut StdRng::seed_from_u64(7))
+        );
+        assert_ne!(sets[0], sets[1]);
+
+        for (set_ind, set) in sets.iter().enumerate() {
+            let mut sim_db =
+                InCompleteGriddedDataBase::new(Array3::from_elem((10, 10, 1), None), spacing, cs);
+            let assigned = DataAssignment::new(0.1).assign(&sim_db, &points, set);
+            let cond_db = InCompleteGriddedDataBase::new(assigned.clone(), spacing, cs);
+
+            SGS::new(
+                GriddedDataBaseOctantQueryEngine::new(
+                    Ellipsoid::new(6.0, 6.0, 6.0, cs),
+                    &cond_db,
+                    4,
+                ),
+                &vgram,
+                SGSParameters {
+                    max_octant_cond_data: 4,
+                    max_octant_sim_data: 4,
+                },
+            )
+            .with_assigned_data(assigned)
+            .simulate_grid(&mut sim_db, &mut StdRng::seed_from_u64(set_ind as u64));
+
+            assert!(sim_db.data_at_ind(&[2, 2, 0]).unwrap() <= -0.5);
+            let interval_value = sim_db.data_at_ind(&[5, 6, 0]).unwrap();
+            assert!((0.2..=0.8).contains(&interval_value));
+            assert_eq!(sim_db.data_at_ind(&[8, 3, 0]), Some(1.0));
+        }
+    }
+}
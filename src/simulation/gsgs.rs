@@ -4,11 +4,12 @@ use indicatif::ParallelProgressIterator;
 use itertools::{iproduct, Itertools};
 use nalgebra::Point3;
 use ndarray::Array3;
-use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use rand::{rngs::StdRng, seq::SliceRandom};
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 
 use crate::{
     geometry::Geometry,
+    kriging::KrigingType,
     spatial_database::{
         gridded_databases::{
             gridded_data_base_query_engine::GriddedDataBaseOctantQueryEngine,
@@ -19,7 +20,13 @@ use crate::{
     variography::model_variograms::VariogramModel,
 };
 
-use super::lu::LUSystem;
+use super::{
+    lu::{LUSystem, MiniLUSystem},
+    multigrid::{node_level, on_level},
+    path::write_assigned_data,
+    realizations::Simulation,
+    seeding::SeedSequence,
+};
 
 pub struct GSGSParameters {
     pub max_octant_cond_data: usize,
@@ -27,6 +34,11 @@ pub struct GSGSParameters {
     pub group_size: [usize; 3],
 }
 
+/// Path and factorized covariance matrices of a simulation
+pub struct GSGSPlan {
+    sequential_data: Vec<(Vec<[usize; 3]>, Vec<f32>, Vec<[usize; 3]>, MiniLUSystem)>,
+}
+
 pub struct GSGS<S, V, G>
 where
     S: SpatialQueryable<f32, G>,
@@ -34,6 +46,9 @@ where
     conditioning_data: S,
     variogram_model: V,
     gsgs_parameters: GSGSParameters,
+    assigned_data: Option<Array3<Option<f32>>>,
+    multigrid_levels: usize,
+    kriging_type: KrigingType,
     phantom: std::marker::PhantomData<G>,
 }
 
@@ -56,10 +71,43 @@ where
             conditioning_data,
             variogram_model,
             gsgs_parameters,
+            assigned_data: None,
+            multigrid_levels: 1,
+            kriging_type: KrigingType::default(),
             phantom: std::marker::PhantomData,
         }
     }
 
+    /// Freeze the nodes holding conditioning data
+    /// frozen nodes are removed from their group and keep their value, they are not used as simulation
+    /// neighbours since the data are already found by the conditioning search
+    /// # Arguments
+    /// * `assigned_data` - Value of each frozen node (see `DataAssignment`), must match the shape of the simulated grid
+    pub fn with_assigned_data(mut self, assigned_data: Array3<Option<f32>>) -> Self {
+        self.assigned_data = Some(assigned_data);
+        self
+    }
+
+    /// Visit coarse sub-grids before the full grid
+    /// level `k` holds every 2^k node, coarse levels are simulated first so long range structure is
+    /// reproduced with a limited number of simulated neighbours, the search for simulated neighbours
+    /// on a level only visits nodes of that level, groups are formed from the nodes of each level
+    /// # Arguments
+    /// * `levels` - Number of levels (1 for a single random path)
+    pub fn with_multigrid(mut self, levels: usize) -> Self {
+        assert!(levels > 0, "at least one multigrid level is required");
+        self.multigrid_levels = levels;
+        self
+    }
+
+    /// Treatment of the mean in the group systems
+    /// ordinary kriging re-estimates the mean from the neighbourhood of each group and simulates the
+    /// uncertainty of that estimate, groups without neighbours fall back to simple kriging
+    pub fn with_kriging_type(mut self, kriging_type: KrigingType) -> Self {
+        self.kriging_type = kriging_type;
+        self
+    }
+
     #[inline(always)]
     fn create_path<GDB>(
         &self,
@@ -70,43 +118,72 @@ where
     where
         GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
     {
-        // Array to store simulation order
-        let mut simulation_order = Array3::from_elem(grid.shape(), 0);
+        if let Some(assigned_data) = self.assigned_data.as_ref() {
+            assert_eq!(
+                assigned_data.shape(),
+                grid.shape().as_slice(),
+                "assigned data must match the shape of the grid"
+            );
+        }
 
-        //create path over groups
-        let i_steps = (grid.shape()[0] as f32 / step[0] as f32).ceil() as usize;
-        let j_steps = (grid.shape()[1] as f32 / step[1] as f32).ceil() as usize;
-        let k_steps = (grid.shape()[2] as f32 / step[2] as f32).ceil() as usize;
-        let grid_shape = grid.shape();
+        // Array to store simulation order (frozen nodes are never simulated)
+        let mut simulation_order = Array3::from_elem(grid.shape(), usize::MAX);
 
+        let grid_shape = grid.shape();
         let mut path = Vec::new();
-        for (group_i, group_j, group_k) in iproduct!(0..i_steps, 0..j_steps, 0..k_steps) {
-            //get group bounds
-            let i_min = group_i * step[0];
-            let i_max = ((group_i + 1) * step[0]).min(grid_shape[0]);
-            let j_min = group_j * step[1];
-            let j_max = ((group_j + 1) * step[1]).min(grid_shape[1]);
-            let k_min = group_k * step[2];
-            let k_max = ((group_k + 1) * step[2]).min(grid_shape[2]);
-
-            //get all points in group
-            let mut group_points = Vec::new();
-            for i in i_min..i_max {
-                for j in j_min..j_max {
-                    for k in k_min..k_max {
-                        group_points.push([i, j, k]);
+        for level in (0..self.multigrid_levels).rev() {
+            //groups are formed in the index space of the level
+            let stride = 1 << level;
+            let level_shape = grid_shape.map(|v| v.div_ceil(stride));
+
+            //create path over groups
+            let i_steps = (level_shape[0] as f32 / step[0] as f32).ceil() as usize;
+            let j_steps = (level_shape[1] as f32 / step[1] as f32).ceil() as usize;
+            let k_steps = (level_shape[2] as f32 / step[2] as f32).ceil() as usize;
+
+            let mut level_path = Vec::new();
+            for (group_i, group_j, group_k) in iproduct!(0..i_steps, 0..j_steps, 0..k_steps) {
+                //get group bounds
+                let i_min = group_i * step[0];
+                let i_max = ((group_i + 1) * step[0]).min(level_shape[0]);
+                let j_min = group_j * step[1];
+                let j_max = ((group_j + 1) * step[1]).min(level_shape[1]);
+                let k_min = group_k * step[2];
+                let k_max = ((group_k + 1) * step[2]).min(level_shape[2]);
+
+                //get all points in group
+                let mut group_points = Vec::new();
+                for i in i_min..i_max {
+                    for j in j_min..j_max {
+                        for k in k_min..k_max {
+                            let ind = [i * stride, j * stride, k * stride];
+                            let frozen = self
+                                .assigned_data
+                                .as_ref()
+                                .is_some_and(|assigned_data| assigned_data[ind].is_some());
+                            //nodes of coarser levels are already simulated
+                            if !frozen && node_level(ind, self.multigrid_levels) == level {
+                                group_points.push(ind);
+                            }
+                        }
                     }
                 }
+
+                //groups holding only frozen nodes are not simulated
+                if group_points.is_empty() {
+                    continue;
+                }
+
+                //shuffle group points
+                group_points.shuffle(rng);
+                level_path.push(group_points);
             }
 
-            //shuffle group points
-            group_points.shuffle(rng);
-            path.push(group_points);
+            //shuffle path order
+            level_path.shuffle(rng);
+            path.extend(level_path);
         }
 
-        //shuffle path order
-        path.shuffle(rng);
-
         //set simulation order
         for (ind, val) in path.iter().enumerate() {
             for val in val.iter() {
@@ -117,32 +194,41 @@ where
         (path, simulation_order)
     }
 
-    /// Perform simple kriging at all kriging points
-    pub fn simulate_grid<GDB>(&self, grid: &mut GDB, rng: &mut StdRng)
+    /// Shuffle the path and factorize the covariance matrix of every group
+    fn build_plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> GSGSPlan
     where
         GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
     {
-        // create query engine for simulation grid
+        // create query engine for simulation grid, one per multigrid level
         let geometry = self.conditioning_data.geometry().clone();
-        let mut sim_qe = GriddedDataBaseOctantQueryEngine::new(
-            geometry.clone(),
-            grid,
-            self.gsgs_parameters.max_octant_sim_data,
-        );
-
-        //filter offsets to avoid unnecessary searching
         let step = self.gsgs_parameters.group_size;
-        let internal_offsets = iproduct!(0..step[0], 0..step[1], 0..step[2])
-            .map(|(i, j, k)| [i, j, k])
-            .collect_vec();
-        let retainer = |offset: &[isize; 3]| {
-            if offset.iter().any(|v| *v < 0) {
-                return true;
-            }
-            let offset = offset.map(|v| v as usize);
-            !internal_offsets.iter().any(|v| v == &offset)
-        };
-        sim_qe.retain_offsets(retainer);
+        let sim_qes = (0..self.multigrid_levels)
+            .map(|level| {
+                let mut sim_qe = GriddedDataBaseOctantQueryEngine::new(
+                    geometry.clone(),
+                    grid,
+                    self.gsgs_parameters.max_octant_sim_data,
+                );
+
+                //filter offsets to avoid unnecessary searching
+                let stride = 1 << level;
+                let internal_offsets = iproduct!(0..step[0], 0..step[1], 0..step[2])
+                    .map(|(i, j, k)| [i * stride, j * stride, k * stride])
+                    .collect_vec();
+                let retainer = |offset: &[isize; 3]| {
+                    if !on_level(offset, level) {
+                        return false;
+                    }
+                    if offset.iter().any(|v| *v < 0) {
+                        return true;
+                    }
+                    let offset = offset.map(|v| v as usize);
+                    !internal_offsets.iter().any(|v| v == &offset)
+                };
+                sim_qe.retain_offsets(retainer);
+                sim_qe
+            })
+            .collect::<Vec<_>>();
 
         //create path over groups
         let (path, simulation_order) = self.create_path(grid, self.gsgs_parameters.group_size, rng);
@@ -154,16 +240,13 @@ where
             .par_iter()
             .progress()
             .map_with(
-                (
-                    LUSystem::new(
-                        self.gsgs_parameters.group_size.iter().product(),
-                        (self.gsgs_parameters.max_octant_cond_data
-                            + self.gsgs_parameters.max_octant_sim_data)
-                            * 8,
-                    ),
-                    StdRng::from_entropy(),
+                LUSystem::new(
+                    self.gsgs_parameters.group_size.iter().product(),
+                    (self.gsgs_parameters.max_octant_cond_data
+                        + self.gsgs_parameters.max_octant_sim_data)
+                        * 8,
                 ),
-                |(local_system, local_rng), inds| {
+                |local_system, inds| {
                     //get point at center of group
                     let sim_points = inds
                         .iter()
@@ -178,6 +261,7 @@ where
                     let (cond_values, mut cond_points) = self.conditioning_data.query(&point);
 
                     // get nearest simulation points
+                    let sim_qe = &sim_qes[node_level(inds[0], self.multigrid_levels)];
                     let (sim_cond_inds, sim_cond_points) =
                         sim_qe.nearest_inds_and_points_masked(&point, |neighbor_ind| {
                             //true
@@ -190,31 +274,42 @@ where
                     cond_points.extend(sim_cond_points.iter());
 
                     // Cholesky error when simulating a point present in conditioning data
-                    // this is a quick but not great fix (randomly shift point by very small value)
-                    // TODO: remove duplicate point(s) from sim_points and populate with conditioning value
-                    // let cond_points = cond_points
-                    //     .iter_mut()
-                    //     .map(|point| {
-                    //         point.coords.x += local_rng.gen::<f32>() * 0.0001;
-                    //         point.coords.y += local_rng.gen::<f32>() * 0.0001;
-                    //         point.coords.z += local_rng.gen::<f32>() * 0.0001;
-                    //         *point
-                    //     })
-                    //     .collect_vec();
-
-                    let mini_system = local_system.create_mini_sk_system(
-                        &cond_points,
-                        &sim_points,
-                        &self.variogram_model,
-                    );
-
-                    (inds, cond_values, sim_cond_inds, mini_system)
+                    // nodes holding data should be frozen with `with_assigned_data`
+
+                    let mini_system = match self.kriging_type {
+                        KrigingType::Simple => {
+                            MiniLUSystem::Simple(local_system.create_mini_sk_system(
+                                &cond_points,
+                                &sim_points,
+                                &self.variogram_model,
+                            ))
+                        }
+                        KrigingType::Ordinary => {
+                            MiniLUSystem::Ordinary(local_system.create_mini_ok_system(
+                                &cond_points,
+                                &sim_points,
+                                &self.variogram_model,
+                            ))
+                        }
+                    };
+
+                    (inds.clone(), cond_values, sim_cond_inds, mini_system)
                 },
             )
             .collect::<Vec<_>>();
 
-        sequential_data.into_iter().for_each(
-            |(inds, cond_values, sim_cond_inds, mut mini_system)| {
+        GSGSPlan { sequential_data }
+    }
+
+    /// Simulate the groups of a plan in path order
+    fn apply_plan<GDB>(&self, plan: &GSGSPlan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        write_assigned_data(grid, self.assigned_data.as_ref());
+
+        plan.sequential_data.iter().enumerate().for_each(
+            |(group_ind, (inds, cond_values, sim_cond_inds, mini_system))| {
                 //get simulation values
                 let sim_values = sim_cond_inds
                     .iter()
@@ -222,11 +317,14 @@ where
                     .collect::<Vec<_>>();
 
                 let values = cond_values
-                    .into_iter()
+                    .iter()
+                    .copied()
                     .chain(sim_values)
                     .collect::<Vec<_>>();
 
-                mini_system.populate_w_vec(values.as_slice(), rng);
+                //the plan may be shared by several realizations
+                let mut mini_system = mini_system.clone();
+                mini_system.populate_w_vec(values.as_slice(), &mut seeds.stream(group_ind as u64));
 
                 let vals = mini_system.simulate();
 
@@ -239,18 +337,42 @@ where
     }
 }
 
+impl<S, V, G> Simulation for GSGS<S, V, G>
+where
+    S: SpatialQueryable<f32, G> + Sync,
+    V: VariogramModel + Sync,
+    G: Geometry + Sync + Clone,
+{
+    type Plan = GSGSPlan;
+
+    fn plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> Self::Plan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.build_plan(grid, rng)
+    }
+
+    fn realize<GDB>(&self, plan: &Self::Plan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.apply_plan(plan, grid, seeds);
+    }
+}
+
 #[cfg(test)]
 mod test {
 
     use super::*;
     use std::{fs::File, io::Write};
 
-    use nalgebra::{UnitQuaternion, Vector3};
+    use nalgebra::{Translation3, UnitQuaternion, Vector3};
     use num_traits::Float;
     use rand::SeedableRng;
 
     use crate::{
         geometry::ellipsoid::Ellipsoid,
+        simulation::{data_assignment::DataAssignment, realizations::simulate_on_threads},
         spatial_database::{
             coordinate_system::{CoordinateSystem, GridSpacing},
             gridded_databases::incomplete_grid::InCompleteGriddedDataBase,
@@ -358,4 +480,201 @@ mod test {
                 .write_all(format!("{} {} {} {}\n", point.x, point.y, point.z, value).as_bytes());
         }
     }
+
+    #[test]
+    fn gsgs_reproducible_across_threads() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        let mut cond_grid = Array3::<Option<f32>>::from_elem((12, 12, 1), None);
+        cond_grid[[2, 3, 0]] = Some(0.5);
+        cond_grid[[9, 8, 0]] = Some(-1.0);
+        cond_grid[[5, 10, 0]] = Some(1.2);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid, spacing, cs);
+
+        let vgram = SphericalVariogram::new(Vector3::new(6.0, 6.0, 6.0), 1.0, 0.1, cs);
+        let ellipsoid = Ellipsoid::new(8.0, 8.0, 8.0, cs);
+        let simulation = GSGS::new(
+            GriddedDataBaseOctantQueryEngine::new(ellipsoid, &cond_db, 4),
+            vgram,
+            GSGSParameters {
+                max_octant_cond_data: 4,
+                max_octant_sim_data: 4,
+                group_size: [3, 3, 1],
+            },
+        );
+
+        let realization = |threads: usize, seed: u64| {
+            let mut sim_db =
+                InCompleteGriddedDataBase::new(Array3::from_elem((12, 12, 1), None), spacing, cs);
+            simulate_on_threads(&simulation, &mut sim_db, threads, seed);
+            sim_db.raw_grid.grid
+        };
+
+        let single_thread = realization(1, 11);
+        assert!(single_thread.iter().all(|value| value.is_some()));
+        assert_eq!(single_thread, realization(4, 11));
+        assert_ne!(single_thread, realization(4, 12));
+    }
+
+    #[test]
+    fn gsgs_reproduces_assigned_data() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        //data lie on the nodes of the simulation grid
+        let mut cond_grid = Array3::<Option<f32>>::from_elem((10, 10, 1), None);
+        cond_grid[[2, 3, 0]] = Some(0.5);
+        cond_grid[[7, 8, 0]] = Some(-1.0);
+        cond_grid[[4, 4, 0]] = Some(1.2);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid.clone(), spacing, cs);
+        let (values, points) = cond_db.data_and_points();
+
+        let mut sim_db = InCompleteGriddedDataBase::new(
+            Array3::<Option<f32>>::from_elem((10, 10, 1), None),
+            spacing,
+            cs,
+        );
+        let assigned = DataAssignment::new(0.1).assign(&sim_db, &points, &values);
+        assert_eq!(assigned, cond_grid);
+
+        let simulation = GSGS::new(
+            GriddedDataBaseOctantQueryEngine::new(Ellipsoid::new(6.0, 6.0, 6.0, cs), &cond_db, 4),
+            SphericalVariogram::new(Vector3::new(5.0, 5.0, 5.0), 1.0, 0.1, cs),
+            GSGSParameters {
+                max_octant_cond_data: 4,
+                max_octant_sim_data: 4,
+                group_size: [3, 3, 1],
+            },
+        )
+        .with_assigned_data(assigned);
+        simulation.simulate_grid(&mut sim_db, &mut StdRng::seed_from_u64(3));
+
+        assert_eq!(sim_db.data_at_ind(&[2, 3, 0]), Some(0.5));
+        assert_eq!(sim_db.data_at_ind(&[7, 8, 0]), Some(-1.0));
+        assert_eq!(sim_db.data_at_ind(&[4, 4, 0]), Some(1.2));
+        assert!(sim_db
+            .raw_grid
+            .grid
+            .iter()
+            .all(|value| value.is_some_and(f32::is_finite)));
+    }
+
+    #[test]
+    fn gsgs_multigrid_path() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        let mut cond_grid = Array3::<Option<f32>>::from_elem((12, 12, 1), None);
+        cond_grid[[2, 3, 0]] = Some(0.5);
+        cond_grid[[9, 8, 0]] = Some(-1.0);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid, spacing, cs);
+
+        let simulation = GSGS::new(
+            GriddedDataBaseOctantQueryEngine::new(Ellipsoid::new(8.0, 8.0, 8.0, cs), &cond_db, 4),
+            SphericalVariogram::new(Vector3::new(8.0, 8.0, 8.0), 1.0, 0.1, cs),
+            GSGSParameters {
+                max_octant_cond_data: 4,
+                max_octant_sim_data: 4,
+                group_size: [2, 2, 1],
+            },
+        )
+        .with_multigrid(3);
+
+        let mut sim_db = InCompleteGriddedDataBase::new(
+            Array3::<Option<f32>>::from_elem((12, 12, 1), None),
+            spacing,
+            cs,
+        );
+
+        //coarse levels are visited first
+        let plan = simulation.build_plan(&sim_db, &mut StdRng::seed_from_u64(1));
+        let levels = plan
+            .sequential_data
+            .iter()
+            .map(|(inds, _, sim_inds, _)| {
+                let level = node_level(inds[0], 3);
+                //groups hold nodes of a single level
+                assert!(inds.iter().all(|ind| node_level(*ind, 3) == level));
+                assert!(sim_inds.iter().all(|ind| node_level(*ind, 3) >= level));
+                level
+            })
+            .collect::<Vec<_>>();
+        let n_nodes = plan
+            .sequential_data
+            .iter()
+            .map(|(inds, ..)| inds.len())
+            .sum::<usize>();
+        assert_eq!(n_nodes, 144);
+        assert!(levels.windows(2).all(|pair| pair[0] >= pair[1]));
+        assert_eq!(levels[0], 2);
+
+        simulation.simulate_grid(&mut sim_db, &mut StdRng::seed_from_u64(1));
+        assert!(sim_db
+            .raw_grid
+            .grid
+            .iter()
+            .all(|value| value.is_some_and(f32::is_finite)));
+    }
+
+    #[test]
+    fn gsgs_ordinary_kriging_follows_shifted_data() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        let realization = |shift: f32| {
+            let mut cond_grid = Array3::<Option<f32>>::from_elem((8, 8, 1), None);
+            cond_grid[[1, 2, 0]] = Some(0.5 + shift);
+            cond_grid[[6, 5, 0]] = Some(-1.0 + shift);
+            cond_grid[[3, 7, 0]] = Some(0.2 + shift);
+            let cond_db = InCompleteGriddedDataBase::new(cond_grid, spacing, cs);
+
+            let gsgs = GSGS::new(
+                GriddedDataBaseOctantQueryEngine::new(
+                    Ellipsoid::new(20.0, 20.0, 20.0, cs),
+                    &cond_db,
+                    4,
+                ),
+                SphericalVariogram::new(Vector3::new(6.0, 6.0, 6.0), 1.0, 0.1, cs),
+                GSGSParameters {
+                    max_octant_cond_data: 4,
+                    max_octant_sim_data: 4,
+                    group_size: [2, 2, 1],
+                },
+            )
+            .with_kriging_type(KrigingType::Ordinary);
+
+            let mut sim_db = InCompleteGriddedDataBase::new(
+                Array3::<Option<f32>>::from_elem((8, 8, 1), None),
+                spacing,
+                cs,
+            );
+            gsgs.simulate_grid(&mut sim_db, &mut StdRng::seed_from_u64(3));
+            sim_db.raw_grid.grid.map(|value| value.unwrap())
+        };
+
+        //ordinary kriging weights sum to one, a shift of the data shifts the realization
+        let values = realization(0.0);
+        let shifted = realization(3.0);
+        assert!(values
+            .iter()
+            .zip(shifted.iter())
+            .all(|(value, shifted)| (shifted - value - 3.0).abs() < 1e-3));
+    }
 }
@@ -1,395 +1,546 @@
-// use std::{collections::HashMap, fs::File, io::Write};
-
-// use itertools::Itertools;
-// use mathru::algebra::abstr::Polynomial;
-// use rand::prelude::*;
-// use rand::seq::SliceRandom;
-
-// use crate::{
-//     geometry::{ellipsoid::Ellipsoid, template::Template},
-//     spatial_database::{
-//         gridded_databases::{
-//             self, complete_grid::CompleteGriddedDataBase,
-//             gridded_data_base_query_engine_mut::GriddedDataBaseOctantQueryEngineMut,
-//             incomplete_grid::InCompleteGriddedDataBase,
-//         },
-//         SpatialDataBase,
-//     },
-// };
-
-// struct CDFSampler<'a> {
-//     cdf: &'a [f32],
-//     val: &'a [f32],
-// }
-
-// impl<'a> CDFSampler<'a> {
-//     pub fn new(cdf: &'a [f32], val: &'a [f32]) -> Self {
-//         assert!(!cdf.is_empty(), "CDF cannot be empty.");
-
-//         assert!(
-//             cdf.len() == val.len(),
-//             "CDF and values must be of same length."
-//         );
-//         CDFSampler { cdf, val }
-//     }
-
-//     pub fn sample(&self) -> f32 {
-//         let random_val = rand::random::<f32>();
-
-//         let Some(index) = self.cdf.iter().position(|&cdf_val| cdf_val > random_val) else {
-//             return self.cdf[self.cdf.len() - 1];
-//         };
-
-//         if index == 0 {
-//             return self.val[0];
-//         }
-
-//         let fraction = (random_val - self.cdf[index - 1]) / (self.cdf[index] - self.cdf[index - 1]);
-
-//         self.val[index - 1] + fraction * (self.val[index] - self.val[index - 1])
-//     }
-// }
-
-// pub struct ReplicateDatabase {
-//     replicates: HashMap<Template, Vec<Vec<f32>>>,
-// }
-
-// pub struct HOSIM {
-//     pub training_image: CompleteGriddedDataBase<f32>,
-//     pub conditioning_data: InCompleteGriddedDataBase<f32>,
-//     pub legendre_polynomials: Vec<Polynomial<f32>>,
-// }
-
-// impl HOSIM {
-//     pub fn new(
-//         training_image: CompleteGriddedDataBase<f32>,
-//         conditioning_data: InCompleteGriddedDataBase<f32>,
-//         order: usize,
-//     ) -> Self {
-//         let legendre_polynomials = Self::compute_legendre_polynomials(order + 1);
-//         Self {
-//             training_image,
-//             conditioning_data,
-//             legendre_polynomials,
-//         }
-//     }
-
-//     pub fn compute_legendre_polynomials(order: usize) -> Vec<Polynomial<f32>> {
-//         let mut polys = vec![Polynomial::from_coef(vec![0.0]); order];
-//         let n_f64 = order as f64;
-
-//         polys[0] = Polynomial::from_coef(vec![1.0]);
-//         polys[1] = Polynomial::from_coef(vec![1.0, 0.0]);
-//         println!("{}", polys[1]);
-//         for n in 2..order {
-//             let p_1 = Polynomial::from_coef(vec![2.0f32 * n as f32 - 1.0f32, 0.0]);
-//             let p_2 = Polynomial::from_coef(vec![n as f32 - 1.0f32]);
-//             let p_3 = Polynomial::from_coef(vec![n as f32]);
-//             polys[n] =
-//                 ((p_1 * polys[n as usize - 1].clone() - p_2 * polys[n as usize - 2].clone()) / p_3)
-//                     .0;
-//         }
-
-//         polys
-//     }
-
-//     pub fn simulate(
-//         &mut self,
-//         simulation_grid: &mut InCompleteGriddedDataBase<f32>,
-//         template_search_ellipsoid: Ellipsoid,
-//     ) {
-//         //simulation path
-//         let mut path = simulation_grid
-//             .raw_grid
-//             .grid
-//             .indexed_iter()
-//             .map(|(ind, _)| ind)
-//             .collect::<Vec<_>>();
-
-//         //shuffle path order
-//         path.shuffle(&mut rand::thread_rng());
-
-//         //create mutable query engine
-//         let mut query_engine =
-//             GriddedDataBaseOctantQueryEngineMut::new(template_search_ellipsoid, simulation_grid, 2);
-
-//         //sequentially simulate each node in patch
-//         let len = path.len();
-//         for (i, ind) in path.into_iter().enumerate() {
-//             println!("Simulating node {} of {}.", i, len);
-//             // hard data already present -> go to next node in path
-//             if query_engine.db.data_at_ind(&ind.into()).is_some() {
-//                 continue;
-//             }
-
-//             // construct data event
-//             let (data_event_inds, data_event_values) =
-//                 query_engine.nearest_inds_and_values_to_ind(&ind.into());
-
-//             //create template
-//             let data_event_template = Template::new(
-//                 data_event_inds
-//                     .iter()
-//                     .map(|t| {
-//                         [
-//                             t[0] as isize - ind.0 as isize,
-//                             t[1] as isize - ind.1 as isize,
-//                             t[2] as isize - ind.2 as isize,
-//                         ]
-//                     })
-//                     .collect_vec(),
-//             );
-
-//             //find replicates in ti
-//             let replicates = self.find_replicates_in_ti(&data_event_template);
-
-//             //compute cdf
-//             let cdf = self.compute_cdf(data_event_values.clone(), replicates.clone());
-
-//             //compute ccdf
-//             let ccdf = Self::compute_ccdf(cdf);
-//             // println!("CCDF: {:?}", ccdf);
-
-//             //sample
-//             let vals = (0..100).map(|i| -1f32 + i as f32 / 50f32).collect_vec();
-//             let ccdf = vals
-//                 .iter()
-//                 .map(|v| {
-//                     ccdf.iter().enumerate().fold(0f32, |mut acc, (ind, val)| {
-//                         acc += self.legendre_polynomials[ind].eval(*v) * val;
-//                         acc
-//                     })
-//                 })
-//                 .collect_vec();
-//             let mut sampler = CDFSampler::new(ccdf.as_slice(), vals.as_slice());
-
-//             query_engine
-//                 .db
-//                 .set_data_at_ind(&ind.into(), sampler.sample());
-
-//             if i % 500 == 0 {
-//                 //save values to file for visualization
-//                 let (values, points) =
-//                     gridded_databases::GriddedDataBaseInterface::data_and_points(query_engine.db);
-
-//                 let mut out =
-//                     File::create(format!("./test_results/hosim_intermediate_{i}.txt")).unwrap();
-//                 let _ = out.write_all(b"surfs\n");
-//                 let _ = out.write_all(b"4\n");
-//                 let _ = out.write_all(b"x\n");
-//                 let _ = out.write_all(b"y\n");
-//                 let _ = out.write_all(b"z\n");
-//                 let _ = out.write_all(b"value\n");
-
-//                 for (point, value) in points.iter().zip(values.iter()) {
-//                     //println!("point: {:?}, value: {}", point, value);
-//                     let _ = out.write_all(
-//                         format!("{} {} {} {}\n", point.x, point.y, point.z, value).as_bytes(),
-//                     );
-//                 }
-//             }
-//         }
-
-//         //find
-//     }
-
-//     #[inline(always)]
-//     fn find_replicates_in_ti(&self, template: &Template) -> Vec<Vec<f32>> {
-//         self.training_image
-//             .raw_grid
-//             .grid
-//             .indexed_iter()
-//             .filter_map(|(ind, val)| template.replicate_at_ind(&self.training_image, &ind.into()))
-//             .collect_vec()
-//     }
-
-//     #[inline(always)]
-//     fn compute_cdf(&self, data_event: Vec<f32>, replicates: Vec<Vec<f32>>) -> Vec<f32> {
-//         let mut c = vec![0.0; self.legendre_polynomials.len() - 1];
-//         let mut x = vec![0.0; self.legendre_polynomials.len() - 1];
-
-//         for replicate in replicates {
-//             for (w, p) in self.legendre_polynomials[0..self.legendre_polynomials.len() - 1]
-//                 .iter()
-//                 .enumerate()
-//             {
-//                 x[w] = (w as f32 + 0.5) * p.eval(replicate[0]);
-//             }
-
-//             let mut x_prod = 1.0;
-
-//             for i in 0..data_event.len() {
-//                 let mut x_t = 0.0;
-//                 for (w, p) in self.legendre_polynomials.iter().enumerate() {
-//                     x_t += (w as f32 + 0.5) * p.eval(replicate[i + 1]) * p.eval(data_event[i]);
-//                 }
-
-//                 x_prod *= x_t;
-//             }
-
-//             for w in 0..self.legendre_polynomials.len() - 1 {
-//                 x[w] *= x_prod;
-//                 c[w] += x[w]
-//             }
-//         }
-
-//         let denom = 2.0 * c[0];
-
-//         for w in 0..self.legendre_polynomials.len() - 1 {
-//             c[w] /= denom
-//         }
-
-//         c
-//     }
-
-//     #[inline(always)]
-//     fn compute_ccdf(c: Vec<f32>) -> Vec<f32> {
-//         let mut ccdf = vec![0.0; c.len() + 1];
-
-//         for i in 1..c.len() {
-//             ccdf[i + 1] += c[i] / (2.0 * i as f32 + 1.0);
-//             ccdf[i - 1] -= c[i] / (2.0 * i as f32 + 1.0);
-//         }
-//         ccdf[0] += 0.5;
-//         ccdf[1] += 0.5;
-//         ccdf
-//     }
-// }
-
-// #[cfg(test)]
-// mod tests {
-//     use std::{fs::File, io::Write};
-
-//     use nalgebra::{Point3, UnitQuaternion};
-//     use ndarray::Array3;
-//     use num_traits::Float;
-
-//     use crate::spatial_database::{
-//         coordinate_system::{CoordinateSystem, GridSpacing},
-//         gridded_databases::{self, GriddedDataBaseInterface},
-//     };
-
-//     use super::*;
-
-//     #[test]
-//     fn cdf_sampler() {
-//         let cdf = vec![0.1, 0.5, 0.8, 0.9, 1.0];
-//         let vals = vec![1.0, 2.0, 3.0, 4.0, 5.0];
-//         let sampler = CDFSampler::new(&cdf, &vals);
-
-//         for _ in 0..10 {
-//             println!("Sampled value: {}", sampler.sample());
-//         }
-//     }
-
-//     fn map_range(from_range: (f32, f32), to_range: (f32, f32), s: f32) -> f32 {
-//         to_range.0 + (s - from_range.0) * (to_range.1 - to_range.0) / (from_range.1 - from_range.0)
-//     }
-
-//     #[test]
-//     fn hosim_test() {
-//         // Define the coordinate system for the grid
-//         // origing at x = 0, y = 0, z = 0
-//         // azimuth = 0, dip = 0, plunge = 0
-//         let coordinate_system = CoordinateSystem::new(
-//             Point3::new(0.0, 0.0, 0.0).into(),
-//             UnitQuaternion::from_euler_angles(0.0.to_radians(), 0.0.to_radians(), 0.0.to_radians()),
-//         );
-
-//         // create a training image from a csv file (walker lake)
-//         let mut ti_gdb = CompleteGriddedDataBase::<f32>::from_csv_index(
-//             "./data/Exhaustive_set.csv",
-//             "X",
-//             "Y",
-//             "Z",
-//             "V",
-//             GridSpacing {
-//                 x: 1.0,
-//                 y: 1.0,
-//                 z: 1.0,
-//             },
-//             coordinate_system,
-//         )
-//         .expect("Failed to create gdb");
-
-//         let min = ti_gdb
-//             .raw_grid
-//             .grid
-//             .iter()
-//             .min_by(|a, b| a.partial_cmp(b).unwrap())
-//             .unwrap()
-//             .clone();
-
-//         let max = ti_gdb
-//             .raw_grid
-//             .grid
-//             .iter()
-//             .max_by(|a, b| a.partial_cmp(b).unwrap())
-//             .unwrap()
-//             .clone();
-
-//         //map data to [-1, 1]
-//         ti_gdb
-//             .raw_grid
-//             .grid
-//             .mapv_inplace(|v| map_range((min, max), (-1.0, 1.0), v));
-
-//         // create a conditioning gridded database from a csv file (walker lake)
-//         let mut conditioning_gdb = InCompleteGriddedDataBase::from_csv_index(
-//             "./data/walker.csv",
-//             "X",
-//             "Y",
-//             "Z",
-//             "V",
-//             GridSpacing {
-//                 x: 1.0,
-//                 y: 1.0,
-//                 z: 1.0,
-//             },
-//             coordinate_system,
-//         )
-//         .expect("Failed to create gdb");
-
-//         //map data to [-1, 1]
-//         conditioning_gdb.raw_grid.grid.mapv_inplace(|v| {
-//             if let Some(val) = v {
-//                 return Some(map_range((min, max), (-1.0, 1.0), val));
-//             } else {
-//                 None
-//             }
-//         });
-
-//         // create a grid to store the simulation values
-//         let sim_grid_arr = Array3::<Option<f32>>::from_elem(ti_gdb.shape(), None);
-//         let mut sim_db = InCompleteGriddedDataBase::new(
-//             sim_grid_arr,
-//             ti_gdb.grid_spacing().clone(),
-//             ti_gdb.coordinate_system().clone(),
-//         );
-
-//         // create search ellipsoid
-//         let search_ellipsoid = Ellipsoid::new(450.0, 150.0, 1.0, coordinate_system.clone());
-
-//         //create HOSIM instance
-//         let mut hosim = HOSIM::new(ti_gdb, conditioning_gdb, 8);
-//         hosim.simulate(&mut sim_db, search_ellipsoid);
-
-//         //save values to file for visualization
-//         let (values, points) =
-//             gridded_databases::GriddedDataBaseInterface::data_and_points(&sim_db);
-
-//         let mut out = File::create("./test_results/hosim.txt").unwrap();
-//         let _ = out.write_all(b"surfs\n");
-//         let _ = out.write_all(b"4\n");
-//         let _ = out.write_all(b"x\n");
-//         let _ = out.write_all(b"y\n");
-//         let _ = out.write_all(b"z\n");
-//         let _ = out.write_all(b"value\n");
-
-//         for (point, value) in points.iter().zip(values.iter()) {
-//             //println!("point: {:?}, value: {}", point, value);
-//             let _ = out
-//                 .write_all(format!("{} {} {} {}\n", point.x, point.y, point.z, value).as_bytes());
-//         }
-//     }
-// }
+use nalgebra::distance_squared;
+use ndarray::Array3;
+use rand::rngs::StdRng;
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    geometry::{template::Template, Geometry},
+    spatial_database::gridded_databases::{
+        complete_grid::CompleteGriddedDataBase, GriddedDataBaseInterface,
+    },
+};
+
+use super::{
+    multigrid::node_level,
+    path::{level_offsets, level_query_engines, random_path, write_assigned_data},
+    realizations::Simulation,
+    seeding::SeedSequence,
+};
+
+/// Number of training image nodes scanned by a task
+const REPLICATE_CHUNK: usize = 4096;
+
+/// Number of values at which the conditional cdf is evaluated
+const CDF_BINS: usize = 201;
+
+/// Legendre polynomials up to an order evaluated at a value in [-1, 1]
+#[inline(always)]
+fn legendre(x: f32, order: usize, polys: &mut [f32]) {
+    polys[0] = 1.0;
+    if order > 0 {
+        polys[1] = x;
+    }
+    for n in 2..=order {
+        let n_f = n as f32;
+        polys[n] = ((2.0 * n_f - 1.0) * x * polys[n - 1] - (n_f - 1.0) * polys[n - 2]) / n_f;
+    }
+}
+
+/// Inverse transform sampling of a cdf tabulated at increasing values
+struct CDFSampler {
+    cdf: Vec<f32>,
+    val: Vec<f32>,
+}
+
+impl CDFSampler {
+    pub fn new(cdf: Vec<f32>, val: Vec<f32>) -> Self {
+        assert!(!cdf.is_empty(), "CDF cannot be empty.");
+
+        assert!(
+            cdf.len() == val.len(),
+            "CDF and values must be of same length."
+        );
+        CDFSampler { cdf, val }
+    }
+
+    /// Value at a probability, interpolated between the tabulated values
+    pub fn sample(&self, p: f32) -> f32 {
+        let Some(index) = self.cdf.iter().position(|&cdf_val| cdf_val > p) else {
+            return self.val[self.val.len() - 1];
+        };
+
+        if index == 0 {
+            return self.val[0];
+        }
+
+        let fraction = (p - self.cdf[index - 1]) / (self.cdf[index] - self.cdf[index - 1]);
+
+        self.val[index - 1] + fraction * (self.val[index] - self.val[index - 1])
+    }
+}
+
+/// Path and data events of a simulation
+pub struct HOSIMPlan {
+    sequential_data: Vec<HOSIMStep>,
+}
+
+/// Node, neighbours forming its data event (nearest first) and their template
+struct HOSIMStep {
+    ind: [usize; 3],
+    sim_inds: Vec<[usize; 3]>,
+    template: Template,
+}
+
+/// High-order sequential simulation
+/// the conditional pdf of a node is a Legendre series whose coefficients are the high-order spatial
+/// cumulants of the training image for the template of the data event, replicates of the template are
+/// weighted by how close their values are to the data event
+/// * values are mapped to [-1, 1] using the range of the training image
+/// * the Legendre values of the training image are computed once and replicates are scanned in parallel
+pub struct HOSIM<G> {
+    training_image: CompleteGriddedDataBase<f32>,
+    legendre_table: Vec<f32>,
+    order: usize,
+    geometry: G,
+    max_octant_size: usize,
+    min: f32,
+    max: f32,
+    assigned_data: Option<Array3<Option<f32>>>,
+    multigrid_levels: usize,
+}
+
+impl<G> HOSIM<G>
+where
+    G: Geometry + Sync + Clone,
+{
+    /// Create a new high-order simulation
+    /// # Arguments
+    /// * `training_image` - Training image scanned for replicates of the data events
+    /// * `geometry` - Search geometry of the data event
+    /// * `max_octant_size` - Maximum number of data event nodes per octant
+    /// * `order` - Order of the Legendre series
+    pub fn new(
+        training_image: CompleteGriddedDataBase<f32>,
+        geometry: G,
+        max_octant_size: usize,
+        order: usize,
+    ) -> Self {
+        let (min, max) = training_image
+            .raw_grid
+            .grid
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(min, max), v| {
+                (min.min(*v), max.max(*v))
+            });
+        assert!(max > min, "training image must not be constant");
+
+        //legendre values of every training image node, row major
+        let mut legendre_table = vec![0.0; training_image.raw_grid.grid.len() * (order + 1)];
+        for (value, polys) in training_image
+            .raw_grid
+            .grid
+            .iter()
+            .zip(legendre_table.chunks_mut(order + 1))
+        {
+            legendre(to_unit(*value, min, max), order, polys);
+        }
+
+        Self {
+            training_image,
+            legendre_table,
+            order,
+            geometry,
+            max_octant_size,
+            min,
+            max,
+            assigned_data: None,
+            multigrid_levels: 1,
+        }
+    }
+
+    /// Freeze the nodes holding conditioning data (see `SGS::with_assigned_data`)
+    /// frozen nodes are part of the data events of the simulated nodes
+    pub fn with_assigned_data(mut self, assigned_data: Array3<Option<f32>>) -> Self {
+        self.assigned_data = Some(assigned_data);
+        self
+    }
+
+    /// Visit coarse sub-grids before the full grid (see `SGS::with_multigrid`)
+    pub fn with_multigrid(mut self, levels: usize) -> Self {
+        assert!(levels > 0, "at least one multigrid level is required");
+        self.multigrid_levels = levels;
+        self
+    }
+
+    /// Shuffle the path and find the data event nodes of every node
+    fn build_plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> HOSIMPlan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        let assigned_data = self.assigned_data.as_ref();
+        let (path, simulation_order) = random_path(grid, assigned_data, self.multigrid_levels, rng);
+        let level_offsets = level_offsets(
+            &self.geometry,
+            grid,
+            self.max_octant_size,
+            self.multigrid_levels,
+        );
+        let sim_qes =
+            level_query_engines(&self.geometry, grid, self.max_octant_size, &level_offsets);
+
+        let sequential_data = path
+            .par_iter()
+            .map(|ind| {
+                let ind = [ind.0, ind.1, ind.2];
+                let point = grid.ind_to_point(&ind.map(|i| i as isize));
+                let sim_qe = &sim_qes[node_level(ind, self.multigrid_levels)];
+
+                //frozen and previously simulated nodes
+                let (sim_inds, sim_points) =
+                    sim_qe.nearest_inds_and_points_masked(&point, |neighbor_ind| {
+                        assigned_data.is_some_and(|data| data[neighbor_ind].is_some())
+                            || simulation_order[neighbor_ind] < simulation_order[ind]
+                    });
+
+                //nearest first so distant nodes are dropped first when no replicate is found
+                let mut neighbors = sim_inds.into_iter().zip(sim_points).collect::<Vec<_>>();
+                neighbors.sort_by(|(_, a), (_, b)| {
+                    distance_squared(a, &point).total_cmp(&distance_squared(b, &point))
+                });
+                let sim_inds = neighbors
+                    .into_iter()
+                    .map(|(ind, _)| ind)
+                    .collect::<Vec<_>>();
+
+                let template = Template::new(
+                    sim_inds
+                        .iter()
+                        .map(|neighbor| {
+                            [0, 1, 2].map(|axis| neighbor[axis] as isize - ind[axis] as isize)
+                        })
+                        .collect(),
+                );
+
+                HOSIMStep {
+                    ind,
+                    sim_inds,
+                    template,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        HOSIMPlan { sequential_data }
+    }
+
+    /// Simulate the nodes of a plan in path order
+    fn apply_plan<GDB>(&self, plan: &HOSIMPlan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        write_assigned_data(grid, self.assigned_data.as_ref());
+
+        let values = (0..CDF_BINS)
+            .map(|i| -1.0 + 2.0 * i as f32 / (CDF_BINS - 1) as f32)
+            .collect::<Vec<_>>();
+
+        for (path_ind, step) in plan.sequential_data.iter().enumerate() {
+            let data_event = step
+                .sim_inds
+                .iter()
+                .map(|ind| {
+                    to_unit(
+                        grid.data_at_ind(ind).expect("No value at ind"),
+                        self.min,
+                        self.max,
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            //drop the farthest nodes of the data event until replicates are found
+            let mut prefix_coefficients = self.cumulants(&step.template, &data_event);
+            let n_event = (1..prefix_coefficients.len())
+                .rev()
+                .find(|n_event| prefix_coefficients[*n_event][0] > 0.0)
+                .unwrap_or(0);
+            let coefficients = prefix_coefficients.swap_remove(n_event);
+
+            let sampler = CDFSampler::new(self.ccdf(&coefficients, &values), values.clone());
+            let value = sampler.sample(seeds.uniform(path_ind as u64));
+
+            grid.set_data_at_ind(&step.ind, from_unit(value, self.min, self.max));
+        }
+    }
+
+    /// Legendre coefficients of the conditional pdf of the centre of a template
+    /// replicates of the template in the training image are weighted by the product over the data
+    /// event of the Legendre kernel between replicate and data event values
+    /// * the coefficients of every prefix of the data event are gathered in a single scan, the
+    ///   weight of a prefix is the running product of the kernels of its nodes
+    /// # Arguments
+    /// * `template` - Template of the data event
+    /// * `data_event` - Data event values mapped to [-1, 1]
+    /// # Returns
+    /// Coefficients for the first `n` nodes of the data event, for `n` in `0..=data_event.len()`
+    fn cumulants(&self, template: &Template, data_event: &[f32]) -> Vec<Vec<f64>> {
+        let n_polys = self.order + 1;
+
+        //normalized legendre values of the data event
+        let event_polys = data_event
+            .iter()
+            .map(|value| {
+                let mut polys = vec![0.0; n_polys];
+                legendre(*value, self.order, &mut polys);
+                polys
+                    .iter()
+                    .enumerate()
+                    .map(|(w, p)| (w as f32 + 0.5) * p)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let shape = self.training_image.shape();
+        let n_nodes = shape.iter().product::<usize>();
+        let flat = |ind: [usize; 3]| (ind[0] * shape[1] + ind[1]) * shape[2] + ind[2];
+        let polys_at =
+            |flat_ind: usize| &self.legendre_table[flat_ind * n_polys..(flat_ind + 1) * n_polys];
+
+        //partial sums are added in chunk order so realizations do not depend on scheduling
+        let chunk_starts = (0..n_nodes).step_by(REPLICATE_CHUNK).collect::<Vec<_>>();
+        let partial_sums = chunk_starts
+            .par_iter()
+            .map(|start| {
+                let mut sums = vec![vec![0f64; n_polys]; data_event.len() + 1];
+                for flat_ind in *start..(start + REPLICATE_CHUNK).min(n_nodes) {
+                    let ind = [
+                        flat_ind / (shape[1] * shape[2]),
+                        flat_ind / shape[2] % shape[1],
+                        flat_ind % shape[2],
+                    ];
+                    let centre_polys = polys_at(flat_ind);
+
+                    let mut weight = 1f64;
+                    for (n_event, prefix_sums) in sums.iter_mut().enumerate() {
+                        if n_event > 0 {
+                            //longer prefixes do not fit in the training image
+                            let Some(replicate_ind) =
+                                Template::get_ind(&ind, template.offsets()[n_event - 1], shape)
+                            else {
+                                break;
+                            };
+                            let kernel = polys_at(flat(replicate_ind))
+                                .iter()
+                                .zip(event_polys[n_event - 1].iter())
+                                .map(|(a, b)| a * b)
+                                .sum::<f32>();
+                            weight *= kernel as f64;
+                        }
+
+                        for (sum, p) in prefix_sums.iter_mut().zip(centre_polys) {
+                            *sum += *p as f64 * weight;
+                        }
+                    }
+                }
+                sums
+            })
+            .collect::<Vec<_>>();
+
+        let mut coefficients = vec![vec![0f64; n_polys]; data_event.len() + 1];
+        for sums in partial_sums {
+            for (prefix_coefficients, prefix_sums) in coefficients.iter_mut().zip(sums) {
+                for (coefficient, sum) in prefix_coefficients.iter_mut().zip(prefix_sums) {
+                    *coefficient += sum;
+                }
+            }
+        }
+        for prefix_coefficients in coefficients.iter_mut() {
+            for (w, coefficient) in prefix_coefficients.iter_mut().enumerate() {
+                *coefficient *= w as f64 + 0.5;
+            }
+        }
+
+        coefficients
+    }
+
+    /// Conditional cdf of a Legendre series at values in [-1, 1]
+    /// the series may be negative, the cdf is clipped to [0, 1] and made non decreasing
+    fn ccdf(&self, coefficients: &[f64], values: &[f32]) -> Vec<f32> {
+        let mut polys = vec![0.0; self.order + 2];
+        let mut max = 0f32;
+
+        values
+            .iter()
+            .map(|value| {
+                legendre(*value, self.order + 1, &mut polys);
+
+                //integral of p_w from -1 is (p_{w+1} - p_{w-1}) / (2w + 1), value + 1 for p_0
+                let integral = coefficients
+                    .iter()
+                    .enumerate()
+                    .map(|(w, c)| {
+                        let integral = if w == 0 {
+                            value + 1.0
+                        } else {
+                            (polys[w + 1] - polys[w - 1]) / (2.0 * w as f32 + 1.0)
+                        };
+                        c * integral as f64
+                    })
+                    .sum::<f64>();
+
+                //the series integrates to 2 c_0 over [-1, 1]
+                let cdf = (integral / (2.0 * coefficients[0])) as f32;
+                max = max.max(cdf.clamp(0.0, 1.0));
+                max
+            })
+            .collect()
+    }
+}
+
+/// Map a value from the range of the training image to [-1, 1]
+#[inline(always)]
+fn to_unit(value: f32, min: f32, max: f32) -> f32 {
+    (2.0 * (value - min) / (max - min) - 1.0).clamp(-1.0, 1.0)
+}
+
+/// Map a value from [-1, 1] to the range of the training image
+#[inline(always)]
+fn from_unit(value: f32, min: f32, max: f32) -> f32 {
+    min + 0.5 * (value + 1.0) * (max - min)
+}
+
+impl<G> Simulation for HOSIM<G>
+where
+    G: Geometry + Sync + Clone,
+{
+    type Plan = HOSIMPlan;
+
+    fn plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> Self::Plan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.build_plan(grid, rng)
+    }
+
+    fn realize<GDB>(&self, plan: &Self::Plan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.apply_plan(plan, grid, seeds);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion};
+    use rand::SeedableRng;
+
+    use crate::{
+        geometry::ellipsoid::Ellipsoid,
+        simulation::realizations::simulate_on_threads,
+        spatial_database::{
+            coordinate_system::{CoordinateSystem, GridSpacing},
+            gridded_databases::incomplete_grid::InCompleteGriddedDataBase,
+        },
+    };
+
+    use super::*;
+
+    fn training_image(shape: (usize, usize, usize)) -> CompleteGriddedDataBase<f32> {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        //diagonal stripes
+        let grid = Array3::from_shape_fn(shape, |(i, j, _)| ((i + j) as f32 / 4.0).sin());
+        CompleteGriddedDataBase::new(grid, GridSpacing::new(1.0, 1.0, 1.0), cs)
+    }
+
+    #[test]
+    fn legendre_recurrence() {
+        let mut polys = vec![0.0; 4];
+        for x in [-1.0, -0.3, 0.0, 0.6, 1.0] {
+            legendre(x, 3, &mut polys);
+            assert_eq!(polys[0], 1.0);
+            assert_eq!(polys[1], x);
+            assert!((polys[2] - (3.0 * x * x - 1.0) / 2.0).abs() < 1e-6);
+            assert!((polys[3] - (5.0 * x * x * x - 3.0 * x) / 2.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn cdf_sampler() {
+        let sampler = CDFSampler::new(vec![0.0, 0.5, 1.0], vec![-1.0, 0.0, 1.0]);
+        assert_eq!(sampler.sample(0.5), 0.0);
+        assert!((sampler.sample(0.75) - 0.5).abs() < 1e-6);
+        assert_eq!(sampler.sample(1.0), 1.0);
+    }
+
+    #[test]
+    fn marginal_ccdf_of_training_image() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        //values uniform over the range of the training image
+        let grid = Array3::from_shape_fn((20, 20, 1), |(i, j, _)| (i * 20 + j) as f32);
+        let ti = CompleteGriddedDataBase::new(grid, GridSpacing::new(1.0, 1.0, 1.0), cs);
+        let hosim = HOSIM::new(ti, Ellipsoid::new(3.0, 3.0, 3.0, cs), 2, 8);
+
+        //an empty data event gives the histogram of the training image
+        let coefficients = &hosim.cumulants(&Template::new(Vec::new()), &[])[0];
+        let values = [-1.0, -0.5, 0.0, 0.5, 1.0];
+        let cdf = hosim.ccdf(coefficients, &values);
+        for (cdf, value) in cdf.iter().zip(values) {
+            assert!((cdf - 0.5 * (value + 1.0)).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn hosim_reproduces_training_image_patterns() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let hosim = HOSIM::new(
+            training_image((40, 40, 1)),
+            Ellipsoid::new(4.0, 4.0, 1.0, cs),
+            2,
+            6,
+        );
+
+        let mut sim_db = InCompleteGriddedDataBase::new(
+            Array3::from_elem((24, 24, 1), None),
+            GridSpacing::new(1.0, 1.0, 1.0),
+            cs,
+        );
+        hosim.simulate_grid(&mut sim_db, &mut StdRng::seed_from_u64(7));
+        let values = sim_db.raw_grid.grid.map(|value| value.unwrap());
+
+        //stripes of the training image are constant along the anti-diagonal and vary along the diagonal
+        let mean_step = |di: usize, dj: isize| {
+            let steps = (0..23)
+                .flat_map(|i| (1..23).map(move |j| (i, j)))
+                .map(|(i, j)| {
+                    let next = [i + di, (j as isize + dj) as usize, 0];
+                    (values[next] - values[[i, j, 0]]).abs()
+                })
+                .collect::<Vec<_>>();
+            steps.iter().sum::<f32>() / steps.len() as f32
+        };
+        assert!(mean_step(1, -1) < 0.5 * mean_step(1, 1));
+    }
+
+    #[test]
+    fn hosim_synthetic_training_image() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing::new(1.0, 1.0, 1.0);
+
+        let mut assigned = Array3::<Option<f32>>::from_elem((16, 16, 1), None);
+        assigned[[3, 4, 0]] = Some(0.8);
+        assigned[[12, 9, 0]] = Some(-0.6);
+
+        let hosim = HOSIM::new(
+            training_image((40, 40, 1)),
+            Ellipsoid::new(4.0, 4.0, 1.0, cs),
+            2,
+            6,
+        )
+        .with_assigned_data(assigned);
+
+        let realization = |threads: usize, seed: u64| {
+            let mut sim_db =
+                InCompleteGriddedDataBase::new(Array3::from_elem((16, 16, 1), None), spacing, cs);
+            simulate_on_threads(&hosim, &mut sim_db, threads, seed);
+            sim_db.raw_grid.grid.map(|value| value.unwrap())
+        };
+
+        let values = realization(1, 4);
+        assert_eq!(values, realization(4, 4));
+
+        assert_eq!(values[[3, 4, 0]], 0.8);
+        assert_eq!(values[[12, 9, 0]], -0.6);
+        assert!(values
+            .iter()
+            .all(|value| value.is_finite() && *value >= hosim.min && *value <= hosim.max));
+    }
+}
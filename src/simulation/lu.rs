@@ -1,22 +1,12 @@
-use core::panic;
-
 use crate::variography::model_variograms::VariogramModel;
 
-use dyn_stack::{DynStack, GlobalMemBuffer, ReborrowMut, StackReq};
-use faer_cholesky::llt::{
-    compute,
-    inverse::invert_lower_in_place,
-    solve::{solve_transpose_req, solve_transpose_with_conj},
-    CholeskyError,
-};
+use dyn_stack::{DynStack, GlobalMemBuffer, ReborrowMut};
+use faer_cholesky::llt::{compute, CholeskyError};
 
 use faer_core::{
     mul::{self, triangular::BlockStructure},
-    zipped, Conj, Mat, Parallelism,
-};
-use faer_core::{
     solve::{solve_lower_triangular_in_place, solve_upper_triangular_in_place},
-    Scale,
+    zipped, Conj, Mat, Parallelism,
 };
 use itertools::Itertools;
 use nalgebra::{Point3, Vector3};
@@ -297,11 +287,17 @@ impl LUSystem {
         }
     }
 
+    /// Create a group system whose values are estimated with ordinary kriging
+    /// the mean is re-estimated from the conditioning data of the group, the variance of the
+    /// unknown mean is simulated with one additional normal draw
+    /// # Arguments
+    /// * `cond_points` - Locations of the conditioning data
+    /// * `sim_points` - Locations of the simulated nodes
+    /// * `vgram` - Variogram model
     #[inline(always)]
     pub fn create_mini_ok_system<V>(
         &mut self,
         cond_points: &[Point3<f32>],
-        cond_values: &[f32],
         sim_points: &[Point3<f32>],
         vgram: &V,
     ) -> MiniLUOKSystem
@@ -312,176 +308,71 @@ impl LUSystem {
         let n_sim = sim_points.len();
 
         self.set_dims(n_cond, n_sim);
-        self.vectorized_build_l_matrix(cond_points, sim_points, vgram);
+        let _ = self.vectorized_build_l_matrix(cond_points, sim_points, vgram);
         self.compute_intermediate_mat();
 
-        println!("intermediate: {:?}", self.intermediate_mat);
-
-        let ones = Mat::<f32>::from_fn(n_cond, 1, |_, _| 1.0);
-        let i = Mat::<f32>::identity(self.intermediate_mat.nrows(), self.intermediate_mat.ncols());
-
-        // //(I - L_gd @ L_dd^-1)
-        // let a = i - &self.intermediate_mat;
-
-        // //e^T @(L_11^-1)^T
-        // let mut b = ones.clone();
-
-        // solve_lower_triangular_in_place(
-        //     self.l_mat
-        //         .as_ref()
-        //         .submatrix(0, 0, self.n_cond, self.n_cond)
-        //         .transpose(),
-        //     b.as_mut(),
-        //     Parallelism::None,
-        // );
-
-        // println!("b: {:?}", b);
-
-        // //L_11^-1 @ z
-        // let mut c = Mat::from_fn(n_cond, 1, |i, _| cond_values[i]);
-
-        // solve_lower_triangular_in_place(
-        //     self.l_mat
-        //         .as_ref()
-        //         .submatrix(0, 0, self.n_cond, self.n_cond),
-        //     c.as_mut(),
-        //     Parallelism::None,
-        // );
-
-        // println!("c: {:?}", c);
-
-        // let mut d = Mat::zeros(1, 1);
-        // mul::matvec::matvec_with_conj(
-        //     d.as_mut(),
-        //     b.as_ref().transpose(),
-        //     Conj::No,
-        //     c.as_ref(),
-        //     Conj::No,
-        //     None,
-        //     1.0,
-        // );
-
-        // println!("d: {:?}", d);
-
-        // let mut e = Mat::zeros(1, 1);
-        // mul::matvec::matvec_with_conj(
-        //     e.as_mut(),
-        //     b.as_ref().transpose(),
-        //     Conj::No,
-        //     b.as_ref(),
-        //     Conj::No,
-        //     None,
-        //     1.0,
-        // );
-
-        // println!("e: {:?}", e);
-
-        // let mut ok = Mat::zeros(n_sim, 1);
-        // mul::matvec::matvec_with_conj(
-        //     ok.as_mut(),
-        //     a.as_ref(),
-        //     Conj::No,
-        //     ones.as_ref(),
-        //     Conj::No,
-        //     None,
-        //     1.0,
-        // );
-
-        // let frac = Scale(d.read(0, 0) / e.read(0, 0));
-
-        // ok *= frac;
-
-        // print!("ok: {:?}", ok);
-
-        // lambda_e - C_dd^-1 @ e
-        let mut lambda_e = ones.clone();
-
-        let mut stack = DynStack::new(&mut self.buffer);
-
-        faer_cholesky::llt::solve::solve_in_place_with_conj(
+        let l_gg = self
+            .l_mat
+            .as_ref()
+            .submatrix(self.n_cond, self.n_cond, self.n_sim, self.n_sim)
+            .to_owned();
+
+        //without conditioning data the mean cannot be estimated, fall back to simple kriging
+        if n_cond == 0 {
+            return MiniLUOKSystem {
+                n_sim,
+                n_cond,
+                l_gg,
+                ok_weights: self.intermediate_mat.clone(),
+                mean_correction: Mat::zeros(n_sim, 1),
+                w_vec: Mat::zeros(n_sim + 1, 1),
+            };
+        }
+
+        //lambda_e = C_dd^-1 @ e = L_dd^-T @ L_dd^-1 @ e
+        let mut lambda_e = Mat::<f32>::from_fn(n_cond, 1, |_, _| 1.0);
+        solve_lower_triangular_in_place(
+            self.l_mat.as_ref().submatrix(0, 0, n_cond, n_cond),
+            lambda_e.as_mut(),
+            Parallelism::None,
+        );
+        solve_upper_triangular_in_place(
             self.l_mat
                 .as_ref()
-                .submatrix(0, 0, self.n_cond, self.n_cond),
-            Conj::No,
+                .submatrix(0, 0, n_cond, n_cond)
+                .transpose(),
             lambda_e.as_mut(),
             Parallelism::None,
-            stack.rb_mut(),
         );
 
-        println!("lambda_e: {:?}", lambda_e);
-        println!("intermediate: {:?}", self.intermediate_mat);
-
+        //e^T @ C_dd^-1 @ e
         let mut denom = 0.0;
         zipped!(lambda_e.as_ref()).for_each(|v| denom += v.read());
 
-        let mut frac = Mat::zeros(n_sim, 1);
-        mul::matvec::matvec_with_conj(
-            frac.as_mut(),
-            self.intermediate_mat.as_ref(),
-            Conj::No,
-            ones.as_ref(),
-            Conj::No,
-            None,
-            1.0,
-        );
-
-        print!("num: {:?}", frac);
-
-        zipped!(frac.as_mut()).for_each(|mut v| v.write((1f32 - v.read()) / denom));
-
-        //num = num + Scale(1.0);
-
-        // let mut denom = 0.0;
-        // zipped!(lambda_e.as_ref()).for_each(|v| denom += v.read());
-
-        // let mut ok = Mat::from_fn(
-        //     self.intermediate_mat.nrows(),
-        //     self.intermediate_mat.ncols(),
-        //     |i, j| self.intermediate_mat.read(i, j) + (1.0 - denom) / denom * lambda_e.read(j, 0),
-        // );
-
-        let mut ok = self.intermediate_mat.clone();
-
-        mul::matmul(
-            ok.as_mut().transpose(),
-            lambda_e.as_ref(),
-            frac.as_ref().transpose(),
-            Some(1.0),
-            1.0,
-            Parallelism::None,
-        );
-
-        println!("ok: {:?}", ok);
+        //f = (e - Lambda_sk @ e) / (e^T @ C_dd^-1 @ e)
+        let frac = Mat::<f32>::from_fn(n_sim, 1, |i, _| {
+            let sk_sum = (0..n_cond)
+                .map(|j| self.intermediate_mat.read(i, j))
+                .sum::<f32>();
+            (1.0 - sk_sum) / denom
+        });
 
-        let temp = Mat::<f32>::from_fn(
-            self.intermediate_mat.nrows(),
-            self.intermediate_mat.ncols(),
-            |i, j| frac.read(i, 0) * lambda_e.read(j, 0),
-        );
-
-        println!("temp: {:?}", temp);
-
-        println!("ok: {:?}", self.intermediate_mat.clone() + temp);
+        //Lambda_ok = Lambda_sk + f @ lambda_e^T
+        let ok_weights = Mat::<f32>::from_fn(n_sim, n_cond, |i, j| {
+            self.intermediate_mat.read(i, j) + frac.read(i, 0) * lambda_e.read(j, 0)
+        });
 
-        //ok += Scale((1f32 - s) / s) * &lambda_e.transpose();
-
-        //println!("ok: {:?}", ok);
-
-        let l_gg = self
-            .l_mat
-            .as_ref()
-            .submatrix(self.n_cond, self.n_cond, self.n_sim, self.n_sim)
-            .to_owned()
-            .clone();
-        let intermediate = self.intermediate_mat.clone();
-        let w = self.w_vec.clone();
+        //ok error covariance is L_gg @ L_gg^T + denom * f @ f^T
+        let scale = denom.sqrt();
+        let mean_correction = Mat::<f32>::from_fn(n_sim, 1, |i, _| scale * frac.read(i, 0));
 
         MiniLUOKSystem {
             n_sim,
             n_cond,
             l_gg,
-            ok_weights: intermediate,
-            w_vec: w,
+            ok_weights,
+            mean_correction,
+            w_vec: Mat::zeros(n_cond + n_sim + 1, 1),
         }
     }
 }
@@ -492,6 +383,7 @@ impl Clone for LUSystem {
     }
 }
 
+#[derive(Clone)]
 pub struct MiniLUSKSystem {
     pub n_sim: usize,
     pub n_cond: usize,
@@ -567,50 +459,114 @@ impl MiniLUSKSystem {
     }
 }
 
+#[derive(Clone)]
 pub struct MiniLUOKSystem {
     pub n_sim: usize,
     pub n_cond: usize,
     pub l_gg: Mat<f32>,
     pub ok_weights: Mat<f32>,
-    pub w_vec: Mat<f32>, // consider not storing w vec on this struct to avoid reallocating memory in hot loop
+    pub mean_correction: Mat<f32>,
+    pub w_vec: Mat<f32>, // conditioning values, normal draws of the nodes and of the mean
 }
 
 impl MiniLUOKSystem {
-    //#[inline(always)]
-    // pub fn ok_values(&self) -> Vec<f32> {
-    //     //y_ok = y_sk + [I-\Lambda_sk] @ e @ (\lambda_e^T @ z)/ (\lambda_e^T @ e)
-    //     //populate sk estimates
-    //     let mut sim_mat = Mat::zeros(self.n_sim, 1);
-    //     mul::matvec::matvec_with_conj(
-    //         sim_mat.as_mut(),
-    //         self.intermediate_mat.as_ref(),
-    //         Conj::No,
-    //         self.w_vec.as_ref().submatrix(0, 0, self.n_cond, 1),
-    //         Conj::No,
-    //         None,
-    //         1.0,
-    //     );
-    //     Mat::<f32>::from_fn(self.intermediate_mat.nrows(), 1, |_, _| {
-    //         1.0
-    //     });
-    //     let a = Mat::<f32>::identity(self.intermediate_mat.nrows(), self.intermediate_mat.ncols())
-    //         - &self.intermediate_mat;
-
-    //     let b = sol
-
-    //     let mut vals = Vec::with_capacity(self.n_sim);
-    //     for i in 0..sim_mat.nrows() {
-    //         vals.push(sim_mat.read(i, 0));
-    //     }
-
-    //     vals
-    // }
+    #[inline(always)]
+    pub fn populate_w_vec(&mut self, values: &[f32], rng: &mut StdRng) {
+        //populate w vector
+        for (i, v) in values.iter().enumerate() {
+            self.w_vec.write(i, 0, *v);
+        }
+        for i in values.len()..self.w_vec.nrows() {
+            self.w_vec.write(i, 0, rng.sample(StandardNormal));
+        }
+    }
+
+    #[inline(always)]
+    pub fn ok_values(&self) -> Vec<f32> {
+        let mut sim_mat = Mat::zeros(self.n_sim, 1);
+        mul::matvec::matvec_with_conj(
+            sim_mat.as_mut(),
+            self.ok_weights.as_ref(),
+            Conj::No,
+            self.w_vec.as_ref().submatrix(0, 0, self.n_cond, 1),
+            Conj::No,
+            None,
+            1.0,
+        );
+
+        let mut vals = Vec::with_capacity(self.n_sim);
+        for i in 0..sim_mat.nrows() {
+            vals.push(sim_mat.read(i, 0));
+        }
+
+        vals
+    }
+
+    #[inline(always)]
+    pub fn simulate(&self) -> Vec<f32> {
+        let mut sim_mat = Mat::zeros(self.n_sim, 1);
+        mul::matvec::matvec_with_conj(
+            sim_mat.as_mut(),
+            self.ok_weights.as_ref(),
+            Conj::No,
+            self.w_vec.as_ref().submatrix(0, 0, self.n_cond, 1),
+            Conj::No,
+            None,
+            1.0,
+        );
+
+        mul::triangular::matmul(
+            sim_mat.as_mut(),
+            BlockStructure::Rectangular,
+            self.l_gg.as_ref(),
+            BlockStructure::TriangularLower,
+            self.w_vec.as_ref().submatrix(self.n_cond, 0, self.n_sim, 1),
+            BlockStructure::Rectangular,
+            Some(1.0),
+            1.0,
+            Parallelism::None,
+        );
+
+        //uncertainty of the estimated mean
+        let mean_draw = self.w_vec.read(self.n_cond + self.n_sim, 0);
+        let mut vals = Vec::with_capacity(self.n_sim);
+        for i in 0..sim_mat.nrows() {
+            vals.push(sim_mat.read(i, 0) + self.mean_correction.read(i, 0) * mean_draw);
+        }
+
+        vals
+    }
+}
+
+/// Group system of a simple or ordinary kriging simulation
+#[derive(Clone)]
+pub enum MiniLUSystem {
+    Simple(MiniLUSKSystem),
+    Ordinary(MiniLUOKSystem),
+}
+
+impl MiniLUSystem {
+    #[inline(always)]
+    pub fn populate_w_vec(&mut self, values: &[f32], rng: &mut StdRng) {
+        match self {
+            MiniLUSystem::Simple(system) => system.populate_w_vec(values, rng),
+            MiniLUSystem::Ordinary(system) => system.populate_w_vec(values, rng),
+        }
+    }
+
+    #[inline(always)]
+    pub fn simulate(&self) -> Vec<f32> {
+        match self {
+            MiniLUSystem::Simple(system) => system.simulate(),
+            MiniLUSystem::Ordinary(system) => system.simulate(),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use nalgebra::UnitQuaternion;
+    use nalgebra::{DMatrix, DVector, UnitQuaternion};
     use num_traits::Float;
     use rand::SeedableRng;
 
@@ -701,7 +657,7 @@ mod tests {
     }
 
     #[test]
-    fn test_lu_ok() {
+    fn lu_ok_vs_dense() {
         let mut lu = LUSystem::new(2, 3);
         let cond_points = vec![
             Point3::new(0.0, 0.0, 0.0),
@@ -710,22 +666,109 @@ mod tests {
         ];
         let values = vec![0.0, 1.0, 2.0];
         let sim_points = vec![Point3::new(0.0, 0.0, 1.0), Point3::new(1.0, 1.0, 1.0)];
-        let vgram_rot =
-            UnitQuaternion::from_euler_angles(0.0.to_radians(), 0.0.to_radians(), 0.0.to_radians());
-        let vgram_origin = Point3::new(0.0, 0.0, 0.0);
-        let vgram_coordinate_system = CoordinateSystem::new(vgram_origin.into(), vgram_rot);
-        let range = Vector3::new(2.0, 2.0, 2.0);
-        let sill = 1.0;
-        let nugget = 0.1;
+        let vgram_coordinate_system =
+            CoordinateSystem::new(Point3::origin().into(), UnitQuaternion::identity());
+        let vgram = SphericalVariogram::new(
+            Vector3::new(2.0, 2.0, 2.0),
+            1.0,
+            0.1,
+            vgram_coordinate_system,
+        );
 
-        let vgram = SphericalVariogram::new(range, sill, nugget, vgram_coordinate_system.clone());
+        let mut mini = lu.create_mini_ok_system(&cond_points, &sim_points, &vgram);
         let mut rng = StdRng::seed_from_u64(0);
+        mini.populate_w_vec(values.as_slice(), &mut rng);
 
-        lu.create_mini_ok_system(
-            cond_points.as_slice(),
-            values.as_slice(),
-            sim_points.as_slice(),
-            &vgram,
+        //dense ordinary kriging system [[C_dd, e], [e^T, 0]] @ [lambda, mu] = [c_dg, 1]
+        let cov = |p1: &Point3<f32>, p2: &Point3<f32>| vgram.covariogram(p1 - p2) as f64;
+        let n = cond_points.len();
+        let mut ok_mat = DMatrix::<f64>::zeros(n + 1, n + 1);
+        for i in 0..n {
+            for j in 0..n {
+                ok_mat[(i, j)] = cov(&cond_points[i], &cond_points[j]);
+            }
+            ok_mat[(i, n)] = 1.0;
+            ok_mat[(n, i)] = 1.0;
+        }
+        let ok_inv = ok_mat.try_inverse().unwrap();
+
+        let mut dense_weights = DMatrix::<f64>::zeros(sim_points.len(), n);
+        let mut lagrange = Vec::new();
+        for (s, sim_point) in sim_points.iter().enumerate() {
+            let mut rhs = DVector::<f64>::from_element(n + 1, 1.0);
+            for i in 0..n {
+                rhs[i] = cov(&cond_points[i], sim_point);
+            }
+            let solution = &ok_inv * rhs;
+            for i in 0..n {
+                dense_weights[(s, i)] = solution[i];
+            }
+            lagrange.push(solution[n]);
+        }
+
+        //weights sum to one and match the dense solution
+        for s in 0..sim_points.len() {
+            let sum = (0..n).map(|i| mini.ok_weights.read(s, i)).sum::<f32>();
+            assert!((sum - 1.0).abs() < 1e-4);
+            for i in 0..n {
+                assert!((mini.ok_weights.read(s, i) as f64 - dense_weights[(s, i)]).abs() < 1e-4);
+            }
+        }
+
+        let dense_values =
+            &dense_weights * DVector::from_iterator(n, values.iter().map(|v| *v as f64));
+        for (value, dense) in mini.ok_values().iter().zip(dense_values.iter()) {
+            assert!((*value as f64 - dense).abs() < 1e-4);
+        }
+
+        //error covariance C_gg - W @ C_dg - C_gd @ W^T + W @ C_dd @ W^T
+        let c_dd = DMatrix::<f64>::from_fn(n, n, |i, j| cov(&cond_points[i], &cond_points[j]));
+        let c_gd = DMatrix::<f64>::from_fn(sim_points.len(), n, |s, i| {
+            cov(&sim_points[s], &cond_points[i])
+        });
+        let c_gg = DMatrix::<f64>::from_fn(sim_points.len(), sim_points.len(), |s, t| {
+            cov(&sim_points[s], &sim_points[t])
+        });
+        let dense_cov =
+            &c_gg - &dense_weights * c_gd.transpose() - &c_gd * dense_weights.transpose()
+                + &dense_weights * &c_dd * dense_weights.transpose();
+
+        for s in 0..sim_points.len() {
+            //ordinary kriging variance c_0 - lambda^T @ c - mu
+            let variance = c_gg[(s, s)]
+                - (0..n)
+                    .map(|i| dense_weights[(s, i)] * c_gd[(s, i)])
+                    .sum::<f64>()
+                - lagrange[s];
+            assert!((dense_cov[(s, s)] - variance).abs() < 1e-4);
+
+            for t in 0..sim_points.len() {
+                let lu_cov = (0..sim_points.len())
+                    .map(|k| mini.l_gg.read(s, k) * mini.l_gg.read(t, k))
+                    .sum::<f32>()
+                    + mini.mean_correction.read(s, 0) * mini.mean_correction.read(t, 0);
+                assert!((lu_cov as f64 - dense_cov[(s, t)]).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn lu_ok_without_conditioning_data() {
+        let mut lu = LUSystem::new(2, 3);
+        let sim_points = vec![Point3::new(0.0, 0.0, 1.0), Point3::new(1.0, 1.0, 1.0)];
+        let vgram_coordinate_system =
+            CoordinateSystem::new(Point3::origin().into(), UnitQuaternion::identity());
+        let vgram = SphericalVariogram::new(
+            Vector3::new(2.0, 2.0, 2.0),
+            1.0,
+            0.1,
+            vgram_coordinate_system,
         );
+
+        let mut ok = lu.create_mini_ok_system(&[], &sim_points, &vgram);
+        let mut sk = lu.create_mini_sk_system(&[], &sim_points, &vgram);
+        ok.populate_w_vec(&[], &mut StdRng::seed_from_u64(0));
+        sk.populate_w_vec(&[], &mut StdRng::seed_from_u64(0));
+        assert_eq!(ok.simulate(), sk.simulate());
     }
 }
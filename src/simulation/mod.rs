@@ -1,4 +1,21 @@
+pub mod annealing;
+pub mod data_assignment;
+pub mod dbsim;
+pub mod direct_sampling;
+pub mod fft_ma;
+pub mod filtersim;
+pub mod gaussian;
+pub mod gibbs;
 pub mod gsgs;
 pub mod hosim;
 pub mod lu;
+pub mod multigrid;
+pub mod path;
+pub mod pfield;
+pub mod realizations;
+pub mod seeding;
 pub mod sgs;
+pub mod sis;
+pub mod snesim;
+pub mod truncated_gaussian;
+pub mod turning_bands;
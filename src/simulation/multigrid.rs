@@ -0,0 +1,36 @@
+/// Coarsest multigrid level of a node
+/// level `k` holds the nodes whose indices are all multiples of 2^k, coarse levels are visited first
+/// # Arguments
+/// * `ind` - Index of the node
+/// * `levels` - Number of levels (1 for a single grid)
+#[inline(always)]
+pub(crate) fn node_level(ind: [usize; 3], levels: usize) -> usize {
+    (1..levels)
+        .rev()
+        .find(|level| ind.iter().all(|i| i % (1 << level) == 0))
+        .unwrap_or(0)
+}
+
+/// Whether a grid offset reaches nodes of a multigrid level from nodes of the same level
+#[inline(always)]
+pub(crate) fn on_level(offset: &[isize; 3], level: usize) -> bool {
+    offset.iter().all(|v| v % (1 << level) == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multigrid_levels() {
+        assert_eq!(node_level([0, 0, 0], 3), 2);
+        assert_eq!(node_level([4, 8, 0], 3), 2);
+        assert_eq!(node_level([2, 4, 0], 3), 1);
+        assert_eq!(node_level([2, 3, 0], 3), 0);
+        assert_eq!(node_level([4, 8, 0], 1), 0);
+
+        assert!(on_level(&[-4, 8, 0], 2));
+        assert!(!on_level(&[-2, 8, 0], 2));
+        assert!(on_level(&[-3, 1, 0], 0));
+    }
+}
@@ -0,0 +1,138 @@
+use ndarray::Array3;
+use rand::{prelude::SliceRandom, rngs::StdRng};
+
+use crate::{
+    geometry::Geometry,
+    spatial_database::gridded_databases::{
+        gridded_data_base_query_engine::GriddedDataBaseOctantQueryEngine, GriddedDataBaseInterface,
+    },
+};
+
+use super::multigrid::{node_level, on_level};
+
+/// Shuffle a path over the nodes of a grid, coarse multigrid levels first
+/// # Arguments
+/// * `grid` - The simulated grid
+/// * `assigned_data` - Value of each frozen node, frozen nodes are not visited
+/// * `multigrid_levels` - Number of multigrid levels (1 for a single random path)
+/// * `rng` - Random number generator used for the path
+/// # Returns
+/// The path and the position of each node along the path (usize::MAX for frozen nodes)
+pub(crate) fn random_path<GDB>(
+    grid: &GDB,
+    assigned_data: Option<&Array3<Option<f32>>>,
+    multigrid_levels: usize,
+    rng: &mut StdRng,
+) -> (Vec<(usize, usize, usize)>, Array3<usize>)
+where
+    GDB: GriddedDataBaseInterface<f32>,
+{
+    if let Some(assigned_data) = assigned_data {
+        assert_eq!(
+            assigned_data.shape(),
+            grid.shape().as_slice(),
+            "assigned data must match the shape of the grid"
+        );
+    }
+
+    // Array to store simulation order (frozen nodes are never simulated)
+    let mut simulation_order = Array3::from_elem(grid.shape(), usize::MAX);
+    let mut path = simulation_order
+        .indexed_iter()
+        .map(|(ind, _)| ind)
+        .filter(|ind| !assigned_data.is_some_and(|assigned_data| assigned_data[*ind].is_some()))
+        .collect::<Vec<_>>();
+
+    //shuffle path order, coarse levels first
+    let mut levels = vec![Vec::new(); multigrid_levels];
+    for ind in path {
+        levels[node_level([ind.0, ind.1, ind.2], multigrid_levels)].push(ind);
+    }
+    path = levels
+        .into_iter()
+        .rev()
+        .flat_map(|mut level| {
+            level.shuffle(rng);
+            level
+        })
+        .collect();
+
+    //set iteration order
+    for (ind, val) in path.iter().enumerate() {
+        simulation_order[*val] = ind;
+    }
+
+    (path, simulation_order)
+}
+
+/// Offsets of the simulated neighbour search of each multigrid level
+/// # Arguments
+/// * `geometry` - Search geometry
+/// * `grid` - The simulated grid
+/// * `max_octant_size` - Maximum number of simulated neighbours per octant
+/// * `multigrid_levels` - Number of multigrid levels
+pub(crate) fn level_offsets<G, GDB>(
+    geometry: &G,
+    grid: &GDB,
+    max_octant_size: usize,
+    multigrid_levels: usize,
+) -> Vec<Vec<Vec<[isize; 3]>>>
+where
+    G: Geometry + Clone,
+    GDB: GriddedDataBaseInterface<f32>,
+{
+    (0..multigrid_levels)
+        .map(|level| {
+            let mut sim_qe =
+                GriddedDataBaseOctantQueryEngine::new(geometry.clone(), grid, max_octant_size);
+            if level > 0 {
+                sim_qe.retain_offsets(|offset| on_level(offset, level));
+            }
+            sim_qe.octant_offsets
+        })
+        .collect()
+}
+
+/// Query engines of the simulated neighbour search, one per multigrid level
+/// # Arguments
+/// * `geometry` - Search geometry
+/// * `grid` - The simulated grid
+/// * `max_octant_size` - Maximum number of simulated neighbours per octant
+/// * `level_offsets` - Offsets of each level (see `level_offsets`)
+pub(crate) fn level_query_engines<'a, G, GDB>(
+    geometry: &G,
+    grid: &'a GDB,
+    max_octant_size: usize,
+    level_offsets: &[Vec<Vec<[isize; 3]>>],
+) -> Vec<GriddedDataBaseOctantQueryEngine<'a, G, GDB, f32>>
+where
+    G: Geometry + Clone,
+    GDB: GriddedDataBaseInterface<f32>,
+{
+    level_offsets
+        .iter()
+        .map(|offsets| {
+            GriddedDataBaseOctantQueryEngine::from_octant_offsets(
+                geometry.clone(),
+                grid,
+                max_octant_size,
+                offsets.clone(),
+            )
+        })
+        .collect()
+}
+
+/// Write the values of the frozen nodes
+pub(crate) fn write_assigned_data<GDB>(grid: &mut GDB, assigned_data: Option<&Array3<Option<f32>>>)
+where
+    GDB: GriddedDataBaseInterface<f32>,
+{
+    //frozen nodes reproduce the data
+    if let Some(assigned_data) = assigned_data {
+        for (ind, value) in assigned_data.indexed_iter() {
+            if let Some(value) = value {
+                grid.set_data_at_ind(&[ind.0, ind.1, ind.2], *value);
+            }
+        }
+    }
+}
@@ -0,0 +1,432 @@
+use faer_core::Mat;
+use ndarray::{Array3, Array4};
+use rand::rngs::StdRng;
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    kriging::simple_kriging::SimpleKrigingSystem,
+    spatial_database::{
+        gridded_databases::{incomplete_grid::InCompleteGriddedDataBase, GriddedDataBaseInterface},
+        SpatialQueryable,
+    },
+    variography::model_variograms::VariogramModel,
+};
+
+use super::{
+    gaussian::normal_cdf, realizations::Simulation, seeding::SeedSequence, sis::IndicatorVariable,
+};
+
+/// Local conditional distributions of the nodes of a grid
+pub enum LocalDistributions {
+    /// Simple kriging mean and variance of each node in gaussian space
+    Gaussian {
+        means: Array3<f32>,
+        variances: Array3<f32>,
+    },
+    /// Indicator kriging ccdf of each node, the last axis holds the indicators of the variable
+    Indicator {
+        variable: IndicatorVariable,
+        ccdfs: Array4<f32>,
+    },
+}
+
+impl LocalDistributions {
+    /// Simple kriging of gaussian conditioning data at every node of a grid
+    /// # Arguments
+    /// * `conditioning_data` - The data to condition on (normal scores)
+    /// * `variogram_model` - Variogram of the normal scores
+    /// * `grid` - Grid defining the nodes (values are not read)
+    pub fn simple_kriging<S, V, G, GDB>(
+        conditioning_data: &S,
+        variogram_model: &V,
+        grid: &GDB,
+    ) -> Self
+    where
+        S: SpatialQueryable<f32, G> + Sync,
+        V: VariogramModel + Sync,
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        let shape = grid.shape();
+        let moments = grid_inds(shape)
+            .par_iter()
+            .map_with(None, |local_system, ind| {
+                let point = grid.ind_to_point(&ind.map(|i| i as isize));
+                let (values, points) = conditioning_data.query(&point);
+                let mini_system = sized_system(local_system, points.len()).build_mini_system(
+                    &points,
+                    &point,
+                    variogram_model,
+                );
+                let values_mat = Mat::from_fn(values.len(), 1, |i, _| values[i]);
+
+                (
+                    mini_system.estimate(values_mat.as_ref()),
+                    mini_system.variance(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let (means, variances): (Vec<_>, Vec<_>) = moments.into_iter().unzip();
+        LocalDistributions::Gaussian {
+            means: Array3::from_shape_vec(shape, means).unwrap(),
+            variances: Array3::from_shape_vec(shape, variances).unwrap(),
+        }
+    }
+
+    /// Indicator kriging of conditioning data at every node of a grid
+    /// each indicator is estimated by simple kriging around its prior, ccdfs are corrected for order
+    /// relation deviations
+    /// # Arguments
+    /// * `conditioning_data` - The data to condition on (category codes or untransformed values)
+    /// * `variograms` - Indicator variogram of each category or threshold, a single variogram is shared by all indicators
+    /// * `variable` - Indicator coding and prior of the variable
+    /// * `grid` - Grid defining the nodes (values are not read)
+    pub fn indicator_kriging<S, V, G, GDB>(
+        conditioning_data: &S,
+        variograms: &[V],
+        variable: IndicatorVariable,
+        grid: &GDB,
+    ) -> Self
+    where
+        S: SpatialQueryable<f32, G> + Sync,
+        V: VariogramModel + Sync,
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        let n_indicators = variable.n_indicators();
+        assert!(
+            variograms.len() == 1 || variograms.len() == n_indicators,
+            "one variogram per indicator or a single shared variogram is required"
+        );
+
+        let shape = grid.shape();
+        let prior = variable.prior();
+        let ccdfs = grid_inds(shape)
+            .par_iter()
+            .map_with(None, |local_system, ind| {
+                let point = grid.ind_to_point(&ind.map(|i| i as isize));
+                let (values, points) = conditioning_data.query(&point);
+                let local_system = sized_system(local_system, points.len());
+                let mini_systems = variograms
+                    .iter()
+                    .map(|variogram| local_system.build_mini_system(&points, &point, variogram))
+                    .collect::<Vec<_>>();
+
+                let mut ccdf = (0..n_indicators)
+                    .map(|indicator| {
+                        let residuals = Mat::from_fn(values.len(), 1, |i, _| {
+                            variable.indicator(indicator, values[i]) - prior[indicator]
+                        });
+                        let mini_system = &mini_systems[indicator.min(mini_systems.len() - 1)];
+                        prior[indicator] + mini_system.estimate(residuals.as_ref())
+                    })
+                    .collect::<Vec<_>>();
+                variable.correct_order_relations(&mut ccdf);
+                ccdf
+            })
+            .flatten()
+            .collect::<Vec<_>>();
+
+        LocalDistributions::Indicator {
+            ccdfs: Array4::from_shape_vec((shape[0], shape[1], shape[2], n_indicators), ccdfs)
+                .unwrap(),
+            variable,
+        }
+    }
+
+    /// Sample the local distributions with a probability field
+    /// # Arguments
+    /// * `field` - Standard normal field (e.g. an unconditional realization of any gaussian simulation)
+    /// * `grid` - The grid receiving the sampled values
+    pub fn sample<LDB, GDB>(&self, field: &LDB, grid: &mut GDB)
+    where
+        LDB: GriddedDataBaseInterface<f32>,
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        let shape = grid.shape();
+        assert_eq!(
+            self.shape(),
+            shape,
+            "local distributions must match the shape of the grid"
+        );
+
+        for ind in grid_inds(shape) {
+            let y = field
+                .data_at_ind(&ind)
+                .expect("the probability field must be simulated at every node");
+
+            let value = match self {
+                //the quantile of the gaussian distribution at the probability of y
+                LocalDistributions::Gaussian { means, variances } => {
+                    means[ind] + variances[ind].max(0.0).sqrt() * y
+                }
+                //ccdfs are corrected for order relations when kriged
+                LocalDistributions::Indicator { variable, ccdfs } => variable.draw(
+                    ccdfs
+                        .slice(ndarray::s![ind[0], ind[1], ind[2], ..])
+                        .as_slice()
+                        .expect("ccdfs are contiguous along the indicators"),
+                    normal_cdf(y as f64) as f32,
+                ),
+            };
+            grid.set_data_at_ind(&ind, value);
+        }
+    }
+
+    fn shape(&self) -> [usize; 3] {
+        let shape = match self {
+            LocalDistributions::Gaussian { means, .. } => means.shape(),
+            LocalDistributions::Indicator { ccdfs, .. } => ccdfs.shape(),
+        };
+        [shape[0], shape[1], shape[2]]
+    }
+}
+
+/// Kriging system of a thread, reallocated when a neighbourhood exceeds its size
+fn sized_system(
+    local_system: &mut Option<SimpleKrigingSystem>,
+    n_data: usize,
+) -> &mut SimpleKrigingSystem {
+    if local_system
+        .as_ref()
+        .map_or(true, |system| system.n_elems < n_data)
+    {
+        *local_system = Some(SimpleKrigingSystem::new(n_data));
+    }
+    local_system.as_mut().unwrap()
+}
+
+/// Indices of all nodes of a grid in row major order
+fn grid_inds(shape: [usize; 3]) -> Vec<[usize; 3]> {
+    (0..shape[0])
+        .flat_map(|i| (0..shape[1]).flat_map(move |j| (0..shape[2]).map(move |k| [i, j, k])))
+        .collect()
+}
+
+/// P-field simulation
+/// local distributions are sampled with spatially correlated probabilities given by unconditional
+/// realizations of a gaussian simulation, realizations sharing a plan (see `Realizations`) only
+/// solve the systems of the probability field simulation once
+pub struct PField<S> {
+    distributions: LocalDistributions,
+    simulation: S,
+}
+
+impl<S> PField<S>
+where
+    S: Simulation,
+{
+    /// Create a new p-field simulation
+    /// # Arguments
+    /// * `distributions` - Local distribution of each node of the simulated grid
+    /// * `simulation` - Unconditional simulation of a standard normal field (unit sill variogram), e.g.
+    ///   `SGS` without data, `TurningBands` or `FftMa`
+    pub fn new(distributions: LocalDistributions, simulation: S) -> Self {
+        Self {
+            distributions,
+            simulation,
+        }
+    }
+}
+
+impl<S> Simulation for PField<S>
+where
+    S: Simulation,
+{
+    type Plan = S::Plan;
+
+    fn plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> Self::Plan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.simulation.plan(grid, rng)
+    }
+
+    fn realize<GDB>(&self, plan: &Self::Plan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        let mut field = InCompleteGriddedDataBase::new(
+            Array3::from_elem(grid.shape(), None),
+            grid.grid_spacing(),
+            grid.coordinate_system(),
+        );
+        self.simulation.realize(plan, &mut field, seeds);
+        self.distributions.sample(&field, grid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion, Vector3};
+    use rand::SeedableRng;
+
+    use crate::{
+        geometry::ellipsoid::Ellipsoid,
+        simulation::{
+            fft_ma::FftMa,
+            realizations::Realizations,
+            sgs::{SGSParameters, SGS},
+            turning_bands::TurningBands,
+        },
+        spatial_database::{
+            coordinate_system::{CoordinateSystem, GridSpacing},
+            gridded_databases::gridded_data_base_query_engine::GriddedDataBaseOctantQueryEngine,
+        },
+        variography::model_variograms::spherical::SphericalVariogram,
+    };
+
+    use super::*;
+
+    fn setup() -> (CoordinateSystem, GridSpacing, SphericalVariogram) {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        let vgram = SphericalVariogram::new(Vector3::new(8.0, 8.0, 8.0), 1.0, 0.0, cs);
+        (cs, spacing, vgram)
+    }
+
+    #[test]
+    fn pfield_gaussian_honours_data() {
+        let (cs, spacing, vgram) = setup();
+        let shape = (16, 16, 1);
+
+        let mut cond_grid = Array3::from_elem(shape, None);
+        cond_grid[[3, 4, 0]] = Some(1.5);
+        cond_grid[[12, 10, 0]] = Some(-0.7);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid, spacing, cs);
+        let empty_db = InCompleteGriddedDataBase::new(Array3::from_elem(shape, None), spacing, cs);
+        let ellipsoid = Ellipsoid::new(8.0, 8.0, 8.0, cs);
+
+        let distributions = LocalDistributions::simple_kriging(
+            &GriddedDataBaseOctantQueryEngine::new(ellipsoid.clone(), &cond_db, 4),
+            &vgram,
+            &empty_db,
+        );
+        let LocalDistributions::Gaussian { means, variances } = &distributions else {
+            panic!("simple kriging gives gaussian distributions");
+        };
+        assert!((means[[3, 4, 0]] - 1.5).abs() < 1e-4);
+        assert!(variances[[3, 4, 0]].abs() < 1e-4);
+        assert!(variances[[8, 8, 0]] > 0.0);
+
+        //unconditional probability field
+        let pfield = PField::new(
+            distributions,
+            SGS::new(
+                GriddedDataBaseOctantQueryEngine::new(ellipsoid, &empty_db, 4),
+                &vgram,
+                SGSParameters {
+                    max_octant_cond_data: 4,
+                    max_octant_sim_data: 4,
+                },
+            ),
+        );
+
+        let realizations = Realizations::new(3, 11)
+            .with_shared_path(true)
+            .to_array(&pfield, |_| {
+                InCompleteGriddedDataBase::new(Array3::from_elem(shape, None), spacing, cs)
+            });
+        for realization in realizations.outer_iter() {
+            assert!((realization[[3, 4, 0]] - 1.5).abs() < 1e-2);
+            assert!((realization[[12, 10, 0]] + 0.7).abs() < 1e-2);
+            assert!(realization.iter().all(|value| value.is_finite()));
+        }
+        assert_ne!(realizations[[0, 8, 8, 0]], realizations[[1, 8, 8, 0]]);
+    }
+
+    #[test]
+    fn pfield_field_generators() {
+        let (cs, spacing, vgram) = setup();
+        let shape = (16, 16, 1);
+
+        let mut cond_grid = Array3::from_elem(shape, None);
+        cond_grid[[5, 9, 0]] = Some(-1.2);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid, spacing, cs);
+        let empty_db = InCompleteGriddedDataBase::new(Array3::from_elem(shape, None), spacing, cs);
+        let distributions = || {
+            LocalDistributions::simple_kriging(
+                &GriddedDataBaseOctantQueryEngine::new(
+                    Ellipsoid::new(8.0, 8.0, 8.0, cs),
+                    &cond_db,
+                    4,
+                ),
+                &vgram,
+                &empty_db,
+            )
+        };
+        let new_grid =
+            |_: usize| InCompleteGriddedDataBase::new(Array3::from_elem(shape, None), spacing, cs);
+
+        //probability fields of any gaussian simulation
+        let turning_bands = Realizations::new(2, 5).to_array(
+            &PField::new(
+                distributions(),
+                TurningBands::new(
+                    SphericalVariogram::new(Vector3::new(8.0, 8.0, 8.0), 1.0, 0.0, cs),
+                    200,
+                ),
+            ),
+            new_grid,
+        );
+        let fft_ma = Realizations::new(2, 5)
+            .with_shared_path(true)
+            .to_array(&PField::new(distributions(), FftMa::new(&vgram)), new_grid);
+
+        for realizations in [turning_bands, fft_ma] {
+            for realization in realizations.outer_iter() {
+                assert!((realization[[5, 9, 0]] + 1.2).abs() < 1e-2);
+                assert!(realization.iter().all(|value| value.is_finite()));
+            }
+            assert_ne!(realizations[[0, 12, 2, 0]], realizations[[1, 12, 2, 0]]);
+        }
+    }
+
+    #[test]
+    fn pfield_indicator_ccdfs() {
+        let (cs, spacing, vgram) = setup();
+        let shape = (12, 12, 1);
+
+        let mut cond_grid = Array3::from_elem(shape, None);
+        cond_grid[[2, 2, 0]] = Some(0.0);
+        cond_grid[[9, 9, 0]] = Some(1.0);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid, spacing, cs);
+        let mut grid = InCompleteGriddedDataBase::new(Array3::from_elem(shape, None), spacing, cs);
+        let ellipsoid = Ellipsoid::new(8.0, 8.0, 8.0, cs);
+
+        let distributions = LocalDistributions::indicator_kriging(
+            &GriddedDataBaseOctantQueryEngine::new(ellipsoid.clone(), &cond_db, 4),
+            &[&vgram],
+            IndicatorVariable::Categorical {
+                codes: vec![0.0, 1.0],
+                proportions: vec![0.5, 0.5],
+            },
+            &grid,
+        );
+
+        let empty_db = InCompleteGriddedDataBase::new(Array3::from_elem(shape, None), spacing, cs);
+        PField::new(
+            distributions,
+            SGS::new(
+                GriddedDataBaseOctantQueryEngine::new(ellipsoid, &empty_db, 4),
+                &vgram,
+                SGSParameters {
+                    max_octant_cond_data: 4,
+                    max_octant_sim_data: 4,
+                },
+            ),
+        )
+        .simulate_grid(&mut grid, &mut StdRng::seed_from_u64(4));
+
+        assert_eq!(grid.data_at_ind(&[2, 2, 0]), Some(0.0));
+        assert_eq!(grid.data_at_ind(&[9, 9, 0]), Some(1.0));
+        assert!(grid
+            .raw_grid
+            .grid
+            .iter()
+            .all(|code| matches!(code, Some(c) if *c == 0.0 || *c == 1.0)));
+    }
+}
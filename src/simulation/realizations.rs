@@ -0,0 +1,302 @@
+use ndarray::{stack, Array3, Array4, Axis};
+use rand::{rngs::StdRng, SeedableRng};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+
+use crate::spatial_database::gridded_databases::GriddedDataBaseInterface;
+
+use super::seeding::SeedSequence;
+
+/// Simulation split into a path dependent plan and the sequential drawing of values
+pub trait Simulation {
+    /// Path and kriging systems of a simulation
+    /// the plan only depends on the locations of the nodes, realizations sharing a path reuse it
+    type Plan: Sync;
+
+    /// Create the path and solve the kriging systems for the nodes of a grid
+    /// # Arguments
+    /// * `grid` - Grid defining the simulated nodes (values are not read)
+    /// * `rng` - Random number generator used for the path
+    fn plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> Self::Plan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync;
+
+    /// Simulate a realization from a plan
+    /// # Arguments
+    /// * `plan` - Plan created for a grid with the same geometry as `grid`
+    /// * `grid` - The grid to simulate
+    /// * `seeds` - Seeds of the random draws
+    fn realize<GDB>(&self, plan: &Self::Plan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync;
+
+    /// Simulate a grid with its own path
+    /// the seeds of the draws are taken from `rng` before the path, a seeded rng gives the same
+    /// realization regardless of the number of threads, simulations that do not need the whole
+    /// plan at once (e.g. `SGS` with a memory limit) override it
+    /// # Arguments
+    /// * `grid` - The grid to simulate
+    /// * `rng` - The random number generator
+    fn simulate_grid<GDB>(&self, grid: &mut GDB, rng: &mut StdRng)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        let seeds = SeedSequence::from_rng(rng);
+        let plan = self.plan(&*grid, rng);
+        self.realize(&plan, grid, seeds);
+    }
+}
+
+/// Generates realizations of a simulation in parallel
+/// realization `r` is derived from stream `r` of the seed, without a shared path it is identical to
+/// `Simulation::simulate_grid` called with `realization_rng(r)`
+pub struct Realizations {
+    n_realizations: usize,
+    seed: u64,
+    shared_path: bool,
+}
+
+impl Realizations {
+    /// Create a realizations driver
+    /// # Arguments
+    /// * `n_realizations` - Number of realizations
+    /// * `seed` - Seed all realizations are derived from
+    pub fn new(n_realizations: usize, seed: u64) -> Self {
+        Self {
+            n_realizations,
+            seed,
+            shared_path: false,
+        }
+    }
+
+    /// Use the same path for all realizations
+    /// the kriging systems are only solved once, at the cost of realizations sharing path artifacts
+    pub fn with_shared_path(mut self, shared_path: bool) -> Self {
+        self.shared_path = shared_path;
+        self
+    }
+
+    /// Random number generator of a realization
+    pub fn realization_rng(&self, realization: usize) -> StdRng {
+        SeedSequence::new(self.seed).stream(realization as u64)
+    }
+
+    /// Simulate all realizations, each finished realization is passed to a callback
+    /// # Arguments
+    /// * `simulation` - The simulation
+    /// * `new_grid` - Creates the empty grid of a realization
+    /// * `callback` - Receives the index and grid of each realization (called from several threads)
+    pub fn for_each<S, GDB, F, C>(&self, simulation: &S, new_grid: F, callback: C)
+    where
+        S: Simulation + Sync,
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+        F: Fn(usize) -> GDB + Sync,
+        C: Fn(usize, GDB) + Sync,
+    {
+        let shared_plan = self.shared_plan(simulation, &new_grid);
+
+        (0..self.n_realizations)
+            .into_par_iter()
+            .for_each(|realization| {
+                let grid =
+                    self.realization(simulation, shared_plan.as_ref(), &new_grid, realization);
+                callback(realization, grid);
+            });
+    }
+
+    /// Simulate all realizations into a single array
+    /// # Arguments
+    /// * `simulation` - The simulation
+    /// * `new_grid` - Creates the empty grid of a realization
+    /// # Returns
+    /// Array of shape (realizations, i, j, k), nodes without a value are NaN
+    pub fn to_array<S, GDB, F>(&self, simulation: &S, new_grid: F) -> Array4<f32>
+    where
+        S: Simulation + Sync,
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+        F: Fn(usize) -> GDB + Sync,
+    {
+        let shared_plan = self.shared_plan(simulation, &new_grid);
+
+        let realizations = (0..self.n_realizations)
+            .into_par_iter()
+            .map(|realization| {
+                let grid =
+                    self.realization(simulation, shared_plan.as_ref(), &new_grid, realization);
+                let shape = grid.shape();
+                Array3::from_shape_fn((shape[0], shape[1], shape[2]), |(i, j, k)| {
+                    grid.data_at_ind(&[i, j, k]).unwrap_or(f32::NAN)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let views = realizations.iter().map(|r| r.view()).collect::<Vec<_>>();
+        stack(Axis(0), &views).expect("realizations must share the same shape")
+    }
+
+    fn shared_plan<S, GDB, F>(&self, simulation: &S, new_grid: &F) -> Option<S::Plan>
+    where
+        S: Simulation,
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+        F: Fn(usize) -> GDB,
+    {
+        self.shared_path
+            .then(|| simulation.plan(&new_grid(0), &mut StdRng::seed_from_u64(self.seed)))
+    }
+
+    fn realization<S, GDB, F>(
+        &self,
+        simulation: &S,
+        shared_plan: Option<&S::Plan>,
+        new_grid: &F,
+        realization: usize,
+    ) -> GDB
+    where
+        S: Simulation,
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+        F: Fn(usize) -> GDB,
+    {
+        let mut grid = new_grid(realization);
+        let mut rng = self.realization_rng(realization);
+
+        match shared_plan {
+            Some(plan) => simulation.realize(plan, &mut grid, SeedSequence::from_rng(&mut rng)),
+            None => simulation.simulate_grid(&mut grid, &mut rng),
+        }
+
+        grid
+    }
+}
+
+/// Simulate a grid on a thread pool with a number of threads (test fixture of the simulations)
+#[cfg(test)]
+pub(crate) fn simulate_on_threads<S, GDB>(simulation: &S, grid: &mut GDB, threads: usize, seed: u64)
+where
+    S: Simulation + Sync,
+    GDB: GriddedDataBaseInterface<f32> + std::marker::Sync + Send,
+{
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .unwrap();
+    pool.install(|| simulation.simulate_grid(grid, &mut StdRng::seed_from_u64(seed)));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use nalgebra::{Translation3, UnitQuaternion, Vector3};
+
+    use crate::{
+        geometry::ellipsoid::Ellipsoid,
+        simulation::sgs::{SGSParameters, SGS},
+        spatial_database::{
+            coordinate_system::{CoordinateSystem, GridSpacing},
+            gridded_databases::{
+                gridded_data_base_query_engine::GriddedDataBaseOctantQueryEngine,
+                incomplete_grid::InCompleteGriddedDataBase,
+            },
+        },
+        variography::model_variograms::spherical::SphericalVariogram,
+    };
+
+    use super::*;
+
+    #[test]
+    fn realizations_from_seed() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        let mut cond_grid = Array3::<Option<f32>>::from_elem((8, 8, 1), None);
+        cond_grid[[1, 2, 0]] = Some(0.5);
+        cond_grid[[6, 5, 0]] = Some(-1.0);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid, spacing, cs);
+
+        let sgs = SGS::new(
+            GriddedDataBaseOctantQueryEngine::new(Ellipsoid::new(6.0, 6.0, 6.0, cs), &cond_db, 4),
+            SphericalVariogram::new(Vector3::new(5.0, 5.0, 5.0), 1.0, 0.1, cs),
+            SGSParameters {
+                max_octant_cond_data: 4,
+                max_octant_sim_data: 4,
+            },
+        );
+        let new_grid = |_: usize| {
+            InCompleteGriddedDataBase::new(
+                Array3::<Option<f32>>::from_elem((8, 8, 1), None),
+                spacing,
+                cs,
+            )
+        };
+
+        //without a shared path each realization matches a single simulation
+        let realizations = Realizations::new(3, 5);
+        let values = realizations.to_array(&sgs, new_grid);
+        assert_eq!(values.shape(), &[3, 8, 8, 1]);
+
+        let mut grid = new_grid(1);
+        sgs.simulate_grid(&mut grid, &mut realizations.realization_rng(1));
+        let single = grid.raw_grid.grid.map(|value| value.unwrap());
+        assert_eq!(values.index_axis(Axis(0), 1), single);
+        assert_ne!(values.index_axis(Axis(0), 0), values.index_axis(Axis(0), 1));
+
+        //a shared path only changes the random draws
+        let shared = Realizations::new(3, 5).with_shared_path(true);
+        let visited = Mutex::new(Vec::new());
+        shared.for_each(&sgs, new_grid, |realization, grid| {
+            assert!(grid.raw_grid.grid.iter().all(|value| value.is_some()));
+            visited.lock().unwrap().push(realization);
+        });
+        let mut visited = visited.into_inner().unwrap();
+        visited.sort();
+        assert_eq!(visited, vec![0, 1, 2]);
+
+        let shared_values = shared.to_array(&sgs, new_grid);
+        assert_eq!(shared_values, shared.to_array(&sgs, new_grid));
+        assert_ne!(
+            shared_values.index_axis(Axis(0), 0),
+            shared_values.index_axis(Axis(0), 1)
+        );
+    }
+
+    #[test]
+    fn realizations_with_memory_limit() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        let mut cond_grid = Array3::<Option<f32>>::from_elem((10, 10, 1), None);
+        cond_grid[[2, 7, 0]] = Some(0.8);
+        cond_grid[[8, 1, 0]] = Some(-0.4);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid, spacing, cs);
+
+        let sgs = SGS::new(
+            GriddedDataBaseOctantQueryEngine::new(Ellipsoid::new(6.0, 6.0, 6.0, cs), &cond_db, 4),
+            SphericalVariogram::new(Vector3::new(5.0, 5.0, 5.0), 1.0, 0.1, cs),
+            SGSParameters {
+                max_octant_cond_data: 4,
+                max_octant_sim_data: 4,
+            },
+        );
+        let new_grid = |_: usize| {
+            InCompleteGriddedDataBase::new(
+                Array3::<Option<f32>>::from_elem((10, 10, 1), None),
+                spacing,
+                cs,
+            )
+        };
+
+        //realizations are simulated in windows along their own path and match unbounded ones
+        let realizations = Realizations::new(3, 11);
+        let unbounded = realizations.to_array(&sgs, new_grid);
+        let bounded = realizations.to_array(&sgs.with_memory_limit(1), new_grid);
+        assert_eq!(unbounded, bounded);
+        assert!(bounded.iter().all(|value| value.is_finite()));
+    }
+}
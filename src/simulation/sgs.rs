@@ -1,27 +1,53 @@
+use std::collections::HashMap;
+
+use faer_core::Mat;
+use nalgebra::distance;
 use ndarray::Array3;
-use rand::{prelude::SliceRandom, rngs::StdRng};
+use rand::rngs::StdRng;
 use rand_distr::Distribution;
 use rand_distr::Normal;
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 
 use crate::{
     geometry::Geometry,
-    kriging::simple_kriging::SimpleKrigingSystem,
-    spatial_database::{
-        gridded_databases::{
-            gridded_data_base_query_engine::GriddedDataBaseOctantQueryEngine,
-            GriddedDataBaseInterface,
-        },
-        SpatialQueryable,
+    kriging::{
+        simple_kriging::{MiniSKSystem, SimpleKrigingSystem},
+        KrigingType,
     },
+    spatial_database::{gridded_databases::GriddedDataBaseInterface, SpatialQueryable},
     variography::model_variograms::VariogramModel,
 };
 
+use super::{
+    multigrid::node_level,
+    path::{level_offsets, level_query_engines, random_path, write_assigned_data},
+    realizations::Simulation,
+    seeding::SeedSequence,
+};
+
 pub struct SGSParameters {
     pub max_octant_cond_data: usize,
     pub max_octant_sim_data: usize,
 }
 
+/// Path and kriging systems of a simulation
+pub struct SGSPlan {
+    sequential_data: Vec<SequentialStep>,
+}
+
+/// Node, conditioning values, simulated neighbours and kriging system of a node along the path
+type SequentialStep = ([isize; 3], Vec<f32>, Vec<[usize; 3]>, MiniSKSystem);
+
+/// Offsets of the simulated neighbour search of each multigrid level, for the search of the
+/// simulation (`None`) and the search of each domain
+type SearchOffsets = HashMap<Option<u32>, Vec<Vec<Vec<[isize; 3]>>>>;
+
+/// Conditioning data search and simulated neighbour limits of a domain
+struct DomainSearch<S> {
+    conditioning_data: S,
+    sgs_parameters: SGSParameters,
+}
+
 pub struct SGS<S, V, G>
 where
     S: SpatialQueryable<f32, G>,
@@ -29,6 +55,12 @@ where
     conditioning_data: S,
     variogram_model: V,
     sgs_parameters: SGSParameters,
+    domain_variograms: HashMap<u32, V>,
+    domain_searches: HashMap<u32, DomainSearch<S>>,
+    assigned_data: Option<Array3<Option<f32>>>,
+    multigrid_levels: usize,
+    memory_limit: Option<usize>,
+    kriging_type: KrigingType,
     phantom: std::marker::PhantomData<G>,
 }
 
@@ -51,82 +83,364 @@ where
             conditioning_data,
             variogram_model,
             sgs_parameters,
+            domain_variograms: HashMap::new(),
+            domain_searches: HashMap::new(),
+            assigned_data: None,
+            multigrid_levels: 1,
+            memory_limit: None,
+            kriging_type: KrigingType::default(),
             phantom: std::marker::PhantomData,
         }
     }
 
+    /// Use a separate variogram for nodes in a domain
+    /// domains without their own variogram use the variogram of the simulation
+    pub fn with_domain_variogram(mut self, domain: u32, variogram_model: V) -> Self {
+        self.domain_variograms.insert(domain, variogram_model);
+        self
+    }
+
+    /// Use a separate search for nodes in a domain
+    /// domains without their own search use the search of the simulation
+    /// # Arguments
+    /// * `domain` - The domain code
+    /// * `conditioning_data` - Conditioning data of the domain with its search (geometry, octants and search specification)
+    /// * `sgs_parameters` - Conditioning and simulated neighbour limits of the domain, the search geometry of `conditioning_data` also bounds the simulated neighbours
+    pub fn with_domain_search(
+        mut self,
+        domain: u32,
+        conditioning_data: S,
+        sgs_parameters: SGSParameters,
+    ) -> Self {
+        self.domain_searches.insert(
+            domain,
+            DomainSearch {
+                conditioning_data,
+                sgs_parameters,
+            },
+        );
+        self
+    }
+
+    /// Freeze the nodes holding conditioning data
+    /// frozen nodes are skipped on the path and keep their value, they are not used as simulation
+    /// neighbours since the data are already found by the conditioning search
+    /// # Arguments
+    /// * `assigned_data` - Value of each frozen node (see `DataAssignment`), must match the shape of the simulated grid
+    pub fn with_assigned_data(mut self, assigned_data: Array3<Option<f32>>) -> Self {
+        self.assigned_data = Some(assigned_data);
+        self
+    }
+
+    /// Visit coarse sub-grids before the full grid
+    /// level `k` holds every 2^k node, coarse levels are simulated first so long range structure is
+    /// reproduced with a limited number of simulated neighbours, the search for simulated neighbours
+    /// on a level only visits nodes of that level
+    /// # Arguments
+    /// * `levels` - Number of levels (1 for a single random path)
+    pub fn with_multigrid(mut self, levels: usize) -> Self {
+        assert!(levels > 0, "at least one multigrid level is required");
+        self.multigrid_levels = levels;
+        self
+    }
+
+    /// Bound the memory used by the kriging systems awaiting their values
+    /// the systems are solved in parallel for a window of the path at a time, realizations are identical
+    /// to those simulated without a limit
+    /// * plans shared by realizations (`Simulation::plan`) always hold the systems of all nodes
+    /// # Arguments
+    /// * `memory_limit` - Approximate memory ceiling in bytes
+    pub fn with_memory_limit(mut self, memory_limit: usize) -> Self {
+        self.memory_limit = Some(memory_limit);
+        self
+    }
+
+    /// Treatment of the mean in the kriging systems
+    /// ordinary kriging re-estimates the mean from the neighbourhood of each node, accommodating
+    /// locally varying means, nodes without neighbours fall back to simple kriging
+    pub fn with_kriging_type(mut self, kriging_type: KrigingType) -> Self {
+        self.kriging_type = kriging_type;
+        self
+    }
+
     /// Perform simple kriging at all kriging points
+    /// the path and all random draws are derived from `rng`, a seeded rng gives the same realization
+    /// regardless of the number of threads
     pub fn simulate_grid<GDB>(&self, grid: &mut GDB, rng: &mut StdRng)
     where
         GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
     {
-        //construct kriging system
-        let mut kriging_system = SimpleKrigingSystem::new(
-            (self.sgs_parameters.max_octant_cond_data + self.sgs_parameters.max_octant_sim_data)
-                * 8,
+        self.simulate(grid, None, rng);
+    }
+
+    /// Simulate all nodes of the grid using the variogram and boundaries of their domain
+    /// the boundaries of the conditioning data (see `GriddedDataBaseOctantQueryEngine::with_domains`)
+    /// also apply to previously simulated nodes, every search must hold domain information
+    /// # Arguments
+    /// * `grid` - The grid to simulate
+    /// * `domains` - Domain code of each node (must have the same shape as the grid)
+    /// * `rng` - The random number generator
+    pub fn simulate_grid_in_domains<GDB>(
+        &self,
+        grid: &mut GDB,
+        domains: &Array3<u32>,
+        rng: &mut StdRng,
+    ) where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        assert_eq!(
+            domains.shape(),
+            grid.shape().as_slice(),
+            "domain codes must match the shape of the grid"
         );
+        assert!(
+            std::iter::once(&self.conditioning_data)
+                .chain(
+                    self.domain_searches
+                        .values()
+                        .map(|search| &search.conditioning_data)
+                )
+                .all(|conditioning_data| conditioning_data.domain_boundaries().is_some()),
+            "domain simulation requires conditioning data with domain codes"
+        );
+        self.simulate(grid, Some(domains), rng);
+    }
+
+    fn simulate<GDB>(&self, grid: &mut GDB, domains: Option<&Array3<u32>>, rng: &mut StdRng)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        //noise of each node is drawn from its own stream so draws do not depend on scheduling
+        let seeds = SeedSequence::from_rng(rng);
+
+        let (path, simulation_order) = self.build_path(&*grid, rng);
+        let level_offsets = self.level_offsets(&*grid);
+        write_assigned_data(grid, self.assigned_data.as_ref());
+
+        //kriging systems are solved for a window of the path at a time to bound memory
+        let window = self.window_size(path.len());
+        let mut values_mat = SimpleKrigingSystem::new(self.system_size()).values;
+        for (window_ind, window_path) in path.chunks(window).enumerate() {
+            let sequential_data = self.solve_nodes(
+                &*grid,
+                domains,
+                window_path,
+                &simulation_order,
+                &level_offsets,
+            );
+            self.draw_values(
+                &sequential_data,
+                window_ind * window,
+                grid,
+                seeds,
+                &mut values_mat,
+            );
+        }
+    }
+
+    /// Shuffle the path and solve the kriging system of every node
+    fn build_plan<GDB>(
+        &self,
+        grid: &GDB,
+        domains: Option<&Array3<u32>>,
+        rng: &mut StdRng,
+    ) -> SGSPlan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        let (path, simulation_order) = self.build_path(grid, rng);
+        let level_offsets = self.level_offsets(grid);
+        let sequential_data =
+            self.solve_nodes(grid, domains, &path, &simulation_order, &level_offsets);
+
+        SGSPlan { sequential_data }
+    }
+
+    /// Simulate the nodes of a plan in path order
+    fn apply_plan<GDB>(&self, plan: &SGSPlan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        let mut values_mat = SimpleKrigingSystem::new(self.system_size()).values;
+        write_assigned_data(grid, self.assigned_data.as_ref());
+        self.draw_values(&plan.sequential_data, 0, grid, seeds, &mut values_mat);
+    }
 
-        // create query engine for simulation grid
-        let geometry = self.conditioning_data.geometry().clone();
-        let sim_qe = GriddedDataBaseOctantQueryEngine::new(
-            geometry.clone(),
+    /// Shuffle the path over the nodes to simulate
+    /// # Returns
+    /// The path and the position of each node along the path (usize::MAX for frozen nodes)
+    fn build_path<GDB>(
+        &self,
+        grid: &GDB,
+        rng: &mut StdRng,
+    ) -> (Vec<(usize, usize, usize)>, Array3<usize>)
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        random_path(
             grid,
-            self.sgs_parameters.max_octant_sim_data,
-        );
+            self.assigned_data.as_ref(),
+            self.multigrid_levels,
+            rng,
+        )
+    }
 
-        // Array to store simulation order
-        let mut simulation_order = Array3::from_elem(grid.shape(), 0);
-        let mut path = simulation_order
-            .indexed_iter()
-            .map(|(ind, _)| ind)
-            .collect::<Vec<_>>();
+    /// Offsets of the simulated neighbour search of each multigrid level, for every search
+    fn level_offsets<GDB>(&self, grid: &GDB) -> SearchOffsets
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        std::iter::once(None)
+            .chain(self.domain_searches.keys().copied().map(Some))
+            .map(|key| {
+                let (conditioning_data, sgs_parameters) = self.search(key);
+                let offsets = level_offsets(
+                    conditioning_data.geometry(),
+                    grid,
+                    sgs_parameters.max_octant_sim_data,
+                    self.multigrid_levels,
+                );
+                (key, offsets)
+            })
+            .collect()
+    }
 
-        //shuffle path order
-        path.shuffle(&mut rand::thread_rng());
+    /// Key of the search used by a node of a domain (`None` for the search of the simulation)
+    #[inline(always)]
+    fn search_key(&self, domain: Option<u32>) -> Option<u32> {
+        domain.filter(|domain| self.domain_searches.contains_key(domain))
+    }
 
-        //set iteration order
-        for (ind, val) in path.iter().enumerate() {
-            simulation_order[*val] = ind;
+    /// Conditioning data and parameters of a search
+    #[inline(always)]
+    fn search(&self, key: Option<u32>) -> (&S, &SGSParameters) {
+        match key.and_then(|key| self.domain_searches.get(&key)) {
+            Some(search) => (&search.conditioning_data, &search.sgs_parameters),
+            None => (&self.conditioning_data, &self.sgs_parameters),
         }
+    }
+
+    /// Solve the kriging systems of the nodes along a section of the path in parallel
+    fn solve_nodes<GDB>(
+        &self,
+        grid: &GDB,
+        domains: Option<&Array3<u32>>,
+        path: &[(usize, usize, usize)],
+        simulation_order: &Array3<usize>,
+        level_offsets: &SearchOffsets,
+    ) -> Vec<SequentialStep>
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        //construct kriging system
+        let kriging_system = SimpleKrigingSystem::new(self.system_size());
+
+        // create query engine for simulation grid, one per multigrid level and search
+        let sim_qes = level_offsets
+            .iter()
+            .map(|(key, offsets)| {
+                let (conditioning_data, sgs_parameters) = self.search(*key);
+                let query_engines = level_query_engines(
+                    conditioning_data.geometry(),
+                    grid,
+                    sgs_parameters.max_octant_sim_data,
+                    offsets,
+                );
+                (*key, query_engines)
+            })
+            .collect::<HashMap<_, _>>();
 
         // Note: We do not know the values so we can't populate the grid
         // But at each location in the grid we now all the points that will be previously simulated and the locations of the conditioning data
         // thus, we can solve for the weights in parrallel, then populate the grid sequentially
-        let sequential_data = path
-            .par_iter()
-            .map_with(kriging_system.clone(), |local_system, ind| {
+        path.par_iter()
+            .map_with(kriging_system, |local_system, ind| {
                 let ind = [ind.0 as isize, ind.1 as isize, ind.2 as isize];
                 //get kriging point
                 let point = grid.ind_to_point(&ind);
+                let domain = domains.map(|domains| domains[ind.map(|ind| ind as usize)]);
+                let search_key = self.search_key(domain);
+                let (conditioning_data, _) = self.search(search_key);
+                let sim_qe = &sim_qes[&search_key]
+                    [node_level(ind.map(|ind| ind as usize), self.multigrid_levels)];
 
                 //get nearest conditioning  points and values
-                let (cond_values, mut cond_points) = self.conditioning_data.query(&point);
+                let (cond_values, mut cond_points) = match domain {
+                    Some(domain) => conditioning_data.query_in_domain(&point, domain),
+                    None => conditioning_data.query(&point),
+                };
 
                 // get nearest simulation points
                 let (sim_inds, sim_points) =
                     sim_qe.nearest_inds_and_points_masked(&point, |neighbor_ind| {
-                        simulation_order[neighbor_ind]
-                            < simulation_order[ind.map(|ind| ind as usize)]
+                        if simulation_order[neighbor_ind]
+                            >= simulation_order[ind.map(|ind| ind as usize)]
+                        {
+                            return false;
+                        }
+
+                        //previously simulated nodes must respect the domain boundaries
+                        match (domain, domains) {
+                            (Some(domain), Some(domains)) => {
+                                let neighbor_domain = domains[neighbor_ind];
+                                neighbor_domain == domain || {
+                                    let neighbor_point =
+                                        grid.ind_to_point(&neighbor_ind.map(|x| x as isize));
+                                    conditioning_data
+                                        .domain_boundaries()
+                                        .expect("conditioning data hold no domain information")
+                                        .accepts(
+                                            domain,
+                                            neighbor_domain,
+                                            distance(&point, &neighbor_point),
+                                        )
+                                }
+                            }
+                            _ => true,
+                        }
                     });
 
                 //append simulation points to conditioning points
                 cond_points.extend(sim_points.iter());
 
-                let mini_system = local_system.build_mini_system(
-                    cond_points.as_slice(),
-                    &point,
-                    &self.variogram_model,
-                );
+                let variogram_model = domain
+                    .and_then(|domain| self.domain_variograms.get(&domain))
+                    .unwrap_or(&self.variogram_model);
+
+                let mini_system = match self.kriging_type {
+                    KrigingType::Simple => local_system.build_mini_system(
+                        cond_points.as_slice(),
+                        &point,
+                        variogram_model,
+                    ),
+                    KrigingType::Ordinary => local_system.build_mini_ok_system(
+                        cond_points.as_slice(),
+                        &point,
+                        variogram_model,
+                    ),
+                };
 
                 (ind, cond_values, sim_inds, mini_system)
             })
-            .collect::<Vec<_>>();
-
-        let mut values_mat = kriging_system.values.clone();
+            .collect::<Vec<_>>()
+    }
 
-        sequential_data
-            .into_iter()
-            .for_each(|(ind, cond_values, sim_inds, mini_system)| {
+    /// Draw the values of solved nodes in path order
+    /// # Arguments
+    /// * `sequential_data` - Solved nodes along a section of the path
+    /// * `first_path_ind` - Position of the first node along the path
+    fn draw_values<GDB>(
+        &self,
+        sequential_data: &[SequentialStep],
+        first_path_ind: usize,
+        grid: &mut GDB,
+        seeds: SeedSequence,
+        values_mat: &mut Mat<f32>,
+    ) where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        sequential_data.iter().enumerate().for_each(
+            |(window_ind, (ind, cond_values, sim_inds, mini_system))| {
                 //get simulation values
                 let sim_values = sim_inds
                     .iter()
@@ -153,11 +467,73 @@ where
                 //compute variance
                 let variance = mini_system.variance();
 
-                let value = Normal::new(mean, variance).unwrap().sample(rng);
+                let value = Normal::new(mean, variance.max(0.0).sqrt())
+                    .unwrap()
+                    .sample(&mut seeds.stream((first_path_ind + window_ind) as u64));
 
                 //set value
                 grid.set_data_at_ind(&ind.map(|v| v as usize), value);
-            });
+            },
+        );
+    }
+
+    /// Number of nodes solved at a time
+    fn window_size(&self, path_len: usize) -> usize {
+        let Some(memory_limit) = self.memory_limit else {
+            return path_len.max(1);
+        };
+
+        //weights, covariances and conditioning values and simulated neighbour indices of a node
+        let node_bytes = self.system_size()
+            * (3 * std::mem::size_of::<f32>() + std::mem::size_of::<[usize; 3]>())
+            + std::mem::size_of::<SequentialStep>();
+
+        (memory_limit / node_bytes).clamp(1, path_len.max(1))
+    }
+
+    /// Size of the kriging systems of the largest search
+    #[inline(always)]
+    fn system_size(&self) -> usize {
+        std::iter::once(&self.sgs_parameters)
+            .chain(
+                self.domain_searches
+                    .values()
+                    .map(|search| &search.sgs_parameters),
+            )
+            .map(|params| (params.max_octant_cond_data + params.max_octant_sim_data) * 8)
+            .max()
+            .unwrap()
+    }
+}
+
+impl<S, V, G> Simulation for SGS<S, V, G>
+where
+    S: SpatialQueryable<f32, G> + Sync,
+    V: VariogramModel + Sync,
+    G: Geometry + Sync + Clone,
+{
+    type Plan = SGSPlan;
+
+    fn plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> Self::Plan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.build_plan(grid, None, rng)
+    }
+
+    fn realize<GDB>(&self, plan: &Self::Plan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.apply_plan(plan, grid, seeds);
+    }
+
+    fn simulate_grid<GDB>(&self, grid: &mut GDB, rng: &mut StdRng)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        //kriging systems are solved in windows along the path instead of for the whole plan
+        self.simulate(grid, None, rng);
     }
 }
 
@@ -166,15 +542,20 @@ mod test {
     use super::*;
     use std::{fs::File, io::Write};
 
-    use nalgebra::{Point3, UnitQuaternion, Vector3};
+    use nalgebra::{Point3, Translation3, UnitQuaternion, Vector3};
     use num_traits::Float;
     use rand::SeedableRng;
 
     use crate::{
         geometry::ellipsoid::Ellipsoid,
+        simulation::{data_assignment::DataAssignment, realizations::simulate_on_threads},
         spatial_database::{
             coordinate_system::{CoordinateSystem, GridSpacing},
-            gridded_databases::incomplete_grid::InCompleteGriddedDataBase,
+            domain_boundaries::DomainBoundaries,
+            gridded_databases::{
+                gridded_data_base_query_engine::GriddedDataBaseOctantQueryEngine,
+                incomplete_grid::InCompleteGriddedDataBase,
+            },
             normalized::Normalize,
         },
         variography::model_variograms::spherical::SphericalVariogram,
@@ -277,4 +658,401 @@ mod test {
                 .write_all(format!("{} {} {} {}\n", point.x, point.y, point.z, value).as_bytes());
         }
     }
+
+    #[test]
+    fn sgs_reproducible_across_threads() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        let mut cond_grid = Array3::<Option<f32>>::from_elem((12, 12, 1), None);
+        cond_grid[[2, 3, 0]] = Some(0.5);
+        cond_grid[[9, 8, 0]] = Some(-1.0);
+        cond_grid[[5, 10, 0]] = Some(1.2);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid, spacing, cs);
+
+        let vgram = SphericalVariogram::new(Vector3::new(6.0, 6.0, 6.0), 1.0, 0.1, cs);
+        let ellipsoid = Ellipsoid::new(8.0, 8.0, 8.0, cs);
+        let simulation = SGS::new(
+            GriddedDataBaseOctantQueryEngine::new(ellipsoid, &cond_db, 4),
+            vgram,
+            SGSParameters {
+                max_octant_cond_data: 4,
+                max_octant_sim_data: 4,
+            },
+        );
+
+        let realization = |threads: usize, seed: u64| {
+            let mut sim_db =
+                InCompleteGriddedDataBase::new(Array3::from_elem((12, 12, 1), None), spacing, cs);
+            simulate_on_threads(&simulation, &mut sim_db, threads, seed);
+            sim_db.raw_grid.grid
+        };
+
+        let single_thread = realization(1, 11);
+        assert!(single_thread.iter().all(|value| value.is_some()));
+        assert_eq!(single_thread, realization(4, 11));
+        assert_ne!(single_thread, realization(4, 12));
+    }
+
+    #[test]
+    fn sgs_reproduces_assigned_data() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        //data lie on the nodes of the simulation grid
+        let mut cond_grid = Array3::<Option<f32>>::from_elem((10, 10, 1), None);
+        cond_grid[[2, 3, 0]] = Some(0.5);
+        cond_grid[[7, 8, 0]] = Some(-1.0);
+        cond_grid[[4, 4, 0]] = Some(1.2);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid.clone(), spacing, cs);
+        let (values, points) = cond_db.data_and_points();
+
+        let mut sim_db = InCompleteGriddedDataBase::new(
+            Array3::<Option<f32>>::from_elem((10, 10, 1), None),
+            spacing,
+            cs,
+        );
+        let assigned = DataAssignment::new(0.1).assign(&sim_db, &points, &values);
+        assert_eq!(assigned, cond_grid);
+
+        let simulation = SGS::new(
+            GriddedDataBaseOctantQueryEngine::new(Ellipsoid::new(6.0, 6.0, 6.0, cs), &cond_db, 4),
+            SphericalVariogram::new(Vector3::new(5.0, 5.0, 5.0), 1.0, 0.1, cs),
+            SGSParameters {
+                max_octant_cond_data: 4,
+                max_octant_sim_data: 4,
+            },
+        )
+        .with_assigned_data(assigned);
+        simulation.simulate_grid(&mut sim_db, &mut StdRng::seed_from_u64(3));
+
+        assert_eq!(sim_db.data_at_ind(&[2, 3, 0]), Some(0.5));
+        assert_eq!(sim_db.data_at_ind(&[7, 8, 0]), Some(-1.0));
+        assert_eq!(sim_db.data_at_ind(&[4, 4, 0]), Some(1.2));
+        assert!(sim_db
+            .raw_grid
+            .grid
+            .iter()
+            .all(|value| value.is_some_and(f32::is_finite)));
+    }
+
+    #[test]
+    #[should_panic(expected = "domain codes")]
+    fn sgs_domains_require_domain_data() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        let cond_db = InCompleteGriddedDataBase::new(
+            Array3::<Option<f32>>::from_elem((6, 6, 1), None),
+            spacing,
+            cs,
+        );
+        let simulation = SGS::new(
+            GriddedDataBaseOctantQueryEngine::new(Ellipsoid::new(4.0, 4.0, 4.0, cs), &cond_db, 4),
+            SphericalVariogram::new(Vector3::new(4.0, 4.0, 4.0), 1.0, 0.1, cs),
+            SGSParameters {
+                max_octant_cond_data: 4,
+                max_octant_sim_data: 4,
+            },
+        );
+
+        //the conditioning data were not given the domain codes, boundaries cannot be honoured
+        let mut sim_db = InCompleteGriddedDataBase::new(
+            Array3::<Option<f32>>::from_elem((6, 6, 1), None),
+            spacing,
+            cs,
+        );
+        simulation.simulate_grid_in_domains(
+            &mut sim_db,
+            &Array3::from_elem((6, 6, 1), 1),
+            &mut StdRng::seed_from_u64(0),
+        );
+    }
+
+    #[test]
+    fn sgs_domain_search() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        let mut cond_grid = Array3::<Option<f32>>::from_elem((12, 12, 1), None);
+        cond_grid[[2, 3, 0]] = Some(0.5);
+        cond_grid[[4, 8, 0]] = Some(-1.0);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid, spacing, cs);
+        let domains = Array3::from_shape_fn((12, 12, 1), |(i, _, _)| if i < 6 { 1 } else { 2 });
+        let query_engine = |radius: f32| {
+            GriddedDataBaseOctantQueryEngine::new(
+                Ellipsoid::new(radius, radius, radius, cs),
+                &cond_db,
+                4,
+            )
+            .with_domains(domains.clone(), DomainBoundaries::Hard)
+        };
+
+        //nodes of domain 2 only search their own node, they have no neighbours
+        let simulation = SGS::new(
+            query_engine(8.0),
+            SphericalVariogram::new(Vector3::new(8.0, 8.0, 8.0), 1.0, 0.1, cs),
+            SGSParameters {
+                max_octant_cond_data: 4,
+                max_octant_sim_data: 4,
+            },
+        )
+        .with_domain_search(
+            2,
+            query_engine(0.5),
+            SGSParameters {
+                max_octant_cond_data: 1,
+                max_octant_sim_data: 1,
+            },
+        );
+
+        let mut sim_db = InCompleteGriddedDataBase::new(
+            Array3::<Option<f32>>::from_elem((12, 12, 1), None),
+            spacing,
+            cs,
+        );
+
+        let plan = simulation.build_plan(&sim_db, Some(&domains), &mut StdRng::seed_from_u64(2));
+        for (ind, cond_values, sim_inds, _) in plan.sequential_data.iter() {
+            if domains[ind.map(|i| i as usize)] == 2 {
+                assert!(cond_values.is_empty() && sim_inds.is_empty());
+            }
+        }
+        assert!(plan
+            .sequential_data
+            .iter()
+            .filter(|(ind, ..)| domains[ind.map(|i| i as usize)] == 1)
+            .all(|(_, cond_values, sim_inds, _)| cond_values.len() + sim_inds.len() > 0));
+
+        simulation.simulate_grid_in_domains(&mut sim_db, &domains, &mut StdRng::seed_from_u64(2));
+        assert!(sim_db
+            .raw_grid
+            .grid
+            .iter()
+            .all(|value| value.is_some_and(f32::is_finite)));
+    }
+
+    #[test]
+    fn sgs_multigrid_path() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        let mut cond_grid = Array3::<Option<f32>>::from_elem((12, 12, 1), None);
+        cond_grid[[2, 3, 0]] = Some(0.5);
+        cond_grid[[9, 8, 0]] = Some(-1.0);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid, spacing, cs);
+
+        let simulation = SGS::new(
+            GriddedDataBaseOctantQueryEngine::new(Ellipsoid::new(8.0, 8.0, 8.0, cs), &cond_db, 4),
+            SphericalVariogram::new(Vector3::new(8.0, 8.0, 8.0), 1.0, 0.1, cs),
+            SGSParameters {
+                max_octant_cond_data: 4,
+                max_octant_sim_data: 4,
+            },
+        )
+        .with_multigrid(3);
+
+        let mut sim_db = InCompleteGriddedDataBase::new(
+            Array3::<Option<f32>>::from_elem((12, 12, 1), None),
+            spacing,
+            cs,
+        );
+
+        //coarse levels are visited first
+        let plan = simulation.build_plan(&sim_db, None, &mut StdRng::seed_from_u64(1));
+        let levels = plan
+            .sequential_data
+            .iter()
+            .map(|(ind, _, sim_inds, _)| {
+                let level = node_level(ind.map(|i| i as usize), 3);
+                //simulated neighbours lie on the level of the node or a coarser one
+                assert!(sim_inds.iter().all(|ind| node_level(*ind, 3) >= level));
+                level
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(levels.len(), 144);
+        assert!(levels.windows(2).all(|pair| pair[0] >= pair[1]));
+        assert_eq!(levels[0], 2);
+
+        simulation.simulate_grid(&mut sim_db, &mut StdRng::seed_from_u64(1));
+        assert!(sim_db
+            .raw_grid
+            .grid
+            .iter()
+            .all(|value| value.is_some_and(f32::is_finite)));
+    }
+
+    #[test]
+    fn sgs_memory_limit_matches_unbounded() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        let mut cond_grid = Array3::<Option<f32>>::from_elem((12, 12, 1), None);
+        cond_grid[[2, 3, 0]] = Some(0.5);
+        cond_grid[[9, 8, 0]] = Some(-1.0);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid, spacing, cs);
+
+        let realization = |memory_limit: Option<usize>| {
+            let mut sgs = SGS::new(
+                GriddedDataBaseOctantQueryEngine::new(
+                    Ellipsoid::new(6.0, 6.0, 6.0, cs),
+                    &cond_db,
+                    4,
+                ),
+                SphericalVariogram::new(Vector3::new(6.0, 6.0, 6.0), 1.0, 0.1, cs),
+                SGSParameters {
+                    max_octant_cond_data: 4,
+                    max_octant_sim_data: 4,
+                },
+            )
+            .with_multigrid(2);
+            if let Some(memory_limit) = memory_limit {
+                sgs = sgs.with_memory_limit(memory_limit);
+            }
+
+            let mut sim_db = InCompleteGriddedDataBase::new(
+                Array3::<Option<f32>>::from_elem((12, 12, 1), None),
+                spacing,
+                cs,
+            );
+            sgs.simulate_grid(&mut sim_db, &mut StdRng::seed_from_u64(9));
+            sim_db.raw_grid.grid
+        };
+
+        let unbounded = realization(None);
+        //a single node per window and a few windows over the path
+        assert_eq!(unbounded, realization(Some(1)));
+        assert_eq!(unbounded, realization(Some(20_000)));
+    }
+
+    #[test]
+    fn sgs_unconditional_unit_variance() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        let cond_db = InCompleteGriddedDataBase::new(
+            Array3::<Option<f32>>::from_elem((40, 40, 1), None),
+            spacing,
+            cs,
+        );
+
+        let sgs = SGS::new(
+            GriddedDataBaseOctantQueryEngine::new(Ellipsoid::new(6.0, 6.0, 6.0, cs), &cond_db, 4),
+            SphericalVariogram::new(Vector3::new(3.0, 3.0, 3.0), 1.0, 0.0, cs),
+            SGSParameters {
+                max_octant_cond_data: 4,
+                max_octant_sim_data: 4,
+            },
+        );
+
+        //a short range over a large grid, the node values estimate the unit variance of the field
+        let values = (0..4)
+            .flat_map(|seed| {
+                let mut sim_db = InCompleteGriddedDataBase::new(
+                    Array3::<Option<f32>>::from_elem((40, 40, 1), None),
+                    spacing,
+                    cs,
+                );
+                sgs.simulate_grid(&mut sim_db, &mut StdRng::seed_from_u64(seed));
+                sim_db
+                    .raw_grid
+                    .grid
+                    .iter()
+                    .map(|value| value.unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let n = values.len() as f32;
+        let mean = values.iter().sum::<f32>() / n;
+        let variance = values
+            .iter()
+            .map(|value| (value - mean).powi(2))
+            .sum::<f32>()
+            / n;
+        assert!(mean.abs() < 0.15);
+        assert!((variance - 1.0).abs() < 0.15);
+    }
+
+    #[test]
+    fn sgs_ordinary_kriging_follows_shifted_data() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        let realization = |shift: f32, kriging_type: KrigingType| {
+            let mut cond_grid = Array3::<Option<f32>>::from_elem((8, 8, 1), None);
+            cond_grid[[1, 2, 0]] = Some(0.5 + shift);
+            cond_grid[[6, 5, 0]] = Some(-1.0 + shift);
+            cond_grid[[3, 7, 0]] = Some(0.2 + shift);
+            let cond_db = InCompleteGriddedDataBase::new(cond_grid, spacing, cs);
+
+            let sgs = SGS::new(
+                GriddedDataBaseOctantQueryEngine::new(
+                    Ellipsoid::new(20.0, 20.0, 20.0, cs),
+                    &cond_db,
+                    4,
+                ),
+                SphericalVariogram::new(Vector3::new(6.0, 6.0, 6.0), 1.0, 0.1, cs),
+                SGSParameters {
+                    max_octant_cond_data: 4,
+                    max_octant_sim_data: 4,
+                },
+            )
+            .with_kriging_type(kriging_type);
+
+            let mut sim_db = InCompleteGriddedDataBase::new(
+                Array3::<Option<f32>>::from_elem((8, 8, 1), None),
+                spacing,
+                cs,
+            );
+            sgs.simulate_grid(&mut sim_db, &mut StdRng::seed_from_u64(3));
+            sim_db.raw_grid.grid.map(|value| value.unwrap())
+        };
+
+        //ordinary kriging weights sum to one, a shift of the data shifts the realization
+        let ok = realization(0.0, KrigingType::Ordinary);
+        let ok_shifted = realization(3.0, KrigingType::Ordinary);
+        assert!(ok
+            .iter()
+            .zip(ok_shifted.iter())
+            .all(|(value, shifted)| (shifted - value - 3.0).abs() < 1e-3));
+
+        //simple kriging pulls the values towards the zero mean
+        let sk = realization(0.0, KrigingType::Simple);
+        let sk_shifted = realization(3.0, KrigingType::Simple);
+        assert!(sk
+            .iter()
+            .zip(sk_shifted.iter())
+            .any(|(value, shifted)| (shifted - value - 3.0).abs() > 1e-2));
+    }
 }
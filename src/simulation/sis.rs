@@ -0,0 +1,683 @@
+use ndarray::Array3;
+use ordered_float::OrderedFloat;
+use rand::{rngs::StdRng, Rng};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    geometry::Geometry,
+    kriging::simple_kriging::{MiniSKSystem, SimpleKrigingSystem},
+    spatial_database::{gridded_databases::GriddedDataBaseInterface, SpatialQueryable},
+    variography::model_variograms::VariogramModel,
+};
+
+use super::{
+    multigrid::node_level,
+    path::{level_offsets, level_query_engines, random_path, write_assigned_data},
+    realizations::Simulation,
+    seeding::SeedSequence,
+};
+
+pub struct SISParameters {
+    pub max_octant_cond_data: usize,
+    pub max_octant_sim_data: usize,
+}
+
+/// Indicator coding of a simulated variable
+#[derive(Clone, Debug, PartialEq)]
+pub enum IndicatorVariable {
+    /// Facies codes and their target proportions (summing to one)
+    Categorical {
+        codes: Vec<f32>,
+        proportions: Vec<f32>,
+    },
+    /// Ascending thresholds, target cdf at each threshold and the bounds of the distribution
+    Continuous {
+        thresholds: Vec<f32>,
+        cdf: Vec<f32>,
+        min: f32,
+        max: f32,
+    },
+}
+
+impl IndicatorVariable {
+    /// Number of indicators (categories or thresholds)
+    pub fn n_indicators(&self) -> usize {
+        self.prior().len()
+    }
+
+    /// Target proportions of the categories or target cdf at the thresholds
+    pub fn prior(&self) -> &[f32] {
+        match self {
+            IndicatorVariable::Categorical { proportions, .. } => proportions,
+            IndicatorVariable::Continuous { cdf, .. } => cdf,
+        }
+    }
+
+    /// Indicator of a value
+    /// # Arguments
+    /// * `indicator` - Index of the category or threshold
+    /// * `value` - Category code or continuous value
+    #[inline(always)]
+    pub fn indicator(&self, indicator: usize, value: f32) -> f32 {
+        let inside = match self {
+            IndicatorVariable::Categorical { codes, .. } => value == codes[indicator],
+            IndicatorVariable::Continuous { thresholds, .. } => value <= thresholds[indicator],
+        };
+        if inside {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Correct order relation deviations of kriged indicators
+    /// probabilities are clipped to [0, 1], category probabilities are rescaled to sum to one and
+    /// cdf values are made non decreasing by averaging an upward and a downward correction
+    pub fn correct_order_relations(&self, ccdf: &mut [f32]) {
+        ccdf.iter_mut().for_each(|p| *p = p.clamp(0.0, 1.0));
+
+        match self {
+            IndicatorVariable::Categorical { proportions, .. } => {
+                let sum = ccdf.iter().sum::<f32>();
+                if sum > 0.0 {
+                    ccdf.iter_mut().for_each(|p| *p /= sum);
+                } else {
+                    ccdf.copy_from_slice(proportions);
+                }
+            }
+            IndicatorVariable::Continuous { .. } => {
+                let mut upward = ccdf.to_vec();
+                for i in 1..upward.len() {
+                    upward[i] = upward[i].max(upward[i - 1]);
+                }
+                let mut downward = ccdf.to_vec();
+                for i in (0..downward.len().saturating_sub(1)).rev() {
+                    downward[i] = downward[i].min(downward[i + 1]);
+                }
+                for (i, p) in ccdf.iter_mut().enumerate() {
+                    *p = 0.5 * (upward[i] + downward[i]);
+                }
+            }
+        }
+    }
+
+    /// Draw a value from a corrected ccdf
+    /// continuous values are interpolated linearly within the classes defined by the thresholds
+    /// # Arguments
+    /// * `ccdf` - Category probabilities or cdf at the thresholds
+    /// * `u` - Uniform draw in [0, 1)
+    pub fn draw(&self, ccdf: &[f32], u: f32) -> f32 {
+        match self {
+            IndicatorVariable::Categorical { codes, .. } => {
+                let mut cumulative = 0.0;
+                for (code, p) in codes.iter().zip(ccdf.iter()) {
+                    cumulative += p;
+                    if u < cumulative {
+                        return *code;
+                    }
+                }
+                //rounding of the cumulative probabilities
+                *codes.last().expect("at least one category is required")
+            }
+            IndicatorVariable::Continuous {
+                thresholds,
+                min,
+                max,
+                ..
+            } => {
+                let knots = std::iter::once((0.0, *min))
+                    .chain(ccdf.iter().copied().zip(thresholds.iter().copied()))
+                    .chain(std::iter::once((1.0, *max)))
+                    .collect::<Vec<_>>();
+
+                for pair in knots.windows(2) {
+                    let ((p_0, v_0), (p_1, v_1)) = (pair[0], pair[1]);
+                    if u <= p_1 {
+                        if p_1 - p_0 <= f32::EPSILON {
+                            return v_0;
+                        }
+                        return v_0 + (u - p_0) / (p_1 - p_0) * (v_1 - v_0);
+                    }
+                }
+                *max
+            }
+        }
+    }
+}
+
+/// Path and kriging systems of a simulation
+pub struct SISPlan {
+    sequential_data: Vec<IndicatorStep>,
+}
+
+/// Node, conditioning values, simulated neighbours and indicator kriging systems of a node along the path
+type IndicatorStep = ([isize; 3], Vec<f32>, Vec<[usize; 3]>, Vec<MiniSKSystem>);
+
+/// Sequential indicator simulation
+/// every indicator is estimated by simple kriging around its target proportion, the corrected
+/// ccdf of each node is sampled along a random path
+pub struct SIS<S, V, G>
+where
+    S: SpatialQueryable<f32, G>,
+{
+    conditioning_data: S,
+    variograms: Vec<V>,
+    variable: IndicatorVariable,
+    sis_parameters: SISParameters,
+    assigned_data: Option<Array3<Option<f32>>>,
+    multigrid_levels: usize,
+    servo_system: f32,
+    honour_proportions: bool,
+    phantom: std::marker::PhantomData<G>,
+}
+
+impl<S, V, G> SIS<S, V, G>
+where
+    S: SpatialQueryable<f32, G> + Sync,
+    V: VariogramModel + Sync,
+    G: Geometry + Sync + Clone,
+{
+    /// Create a new sequential indicator simulation
+    /// # Arguments
+    /// * `conditioning_data` - The data to condition on (category codes or untransformed values)
+    /// * `variograms` - Indicator variogram of each category or threshold, a single variogram is shared by all indicators
+    /// * `variable` - Indicator coding and target proportions of the variable
+    /// * `sis_parameters` - The SIS parameters to use
+    pub fn new(
+        conditioning_data: S,
+        variograms: Vec<V>,
+        variable: IndicatorVariable,
+        sis_parameters: SISParameters,
+    ) -> Self {
+        let n_indicators = variable.n_indicators();
+        match &variable {
+            IndicatorVariable::Categorical { codes, .. } => assert_eq!(
+                codes.len(),
+                n_indicators,
+                "each category requires a proportion"
+            ),
+            IndicatorVariable::Continuous { thresholds, .. } => assert_eq!(
+                thresholds.len(),
+                n_indicators,
+                "each threshold requires a cdf value"
+            ),
+        }
+        assert!(
+            variograms.len() == 1 || variograms.len() == n_indicators,
+            "one variogram per indicator or a single shared variogram is required"
+        );
+
+        Self {
+            conditioning_data,
+            variograms,
+            variable,
+            sis_parameters,
+            assigned_data: None,
+            multigrid_levels: 1,
+            servo_system: 0.0,
+            honour_proportions: false,
+            phantom: std::marker::PhantomData,
+        }
+    }
+
+    /// Freeze the nodes holding conditioning data
+    /// frozen nodes are skipped on the path and keep their value
+    /// # Arguments
+    /// * `assigned_data` - Value of each frozen node (see `DataAssignment`), must match the shape of the simulated grid
+    pub fn with_assigned_data(mut self, assigned_data: Array3<Option<f32>>) -> Self {
+        self.assigned_data = Some(assigned_data);
+        self
+    }
+
+    /// Visit coarse sub-grids before the full grid (see `SGS::with_multigrid`)
+    /// # Arguments
+    /// * `levels` - Number of levels (1 for a single random path)
+    pub fn with_multigrid(mut self, levels: usize) -> Self {
+        assert!(levels > 0, "at least one multigrid level is required");
+        self.multigrid_levels = levels;
+        self
+    }
+
+    /// Pull the ccdf of each node towards the target proportions
+    /// the correction is proportional to the difference between the target proportions and the
+    /// proportions simulated so far
+    /// # Arguments
+    /// * `strength` - Strength of the correction in [0, 1), 0 disables the servo system
+    pub fn with_servo_system(mut self, strength: f32) -> Self {
+        assert!(
+            (0.0..1.0).contains(&strength),
+            "servo system strength must be in [0, 1)"
+        );
+        self.servo_system = strength;
+        self
+    }
+
+    /// Post-process realizations to reproduce the target proportions exactly
+    /// categories in excess are reassigned to categories in deficit at the nodes where the kriged
+    /// probabilities favour them most, continuous values are rank transformed to the target cdf
+    pub fn with_proportion_correction(mut self, honour_proportions: bool) -> Self {
+        self.honour_proportions = honour_proportions;
+        self
+    }
+
+    /// Shuffle the path and solve the indicator kriging systems of every node in parallel
+    fn build_plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> SISPlan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        let (path, simulation_order) = random_path(
+            grid,
+            self.assigned_data.as_ref(),
+            self.multigrid_levels,
+            rng,
+        );
+
+        let geometry = self.conditioning_data.geometry();
+        let max_octant_sim_data = self.sis_parameters.max_octant_sim_data;
+        let offsets = level_offsets(geometry, grid, max_octant_sim_data, self.multigrid_levels);
+        let sim_qes = level_query_engines(geometry, grid, max_octant_sim_data, &offsets);
+
+        let kriging_system = SimpleKrigingSystem::new(self.system_size());
+
+        let sequential_data = path
+            .par_iter()
+            .map_with(kriging_system, |local_system, ind| {
+                let ind = [ind.0 as isize, ind.1 as isize, ind.2 as isize];
+                let point = grid.ind_to_point(&ind);
+                let sim_qe =
+                    &sim_qes[node_level(ind.map(|ind| ind as usize), self.multigrid_levels)];
+
+                //get nearest conditioning  points and values
+                let (cond_values, mut cond_points) = self.conditioning_data.query(&point);
+
+                // get nearest simulation points
+                let (sim_inds, sim_points) =
+                    sim_qe.nearest_inds_and_points_masked(&point, |neighbor_ind| {
+                        simulation_order[neighbor_ind]
+                            < simulation_order[ind.map(|ind| ind as usize)]
+                    });
+
+                //append simulation points to conditioning points
+                cond_points.extend(sim_points.iter());
+
+                //one system per indicator variogram
+                let mini_systems = self
+                    .variograms
+                    .iter()
+                    .map(|variogram_model| {
+                        local_system.build_mini_system(
+                            cond_points.as_slice(),
+                            &point,
+                            variogram_model,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+
+                (ind, cond_values, sim_inds, mini_systems)
+            })
+            .collect::<Vec<_>>();
+
+        SISPlan { sequential_data }
+    }
+
+    /// Draw the values of the nodes of a plan in path order
+    fn apply_plan<GDB>(&self, plan: &SISPlan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        write_assigned_data(grid, self.assigned_data.as_ref());
+
+        let prior = self.variable.prior();
+        let n_indicators = prior.len();
+        let mut values_mat = SimpleKrigingSystem::new(self.system_size()).values;
+
+        //indicators of the simulated values, used by the servo system
+        let mut simulated_sums = vec![0.0; n_indicators];
+        let mut ccdfs = Vec::with_capacity(if self.honour_proportions {
+            plan.sequential_data.len()
+        } else {
+            0
+        });
+
+        for (path_ind, (ind, cond_values, sim_inds, mini_systems)) in
+            plan.sequential_data.iter().enumerate()
+        {
+            //get simulation values
+            let values = cond_values
+                .iter()
+                .copied()
+                .chain(
+                    sim_inds
+                        .iter()
+                        .map(|ind| grid.data_at_ind(ind).expect("No value at ind")),
+                )
+                .collect::<Vec<_>>();
+
+            let mut ccdf = (0..n_indicators)
+                .map(|indicator| {
+                    //store indicator residuals in kriging system
+                    unsafe { values_mat.set_dims(values.len(), 1) };
+                    for (i, value) in values.iter().enumerate() {
+                        let residual =
+                            self.variable.indicator(indicator, *value) - prior[indicator];
+                        unsafe { values_mat.write_unchecked(i, 0, residual) };
+                    }
+
+                    let mini_system = &mini_systems[indicator.min(mini_systems.len() - 1)];
+                    let mut p = prior[indicator] + mini_system.estimate(values_mat.as_ref());
+
+                    if self.servo_system > 0.0 && path_ind > 0 {
+                        let simulated = simulated_sums[indicator] / path_ind as f32;
+                        p += self.servo_system / (1.0 - self.servo_system)
+                            * (prior[indicator] - simulated);
+                    }
+                    p
+                })
+                .collect::<Vec<_>>();
+            self.variable.correct_order_relations(&mut ccdf);
+
+            let value = self
+                .variable
+                .draw(&ccdf, seeds.stream(path_ind as u64).gen::<f32>());
+
+            for (indicator, sum) in simulated_sums.iter_mut().enumerate() {
+                *sum += self.variable.indicator(indicator, value);
+            }
+
+            let ind = ind.map(|v| v as usize);
+            grid.set_data_at_ind(&ind, value);
+            if self.honour_proportions {
+                ccdfs.push((ind, ccdf));
+            }
+        }
+
+        if self.honour_proportions {
+            self.correct_proportions(grid, &ccdfs);
+        }
+    }
+
+    /// Reproduce the target proportions over the simulated nodes
+    /// # Arguments
+    /// * `grid` - The simulated grid
+    /// * `ccdfs` - Simulated nodes and their corrected ccdf
+    fn correct_proportions<GDB>(&self, grid: &mut GDB, ccdfs: &[([usize; 3], Vec<f32>)])
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        let n_nodes = ccdfs.len();
+        let values = ccdfs
+            .iter()
+            .map(|(ind, _)| grid.data_at_ind(ind).expect("No value at ind"))
+            .collect::<Vec<_>>();
+
+        match &self.variable {
+            IndicatorVariable::Categorical { codes, proportions } => {
+                let mut categories = values
+                    .iter()
+                    .map(|value| codes.iter().position(|code| code == value).unwrap())
+                    .collect::<Vec<_>>();
+                let targets = proportions
+                    .iter()
+                    .map(|p| (p * n_nodes as f32).round() as isize)
+                    .collect::<Vec<_>>();
+
+                //move nodes from the category most in excess to the category most in deficit
+                loop {
+                    let mut counts = vec![0isize; codes.len()];
+                    categories.iter().for_each(|c| counts[*c] += 1);
+                    let excess = (0..codes.len())
+                        .max_by_key(|c| counts[*c] - targets[*c])
+                        .unwrap();
+                    let deficit = (0..codes.len())
+                        .max_by_key(|c| targets[*c] - counts[*c])
+                        .unwrap();
+                    let n_moves =
+                        (counts[excess] - targets[excess]).min(targets[deficit] - counts[deficit]);
+                    if n_moves <= 0 {
+                        break;
+                    }
+
+                    let mut candidates = (0..n_nodes)
+                        .filter(|node| categories[*node] == excess)
+                        .collect::<Vec<_>>();
+                    candidates.sort_by_key(|node| {
+                        let ccdf = &ccdfs[*node].1;
+                        OrderedFloat(ccdf[excess] - ccdf[deficit])
+                    });
+                    for node in candidates.into_iter().take(n_moves as usize) {
+                        categories[node] = deficit;
+                    }
+                }
+
+                for ((ind, _), category) in ccdfs.iter().zip(categories) {
+                    grid.set_data_at_ind(ind, codes[category]);
+                }
+            }
+            IndicatorVariable::Continuous { cdf, .. } => {
+                //rank transform to the quantiles of the target distribution
+                let mut ranks = (0..n_nodes).collect::<Vec<_>>();
+                ranks.sort_by_key(|node| OrderedFloat(values[*node]));
+                for (rank, node) in ranks.into_iter().enumerate() {
+                    let quantile = (rank as f32 + 0.5) / n_nodes as f32;
+                    grid.set_data_at_ind(&ccdfs[node].0, self.variable.draw(cdf, quantile));
+                }
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn system_size(&self) -> usize {
+        (self.sis_parameters.max_octant_cond_data + self.sis_parameters.max_octant_sim_data) * 8
+    }
+}
+
+impl<S, V, G> Simulation for SIS<S, V, G>
+where
+    S: SpatialQueryable<f32, G> + Sync,
+    V: VariogramModel + Sync,
+    G: Geometry + Sync + Clone,
+{
+    type Plan = SISPlan;
+
+    fn plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> Self::Plan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.build_plan(grid, rng)
+    }
+
+    fn realize<GDB>(&self, plan: &Self::Plan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.apply_plan(plan, grid, seeds);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion, Vector3};
+    use rand::SeedableRng;
+
+    use crate::{
+        geometry::ellipsoid::Ellipsoid,
+        simulation::data_assignment::DataAssignment,
+        spatial_database::{
+            coordinate_system::{CoordinateSystem, GridSpacing},
+            gridded_databases::{
+                gridded_data_base_query_engine::GriddedDataBaseOctantQueryEngine,
+                incomplete_grid::InCompleteGriddedDataBase,
+            },
+        },
+        variography::model_variograms::spherical::SphericalVariogram,
+    };
+
+    use super::*;
+
+    #[test]
+    fn indicator_ccdf() {
+        let continuous = IndicatorVariable::Continuous {
+            thresholds: vec![1.0, 2.0, 4.0],
+            cdf: vec![0.2, 0.5, 0.9],
+            min: 0.0,
+            max: 5.0,
+        };
+        assert_eq!(continuous.indicator(1, 2.0), 1.0);
+        assert_eq!(continuous.indicator(0, 2.0), 0.0);
+
+        let mut ccdf = vec![0.4, 0.3, 1.2];
+        continuous.correct_order_relations(&mut ccdf);
+        assert!(ccdf
+            .iter()
+            .zip([0.35, 0.35, 1.0])
+            .all(|(p, expected)| (p - expected).abs() < 1e-6));
+
+        let prior = continuous.prior().to_vec();
+        assert!((continuous.draw(&prior, 0.1) - 0.5).abs() < 1e-5);
+        assert!((continuous.draw(&prior, 0.7) - 3.0).abs() < 1e-5);
+        assert!((continuous.draw(&prior, 0.95) - 4.5).abs() < 1e-5);
+
+        let categorical = IndicatorVariable::Categorical {
+            codes: vec![1.0, 2.0, 3.0],
+            proportions: vec![0.5, 0.3, 0.2],
+        };
+        let mut ccdf = vec![0.6, -0.1, 0.6];
+        categorical.correct_order_relations(&mut ccdf);
+        assert_eq!(ccdf, vec![0.5, 0.0, 0.5]);
+        assert_eq!(categorical.draw(&ccdf, 0.49), 1.0);
+        assert_eq!(categorical.draw(&ccdf, 0.51), 3.0);
+    }
+
+    #[test]
+    fn sis_categorical_honours_data_and_proportions() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        let mut cond_grid = Array3::<Option<f32>>::from_elem((10, 10, 1), None);
+        cond_grid[[1, 2, 0]] = Some(1.0);
+        cond_grid[[6, 5, 0]] = Some(2.0);
+        cond_grid[[8, 8, 0]] = Some(3.0);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid.clone(), spacing, cs);
+
+        let points = cond_db.raw_grid.grid.indexed_iter().filter_map(|(ind, v)| {
+            v.map(|v| {
+                (
+                    cond_db.ind_to_point(&[ind.0 as isize, ind.1 as isize, 0]),
+                    v,
+                )
+            })
+        });
+        let (points, values): (Vec<_>, Vec<_>) = points.unzip();
+        let assigned = DataAssignment::new(0.1).assign(&cond_db, &points, &values);
+
+        let variable = IndicatorVariable::Categorical {
+            codes: vec![1.0, 2.0, 3.0],
+            proportions: vec![0.5, 0.3, 0.2],
+        };
+        let sis = SIS::new(
+            GriddedDataBaseOctantQueryEngine::new(Ellipsoid::new(6.0, 6.0, 6.0, cs), &cond_db, 4),
+            vec![SphericalVariogram::new(
+                Vector3::new(5.0, 5.0, 5.0),
+                0.2,
+                0.05,
+                cs,
+            )],
+            variable,
+            SISParameters {
+                max_octant_cond_data: 4,
+                max_octant_sim_data: 4,
+            },
+        )
+        .with_assigned_data(assigned)
+        .with_servo_system(0.5)
+        .with_proportion_correction(true);
+
+        let new_grid = || {
+            InCompleteGriddedDataBase::new(
+                Array3::<Option<f32>>::from_elem((10, 10, 1), None),
+                spacing,
+                cs,
+            )
+        };
+        let mut grid = new_grid();
+        sis.simulate_grid(&mut grid, &mut StdRng::seed_from_u64(4));
+
+        //data are reproduced and all nodes hold a category
+        assert_eq!(grid.raw_grid.grid[[1, 2, 0]], Some(1.0));
+        assert_eq!(grid.raw_grid.grid[[8, 8, 0]], Some(3.0));
+        let count = |code: f32| {
+            grid.raw_grid
+                .grid
+                .indexed_iter()
+                .filter(|(ind, v)| cond_grid[*ind].is_none() && **v == Some(code))
+                .count()
+        };
+        //97 simulated nodes
+        assert_eq!(count(1.0), 49);
+        assert_eq!(count(2.0), 29);
+        assert_eq!(count(3.0), 19);
+
+        //seeded realizations are reproducible
+        let mut other = new_grid();
+        sis.simulate_grid(&mut other, &mut StdRng::seed_from_u64(4));
+        assert_eq!(grid.raw_grid.grid, other.raw_grid.grid);
+    }
+
+    #[test]
+    fn sis_continuous_within_bounds() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+
+        let mut cond_grid = Array3::<Option<f32>>::from_elem((8, 8, 1), None);
+        cond_grid[[1, 2, 0]] = Some(0.5);
+        cond_grid[[6, 5, 0]] = Some(3.5);
+        let cond_db = InCompleteGriddedDataBase::new(cond_grid, spacing, cs);
+
+        let variogram =
+            |sill: f32| SphericalVariogram::new(Vector3::new(5.0, 5.0, 5.0), sill, 0.02, cs);
+        let sis = SIS::new(
+            GriddedDataBaseOctantQueryEngine::new(Ellipsoid::new(6.0, 6.0, 6.0, cs), &cond_db, 4),
+            vec![variogram(0.16), variogram(0.25), variogram(0.16)],
+            IndicatorVariable::Continuous {
+                thresholds: vec![1.0, 2.0, 3.0],
+                cdf: vec![0.2, 0.5, 0.8],
+                min: 0.0,
+                max: 4.0,
+            },
+            SISParameters {
+                max_octant_cond_data: 4,
+                max_octant_sim_data: 4,
+            },
+        )
+        .with_proportion_correction(true);
+
+        let mut grid = InCompleteGriddedDataBase::new(
+            Array3::<Option<f32>>::from_elem((8, 8, 1), None),
+            spacing,
+            cs,
+        );
+        sis.simulate_grid(&mut grid, &mut StdRng::seed_from_u64(2));
+
+        let values = grid
+            .raw_grid
+            .grid
+            .iter()
+            .map(|value| value.unwrap())
+            .collect::<Vec<_>>();
+        assert!(values.iter().all(|value| (0.0..=4.0).contains(value)));
+
+        //rank transform reproduces the target cdf
+        let below = |threshold: f32| values.iter().filter(|v| **v <= threshold).count();
+        assert!((below(1.0) as i32 - 13).abs() <= 1);
+        assert!((below(2.0) as i32 - 32).abs() <= 1);
+        assert!((below(3.0) as i32 - 51).abs() <= 1);
+    }
+}
@@ -0,0 +1,515 @@
+use ndarray::Array3;
+use rand::rngs::StdRng;
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    geometry::{template::Template, Geometry},
+    spatial_database::gridded_databases::{
+        complete_grid::CompleteGriddedDataBase,
+        gridded_data_base_query_engine::GriddedDataBaseOctantQueryEngine, GriddedDataBaseInterface,
+    },
+};
+
+use super::{
+    multigrid::node_level,
+    path::{random_path, write_assigned_data},
+    realizations::Simulation,
+    seeding::SeedSequence,
+};
+
+/// Node of a search tree
+/// a node at depth `d` counts the centre categories of the patterns sharing the categories of the
+/// first `d` template nodes
+struct TreeNode {
+    counts: Vec<u32>,
+    children: Vec<Option<usize>>,
+}
+
+/// Search tree of the patterns of a training image for a template
+struct SearchTree {
+    nodes: Vec<TreeNode>,
+    n_categories: usize,
+}
+
+impl SearchTree {
+    /// Scan every node of a training image with a template
+    /// patterns are stored up to the first template node outside the training image
+    /// # Arguments
+    /// * `categories` - Category index of every training image node
+    /// * `n_categories` - Number of categories
+    /// * `template` - Template ordered nearest node first
+    fn new(categories: &Array3<usize>, n_categories: usize, template: &Template) -> Self {
+        let mut tree = SearchTree {
+            nodes: vec![TreeNode::new(n_categories)],
+            n_categories,
+        };
+
+        let shape = categories.shape();
+        let shape = [shape[0], shape[1], shape[2]];
+        for (ind, centre) in categories.indexed_iter() {
+            let ind = [ind.0, ind.1, ind.2];
+            let mut node = 0;
+            tree.nodes[node].counts[*centre] += 1;
+
+            for offset in template.offsets() {
+                let Some(neighbor_ind) = Template::get_ind(&ind, *offset, shape) else {
+                    break;
+                };
+                let category = categories[neighbor_ind];
+                node = match tree.nodes[node].children[category] {
+                    Some(child) => child,
+                    None => {
+                        tree.nodes.push(TreeNode::new(n_categories));
+                        let child = tree.nodes.len() - 1;
+                        tree.nodes[node].children[category] = Some(child);
+                        child
+                    }
+                };
+                tree.nodes[node].counts[*centre] += 1;
+            }
+        }
+
+        tree
+    }
+
+    /// Counts of the centre categories of the patterns matching a data event
+    /// # Arguments
+    /// * `data_event` - Category of each template node, None for uninformed nodes
+    fn counts(&self, data_event: &[Option<usize>]) -> Vec<u32> {
+        //uninformed nodes after the last informed node do not constrain the patterns
+        let depth = data_event
+            .iter()
+            .rposition(Option::is_some)
+            .map_or(0, |last| last + 1);
+
+        let mut counts = vec![0; self.n_categories];
+        self.accumulate(0, &data_event[..depth], &mut counts);
+        counts
+    }
+
+    /// Add the counts of the nodes below `node` matching a data event
+    fn accumulate(&self, node: usize, data_event: &[Option<usize>], counts: &mut [u32]) {
+        let Some((category, rest)) = data_event.split_first() else {
+            for (count, node_count) in counts.iter_mut().zip(self.nodes[node].counts.iter()) {
+                *count += node_count;
+            }
+            return;
+        };
+
+        match category {
+            Some(category) => {
+                if let Some(child) = self.nodes[node].children[*category] {
+                    self.accumulate(child, rest, counts);
+                }
+            }
+            //uninformed node, every pattern matches
+            None => {
+                for child in self.nodes[node].children.iter().flatten() {
+                    self.accumulate(*child, rest, counts);
+                }
+            }
+        }
+    }
+}
+
+impl TreeNode {
+    fn new(n_categories: usize) -> Self {
+        Self {
+            counts: vec![0; n_categories],
+            children: vec![None; n_categories],
+        }
+    }
+}
+
+/// Search trees and path of a simulation
+pub struct SNESIMPlan {
+    trees: Vec<SearchTree>,
+    sequential_data: Vec<SNESIMStep>,
+}
+
+/// Node and the template nodes informed when it is simulated (template position and grid index)
+type SNESIMStep = ([usize; 3], Vec<(usize, [usize; 3])>);
+
+/// Single normal equation simulation of categorical variables
+/// the training image is scanned once per multigrid level with the template expanded to the level,
+/// the pattern counts stored in a search tree give the conditional proportions of every node
+/// * values of the simulated grid are the category codes of the training image
+/// * informed nodes farthest from the node are dropped until enough replicates are found
+pub struct SNESIM {
+    codes: Vec<u8>,
+    categories: Array3<usize>,
+    template: Template,
+    target_proportions: Vec<f32>,
+    min_replicates: u32,
+    assigned_data: Option<Array3<Option<f32>>>,
+    multigrid_levels: usize,
+    servo_system: f32,
+}
+
+impl SNESIM {
+    /// Create a new SNESIM simulation
+    /// # Arguments
+    /// * `training_image` - Categorical training image, with the grid spacing of the simulated grid
+    /// * `geometry` - Search geometry defining the template
+    /// * `max_octant_size` - Maximum number of template nodes per octant
+    pub fn new<G>(
+        training_image: &CompleteGriddedDataBase<u8>,
+        geometry: G,
+        max_octant_size: usize,
+    ) -> Self
+    where
+        G: Geometry,
+    {
+        let mut codes = training_image
+            .raw_grid
+            .grid
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        codes.sort_unstable();
+        codes.dedup();
+
+        let categories = training_image.raw_grid.grid.mapv(|code| {
+            codes
+                .binary_search(&code)
+                .expect("code of the training image")
+        });
+
+        //proportions of the training image
+        let mut target_proportions = vec![0.0; codes.len()];
+        for category in categories.iter() {
+            target_proportions[*category] += 1.0;
+        }
+        let n_nodes = categories.len() as f32;
+        target_proportions.iter_mut().for_each(|p| *p /= n_nodes);
+
+        //template nodes of every octant, nearest first
+        let spacing = training_image.grid_spacing();
+        let query_engine =
+            GriddedDataBaseOctantQueryEngine::new(geometry, training_image, max_octant_size);
+        let mut offsets = query_engine
+            .octant_offsets
+            .iter()
+            .flat_map(|offsets| offsets.iter().take(max_octant_size).copied())
+            .filter(|offset| *offset != [0, 0, 0])
+            .collect::<Vec<_>>();
+        let distance = |offset: &[isize; 3]| {
+            (offset[0] as f32 * spacing.x).powi(2)
+                + (offset[1] as f32 * spacing.y).powi(2)
+                + (offset[2] as f32 * spacing.z).powi(2)
+        };
+        offsets.sort_by(|a, b| distance(a).total_cmp(&distance(b)));
+
+        Self {
+            codes,
+            categories,
+            template: Template::new(offsets),
+            target_proportions,
+            min_replicates: 1,
+            assigned_data: None,
+            multigrid_levels: 1,
+            servo_system: 0.0,
+        }
+    }
+
+    /// Target proportions of the categories, in ascending order of the codes (training image proportions by default)
+    pub fn with_target_proportions(mut self, target_proportions: Vec<f32>) -> Self {
+        assert_eq!(
+            target_proportions.len(),
+            self.codes.len(),
+            "each category requires a proportion"
+        );
+        assert!(
+            (target_proportions.iter().sum::<f32>() - 1.0).abs() < 1e-4,
+            "target proportions must sum to one"
+        );
+        self.target_proportions = target_proportions;
+        self
+    }
+
+    /// Minimum number of replicates of a data event
+    pub fn with_min_replicates(mut self, min_replicates: u32) -> Self {
+        self.min_replicates = min_replicates.max(1);
+        self
+    }
+
+    /// Freeze the nodes holding hard data (category codes)
+    /// frozen nodes are skipped on the path, keep their value and inform the data events
+    /// * every code must be a category of the training image
+    pub fn with_assigned_data(mut self, assigned_data: Array3<Option<f32>>) -> Self {
+        if let Some(code) = assigned_data
+            .iter()
+            .flatten()
+            .find(|value| !self.codes.iter().any(|code| *code as f32 == **value))
+        {
+            panic!("hard data code {} is absent from the training image", code);
+        }
+        self.assigned_data = Some(assigned_data);
+        self
+    }
+
+    /// Visit coarse sub-grids before the full grid (see `SGS::with_multigrid`)
+    /// the template of level `k` is expanded by 2^k
+    pub fn with_multigrid(mut self, levels: usize) -> Self {
+        assert!(levels > 0, "at least one multigrid level is required");
+        self.multigrid_levels = levels;
+        self
+    }
+
+    /// Pull the conditional proportions towards the target proportions (see `SIS::with_servo_system`)
+    /// # Arguments
+    /// * `strength` - Strength of the correction in [0, 1), 0 disables the servo system
+    pub fn with_servo_system(mut self, strength: f32) -> Self {
+        assert!(
+            (0.0..1.0).contains(&strength),
+            "servo system strength must be in [0, 1)"
+        );
+        self.servo_system = strength;
+        self
+    }
+
+    /// Category codes of the training image, in ascending order
+    pub fn codes(&self) -> &[u8] {
+        &self.codes
+    }
+
+    /// Template expanded to a multigrid level
+    fn level_template(&self, level: usize) -> Template {
+        Template::new(
+            self.template
+                .offsets()
+                .iter()
+                .map(|offset| offset.map(|v| v * (1 << level)))
+                .collect(),
+        )
+    }
+
+    /// Build the search tree of each level and find the informed template nodes of every node
+    fn build_plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> SNESIMPlan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        let assigned_data = self.assigned_data.as_ref();
+        let (path, simulation_order) = random_path(grid, assigned_data, self.multigrid_levels, rng);
+
+        let templates = (0..self.multigrid_levels)
+            .map(|level| self.level_template(level))
+            .collect::<Vec<_>>();
+        let trees = templates
+            .par_iter()
+            .map(|template| SearchTree::new(&self.categories, self.codes.len(), template))
+            .collect::<Vec<_>>();
+
+        let shape = grid.shape();
+        let sequential_data = path
+            .par_iter()
+            .map(|ind| {
+                let ind = [ind.0, ind.1, ind.2];
+                let template = &templates[node_level(ind, self.multigrid_levels)];
+
+                //frozen and previously simulated nodes
+                let informed = template
+                    .offsets()
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(position, offset)| {
+                        let neighbor_ind = Template::get_ind(&ind, *offset, shape)?;
+                        let frozen = assigned_data.is_some_and(|data| data[neighbor_ind].is_some());
+                        (frozen || simulation_order[neighbor_ind] < simulation_order[ind])
+                            .then_some((position, neighbor_ind))
+                    })
+                    .collect::<Vec<_>>();
+
+                (ind, informed)
+            })
+            .collect::<Vec<_>>();
+
+        SNESIMPlan {
+            trees,
+            sequential_data,
+        }
+    }
+
+    /// Simulate the nodes of a plan in path order
+    fn apply_plan<GDB>(&self, plan: &SNESIMPlan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        write_assigned_data(grid, self.assigned_data.as_ref());
+
+        let n_categories = self.codes.len();
+        let mut simulated_counts = vec![0.0; n_categories];
+
+        for (path_ind, (ind, informed)) in plan.sequential_data.iter().enumerate() {
+            let tree = &plan.trees[node_level(*ind, self.multigrid_levels)];
+
+            let mut data_event = vec![None; self.template.offsets().len()];
+            for (position, neighbor_ind) in informed {
+                let value = grid.data_at_ind(neighbor_ind).expect("No value at ind");
+                data_event[*position] = Some(self.category(value));
+            }
+
+            //drop the farthest informed nodes until enough replicates are found
+            let counts = loop {
+                let counts = tree.counts(&data_event);
+                let replicates = counts.iter().sum::<u32>();
+                if replicates >= self.min_replicates {
+                    break counts;
+                }
+                match data_event.iter().rposition(Option::is_some) {
+                    Some(last) => data_event[last] = None,
+                    None => break counts,
+                }
+            };
+
+            let replicates = counts.iter().sum::<u32>().max(1) as f32;
+            let mut proportions = counts
+                .iter()
+                .zip(self.target_proportions.iter())
+                .enumerate()
+                .map(|(category, (count, target))| {
+                    let mut p = *count as f32 / replicates;
+                    if self.servo_system > 0.0 && path_ind > 0 {
+                        let simulated = simulated_counts[category] / path_ind as f32;
+                        p += self.servo_system / (1.0 - self.servo_system) * (target - simulated);
+                    }
+                    p.max(0.0)
+                })
+                .collect::<Vec<_>>();
+            let sum = proportions.iter().sum::<f32>();
+            if sum > 0.0 {
+                proportions.iter_mut().for_each(|p| *p /= sum);
+            } else {
+                proportions.copy_from_slice(&self.target_proportions);
+            }
+
+            let u = seeds.uniform(path_ind as u64);
+            let mut cumulative = 0.0;
+            let category = proportions
+                .iter()
+                .position(|p| {
+                    cumulative += p;
+                    u < cumulative
+                })
+                //rounding of the cumulative proportions
+                .unwrap_or(n_categories - 1);
+
+            simulated_counts[category] += 1.0;
+            grid.set_data_at_ind(ind, self.codes[category] as f32);
+        }
+    }
+
+    /// Category index of a code
+    #[inline(always)]
+    fn category(&self, value: f32) -> usize {
+        self.codes
+            .iter()
+            .position(|code| *code as f32 == value)
+            .expect("category code absent from the training image")
+    }
+}
+
+impl Simulation for SNESIM {
+    type Plan = SNESIMPlan;
+
+    fn plan<GDB>(&self, grid: &GDB, rng: &mut StdRng) -> Self::Plan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.build_plan(grid, rng)
+    }
+
+    fn realize<GDB>(&self, plan: &Self::Plan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        self.apply_plan(plan, grid, seeds);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion};
+
+    use crate::{
+        geometry::ellipsoid::Ellipsoid,
+        simulation::realizations::simulate_on_threads,
+        spatial_database::{
+            coordinate_system::{CoordinateSystem, GridSpacing},
+            gridded_databases::incomplete_grid::InCompleteGriddedDataBase,
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn search_tree_counts() {
+        let categories = Array3::from_shape_vec((4, 1, 1), vec![0, 1, 0, 1]).unwrap();
+        let tree = SearchTree::new(&categories, 2, &Template::new(vec![[1, 0, 0]]));
+
+        assert_eq!(tree.counts(&[None]), vec![2, 2]);
+        assert_eq!(tree.counts(&[Some(1)]), vec![2, 0]);
+        assert_eq!(tree.counts(&[Some(0)]), vec![0, 1]);
+    }
+
+    #[test]
+    fn snesim_reproduces_stripes() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing::new(1.0, 1.0, 1.0);
+
+        //stripes of width 3 along the first axis, continuous along the second
+        let ti = CompleteGriddedDataBase::new(
+            Array3::from_shape_fn((40, 40, 1), |(i, _, _)| (i / 3 % 2) as u8 * 5),
+            spacing,
+            cs,
+        );
+
+        let mut assigned = Array3::<Option<f32>>::from_elem((20, 20, 1), None);
+        assigned[[4, 7, 0]] = Some(5.0);
+        assigned[[15, 2, 0]] = Some(0.0);
+
+        let snesim = SNESIM::new(&ti, Ellipsoid::new(3.0, 3.0, 1.0, cs), 3)
+            .with_assigned_data(assigned)
+            .with_servo_system(0.3)
+            .with_multigrid(2);
+        assert_eq!(snesim.codes(), &[0, 5]);
+
+        let realization = |threads: usize, seed: u64| {
+            let mut sim_db =
+                InCompleteGriddedDataBase::new(Array3::from_elem((20, 20, 1), None), spacing, cs);
+            simulate_on_threads(&snesim, &mut sim_db, threads, seed);
+            sim_db.raw_grid.grid.map(|value| value.unwrap())
+        };
+
+        let values = realization(1, 8);
+        assert_eq!(values, realization(4, 8));
+        assert_eq!(values[[4, 7, 0]], 5.0);
+        assert_eq!(values[[15, 2, 0]], 0.0);
+        assert!(values.iter().all(|value| *value == 0.0 || *value == 5.0));
+
+        //stripes are continuous along the second axis
+        let pairs = (0..20)
+            .flat_map(|i| (1..20).map(move |j| (i, j)))
+            .collect::<Vec<_>>();
+        let continuous = pairs
+            .iter()
+            .filter(|(i, j)| values[[*i, *j, 0]] == values[[*i, j - 1, 0]])
+            .count();
+        assert!(continuous as f32 / pairs.len() as f32 > 0.7);
+    }
+
+    #[test]
+    #[should_panic(expected = "absent from the training image")]
+    fn snesim_rejects_unknown_hard_data() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let ti = CompleteGriddedDataBase::new(
+            Array3::from_shape_fn((10, 10, 1), |(i, _, _)| (i % 2) as u8),
+            GridSpacing::new(1.0, 1.0, 1.0),
+            cs,
+        );
+
+        let mut assigned = Array3::<Option<f32>>::from_elem((5, 5, 1), None);
+        assigned[[2, 2, 0]] = Some(3.0);
+        let _ = SNESIM::new(&ti, Ellipsoid::new(2.0, 2.0, 1.0, cs), 2).with_assigned_data(assigned);
+    }
+}
@@ -0,0 +1,578 @@
+use ndarray::Array3;
+use rand::rngs::StdRng;
+
+use crate::{
+    geometry::Geometry,
+    spatial_database::gridded_databases::{
+        gridded_data_base_query_engine::GriddedDataBaseOctantQueryEngine,
+        incomplete_grid::InCompleteGriddedDataBase, GriddedDataBaseInterface,
+    },
+    variography::model_variograms::VariogramModel,
+};
+
+use super::{
+    gaussian::normal_quantile,
+    gibbs::GibbsSampler,
+    sgs::{SGSParameters, SGS},
+};
+
+/// Assignment of facies to regions of the latent gaussian fields
+/// facies are gathered in groups ordered along the first field, the facies of a group are ordered
+/// along the second field, a rule with a single facies per group only uses the first field
+pub struct TruncationRule {
+    groups: Vec<Vec<f32>>,
+}
+
+impl TruncationRule {
+    /// Ordered facies sequence truncating a single field (truncated gaussian simulation)
+    /// # Arguments
+    /// * `codes` - Facies codes in the order of the sequence
+    pub fn ordered(codes: &[f32]) -> Self {
+        Self::plurigaussian(codes.iter().map(|code| vec![*code]).collect())
+    }
+
+    /// Facies groups truncating two fields (plurigaussian simulation)
+    /// # Arguments
+    /// * `groups` - Facies codes of each group, groups are contacts along the first field and facies
+    ///   within a group are contacts along the second field
+    pub fn plurigaussian(groups: Vec<Vec<f32>>) -> Self {
+        assert!(
+            !groups.is_empty() && groups.iter().all(|group| !group.is_empty()),
+            "a truncation rule requires non empty facies groups"
+        );
+        Self { groups }
+    }
+
+    /// Number of latent fields truncated by the rule
+    pub fn n_fields(&self) -> usize {
+        if self.groups.iter().any(|group| group.len() > 1) {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Facies codes in the order of the proportions
+    pub fn codes(&self) -> Vec<f32> {
+        self.groups.iter().flatten().copied().collect()
+    }
+
+    /// Group and position within the group of a facies
+    fn locate(&self, code: f32) -> Option<(usize, usize)> {
+        self.groups
+            .iter()
+            .enumerate()
+            .find_map(|(group_ind, group)| {
+                group
+                    .iter()
+                    .position(|c| *c == code)
+                    .map(|facies_ind| (group_ind, facies_ind))
+            })
+    }
+}
+
+/// Thresholds of the latent fields for one set of facies proportions
+/// bounds include the infinite ends, a facies with a zero proportion has equal bounds
+pub struct Thresholds {
+    /// Bounds of the groups along the first field
+    pub first: Vec<f32>,
+    /// Bounds of the facies of each group along the second field
+    pub second: Vec<Vec<f32>>,
+    /// Proportion of each facies of each group
+    proportions: Vec<Vec<f32>>,
+}
+
+impl Thresholds {
+    /// Fit thresholds to proportions of standard normal fields
+    fn new(rule: &TruncationRule, proportions: &[f32]) -> Self {
+        let total = proportions.iter().sum::<f32>();
+        assert!(total > 0.0, "facies proportions must not all be zero");
+
+        let mut proportions = proportions.iter().map(|p| p / total);
+        let group_proportions = rule
+            .groups
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .map(|_| proportions.next().unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let first = cumulative_quantiles(
+            &group_proportions
+                .iter()
+                .map(|group| group.iter().sum::<f32>())
+                .collect::<Vec<_>>(),
+        );
+        let second = group_proportions
+            .iter()
+            .map(|group| cumulative_quantiles(group))
+            .collect();
+
+        Self {
+            first,
+            second,
+            proportions: group_proportions,
+        }
+    }
+
+    /// Interval of each field for a facies
+    fn intervals(&self, group_ind: usize, facies_ind: usize) -> [[f32; 2]; 2] {
+        [
+            [self.first[group_ind], self.first[group_ind + 1]],
+            [
+                self.second[group_ind][facies_ind],
+                self.second[group_ind][facies_ind + 1],
+            ],
+        ]
+    }
+
+    /// Group and position within the group of the facies holding latent values
+    fn facies(&self, values: &[f32]) -> (usize, usize) {
+        let region = |bounds: &[f32], value: f32| {
+            bounds[1..bounds.len() - 1]
+                .iter()
+                .filter(|bound| value >= **bound)
+                .count()
+        };
+
+        let group_ind = region(&self.first, values[0]);
+        //single facies groups do not read the second field
+        let facies_ind = match values.get(1) {
+            Some(value) => region(&self.second[group_ind], *value),
+            None => 0,
+        };
+        (group_ind, facies_ind)
+    }
+}
+
+/// Normal scores bounding classes of given proportions
+/// proportions are rescaled to sum to one, classes of an empty set all sit at the upper end
+fn cumulative_quantiles(proportions: &[f32]) -> Vec<f32> {
+    let total = proportions.iter().sum::<f32>();
+    let mut cumulative = 0.0;
+    let mut bounds = vec![f32::NEG_INFINITY];
+    for proportion in &proportions[..proportions.len() - 1] {
+        cumulative += if total > 0.0 { proportion / total } else { 0.0 };
+        bounds.push(normal_quantile(cumulative as f64) as f32);
+    }
+    bounds.push(f32::INFINITY);
+    bounds
+}
+
+/// Truncated gaussian and plurigaussian facies simulation
+/// facies are obtained by truncating standard normal latent fields with thresholds fitted to the
+/// facies proportions, facies data are converted to latent conditioning values by Gibbs sampling
+/// and the latent fields may be simulated with any gaussian simulation before being truncated
+pub struct TruncatedGaussian<V> {
+    rule: TruncationRule,
+    thresholds: Vec<Thresholds>,
+    variogram_models: Vec<V>,
+    gibbs_sweeps: usize,
+}
+
+impl<V> TruncatedGaussian<V>
+where
+    V: VariogramModel + Sync,
+{
+    /// Create a new truncated gaussian simulation
+    /// # Arguments
+    /// * `rule` - The truncation rule
+    /// * `proportions` - Proportion of each facies in the order of `TruncationRule::codes`, a single set
+    ///   for the whole grid or one set per layer of the grid (vertical proportion curve)
+    /// * `variogram_models` - Variogram of each latent field (unit sill)
+    pub fn new(rule: TruncationRule, proportions: &[Vec<f32>], variogram_models: Vec<V>) -> Self {
+        let n_facies = rule.codes().len();
+        assert!(!proportions.is_empty(), "facies proportions are required");
+        assert!(
+            proportions.iter().all(|p| p.len() == n_facies),
+            "a proportion is required for each facies of the truncation rule"
+        );
+        assert_eq!(
+            variogram_models.len(),
+            rule.n_fields(),
+            "a variogram is required for each latent field"
+        );
+
+        let thresholds = proportions
+            .iter()
+            .map(|p| Thresholds::new(&rule, p))
+            .collect();
+
+        Self {
+            rule,
+            thresholds,
+            variogram_models,
+            gibbs_sweeps: 100,
+        }
+    }
+
+    /// Number of Gibbs sweeps used to draw the latent values of the facies data (100 by default)
+    pub fn with_gibbs_sweeps(mut self, sweeps: usize) -> Self {
+        self.gibbs_sweeps = sweeps;
+        self
+    }
+
+    /// Thresholds of a layer of the grid
+    pub fn thresholds(&self, layer: usize) -> &Thresholds {
+        if self.thresholds.len() == 1 {
+            &self.thresholds[0]
+        } else {
+            &self.thresholds[layer]
+        }
+    }
+
+    fn check_layers(&self, shape: [usize; 3]) {
+        assert!(
+            self.thresholds.len() == 1 || self.thresholds.len() == shape[2],
+            "proportions must be given for the whole grid or for each layer"
+        );
+    }
+
+    /// Draw latent conditioning values consistent with the facies data
+    /// every facies datum must have a positive proportion in its layer, the Gibbs sampler inverts the
+    /// dense covariance of all data (see `GibbsSampler::new`)
+    /// # Arguments
+    /// * `grid` - The simulation grid
+    /// * `facies` - Facies code of the nodes holding data (see `DataAssignment`)
+    /// * `rng` - The random number generator
+    /// # Returns
+    /// Latent value of the data nodes for each field, to freeze when simulating the fields
+    pub fn latent_data<GDB>(
+        &self,
+        grid: &GDB,
+        facies: &Array3<Option<f32>>,
+        rng: &mut StdRng,
+    ) -> Vec<Array3<Option<f32>>>
+    where
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        let shape = grid.shape();
+        assert_eq!(
+            facies.shape(),
+            shape.as_slice(),
+            "facies data must match the shape of the grid"
+        );
+        self.check_layers(shape);
+
+        let mut inds = Vec::new();
+        let mut intervals = Vec::new();
+        for ((i, j, k), code) in facies.indexed_iter() {
+            let Some(code) = code else {
+                continue;
+            };
+            let (group_ind, facies_ind) = self
+                .rule
+                .locate(*code)
+                .unwrap_or_else(|| panic!("facies {} is not in the truncation rule", code));
+            let thresholds = self.thresholds(k);
+            //a facies without proportion has an empty interval, no latent value can honour it
+            assert!(
+                thresholds.proportions[group_ind][facies_ind] > 0.0,
+                "facies {} has a zero proportion in layer {} but is present in the data",
+                code,
+                k
+            );
+            inds.push([i, j, k]);
+            intervals.push(thresholds.intervals(group_ind, facies_ind));
+        }
+
+        let mut latent = vec![Array3::from_elem(shape, None); self.rule.n_fields()];
+        if inds.is_empty() {
+            return latent;
+        }
+
+        let points = inds
+            .iter()
+            .map(|ind| grid.ind_to_point(&ind.map(|i| i as isize)))
+            .collect::<Vec<_>>();
+
+        //fields are independent, each is sampled on its own
+        for (field, field_latent) in latent.iter_mut().enumerate() {
+            let field_intervals = intervals
+                .iter()
+                .map(|interval| interval[field])
+                .collect::<Vec<_>>();
+            let values = GibbsSampler::new(&points, &self.variogram_models[field])
+                .with_sweeps(self.gibbs_sweeps)
+                .sample(&field_intervals, rng);
+
+            for (ind, value) in inds.iter().zip(values) {
+                field_latent[*ind] = Some(value);
+            }
+        }
+
+        latent
+    }
+
+    /// Write the facies of simulated latent fields to a grid
+    /// # Arguments
+    /// * `latent_grids` - Simulated value of each latent field at every node
+    /// * `grid` - The grid receiving the facies codes
+    pub fn truncate<LDB, GDB>(&self, latent_grids: &[LDB], grid: &mut GDB)
+    where
+        LDB: GriddedDataBaseInterface<f32>,
+        GDB: GriddedDataBaseInterface<f32>,
+    {
+        assert_eq!(
+            latent_grids.len(),
+            self.rule.n_fields(),
+            "a latent grid is required for each field"
+        );
+        let shape = grid.shape();
+        self.check_layers(shape);
+
+        let codes = Array3::from_shape_fn(shape, |(i, j, k)| {
+            let values = latent_grids
+                .iter()
+                .map(|latent| {
+                    latent
+                        .data_at_ind(&[i, j, k])
+                        .expect("latent fields must be simulated at every node")
+                })
+                .collect::<Vec<_>>();
+            let (group_ind, facies_ind) = self.thresholds(k).facies(&values);
+            self.rule.groups[group_ind][facies_ind]
+        });
+
+        for ((i, j, k), code) in codes.indexed_iter() {
+            grid.set_data_at_ind(&[i, j, k], *code);
+        }
+    }
+
+    /// Simulate the latent fields with SGS conditioned to the facies data and truncate them
+    /// # Arguments
+    /// * `grid` - The grid receiving the facies codes
+    /// * `facies` - Facies code of the nodes holding data (see `DataAssignment`)
+    /// * `geometry` - Search neighbourhood of the latent simulations
+    /// * `sgs_parameters` - The SGS parameters of the latent simulations
+    /// * `rng` - The random number generator
+    pub fn simulate_grid<GDB, G>(
+        &self,
+        grid: &mut GDB,
+        facies: &Array3<Option<f32>>,
+        geometry: G,
+        sgs_parameters: &SGSParameters,
+        rng: &mut StdRng,
+    ) where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+        G: Geometry + Sync + Clone,
+    {
+        let shape = grid.shape();
+        let spacing = grid.grid_spacing();
+        let cs = grid.coordinate_system();
+
+        let latent_grids = self
+            .latent_data(&*grid, facies, rng)
+            .into_iter()
+            .zip(self.variogram_models.iter())
+            .map(|(latent, variogram_model)| {
+                let cond_db = InCompleteGriddedDataBase::new(latent.clone(), spacing, cs);
+                let simulation = SGS::new(
+                    GriddedDataBaseOctantQueryEngine::new(
+                        geometry.clone(),
+                        &cond_db,
+                        sgs_parameters.max_octant_cond_data,
+                    ),
+                    variogram_model,
+                    SGSParameters {
+                        max_octant_cond_data: sgs_parameters.max_octant_cond_data,
+                        max_octant_sim_data: sgs_parameters.max_octant_sim_data,
+                    },
+                )
+                .with_assigned_data(latent);
+
+                let mut latent_grid =
+                    InCompleteGriddedDataBase::new(Array3::from_elem(shape, None), spacing, cs);
+                simulation.simulate_grid(&mut latent_grid, rng);
+                latent_grid
+            })
+            .collect::<Vec<_>>();
+
+        self.truncate(&latent_grids, grid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion, Vector3};
+    use rand::SeedableRng;
+
+    use crate::{
+        geometry::ellipsoid::Ellipsoid,
+        spatial_database::coordinate_system::{CoordinateSystem, GridSpacing},
+        variography::model_variograms::spherical::SphericalVariogram,
+    };
+
+    use super::*;
+
+    #[test]
+    fn thresholds_from_proportions() {
+        let rule = TruncationRule::ordered(&[1.0, 2.0, 3.0]);
+        let thresholds = Thresholds::new(&rule, &[0.2, 0.5, 0.3]);
+        assert_eq!(thresholds.first.len(), 4);
+        assert_eq!(thresholds.first[0], f32::NEG_INFINITY);
+        assert!((thresholds.first[1] + 0.841621).abs() < 1e-4);
+        assert!((thresholds.first[2] - 0.524401).abs() < 1e-4);
+        assert_eq!(thresholds.first[3], f32::INFINITY);
+        assert_eq!(thresholds.facies(&[0.0]), (1, 0));
+
+        //two groups split the first field, the second group is split in halves along the second field
+        let rule = TruncationRule::plurigaussian(vec![vec![1.0], vec![2.0, 3.0]]);
+        assert_eq!(rule.n_fields(), 2);
+        let thresholds = Thresholds::new(&rule, &[4.0, 3.0, 3.0]);
+        assert!((thresholds.first[1] + 0.253347).abs() < 1e-4);
+        assert!(thresholds.second[1][1].abs() < 1e-6);
+        assert_eq!(thresholds.facies(&[-1.0, 1.0]), (0, 0));
+        assert_eq!(thresholds.facies(&[1.0, -1.0]), (1, 0));
+        assert_eq!(thresholds.facies(&[1.0, 1.0]), (1, 1));
+        assert_eq!(
+            thresholds.intervals(1, 1),
+            [[thresholds.first[1], f32::INFINITY], [0.0, f32::INFINITY]]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "zero proportion")]
+    fn latent_data_rejects_absent_facies() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        let simulation = TruncatedGaussian::new(
+            TruncationRule::ordered(&[0.0, 1.0, 2.0]),
+            &[vec![0.5, 0.5, 0.0]],
+            vec![SphericalVariogram::new(
+                Vector3::new(6.0, 6.0, 3.0),
+                1.0,
+                0.0,
+                cs,
+            )],
+        );
+
+        let mut facies = Array3::from_elem([5, 5, 1], None);
+        facies[[2, 3, 0]] = Some(2.0);
+        let grid = InCompleteGriddedDataBase::new(Array3::from_elem([5, 5, 1], None), spacing, cs);
+        simulation.latent_data(&grid, &facies, &mut StdRng::seed_from_u64(0));
+    }
+
+    #[test]
+    fn truncated_gaussian_vertical_proportions() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        let shape = [20, 20, 2];
+
+        //the top layer is dominated by the last facies of the sequence
+        let proportions = vec![vec![0.6, 0.3, 0.1], vec![0.1, 0.3, 0.6]];
+        let simulation = TruncatedGaussian::new(
+            TruncationRule::ordered(&[0.0, 1.0, 2.0]),
+            &proportions,
+            vec![SphericalVariogram::new(
+                Vector3::new(6.0, 6.0, 3.0),
+                1.0,
+                0.0,
+                cs,
+            )],
+        )
+        .with_gibbs_sweeps(20);
+
+        let mut facies = Array3::from_elem(shape, None);
+        facies[[2, 3, 0]] = Some(2.0);
+        facies[[3, 3, 0]] = Some(0.0);
+        facies[[15, 12, 1]] = Some(1.0);
+
+        let mut grid = InCompleteGriddedDataBase::new(Array3::from_elem(shape, None), spacing, cs);
+        simulation.simulate_grid(
+            &mut grid,
+            &facies,
+            Ellipsoid::new(6.0, 6.0, 3.0, cs),
+            &SGSParameters {
+                max_octant_cond_data: 4,
+                max_octant_sim_data: 4,
+            },
+            &mut StdRng::seed_from_u64(2),
+        );
+
+        assert_eq!(grid.data_at_ind(&[2, 3, 0]), Some(2.0));
+        assert_eq!(grid.data_at_ind(&[3, 3, 0]), Some(0.0));
+        assert_eq!(grid.data_at_ind(&[15, 12, 1]), Some(1.0));
+
+        let layer_count = |k: usize, code: f32| {
+            (0..shape[0])
+                .flat_map(|i| (0..shape[1]).map(move |j| [i, j, k]))
+                .filter(|ind| grid.data_at_ind(ind) == Some(code))
+                .count()
+        };
+        assert!(layer_count(0, 0.0) > layer_count(0, 2.0));
+        assert!(layer_count(1, 2.0) > layer_count(1, 0.0));
+    }
+
+    #[test]
+    fn plurigaussian_honours_facies_data() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let spacing = GridSpacing {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        let shape = [16, 16, 1];
+
+        let vgram =
+            |range: f32| SphericalVariogram::new(Vector3::new(range, range, 1.0), 1.0, 0.0, cs);
+        let simulation = TruncatedGaussian::new(
+            TruncationRule::plurigaussian(vec![vec![0.0], vec![1.0, 2.0]]),
+            &[vec![0.4, 0.3, 0.3]],
+            vec![vgram(8.0), vgram(4.0)],
+        )
+        .with_gibbs_sweeps(20);
+
+        let mut facies = Array3::from_elem(shape, None);
+        let data = [
+            ([1, 1, 0], 0.0),
+            ([8, 8, 0], 1.0),
+            ([9, 8, 0], 2.0),
+            ([14, 3, 0], 2.0),
+        ];
+        for (ind, code) in data {
+            facies[ind] = Some(code);
+        }
+
+        let mut grid = InCompleteGriddedDataBase::new(Array3::from_elem(shape, None), spacing, cs);
+        let mut rng = StdRng::seed_from_u64(5);
+
+        //latent data fall within the intervals of their facies
+        let latent = simulation.latent_data(&grid, &facies, &mut rng);
+        for (ind, code) in data {
+            let values = [latent[0][ind].unwrap(), latent[1][ind].unwrap()];
+            let (group_ind, facies_ind) = simulation.thresholds(0).facies(&values);
+            assert_eq!(simulation.rule.groups[group_ind][facies_ind], code);
+        }
+
+        simulation.simulate_grid(
+            &mut grid,
+            &facies,
+            Ellipsoid::new(8.0, 8.0, 1.0, cs),
+            &SGSParameters {
+                max_octant_cond_data: 4,
+                max_octant_sim_data: 4,
+            },
+            &mut rng,
+        );
+        for (ind, code) in data {
+            assert_eq!(grid.data_at_ind(&ind), Some(code));
+        }
+        assert!(grid
+            .raw_grid
+            .grid
+            .iter()
+            .all(|code| matches!(code, Some(c) if [0.0, 1.0, 2.0].contains(c))));
+    }
+}
@@ -0,0 +1,439 @@
+use std::f64::consts::{PI, SQRT_2};
+
+use nalgebra::{Point3, Vector3};
+use rand::{rngs::StdRng, Rng};
+use rand_distr::StandardNormal;
+use rayon::prelude::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    geometry::ellipsoid::Ellipsoid,
+    kriging::simple_kriging::SimpleKriging,
+    spatial_database::{
+        gridded_databases::GriddedDataBaseInterface,
+        qbvh::point_set::{ConditioningParams, PointSet},
+    },
+    variography::model_variograms::{
+        NestedStructures, StructureShape, VariogramModel, VariogramStructure,
+    },
+};
+
+use super::{realizations::Simulation, seeding::SeedSequence};
+
+/// Spacing of the dilution germs along a line (in ranges)
+const GERM_SPACING: f64 = 0.25;
+
+/// Process simulated along a line, with unit variance
+enum LineProcess {
+    /// Cosine wave with a frequency drawn from the spectral measure of the structure
+    Spectral { frequency: f64, phase: f64 },
+    /// Dilution of f(u) = u on [-1/2, 1/2] by regularly spaced germs with random weights
+    Dilution { offset: f64, weights: SeedSequence },
+}
+
+impl LineProcess {
+    /// Draw the process of a line for a structure shape
+    fn new(shape: StructureShape, rng: &mut StdRng) -> Self {
+        let phase = rng.gen_range(0.0..2.0 * PI);
+
+        match shape {
+            //sphere indicator convolution, line covariance 1 - 3r + 2r^3
+            StructureShape::Spherical => LineProcess::Dilution {
+                offset: rng.gen(),
+                weights: SeedSequence::from_rng(rng),
+            },
+            //spectral measure is a 3D gaussian with variance 6
+            StructureShape::Gaussian => {
+                let frequency = 6f64.sqrt() * gaussian_norm(rng);
+                LineProcess::Spectral { frequency, phase }
+            }
+            //spectral measure is a 3D student distribution with one degree of freedom
+            StructureShape::Exponential => {
+                let frequency = 3.0 * gaussian_norm(rng);
+                let scale = rng.sample::<f64, _>(StandardNormal).abs();
+                LineProcess::Spectral {
+                    frequency: frequency / scale,
+                    phase,
+                }
+            }
+        }
+    }
+
+    /// Value of the process at a coordinate along the line
+    #[inline(always)]
+    fn value(&self, t: f64) -> f64 {
+        match self {
+            LineProcess::Spectral { frequency, phase } => SQRT_2 * (frequency * t + phase).cos(),
+            LineProcess::Dilution { offset, weights } => {
+                //germs within half a range of t
+                let first = ((t - 0.5) / GERM_SPACING - offset).ceil() as i64;
+                let last = ((t + 0.5) / GERM_SPACING - offset).floor() as i64;
+
+                let sum = (first..=last)
+                    .map(|germ| {
+                        let u = t - (germ as f64 + offset) * GERM_SPACING;
+                        //uniform weights with unit variance
+                        let weight =
+                            3f64.sqrt() * (2.0 * weights.uniform(germ as u64) as f64 - 1.0);
+                        weight * u
+                    })
+                    .sum::<f64>();
+
+                sum * (12.0 * GERM_SPACING).sqrt()
+            }
+        }
+    }
+}
+
+/// Norm of a standard gaussian vector in 3D
+fn gaussian_norm(rng: &mut StdRng) -> f64 {
+    (0..3)
+        .map(|_| rng.sample::<f64, _>(StandardNormal).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Line of a structure
+struct Band {
+    direction: Vector3<f64>,
+    process: LineProcess,
+}
+
+/// Conditioning data and neighbourhood of the kriging of residuals
+struct Conditioning {
+    data: PointSet<f32>,
+    search_ellipsoid: Ellipsoid,
+    query_params: ConditioningParams,
+}
+
+/// Turning bands simulation of gaussian fields
+/// every structure of the variogram is simulated as the sum of one dimensional processes along
+/// lines with random directions, points are simulated independently and in parallel
+/// conditioning adds the simple kriging of the residuals between data and unconditional values
+pub struct TurningBands<V> {
+    variogram_model: V,
+    n_lines: usize,
+    conditioning: Option<Conditioning>,
+}
+
+impl<V> TurningBands<V>
+where
+    V: VariogramModel + NestedStructures + Sync + Send,
+{
+    /// Create an unconditional turning bands simulation
+    /// # Arguments
+    /// * `variogram_model` - The variogram model (nugget and spherical, exponential or gaussian structures)
+    /// * `n_lines` - Number of lines per structure, a few hundred lines avoid visible banding
+    pub fn new(variogram_model: V, n_lines: usize) -> Self {
+        assert!(n_lines > 0, "at least one line is required");
+        Self {
+            variogram_model,
+            n_lines,
+            conditioning: None,
+        }
+    }
+
+    /// Condition the realizations to data
+    /// # Arguments
+    /// * `data` - Conditioning data (must be normalized)
+    /// * `search_ellipsoid` - Search ellipsoid of the kriging of residuals
+    /// * `query_params` - Conditioning parameters of the kriging of residuals
+    pub fn with_conditioning(
+        mut self,
+        data: PointSet<f32>,
+        search_ellipsoid: Ellipsoid,
+        query_params: ConditioningParams,
+    ) -> Self {
+        self.conditioning = Some(Conditioning {
+            data,
+            search_ellipsoid,
+            query_params,
+        });
+        self
+    }
+
+    /// Simulate values at points
+    /// all random draws are derived from `rng`, a seeded rng gives the same realization
+    /// # Arguments
+    /// * `points` - Points to simulate
+    /// * `rng` - The random number generator
+    pub fn simulate_points(&self, points: &[Point3<f32>], rng: &mut StdRng) -> Vec<f32> {
+        self.points_from_seeds(points, SeedSequence::from_rng(rng))
+    }
+
+    /// Simulate values at points from a seed sequence
+    /// lines and the nugget effect are seeded from stream 0 of the sequence
+    fn points_from_seeds(&self, points: &[Point3<f32>], seeds: SeedSequence) -> Vec<f32> {
+        let mut rng = seeds.stream(0);
+        let seeds = SeedSequence::from_rng(&mut rng);
+        let nugget_seeds = SeedSequence::from_rng(&mut rng);
+
+        let Some(conditioning) = self.conditioning.as_ref() else {
+            return self.unconditional(points, seeds, nugget_seeds);
+        };
+
+        //data and target points share the same unconditional realization
+        let all_points = points
+            .iter()
+            .chain(conditioning.data.points.iter())
+            .copied()
+            .collect::<Vec<_>>();
+        let mut values = self.unconditional(&all_points, seeds, nugget_seeds);
+        let data_values = values.split_off(points.len());
+
+        let residuals = conditioning
+            .data
+            .data
+            .iter()
+            .zip(data_values.iter())
+            .map(|(data, simulated)| data - simulated)
+            .collect::<Vec<_>>();
+
+        let kriging = SimpleKriging::new(
+            PointSet::new(conditioning.data.points.clone(), residuals),
+            &self.variogram_model,
+            conditioning.search_ellipsoid.clone(),
+            conditioning.query_params.clone(),
+        );
+
+        //points without conditioning data keep their unconditional value
+        values
+            .iter()
+            .zip(kriging.krig(points))
+            .map(|(value, residual)| {
+                if residual.is_nan() {
+                    *value
+                } else {
+                    value + residual
+                }
+            })
+            .collect()
+    }
+
+    /// Unconditional values at points
+    /// # Arguments
+    /// * `points` - Points to simulate
+    /// * `seeds` - Seeds of the lines
+    /// * `nugget_seeds` - Seeds of the nugget effect of each point
+    fn unconditional(
+        &self,
+        points: &[Point3<f32>],
+        seeds: SeedSequence,
+        nugget_seeds: SeedSequence,
+    ) -> Vec<f32> {
+        let structures = self.variogram_model.structures();
+        let bands = self.bands(&structures, seeds);
+        let nugget = self.variogram_model.nugget().sqrt() as f64;
+
+        points
+            .par_iter()
+            .enumerate()
+            .map(|(point_ind, point)| {
+                let mut value = structures
+                    .iter()
+                    .zip(bands.iter())
+                    .map(|(structure, bands)| {
+                        //lines live in the isotropic space of the structure
+                        let iso_point = structure.isotropic_lag(&point.coords).cast::<f64>();
+                        let sum = bands
+                            .iter()
+                            .map(|band| band.process.value(iso_point.dot(&band.direction)))
+                            .sum::<f64>();
+                        sum * (structure.contribution as f64 / self.n_lines as f64).sqrt()
+                    })
+                    .sum::<f64>();
+
+                if nugget > 0.0 {
+                    let noise: f64 = nugget_seeds.stream(point_ind as u64).sample(StandardNormal);
+                    value += nugget * noise;
+                }
+
+                value as f32
+            })
+            .collect()
+    }
+
+    /// Lines of each structure, line `l` of structure `s` is drawn from stream `s * n_lines + l`
+    fn bands(&self, structures: &[VariogramStructure], seeds: SeedSequence) -> Vec<Vec<Band>> {
+        structures
+            .iter()
+            .enumerate()
+            .map(|(structure_ind, structure)| {
+                (0..self.n_lines)
+                    .map(|line| {
+                        let mut rng = seeds.stream((structure_ind * self.n_lines + line) as u64);
+
+                        //uniform direction on the sphere
+                        let direction =
+                            Vector3::<f64>::from_fn(|_, _| rng.sample(StandardNormal)).normalize();
+
+                        Band {
+                            direction,
+                            process: LineProcess::new(structure.shape, &mut rng),
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Nodes of a grid and their locations
+pub struct TurningBandsPlan {
+    inds: Vec<[usize; 3]>,
+    points: Vec<Point3<f32>>,
+}
+
+impl<V> Simulation for TurningBands<V>
+where
+    V: VariogramModel + NestedStructures + Sync + Send,
+{
+    type Plan = TurningBandsPlan;
+
+    fn plan<GDB>(&self, grid: &GDB, _rng: &mut StdRng) -> Self::Plan
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        let shape = grid.shape();
+        let inds = (0..shape[0])
+            .flat_map(|i| (0..shape[1]).flat_map(move |j| (0..shape[2]).map(move |k| [i, j, k])))
+            .collect::<Vec<_>>();
+        let points = inds
+            .iter()
+            .map(|ind| grid.ind_to_point(&ind.map(|i| i as isize)))
+            .collect::<Vec<_>>();
+
+        TurningBandsPlan { inds, points }
+    }
+
+    fn realize<GDB>(&self, plan: &Self::Plan, grid: &mut GDB, seeds: SeedSequence)
+    where
+        GDB: GriddedDataBaseInterface<f32> + std::marker::Sync,
+    {
+        let values = self.points_from_seeds(&plan.points, seeds);
+        for (ind, value) in plan.inds.iter().zip(values) {
+            grid.set_data_at_ind(ind, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion};
+    use rand::SeedableRng;
+
+    use crate::{
+        spatial_database::coordinate_system::CoordinateSystem,
+        variography::model_variograms::{basic::BasicVariogram, spherical::SphericalVariogram},
+    };
+
+    use super::*;
+
+    /// Variance and correlation at a lag of pairs of distant points over several realizations
+    fn pair_statistics<V>(simulation: &TurningBands<V>, lag: Vector3<f32>) -> (f32, f32)
+    where
+        V: VariogramModel + NestedStructures + Sync + Send,
+    {
+        //pairs are 20 ranges apart
+        let points = (0..300)
+            .flat_map(|i| {
+                let base = Point3::new(
+                    (i % 10) as f32 * 200.0,
+                    (i / 10 % 10) as f32 * 200.0,
+                    (i / 100) as f32 * 200.0,
+                );
+                [base, base + lag]
+            })
+            .collect::<Vec<_>>();
+
+        let mut rng = StdRng::seed_from_u64(17);
+        let values = (0..10)
+            .flat_map(|_| simulation.simulate_points(&points, &mut rng))
+            .collect::<Vec<_>>();
+
+        let n = values.len() as f32;
+        let mean = values.iter().sum::<f32>() / n;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n;
+        let covariance = values
+            .chunks(2)
+            .map(|pair| (pair[0] - mean) * (pair[1] - mean))
+            .sum::<f32>()
+            / (n / 2.0);
+
+        (variance, covariance / variance)
+    }
+
+    #[test]
+    fn turning_bands_reproduce_covariance() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let range = Vector3::new(10.0, 10.0, 10.0);
+        let lag = Vector3::new(2.0, 1.0, 1.0);
+
+        let spherical = SphericalVariogram::new(range, 1.0, 0.2, cs);
+        let expected = spherical.covariogram(lag);
+        let (variance, correlation) = pair_statistics(&TurningBands::new(spherical, 500), lag);
+        assert!((variance - 1.0).abs() < 0.1);
+        assert!((correlation - expected).abs() < 0.08);
+
+        let exponential = BasicVariogram::new(StructureShape::Exponential, range, 1.0, 0.0, cs);
+        let expected = exponential.covariogram(lag);
+        let (variance, correlation) = pair_statistics(&TurningBands::new(exponential, 500), lag);
+        assert!((variance - 1.0).abs() < 0.1);
+        assert!((correlation - expected).abs() < 0.08);
+
+        let gaussian = BasicVariogram::new(StructureShape::Gaussian, range, 1.0, 0.0, cs);
+        let expected = gaussian.covariogram(lag);
+        let (variance, correlation) = pair_statistics(&TurningBands::new(gaussian, 500), lag);
+        assert!((variance - 1.0).abs() < 0.1);
+        assert!((correlation - expected).abs() < 0.08);
+
+        //lag along the major axis of a rotated anisotropic model
+        let rotated_cs = CoordinateSystem::new(
+            Translation3::identity(),
+            UnitQuaternion::from_euler_angles(0.0, 0.0, 45f32.to_radians()),
+        );
+        let anisotropic =
+            SphericalVariogram::new(Vector3::new(12.0, 4.0, 4.0), 1.0, 0.0, rotated_cs);
+        let lag = rotated_cs
+            .rotation
+            .transform_vector(&Vector3::new(4.0, 0.0, 0.0));
+        let expected = anisotropic.covariogram(lag);
+        assert!((expected - 0.5185).abs() < 1e-3);
+        let (variance, correlation) = pair_statistics(&TurningBands::new(anisotropic, 500), lag);
+        assert!((variance - 1.0).abs() < 0.1);
+        assert!((correlation - expected).abs() < 0.08);
+    }
+
+    #[test]
+    fn conditional_turning_bands_honour_data() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let vgram = SphericalVariogram::new(Vector3::new(10.0, 10.0, 10.0), 1.0, 0.0, cs);
+
+        let data_points = vec![
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(4.0, 1.0, 0.0),
+            Point3::new(1.0, 6.0, 0.0),
+        ];
+        let data_values = vec![1.5, -0.5, 0.3];
+        let simulation = TurningBands::new(vgram, 200).with_conditioning(
+            PointSet::new(data_points.clone(), data_values.clone()),
+            Ellipsoid::new(20.0, 20.0, 20.0, cs),
+            ConditioningParams::new(8),
+        );
+
+        let mut points = data_points.clone();
+        points.push(Point3::new(2.0, 2.0, 0.0));
+        points.push(Point3::new(100.0, 0.0, 0.0));
+
+        let values = simulation.simulate_points(&points, &mut StdRng::seed_from_u64(3));
+        for (value, data) in values.iter().zip(data_values.iter()) {
+            assert!((value - data).abs() < 1e-3);
+        }
+        assert!(values.iter().all(|value| value.is_finite()));
+
+        //seeded realizations are reproducible
+        assert_eq!(
+            values,
+            simulation.simulate_points(&points, &mut StdRng::seed_from_u64(3))
+        );
+    }
+}
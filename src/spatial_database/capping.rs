@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+
+use num_traits::Float;
+
+use super::qbvh::point_set::PointSet;
+
+/// Top-cut (capping) values by domain
+/// # Members
+/// * `default_cap` - Cap applied to values without a domain specific cap
+/// * `domain_caps` - Caps for individual domains
+#[derive(Debug, Clone, Default)]
+pub struct TopCut {
+    pub default_cap: Option<f32>,
+    pub domain_caps: HashMap<u32, f32>,
+}
+
+impl TopCut {
+    /// Create a new top-cut
+    /// # Arguments
+    /// * `default_cap` - Cap applied to values without a domain specific cap (None for no cap)
+    pub fn new(default_cap: Option<f32>) -> Self {
+        Self {
+            default_cap,
+            domain_caps: HashMap::new(),
+        }
+    }
+
+    /// Set the cap for a domain
+    pub fn with_domain_cap(mut self, domain: u32, cap: f32) -> Self {
+        self.domain_caps.insert(domain, cap);
+        self
+    }
+
+    /// Cap applicable to a value in the given domain
+    pub fn cap_for(&self, domain: Option<u32>) -> Option<f32> {
+        domain
+            .and_then(|domain| self.domain_caps.get(&domain).copied())
+            .or(self.default_cap)
+    }
+}
+
+pub trait Capping {
+    /// Cap values in place
+    /// # Returns
+    /// The number of values that were capped
+    fn cap(&mut self, top_cut: &TopCut) -> usize;
+}
+
+impl<T> Capping for PointSet<T>
+where
+    T: Float,
+{
+    fn cap(&mut self, top_cut: &TopCut) -> usize {
+        let mut n_capped = 0;
+        for (i, value) in self.data.iter_mut().enumerate() {
+            let domain = self.domains.as_ref().map(|domains| domains[i]);
+            let Some(cap) = top_cut.cap_for(domain).and_then(num_traits::cast) else {
+                continue;
+            };
+
+            if *value > cap {
+                *value = cap;
+                n_capped += 1;
+            }
+        }
+
+        n_capped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Point3;
+
+    use super::*;
+
+    #[test]
+    fn cap_by_domain() {
+        let points = (0..4)
+            .map(|i| Point3::new(i as f32, 0.0, 0.0))
+            .collect::<Vec<_>>();
+        let mut point_set =
+            PointSet::new(points, vec![1.0f32, 20.0, 20.0, 50.0]).with_domains(vec![1, 1, 2, 3]);
+
+        let top_cut = TopCut::new(Some(30.0))
+            .with_domain_cap(1, 10.0)
+            .with_domain_cap(2, 25.0);
+
+        let n_capped = point_set.cap(&top_cut);
+
+        assert_eq!(n_capped, 2);
+        assert_eq!(point_set.data, vec![1.0, 10.0, 20.0, 30.0]);
+    }
+}
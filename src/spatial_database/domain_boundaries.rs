@@ -0,0 +1,76 @@
+/// Soft boundary allowing samples to be shared between two domains
+/// # Members
+/// * `domains` - Pair of domain codes sharing samples (in both directions)
+/// * `max_distance` - Samples across the boundary are only used within this distance of the target
+#[derive(Clone, Debug, PartialEq)]
+pub struct SoftBoundary {
+    pub domains: (u32, u32),
+    pub max_distance: Option<f32>,
+}
+
+impl SoftBoundary {
+    pub fn new(domain_a: u32, domain_b: u32, max_distance: Option<f32>) -> Self {
+        Self {
+            domains: (domain_a, domain_b),
+            max_distance,
+        }
+    }
+
+    /// Checks if a sample from `sample_domain` at `distance` may inform a target in `target_domain`
+    #[inline(always)]
+    pub fn allows(&self, target_domain: u32, sample_domain: u32, distance: f32) -> bool {
+        let pair = self.domains;
+        let listed = (pair.0 == target_domain && pair.1 == sample_domain)
+            || (pair.1 == target_domain && pair.0 == sample_domain);
+
+        listed && self.max_distance.map_or(true, |max| distance <= max)
+    }
+}
+
+/// Domain boundary conditions applied when searching for conditioning data
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum DomainBoundaries {
+    /// Samples from any domain are used
+    #[default]
+    Unconstrained,
+    /// Only samples from the target domain are used
+    Hard,
+    /// Samples from the target domain and from the domains sharing a soft boundary with it are used
+    Soft(Vec<SoftBoundary>),
+}
+
+impl DomainBoundaries {
+    /// Checks if a sample from `sample_domain` at `distance` may inform a target in `target_domain`
+    #[inline(always)]
+    pub fn accepts(&self, target_domain: u32, sample_domain: u32, distance: f32) -> bool {
+        if target_domain == sample_domain {
+            return true;
+        }
+
+        match self {
+            DomainBoundaries::Unconstrained => true,
+            DomainBoundaries::Hard => false,
+            DomainBoundaries::Soft(boundaries) => boundaries
+                .iter()
+                .any(|boundary| boundary.allows(target_domain, sample_domain, distance)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boundary_rules() {
+        assert!(DomainBoundaries::Unconstrained.accepts(1, 2, 100.0));
+        assert!(DomainBoundaries::Hard.accepts(1, 1, 100.0));
+        assert!(!DomainBoundaries::Hard.accepts(1, 2, 0.0));
+
+        let soft = DomainBoundaries::Soft(vec![SoftBoundary::new(1, 2, Some(10.0))]);
+        assert!(soft.accepts(1, 2, 5.0));
+        assert!(soft.accepts(2, 1, 5.0));
+        assert!(!soft.accepts(1, 2, 15.0));
+        assert!(!soft.accepts(1, 3, 5.0));
+    }
+}
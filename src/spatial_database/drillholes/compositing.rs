@@ -0,0 +1,494 @@
+use itertools::Itertools;
+use nalgebra::Point3;
+
+use crate::spatial_database::qbvh::point_set::PointSet;
+
+use super::drillhole::{Drillhole, DrillholeDataBase, Interval};
+
+/// Method used to place composite boundaries along a hole
+pub enum CompositingMethod {
+    /// Fixed length composites starting at the top of the first interval
+    FixedLength(f32),
+    /// Composites broken at domain boundaries
+    /// each domain run is split into composites of the given length (or kept whole if None)
+    Domain(Option<f32>),
+    /// Composites broken at bench elevations (`reference_elevation + k * bench_height`)
+    Bench {
+        bench_height: f32,
+        reference_elevation: f32,
+    },
+}
+
+/// Handling of short composites at the ends of a compositing run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResidualRule {
+    /// Keep residual composites
+    Keep,
+    /// Drop residual composites
+    Discard,
+    /// Merge residual composites into the adjacent composite of the same run
+    Merge,
+    /// Adjust the composite length of the run so that it divides into equal composites
+    /// (bench composites fall back to `Keep`)
+    Distribute,
+}
+
+/// Compositing parameters
+/// # Members
+/// * `method` - Method used to place composite boundaries
+/// * `residual_rule` - Handling of residual composites
+/// * `min_residual_fraction` - Residuals shorter than this fraction of the nominal composite are handled by `residual_rule`
+/// * `min_coverage` - Minimum sampled fraction of a composite for it to be retained
+pub struct CompositingParameters {
+    pub method: CompositingMethod,
+    pub residual_rule: ResidualRule,
+    pub min_residual_fraction: f32,
+    pub min_coverage: f32,
+}
+
+impl CompositingParameters {
+    pub fn new(
+        method: CompositingMethod,
+        residual_rule: ResidualRule,
+        min_residual_fraction: f32,
+        min_coverage: f32,
+    ) -> Self {
+        Self {
+            method,
+            residual_rule,
+            min_residual_fraction,
+            min_coverage,
+        }
+    }
+}
+
+/// A length weighted composite
+/// # Members
+/// * `hole_id` - Id of the hole the composite belongs to
+/// * `from` - Depth of the top of the composite
+/// * `to` - Depth of the bottom of the composite
+/// * `sampled_length` - Length of the composite covered by sampled intervals
+/// * `value` - Length weighted value
+/// * `domain` - Domain covering the largest length of the composite
+/// * `point` - Location of the composite mid point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Composite {
+    pub hole_id: u32,
+    pub from: f32,
+    pub to: f32,
+    pub sampled_length: f32,
+    pub value: f32,
+    pub domain: Option<u32>,
+    pub point: Point3<f32>,
+}
+
+/// Composite boundary segment along with its size relative to the nominal composite size
+struct Segment {
+    from: f32,
+    to: f32,
+    fraction: f32,
+}
+
+impl DrillholeDataBase {
+    /// Composite all holes in the database
+    /// # Arguments
+    /// * `params` - Compositing parameters
+    /// # Returns
+    /// A point set of composite mid points with hole ids and sampled lengths retained
+    /// (domains are retained when every composite has a domain)
+    pub fn composite(&self, params: &CompositingParameters) -> PointSet<f32> {
+        let composites = self
+            .holes
+            .iter()
+            .flat_map(|hole| composite_hole(hole, params))
+            .collect_vec();
+
+        let points = composites.iter().map(|c| c.point).collect_vec();
+        let data = composites.iter().map(|c| c.value).collect_vec();
+        let hole_ids = composites.iter().map(|c| c.hole_id).collect_vec();
+        let lengths = composites.iter().map(|c| c.sampled_length).collect_vec();
+
+        let point_set = PointSet::new(points, data)
+            .with_hole_ids(hole_ids)
+            .with_lengths(lengths);
+
+        match composites
+            .iter()
+            .map(|c| c.domain)
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(domains) => point_set.with_domains(domains),
+            None => point_set,
+        }
+    }
+}
+
+/// Composite a single hole
+/// # Arguments
+/// * `hole` - Drillhole to composite
+/// * `params` - Compositing parameters
+pub fn composite_hole(hole: &Drillhole, params: &CompositingParameters) -> Vec<Composite> {
+    compositing_runs(hole, &params.method)
+        .into_iter()
+        .flat_map(|(run_from, run_to)| {
+            let segments = match params.method {
+                CompositingMethod::FixedLength(length) => {
+                    length_segments(run_from, run_to, length, params.residual_rule)
+                }
+                CompositingMethod::Domain(Some(length)) => {
+                    length_segments(run_from, run_to, length, params.residual_rule)
+                }
+                CompositingMethod::Domain(None) => vec![Segment {
+                    from: run_from,
+                    to: run_to,
+                    fraction: 1.0,
+                }],
+                CompositingMethod::Bench {
+                    bench_height,
+                    reference_elevation,
+                } => bench_segments(hole, run_from, run_to, bench_height, reference_elevation),
+            };
+
+            apply_residual_rule(segments, params.residual_rule, params.min_residual_fraction)
+        })
+        .filter_map(|(from, to)| composite_segment(hole, from, to, params.min_coverage))
+        .collect()
+}
+
+/// Depth ranges composited independently of each other
+fn compositing_runs(hole: &Drillhole, method: &CompositingMethod) -> Vec<(f32, f32)> {
+    if hole.intervals.is_empty() {
+        return vec![];
+    }
+
+    match method {
+        CompositingMethod::Domain(_) => hole
+            .intervals
+            .iter()
+            .group_by(|interval| interval.domain)
+            .into_iter()
+            .map(|(_, run)| {
+                let run = run.collect_vec();
+                (run[0].from, run[run.len() - 1].to)
+            })
+            .collect(),
+        _ => vec![(hole.intervals[0].from, hole.end_depth())],
+    }
+}
+
+/// Split a run into composites of the given length
+fn length_segments(from: f32, to: f32, length: f32, rule: ResidualRule) -> Vec<Segment> {
+    let run_length = to - from;
+
+    //adjust composite length so the run divides into equal composites
+    let length = if rule == ResidualRule::Distribute {
+        let n = (run_length / length).round().max(1.0);
+        run_length / n
+    } else {
+        length
+    };
+
+    let mut segments = Vec::new();
+    let mut start = from;
+    while to - start > 1e-4 * length {
+        let end = (start + length).min(to);
+        segments.push(Segment {
+            from: start,
+            to: end,
+            fraction: (end - start) / length,
+        });
+        start = end;
+    }
+
+    segments
+}
+
+/// Split a run at bench elevations
+fn bench_segments(
+    hole: &Drillhole,
+    from: f32,
+    to: f32,
+    bench_height: f32,
+    reference_elevation: f32,
+) -> Vec<Segment> {
+    //z is linear between survey stations, so crossings can be solved exactly on each piece
+    let breaks = std::iter::once(from)
+        .chain(
+            hole.survey_depths()
+                .into_iter()
+                .filter(|d| *d > from && *d < to),
+        )
+        .chain(std::iter::once(to))
+        .collect_vec();
+
+    let mut depths = vec![from, to];
+    for (d_0, d_1) in breaks.iter().tuple_windows() {
+        let z_0 = hole.point_at_depth(*d_0).z;
+        let z_1 = hole.point_at_depth(*d_1).z;
+        if z_0 == z_1 {
+            continue;
+        }
+
+        let z_min = z_0.min(z_1);
+        let z_max = z_0.max(z_1);
+        let k_min = ((z_min - reference_elevation) / bench_height).ceil() as i64;
+        let k_max = ((z_max - reference_elevation) / bench_height).floor() as i64;
+        for k in k_min..=k_max {
+            let level = reference_elevation + k as f32 * bench_height;
+            depths.push(d_0 + (level - z_0) / (z_1 - z_0) * (d_1 - d_0));
+        }
+    }
+
+    depths.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    depths.dedup_by(|a, b| (*a - *b).abs() < 1e-4);
+
+    depths
+        .iter()
+        .tuple_windows()
+        .map(|(d_0, d_1)| {
+            let dz = (hole.point_at_depth(*d_0).z - hole.point_at_depth(*d_1).z).abs();
+            Segment {
+                from: *d_0,
+                to: *d_1,
+                fraction: (dz / bench_height).min(1.0),
+            }
+        })
+        .collect()
+}
+
+/// Handle short composites at the ends of a run
+fn apply_residual_rule(
+    segments: Vec<Segment>,
+    rule: ResidualRule,
+    min_residual_fraction: f32,
+) -> Vec<(f32, f32)> {
+    let mut bounds = segments.iter().map(|s| (s.from, s.to)).collect_vec();
+    if segments.len() < 2 || matches!(rule, ResidualRule::Keep | ResidualRule::Distribute) {
+        return bounds;
+    }
+
+    let is_residual = |segment: &Segment| segment.fraction < min_residual_fraction;
+    let last = segments.len() - 1;
+
+    //bottom of run
+    if is_residual(&segments[last]) {
+        let (_, to) = bounds.pop().unwrap();
+        if rule == ResidualRule::Merge {
+            bounds.last_mut().unwrap().1 = to;
+        }
+    }
+
+    //top of run (only partial for bench composites)
+    if is_residual(&segments[0]) && !bounds.is_empty() {
+        let (from, _) = bounds.remove(0);
+        if rule == ResidualRule::Merge {
+            match bounds.first_mut() {
+                Some(first) => first.0 = from,
+                None => bounds.push((from, segments[last].to)),
+            }
+        }
+    }
+
+    bounds
+}
+
+/// Length weighted average of the sampled intervals overlapping [from, to]
+fn composite_segment(hole: &Drillhole, from: f32, to: f32, min_coverage: f32) -> Option<Composite> {
+    let (weighted_sum, sampled_length) = hole
+        .intervals
+        .iter()
+        .filter(|interval| interval.to > from && interval.from < to)
+        .filter_map(|interval: &Interval| interval.value.map(|v| (v, interval.overlap(from, to))))
+        .fold((0.0, 0.0), |(sum, len), (v, overlap)| {
+            (sum + v * overlap, len + overlap)
+        });
+
+    let length = to - from;
+    if sampled_length <= 0.0 || sampled_length < min_coverage * length {
+        return None;
+    }
+
+    //ties are resolved in favour of the shallower domain
+    let mut domain_lengths: Vec<(Option<u32>, f32)> = Vec::new();
+    for interval in hole.intervals.iter() {
+        let overlap = interval.overlap(from, to);
+        if overlap <= 0.0 {
+            continue;
+        }
+        match domain_lengths
+            .iter_mut()
+            .find(|(d, _)| *d == interval.domain)
+        {
+            Some((_, length)) => *length += overlap,
+            None => domain_lengths.push((interval.domain, overlap)),
+        }
+    }
+    let domain = domain_lengths
+        .iter()
+        .fold(
+            None,
+            |best: Option<&(Option<u32>, f32)>, current| match best {
+                Some(best) if best.1 >= current.1 => Some(best),
+                _ => Some(current),
+            },
+        )
+        .and_then(|(domain, _)| *domain);
+
+    Some(Composite {
+        hole_id: hole.id,
+        from,
+        to,
+        sampled_length,
+        value: weighted_sum / sampled_length,
+        domain,
+        point: hole.point_at_depth(0.5 * (from + to)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::spatial_database::drillholes::drillhole::Survey;
+
+    fn test_hole() -> Drillhole {
+        Drillhole::new(
+            7,
+            "DH007".to_string(),
+            Point3::new(0.0, 0.0, 100.0),
+            vec![Survey::new(0.0, 0.0, 90.0)],
+            vec![
+                Interval::new(0.0, 1.0, Some(1.0), Some(1)),
+                Interval::new(1.0, 3.0, Some(2.0), Some(1)),
+                Interval::new(3.0, 4.0, None, Some(2)),
+                Interval::new(4.0, 5.5, Some(4.0), Some(2)),
+            ],
+        )
+    }
+
+    #[test]
+    fn fixed_length_composites() {
+        let params = CompositingParameters::new(
+            CompositingMethod::FixedLength(2.0),
+            ResidualRule::Keep,
+            0.5,
+            0.5,
+        );
+        let composites = composite_hole(&test_hole(), &params);
+
+        assert_eq!(composites.len(), 3);
+        assert_relative_eq!(composites[0].value, 1.5, epsilon = 1e-6);
+        assert_relative_eq!(composites[1].value, 2.0, epsilon = 1e-6);
+        assert_relative_eq!(composites[1].sampled_length, 1.0, epsilon = 1e-6);
+        assert_relative_eq!(composites[2].value, 4.0, epsilon = 1e-6);
+        assert_relative_eq!(
+            composites[0].point,
+            Point3::new(0.0, 0.0, 99.0),
+            epsilon = 1e-5
+        );
+        assert!(composites.iter().all(|c| c.hole_id == 7));
+    }
+
+    #[test]
+    fn residual_rules() {
+        let hole = test_hole();
+
+        let discard = CompositingParameters::new(
+            CompositingMethod::FixedLength(2.0),
+            ResidualRule::Discard,
+            0.8,
+            0.0,
+        );
+        assert_eq!(composite_hole(&hole, &discard).len(), 2);
+
+        let merge = CompositingParameters::new(
+            CompositingMethod::FixedLength(2.0),
+            ResidualRule::Merge,
+            0.8,
+            0.0,
+        );
+        let composites = composite_hole(&hole, &merge);
+        assert_eq!(composites.len(), 2);
+        assert_relative_eq!(composites[1].to, 5.5, epsilon = 1e-6);
+
+        let distribute = CompositingParameters::new(
+            CompositingMethod::FixedLength(2.0),
+            ResidualRule::Distribute,
+            0.8,
+            0.0,
+        );
+        let composites = composite_hole(&hole, &distribute);
+        assert_eq!(composites.len(), 3);
+        composites
+            .iter()
+            .for_each(|c| assert_relative_eq!(c.to - c.from, 5.5 / 3.0, epsilon = 1e-5));
+    }
+
+    #[test]
+    fn min_coverage() {
+        let params = CompositingParameters::new(
+            CompositingMethod::FixedLength(2.0),
+            ResidualRule::Keep,
+            0.5,
+            0.75,
+        );
+        let composites = composite_hole(&test_hole(), &params);
+
+        //[2, 4] is only half sampled
+        assert_eq!(composites.len(), 2);
+    }
+
+    #[test]
+    fn domain_composites() {
+        let params = CompositingParameters::new(
+            CompositingMethod::Domain(None),
+            ResidualRule::Keep,
+            0.5,
+            0.0,
+        );
+        let composites = composite_hole(&test_hole(), &params);
+
+        assert_eq!(composites.len(), 2);
+        assert_relative_eq!(composites[0].value, 5.0 / 3.0, epsilon = 1e-6);
+        assert_relative_eq!(composites[1].value, 4.0, epsilon = 1e-6);
+        assert_relative_eq!(composites[1].from, 3.0, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn bench_composites() {
+        let params = CompositingParameters::new(
+            CompositingMethod::Bench {
+                bench_height: 3.0,
+                reference_elevation: 0.0,
+            },
+            ResidualRule::Keep,
+            0.5,
+            0.0,
+        );
+        let composites = composite_hole(&test_hole(), &params);
+
+        //benches at elevations 99 and 96 -> depths 1 and 4
+        assert_eq!(composites.len(), 3);
+        assert_relative_eq!(composites[1].from, 1.0, epsilon = 1e-4);
+        assert_relative_eq!(composites[1].to, 4.0, epsilon = 1e-4);
+        assert_relative_eq!(composites[1].value, 2.0, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn composite_point_set() {
+        let db = DrillholeDataBase::new(vec![test_hole()]);
+        let params = CompositingParameters::new(
+            CompositingMethod::FixedLength(2.0),
+            ResidualRule::Keep,
+            0.5,
+            0.5,
+        );
+        let point_set = db.composite(&params);
+
+        assert_eq!(point_set.points.len(), 3);
+        assert_eq!(point_set.hole_ids, Some(vec![7, 7, 7]));
+        assert_eq!(point_set.domains, Some(vec![1, 1, 2]));
+        assert_relative_eq!(point_set.lengths.as_ref().unwrap()[2], 1.5, epsilon = 1e-6);
+    }
+}
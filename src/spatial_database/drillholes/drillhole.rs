@@ -0,0 +1,176 @@
+use nalgebra::{Point3, Vector3};
+
+/// Downhole survey station
+/// # Members
+/// * `depth` - Depth of the station along the hole
+/// * `azimuth` - Azimuth in degrees, clockwise from the y axis (north)
+/// * `dip` - Dip in degrees below the horizontal (positive downwards)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Survey {
+    pub depth: f32,
+    pub azimuth: f32,
+    pub dip: f32,
+}
+
+impl Survey {
+    pub fn new(depth: f32, azimuth: f32, dip: f32) -> Self {
+        Self {
+            depth,
+            azimuth,
+            dip,
+        }
+    }
+
+    /// Unit direction of the hole at the station in world coordinates
+    pub fn direction(&self) -> Vector3<f32> {
+        let azimuth = self.azimuth.to_radians();
+        let dip = self.dip.to_radians();
+        Vector3::new(
+            azimuth.sin() * dip.cos(),
+            azimuth.cos() * dip.cos(),
+            -dip.sin(),
+        )
+    }
+}
+
+/// Sampled interval along a drillhole
+/// # Members
+/// * `from` - Depth of the top of the interval
+/// * `to` - Depth of the bottom of the interval
+/// * `value` - Sample value (None if the interval was not sampled)
+/// * `domain` - Domain code of the interval
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval {
+    pub from: f32,
+    pub to: f32,
+    pub value: Option<f32>,
+    pub domain: Option<u32>,
+}
+
+impl Interval {
+    pub fn new(from: f32, to: f32, value: Option<f32>, domain: Option<u32>) -> Self {
+        Self {
+            from,
+            to,
+            value,
+            domain,
+        }
+    }
+
+    /// Length of the interval along the hole
+    #[inline(always)]
+    pub fn length(&self) -> f32 {
+        self.to - self.from
+    }
+
+    /// Length of the overlap between the interval and the depth range [from, to]
+    #[inline(always)]
+    pub fn overlap(&self, from: f32, to: f32) -> f32 {
+        (self.to.min(to) - self.from.max(from)).max(0.0)
+    }
+}
+
+/// A single drillhole with its collar, downhole surveys and sampled intervals
+/// # Members
+/// * `id` - Numeric id of the hole (retained on composites)
+/// * `name` - Name of the hole
+/// * `collar` - Collar location in world coordinates
+/// * `surveys` - Survey stations sorted by depth
+/// * `intervals` - Intervals sorted by depth
+#[derive(Debug, Clone)]
+pub struct Drillhole {
+    pub id: u32,
+    pub name: String,
+    pub collar: Point3<f32>,
+    pub surveys: Vec<Survey>,
+    pub intervals: Vec<Interval>,
+}
+
+impl Drillhole {
+    /// Create a new drillhole
+    /// # Arguments
+    /// * `id` - Numeric id of the hole
+    /// * `name` - Name of the hole
+    /// * `collar` - Collar location in world coordinates
+    /// * `surveys` - Survey stations (a vertical hole is assumed if empty)
+    /// * `intervals` - Sampled intervals
+    pub fn new(
+        id: u32,
+        name: String,
+        collar: Point3<f32>,
+        surveys: Vec<Survey>,
+        intervals: Vec<Interval>,
+    ) -> Self {
+        let mut surveys = surveys;
+        let mut intervals = intervals;
+        surveys.sort_by(|a, b| a.depth.partial_cmp(&b.depth).unwrap());
+        intervals.sort_by(|a, b| a.from.partial_cmp(&b.from).unwrap());
+        Self {
+            id,
+            name,
+            collar,
+            surveys,
+            intervals,
+        }
+    }
+
+    /// Depth of the bottom of the deepest interval
+    pub fn end_depth(&self) -> f32 {
+        self.intervals
+            .iter()
+            .map(|interval| interval.to)
+            .fold(0.0, f32::max)
+    }
+
+    /// Depths at which the hole changes direction
+    pub fn survey_depths(&self) -> Vec<f32> {
+        self.surveys.iter().map(|survey| survey.depth).collect()
+    }
+
+    /// Location of a point at the given depth along the hole (tangent method)
+    /// the direction of each survey station is used until the next station
+    pub fn point_at_depth(&self, depth: f32) -> Point3<f32> {
+        if self.surveys.is_empty() {
+            return self.collar - Vector3::z() * depth;
+        }
+
+        let mut point = self.collar;
+        let mut current_depth = 0.0;
+        for (i, survey) in self.surveys.iter().enumerate() {
+            //the last survey extends to the bottom of the hole
+            let segment_end = self
+                .surveys
+                .get(i + 1)
+                .map(|next| next.depth)
+                .unwrap_or(f32::MAX)
+                .min(depth);
+
+            if segment_end > current_depth {
+                point += survey.direction() * (segment_end - current_depth);
+                current_depth = segment_end;
+            }
+
+            if current_depth >= depth {
+                break;
+            }
+        }
+
+        point
+    }
+}
+
+/// Collection of drillholes
+pub struct DrillholeDataBase {
+    pub holes: Vec<Drillhole>,
+}
+
+impl DrillholeDataBase {
+    pub fn new(holes: Vec<Drillhole>) -> Self {
+        Self { holes }
+    }
+
+    /// Hole with the given id
+    pub fn hole(&self, id: u32) -> Option<&Drillhole> {
+        self.holes.iter().find(|hole| hole.id == id)
+    }
+}
@@ -0,0 +1,2 @@
+pub mod compositing;
+pub mod drillhole;
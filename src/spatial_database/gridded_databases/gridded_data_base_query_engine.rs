@@ -1,14 +1,19 @@
-use nalgebra::Point3;
-use ordered_float::OrderedFloat;
+use nalgebra::{distance, Point3};
+use ndarray::Array3;
 
 use crate::{
     geometry::Geometry,
-    spatial_database::{coordinate_system::octant, SpatialQueryable},
+    spatial_database::{
+        domain_boundaries::DomainBoundaries,
+        search::{SearchSpecification, SearchStatus, Sectors},
+        SpatialQueryable,
+    },
 };
 
-use super::GriddedDataBaseInterface;
+use super::{ind_at_offset, sector_offsets, GriddedDataBaseInterface};
 
 /// Stores offsets for each octant of a geometry, allowing for fast queries of points in geometry
+/// the grid and geometry may have any orientation, queries are centered on the grid node nearest to the point
 pub struct GriddedDataBaseOctantQueryEngine<'a, G, GDB, T>
 where
     G: Geometry,
@@ -17,7 +22,9 @@ where
     pub(crate) octant_offsets: Vec<Vec<[isize; 3]>>,
     pub(crate) geometry: G,
     pub(crate) max_octant_size: usize,
+    pub(crate) search: SearchSpecification,
     pub(crate) db: &'a GDB,
+    pub(crate) domains: Option<(Array3<u32>, DomainBoundaries)>,
     pub(crate) phantom: std::marker::PhantomData<T>,
 }
 
@@ -38,40 +45,69 @@ where
         //get all offsets for geometry
         let offsets = gdb.offsets_from_ind_in_geometry(&[0, 0, 0], &geometry);
 
-        //splits offsets into octant groups
-        let mut octants = vec![Vec::new(); 8];
-        for offset in offsets {
-            //get point in world space
-            let mut p = gdb.ind_to_point(&offset);
-            //transform to local space of geometry
-            p = geometry
-                .coordinate_system()
-                .world_to_local
-                .transform_point(&p);
-            let octant = octant(&p);
-            octants[octant as usize].push(offset);
-        }
-
-        //sort offsets by iso_distance to ref point
-        octants.iter_mut().for_each(|offset| {
-            offset.sort_by_key(|offset| {
-                //can simply use offset since reference point is origin
-                let p = gdb.ind_to_point(offset);
-                let d = geometry.iso_distance(&p);
-                OrderedFloat(d)
-            });
-        });
+        //splits offsets into octant groups sorted by iso distance
+        let octants = sector_offsets::<_, _, T>(gdb, &geometry, offsets, Sectors::Eight);
 
         //create query engine
         GriddedDataBaseOctantQueryEngine {
             octant_offsets: octants,
             geometry,
             max_octant_size,
+            search: SearchSpecification::default(),
+            db: gdb,
+            domains: None,
+            phantom: std::marker::PhantomData,
+        }
+    }
+
+    /// Create a query engine from offsets computed by another engine
+    /// avoids recomputing the offsets when an engine is rebuilt for a grid with the same geometry
+    /// # Arguments
+    /// * `geometry` - The geometry of the engine the offsets were taken from
+    /// * `gdb` - The gridded database to use for the query engine
+    /// * `max_octant_size` - The number of points to get from each octant
+    /// * `octant_offsets` - Sorted offsets of each octant
+    pub(crate) fn from_octant_offsets(
+        geometry: G,
+        gdb: &'a GDB,
+        max_octant_size: usize,
+        octant_offsets: Vec<Vec<[isize; 3]>>,
+    ) -> Self {
+        GriddedDataBaseOctantQueryEngine {
+            octant_offsets,
+            geometry,
+            max_octant_size,
+            search: SearchSpecification::default(),
             db: gdb,
+            domains: None,
             phantom: std::marker::PhantomData,
         }
     }
 
+    /// Apply the sectors and minimums of a search specification
+    /// the maximum per sector remains `max_octant_size`, drillhole limits do not apply to grids
+    pub fn with_search(mut self, search: SearchSpecification) -> Self {
+        let offsets = self.octant_offsets.drain(..).flatten().collect();
+        self.octant_offsets =
+            sector_offsets::<_, _, T>(&*self.db, &self.geometry, offsets, search.sectors);
+        self.search = search;
+        self
+    }
+
+    /// Attach domain codes to the nodes of the gridded database
+    /// # Arguments
+    /// * `domains` - Domain code of each node (must have the same shape as the gridded database)
+    /// * `boundaries` - Boundary conditions applied by domain queries
+    pub fn with_domains(mut self, domains: Array3<u32>, boundaries: DomainBoundaries) -> Self {
+        assert_eq!(
+            domains.shape(),
+            self.db.shape().as_slice(),
+            "domain codes must match the shape of the gridded database"
+        );
+        self.domains = Some((domains, boundaries));
+        self
+    }
+
     /// Get the nearest points and values to a point in the geometry
     /// # Arguments
     /// * `point` - The point to get the nearest points and values for
@@ -79,16 +115,15 @@ where
     /// * `gdb` - The gridded database to use for the query engine
     ///     * must have same grid size and orientation as gdb used for construction of query engine
     pub fn nearest_points_and_values(&self, point: &Point3<f32>) -> (Vec<Point3<f32>>, Vec<T>) {
-        let _ = self.geometry;
-        //this only works if the grid ang geometry have similar orientation
-        //TODO: convert to high ind relative to geometry rotation
-        let point_ind = self.db.coord_to_high_ind(point).map(|x| x as usize);
+        //offsets are relative to the grid node nearest to the point
+        let point_ind = self.db.coord_to_nearest_ind(point);
+        let shape = self.db.shape();
         let mut points = Vec::<Point3<f32>>::new();
         let mut values = Vec::with_capacity(self.max_octant_size * 8);
         for offsets in self.octant_offsets.iter() {
             let mut oct_cnt = 0;
             for offset in offsets {
-                let Some(ind) = self.db.offset_ind(point_ind, *offset) else {
+                let Some(ind) = ind_at_offset(shape, point_ind, *offset) else {
                     continue;
                 };
                 if let Some(v) = self.db.data_at_ind(&ind) {
@@ -120,16 +155,15 @@ where
     where
         F: Fn([usize; 3]) -> bool,
     {
-        let _ = self.geometry;
-        //this only works if the grid ang geometry have similar orientation
-        //TODO: convert to high ind relative to geometry rotation
-        let point_ind = self.db.coord_to_high_ind(point).map(|x| x as usize);
+        //offsets are relative to the grid node nearest to the point
+        let point_ind = self.db.coord_to_nearest_ind(point);
+        let shape = self.db.shape();
         let mut points = Vec::<Point3<f32>>::new();
         let mut values = Vec::with_capacity(self.max_octant_size * 8);
         for offsets in self.octant_offsets.iter() {
             let mut oct_cnt = 0;
             for offset in offsets {
-                let Some(ind) = self.db.offset_ind(point_ind, *offset) else {
+                let Some(ind) = ind_at_offset(shape, point_ind, *offset) else {
                     continue;
                 };
 
@@ -158,15 +192,14 @@ where
     /// * `gdb` - The gridded database to use for the query engine
     ///     * must have same grid size and orientation as gdb used for construction of query engine
     pub fn nearest_inds<F>(&self, point: &Point3<f32>) -> Vec<[usize; 3]> {
-        let _ = self.geometry;
-        //this only works if the grid ang geometry have similar orientation
-        //TODO: convert to high ind relative to geometry rotation
-        let point_ind = self.db.coord_to_high_ind(point).map(|x| x as usize);
+        //offsets are relative to the grid node nearest to the point
+        let point_ind = self.db.coord_to_nearest_ind(point);
+        let shape = self.db.shape();
         let mut inds = Vec::new();
         for offsets in self.octant_offsets.iter() {
             let mut oct_cnt = 0;
             for offset in offsets {
-                let Some(ind) = self.db.offset_ind(point_ind, *offset) else {
+                let Some(ind) = ind_at_offset(shape, point_ind, *offset) else {
                     continue;
                 };
 
@@ -197,16 +230,15 @@ where
     where
         F: Fn([usize; 3]) -> bool,
     {
-        let _ = self.geometry;
-        //this only works if the grid ang geometry have similar orientation
-        //TODO: convert to high ind relative to geometry rotation
-        let point_ind = self.db.coord_to_high_ind(point).map(|x| x as usize);
+        //offsets are relative to the grid node nearest to the point
+        let point_ind = self.db.coord_to_nearest_ind(point);
+        let shape = self.db.shape();
         let mut inds = Vec::new();
         let mut points = Vec::<Point3<f32>>::new();
         for offsets in self.octant_offsets.iter() {
             let mut oct_cnt = 0;
             for offset in offsets {
-                let Some(ind) = self.db.offset_ind(point_ind, *offset) else {
+                let Some(ind) = ind_at_offset(shape, point_ind, *offset) else {
                     continue;
                 };
                 //print!("ind: {:?}, ", ind);
@@ -227,6 +259,33 @@ where
         (inds, points)
     }
 
+    /// Get the nearest points and values to a point in the given domain
+    /// # Arguments
+    /// * `point` - The point to get the nearest points and values for
+    /// * `domain` - The domain of the point
+    ///     * nodes from other domains are only used across boundaries permitted by the engine
+    /// # Panics
+    /// If the engine was built without domains (see `with_domains`)
+    pub fn nearest_points_and_values_in_domain(
+        &self,
+        point: &Point3<f32>,
+        domain: u32,
+    ) -> (Vec<Point3<f32>>, Vec<T>) {
+        let (domains, boundaries) = self
+            .domains
+            .as_ref()
+            .expect("conditioning data hold no domain information");
+
+        self.nearest_points_and_values_masked(point, |ind| {
+            let node_domain = domains[ind];
+            if node_domain == domain {
+                return true;
+            }
+            let dist = distance(point, &self.db.ind_to_point(&ind.map(|x| x as isize)));
+            boundaries.accepts(domain, node_domain, dist)
+        })
+    }
+
     pub fn retain_offsets<F: Fn(&[isize; 3]) -> bool>(&mut self, filter: F) {
         for offsets in self.octant_offsets.iter_mut() {
             offsets.retain(|offset| filter(offset));
@@ -244,6 +303,20 @@ where
         (values, points)
     }
 
+    fn query_in_domain(&self, point: &Point3<f32>, domain: u32) -> (Vec<T>, Vec<Point3<f32>>) {
+        let (points, values) = self.nearest_points_and_values_in_domain(point, domain);
+        (values, points)
+    }
+
+    fn domain_boundaries(&self) -> Option<&DomainBoundaries> {
+        self.domains.as_ref().map(|(_, boundaries)| boundaries)
+    }
+
+    fn search_status(&self, point: &Point3<f32>, points: &[Point3<f32>]) -> SearchStatus {
+        self.search
+            .status_of_points(point, points, self.geometry.coordinate_system())
+    }
+
     fn geometry(&self) -> &G {
         &self.geometry
     }
@@ -266,3 +339,165 @@ where
 //         (values, points)
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use nalgebra::{Translation3, UnitQuaternion};
+    use ndarray::Array3;
+
+    use crate::{
+        geometry::ellipsoid::Ellipsoid,
+        spatial_database::{
+            coordinate_system::{octant, CoordinateSystem, GridSpacing},
+            gridded_databases::complete_grid::CompleteGriddedDataBase,
+        },
+    };
+
+    use super::*;
+
+    fn grid(rotation: UnitQuaternion<f32>) -> CompleteGriddedDataBase<f32> {
+        CompleteGriddedDataBase::new(
+            Array3::from_elem((15, 15, 9), 1.0),
+            GridSpacing::new(1.0, 1.5, 2.0),
+            CoordinateSystem::new(Translation3::new(10.0, -5.0, 3.0), rotation),
+        )
+    }
+
+    fn ellipsoid(rotation: UnitQuaternion<f32>) -> Ellipsoid {
+        Ellipsoid::new(
+            5.3,
+            3.1,
+            2.7,
+            CoordinateSystem::new(Translation3::identity(), rotation),
+        )
+    }
+
+    /// Compare the engine neighbourhood of a few nodes against a brute force search
+    fn check_neighbourhoods(
+        grid_rotation: UnitQuaternion<f32>,
+        geometry_rotation: UnitQuaternion<f32>,
+    ) {
+        let gdb = grid(grid_rotation);
+        let engine =
+            GriddedDataBaseOctantQueryEngine::new(ellipsoid(geometry_rotation), &gdb, usize::MAX);
+
+        for ind in [[7, 7, 4], [6, 8, 3], [8, 5, 5]] {
+            let point = gdb.ind_to_point(&ind.map(|i| i as isize));
+            let (inds, _) = engine.nearest_inds_and_points_masked(&point, |_| true);
+
+            let mut search = ellipsoid(geometry_rotation);
+            search.translate_to(&point);
+            let expected = gdb
+                .raw_grid
+                .grid
+                .indexed_iter()
+                .map(|(ind, _)| [ind.0, ind.1, ind.2])
+                .filter(|ind| search.contains(&gdb.ind_to_point(&ind.map(|i| i as isize))))
+                .collect::<HashSet<_>>();
+
+            assert_eq!(inds.iter().copied().collect::<HashSet<_>>(), expected);
+
+            //octants are defined in the coordinate system of the geometry
+            for (sector, offsets) in engine.octant_offsets.iter().enumerate() {
+                for offset in offsets {
+                    let Some(neighbor) =
+                        ind_at_offset(gdb.shape(), ind.map(|i| i as isize), *offset)
+                    else {
+                        continue;
+                    };
+                    let local = search
+                        .coordinate_system
+                        .global_to_local(&gdb.ind_to_point(&neighbor.map(|i| i as isize)));
+                    //nodes on the planes of the geometry may fall on either side
+                    if local.iter().any(|c| c.abs() < 1e-4) {
+                        continue;
+                    }
+                    assert_eq!(octant(&local) as usize, sector);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn aligned_grid_and_geometry() {
+        check_neighbourhoods(UnitQuaternion::identity(), UnitQuaternion::identity());
+    }
+
+    #[test]
+    fn rotated_grid() {
+        check_neighbourhoods(
+            UnitQuaternion::from_euler_angles(0.0, 0.0, 30f32.to_radians()),
+            UnitQuaternion::identity(),
+        );
+    }
+
+    #[test]
+    fn rotated_geometry() {
+        check_neighbourhoods(
+            UnitQuaternion::identity(),
+            UnitQuaternion::from_euler_angles(0.0, 20f32.to_radians(), 45f32.to_radians()),
+        );
+    }
+
+    #[test]
+    fn rotated_grid_and_geometry() {
+        check_neighbourhoods(
+            UnitQuaternion::from_euler_angles(10f32.to_radians(), 0.0, -35f32.to_radians()),
+            UnitQuaternion::from_euler_angles(0.0, 20f32.to_radians(), 60f32.to_radians()),
+        );
+    }
+
+    #[test]
+    fn nearest_ind_of_rotated_grid() {
+        let gdb = grid(UnitQuaternion::from_euler_angles(
+            15f32.to_radians(),
+            25f32.to_radians(),
+            35f32.to_radians(),
+        ));
+
+        for (ind, _) in gdb.raw_grid.grid.indexed_iter() {
+            let ind = [ind.0 as isize, ind.1 as isize, ind.2 as isize];
+            assert_eq!(gdb.coord_to_nearest_ind(&gdb.ind_to_point(&ind)), ind);
+        }
+    }
+
+    #[test]
+    fn closest_node_per_octant() {
+        let grid_rotation =
+            UnitQuaternion::from_euler_angles(5f32.to_radians(), 0.0, 30f32.to_radians());
+        let geometry_rotation =
+            UnitQuaternion::from_euler_angles(0.0, 15f32.to_radians(), -40f32.to_radians());
+        let gdb = grid(grid_rotation);
+        let engine = GriddedDataBaseOctantQueryEngine::new(ellipsoid(geometry_rotation), &gdb, 1);
+
+        let ind = [7, 7, 4];
+        let point = gdb.ind_to_point(&ind);
+        let (inds, _) = engine
+            .nearest_inds_and_points_masked(&point, |neighbor| neighbor != ind.map(|i| i as usize));
+
+        //the retained node of each octant is the closest in anisotropic distance
+        let mut search = ellipsoid(geometry_rotation);
+        search.translate_to(&point);
+        for neighbor in inds {
+            let neighbor_point = gdb.ind_to_point(&neighbor.map(|i| i as isize));
+            let neighbor_octant =
+                octant(&search.coordinate_system.global_to_local(&neighbor_point));
+            let dist = search.normalized_distance(&neighbor_point);
+
+            for (other, _) in gdb.raw_grid.grid.indexed_iter() {
+                let other = [other.0, other.1, other.2];
+                let other_point = gdb.ind_to_point(&other.map(|i| i as isize));
+                if other == ind.map(|i| i as usize)
+                    || !search.contains(&other_point)
+                    || octant(&search.coordinate_system.global_to_local(&other_point))
+                        != neighbor_octant
+                {
+                    continue;
+                }
+                assert!(search.normalized_distance(&other_point) >= dist - 1e-5);
+            }
+        }
+    }
+}
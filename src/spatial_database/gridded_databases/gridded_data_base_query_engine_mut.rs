@@ -1,14 +1,17 @@
 use nalgebra::Point3;
-use ordered_float::OrderedFloat;
 
 use crate::{
     geometry::Geometry,
-    spatial_database::{coordinate_system::octant, SpatialQueryable},
+    spatial_database::{
+        search::{SearchSpecification, SearchStatus, Sectors},
+        SpatialQueryable,
+    },
 };
 
-use super::GriddedDataBaseInterface;
+use super::{ind_at_offset, sector_offsets, GriddedDataBaseInterface};
 
 /// Stores offsets for each octant of a geometry, allowing for fast queries of points in geometry
+/// the grid and geometry may have any orientation, queries are centered on the grid node nearest to the point
 pub struct GriddedDataBaseOctantQueryEngineMut<'a, G, GDB, T>
 where
     G: Geometry,
@@ -17,6 +20,7 @@ where
     pub(crate) octant_offsets: Vec<Vec<[isize; 3]>>,
     pub(crate) geometry: G,
     pub(crate) max_octant_size: usize,
+    pub(crate) search: SearchSpecification,
     pub(crate) db: &'a mut GDB,
     pub(crate) phantom: std::marker::PhantomData<T>,
 }
@@ -38,40 +42,30 @@ where
         //get all offsets for geometry
         let offsets = gdb.offsets_from_ind_in_geometry(&[0, 0, 0], &geometry);
 
-        //splits offsets into octant groups
-        let mut octants = vec![Vec::new(); 8];
-        for offset in offsets {
-            //get point in world space
-            let mut p = gdb.ind_to_point(&offset);
-            //transform to local space of geometry
-            p = geometry
-                .coordinate_system()
-                .world_to_local
-                .transform_point(&p);
-            let octant = octant(&p);
-            octants[octant as usize].push(offset);
-        }
-
-        //sort offsets by iso_distance to ref point
-        octants.iter_mut().for_each(|offset| {
-            offset.sort_by_key(|offset| {
-                //can simply use offset since reference point is origin
-                let p = gdb.ind_to_point(offset);
-                let d = geometry.iso_distance(&p);
-                OrderedFloat(d)
-            });
-        });
+        //splits offsets into octant groups sorted by iso distance
+        let octants = sector_offsets::<_, _, T>(gdb, &geometry, offsets, Sectors::Eight);
 
         //create query engine
         Self {
             octant_offsets: octants,
             geometry,
             max_octant_size,
+            search: SearchSpecification::default(),
             db: gdb,
             phantom: std::marker::PhantomData,
         }
     }
 
+    /// Apply the sectors and minimums of a search specification
+    /// the maximum per sector remains `max_octant_size`, drillhole limits do not apply to grids
+    pub fn with_search(mut self, search: SearchSpecification) -> Self {
+        let offsets = self.octant_offsets.drain(..).flatten().collect();
+        self.octant_offsets =
+            sector_offsets::<_, _, T>(&*self.db, &self.geometry, offsets, search.sectors);
+        self.search = search;
+        self
+    }
+
     /// Get the nearest points and values to a point in the geometry
     /// # Arguments
     /// * `point` - The point to get the nearest points and values for
@@ -79,16 +73,15 @@ where
     /// * `gdb` - The gridded database to use for the query engine
     ///     * must have same grid size and orientation as gdb used for construction of query engine
     pub fn nearest_points_and_values(&self, point: &Point3<f32>) -> (Vec<Point3<f32>>, Vec<T>) {
-        let _ = self.geometry;
-        //this only works if the grid ang geometry have similar orientation
-        //TODO: convert to high ind relative to geometry rotation
-        let point_ind = self.db.coord_to_high_ind(point).map(|x| x as usize);
+        //offsets are relative to the grid node nearest to the point
+        let point_ind = self.db.coord_to_nearest_ind(point);
+        let shape = self.db.shape();
         let mut points = Vec::<Point3<f32>>::new();
         let mut values = Vec::with_capacity(self.max_octant_size * 8);
         for offsets in self.octant_offsets.iter() {
             let mut oct_cnt = 0;
             for offset in offsets {
-                let Some(ind) = self.db.offset_ind(point_ind, *offset) else {
+                let Some(ind) = ind_at_offset(shape, point_ind, *offset) else {
                     continue;
                 };
                 if let Some(v) = self.db.data_at_ind(&ind) {
@@ -120,16 +113,15 @@ where
     where
         F: Fn([usize; 3]) -> bool,
     {
-        let _ = self.geometry;
-        //this only works if the grid ang geometry have similar orientation
-        //TODO: convert to high ind relative to geometry rotation
-        let point_ind = self.db.coord_to_high_ind(point).map(|x| x as usize);
+        //offsets are relative to the grid node nearest to the point
+        let point_ind = self.db.coord_to_nearest_ind(point);
+        let shape = self.db.shape();
         let mut points = Vec::<Point3<f32>>::new();
         let mut values = Vec::with_capacity(self.max_octant_size * 8);
         for offsets in self.octant_offsets.iter() {
             let mut oct_cnt = 0;
             for offset in offsets {
-                let Some(ind) = self.db.offset_ind(point_ind, *offset) else {
+                let Some(ind) = ind_at_offset(shape, point_ind, *offset) else {
                     continue;
                 };
 
@@ -158,15 +150,14 @@ where
     /// * `gdb` - The gridded database to use for the query engine
     ///     * must have same grid size and orientation as gdb used for construction of query engine
     pub fn nearest_inds<F>(&self, point: &Point3<f32>) -> Vec<[usize; 3]> {
-        let _ = self.geometry;
-        //this only works if the grid ang geometry have similar orientation
-        //TODO: convert to high ind relative to geometry rotation
-        let point_ind = self.db.coord_to_high_ind(point).map(|x| x as usize);
+        //offsets are relative to the grid node nearest to the point
+        let point_ind = self.db.coord_to_nearest_ind(point);
+        let shape = self.db.shape();
         let mut inds = Vec::new();
         for offsets in self.octant_offsets.iter() {
             let mut oct_cnt = 0;
             for offset in offsets {
-                let Some(ind) = self.db.offset_ind(point_ind, *offset) else {
+                let Some(ind) = ind_at_offset(shape, point_ind, *offset) else {
                     continue;
                 };
 
@@ -190,9 +181,6 @@ where
     /// * `gdb` - The gridded database to use for the query engine
     ///     * must have same grid size and orientation as gdb used for construction of query engine
     pub fn nearest_inds_to_ind(&self, ind: &[usize; 3]) -> Vec<[usize; 3]> {
-        let _ = self.geometry;
-        //this only works if the grid ang geometry have similar orientation
-        //TODO: convert to high ind relative to geometry rotation
         let mut inds = Vec::new();
         for offsets in self.octant_offsets.iter() {
             let mut oct_cnt = 0;
@@ -221,9 +209,6 @@ where
     /// * `gdb` - The gridded database to use for the query engine
     ///     * must have same grid size and orientation as gdb used for construction of query engine
     pub fn nearest_inds_and_values_to_ind(&self, ind: &[usize; 3]) -> (Vec<[usize; 3]>, Vec<T>) {
-        let _ = self.geometry;
-        //this only works if the grid ang geometry have similar orientation
-        //TODO: convert to high ind relative to geometry rotation
         let mut inds = Vec::new();
         let mut values = Vec::new();
         for offsets in self.octant_offsets.iter() {
@@ -261,16 +246,15 @@ where
     where
         F: Fn([usize; 3]) -> bool,
     {
-        let _ = self.geometry;
-        //this only works if the grid ang geometry have similar orientation
-        //TODO: convert to high ind relative to geometry rotation
-        let point_ind = self.db.coord_to_high_ind(point).map(|x| x as usize);
+        //offsets are relative to the grid node nearest to the point
+        let point_ind = self.db.coord_to_nearest_ind(point);
+        let shape = self.db.shape();
         let mut inds = Vec::new();
         let mut points = Vec::<Point3<f32>>::new();
         for offsets in self.octant_offsets.iter() {
             let mut oct_cnt = 0;
             for offset in offsets {
-                let Some(ind) = self.db.offset_ind(point_ind, *offset) else {
+                let Some(ind) = ind_at_offset(shape, point_ind, *offset) else {
                     continue;
                 };
 
@@ -309,6 +293,11 @@ where
         (values, points)
     }
 
+    fn search_status(&self, point: &Point3<f32>, points: &[Point3<f32>]) -> SearchStatus {
+        self.search
+            .status_of_points(point, points, self.geometry.coordinate_system())
+    }
+
     fn geometry(&self) -> &G {
         &self.geometry
     }
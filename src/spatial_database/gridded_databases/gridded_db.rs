@@ -122,6 +122,19 @@ impl<T> RawGriddedDataBase<T> {
         self.grid_aligned_coord_to_high_ind_with_negative(&point)
     }
 
+    /// Convert a point to the index of the nearest grid node (may be negative)
+    /// rounding makes the index robust to floating point error of rotated grids
+    pub fn coord_to_nearest_ind_with_negative(&self, point: &Point3<f32>) -> [isize; 3] {
+        let point = self.transform_point_to_grid(point);
+        let point = self.normalize_point_to_grid_spacing(&point);
+
+        [
+            point.x.round() as isize,
+            point.y.round() as isize,
+            point.z.round() as isize,
+        ]
+    }
+
     /// Convert a point to a grid index defined by the floor of the normalized local coordinates
     pub fn grid_aligned_coord_to_low_ind(&self, point: &Point3<f32>) -> Option<[usize; 3]> {
         //normalize coords to block size
@@ -325,6 +338,10 @@ where
         self.coord_to_high_ind_with_negative(point)
     }
 
+    fn coord_to_nearest_ind(&self, point: &Point3<f32>) -> [isize; 3] {
+        self.coord_to_nearest_ind_with_negative(point)
+    }
+
     fn offset_ind(&self, ind: [usize; 3], offset: [isize; 3]) -> Option<[usize; 3]> {
         let mut new_ind = ind;
 
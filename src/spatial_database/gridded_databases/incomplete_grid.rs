@@ -97,6 +97,10 @@ where
         self.raw_grid.coord_to_high_ind_with_negative(point)
     }
 
+    fn coord_to_nearest_ind(&self, point: &Point3<f32>) -> [isize; 3] {
+        self.raw_grid.coord_to_nearest_ind_with_negative(point)
+    }
+
     fn offset_ind(&self, ind: [usize; 3], offset: [isize; 3]) -> Option<[usize; 3]> {
         self.raw_grid.offset_ind(ind, offset)
     }
@@ -1,9 +1,13 @@
 use nalgebra::Point3;
+use ordered_float::OrderedFloat;
 use parry3d::bounding_volume::Aabb;
 
 use crate::geometry::Geometry;
 
-use super::coordinate_system::{CoordinateSystem, GridSpacing};
+use super::{
+    coordinate_system::{CoordinateSystem, GridSpacing},
+    search::Sectors,
+};
 
 pub mod complete_grid;
 pub mod gridded_data_base_query_engine;
@@ -14,6 +18,7 @@ pub mod incomplete_grid;
 /// Gridded database interface.
 pub trait GriddedDataBaseInterface<T> {
     fn coord_to_high_ind(&self, point: &Point3<f32>) -> [isize; 3];
+    fn coord_to_nearest_ind(&self, point: &Point3<f32>) -> [isize; 3];
     fn offset_ind(&self, ind: [usize; 3], offset: [isize; 3]) -> Option<[usize; 3]>;
     fn data_at_ind(&self, ind: &[usize; 3]) -> Option<T>;
     fn ind_to_point(&self, ind: &[isize; 3]) -> Point3<f32>;
@@ -37,3 +42,70 @@ pub trait GriddedDataBaseInterface<T> {
     fn grid_spacing(&self) -> GridSpacing;
     fn coordinate_system(&self) -> CoordinateSystem;
 }
+
+/// Split grid offsets into sectors of a geometry, each sector is sorted by iso distance
+/// # Arguments
+/// * `gdb` - The gridded database the offsets belong to
+/// * `geometry` - The geometry (translated to the node at [0, 0, 0])
+/// * `offsets` - Offsets from the node at [0, 0, 0]
+/// * `sectors` - The sectors to split the offsets into
+pub(crate) fn sector_offsets<G, GDB, T>(
+    gdb: &GDB,
+    geometry: &G,
+    offsets: Vec<[isize; 3]>,
+    sectors: Sectors,
+) -> Vec<Vec<[isize; 3]>>
+where
+    G: Geometry,
+    GDB: GriddedDataBaseInterface<T>,
+{
+    let mut groups = vec![Vec::new(); sectors.count()];
+    for offset in offsets {
+        //get point in world space
+        let mut p = gdb.ind_to_point(&offset);
+        //transform to local space of geometry
+        p = geometry
+            .coordinate_system()
+            .world_to_local
+            .transform_point(&p);
+        let sector = sectors.sector(&p);
+        groups[sector as usize].push(offset);
+    }
+
+    //sort offsets by iso_distance to ref point
+    groups.iter_mut().for_each(|offset| {
+        offset.sort_by_key(|offset| {
+            //can simply use offset since reference point is origin
+            let p = gdb.ind_to_point(offset);
+            let d = geometry.iso_distance(&p);
+            OrderedFloat(d)
+        });
+    });
+
+    groups
+}
+
+/// Index of the node at an offset from a reference node
+/// # Arguments
+/// * `shape` - Shape of the grid
+/// * `ind` - Index of the reference node (may lie outside of the grid)
+/// * `offset` - Offset from the reference node
+/// # Returns
+/// None if the node lies outside of the grid
+#[inline(always)]
+pub(crate) fn ind_at_offset(
+    shape: [usize; 3],
+    ind: [isize; 3],
+    offset: [isize; 3],
+) -> Option<[usize; 3]> {
+    let mut new_ind = [0; 3];
+    for i in 0..3 {
+        let v = ind[i] + offset[i];
+        if v < 0 || v as usize >= shape[i] {
+            return None;
+        }
+        new_ind[i] = v as usize;
+    }
+
+    Some(new_ind)
+}
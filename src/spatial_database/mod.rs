@@ -5,12 +5,18 @@ use parry3d::bounding_volume::Aabb;
 
 use crate::geometry::ellipsoid::Ellipsoid;
 
+use self::domain_boundaries::DomainBoundaries;
 use self::gridded_databases::GriddedDataBaseInterface;
+use self::search::SearchStatus;
 
+pub mod capping;
 pub mod coordinate_system;
+pub mod domain_boundaries;
+pub mod drillholes;
 pub mod gridded_databases;
 pub mod normalized;
 pub mod qbvh;
+pub mod search;
 
 pub trait SpatialDataBase<T> {
     type INDEX: Debug;
@@ -77,6 +83,19 @@ impl_spatial_database_for_grid!(
 
 pub trait SpatialQueryable<T, G> {
     fn query(&self, point: &Point3<f32>) -> (Vec<T>, Vec<Point3<f32>>);
+    /// Query for a target in the given domain
+    /// only providers holding domain information (see `domain_boundaries`) answer domain queries
+    fn query_in_domain(&self, _point: &Point3<f32>, _domain: u32) -> (Vec<T>, Vec<Point3<f32>>) {
+        panic!("conditioning data hold no domain information");
+    }
+    /// Boundary conditions applied to data of other domains, None for providers without domain information
+    fn domain_boundaries(&self) -> Option<&DomainBoundaries> {
+        None
+    }
+    /// Status of the search which found the given points around a target
+    fn search_status(&self, _point: &Point3<f32>, _points: &[Point3<f32>]) -> SearchStatus {
+        SearchStatus::Satisfied
+    }
     fn geometry(&self) -> &G;
 }
 
@@ -87,4 +106,70 @@ pub trait ConditioningProvider<G, T, P> {
         ellipsoid: &G,
         params: &P,
     ) -> (Vec<usize>, Vec<T>, Vec<Point3<f32>>);
+
+    /// Query for a target in the given domain, the boundary conditions are part of the parameters
+    /// only providers holding domain information (see `has_domains`) answer domain queries
+    fn query_in_domain(
+        &self,
+        _point: &Point3<f32>,
+        _domain: u32,
+        _ellipsoid: &G,
+        _params: &P,
+    ) -> (Vec<usize>, Vec<T>, Vec<Point3<f32>>) {
+        panic!("conditioning data hold no domain information");
+    }
+
+    /// Whether the data hold domain codes
+    fn has_domains(&self) -> bool {
+        false
+    }
+
+    /// Status of the search which found the given points around a target
+    fn search_status(
+        &self,
+        _point: &Point3<f32>,
+        _points: &[Point3<f32>],
+        _ellipsoid: &G,
+        _params: &P,
+    ) -> SearchStatus {
+        SearchStatus::Satisfied
+    }
+}
+
+impl<C, G, T, P> ConditioningProvider<G, T, P> for &C
+where
+    C: ConditioningProvider<G, T, P>,
+{
+    fn query(
+        &self,
+        point: &Point3<f32>,
+        ellipsoid: &G,
+        params: &P,
+    ) -> (Vec<usize>, Vec<T>, Vec<Point3<f32>>) {
+        (*self).query(point, ellipsoid, params)
+    }
+
+    fn query_in_domain(
+        &self,
+        point: &Point3<f32>,
+        domain: u32,
+        ellipsoid: &G,
+        params: &P,
+    ) -> (Vec<usize>, Vec<T>, Vec<Point3<f32>>) {
+        (*self).query_in_domain(point, domain, ellipsoid, params)
+    }
+
+    fn has_domains(&self) -> bool {
+        (*self).has_domains()
+    }
+
+    fn search_status(
+        &self,
+        point: &Point3<f32>,
+        points: &[Point3<f32>],
+        ellipsoid: &G,
+        params: &P,
+    ) -> SearchStatus {
+        (*self).search_status(point, points, ellipsoid, params)
+    }
 }
@@ -1,15 +1,21 @@
 use crate::{
     geometry::ellipsoid::Ellipsoid,
-    spatial_database::{coordinate_system::octant, qbvh::point_set::PointSet},
+    spatial_database::{
+        domain_boundaries::DomainBoundaries,
+        qbvh::point_set::{DistanceRanking, PointSet},
+        search::{SearchSpecification, Sectors},
+    },
 };
 
 use nalgebra::{distance, Point3, SimdBool as _, SimdPartialOrd, SimdValue};
+use num_traits::ToPrimitive;
 use parry3d::{
     bounding_volume::SimdAabb,
     math::{SimdBool, SimdReal, SIMD_WIDTH},
     partitioning::SimdBestFirstVisitStatus,
     simba::simd::AutoSimd,
 };
+use std::collections::HashMap;
 
 use super::simd_n_best_first_visitor::SimdNBestFirstVisitor;
 
@@ -21,7 +27,8 @@ pub enum InsertionResult {
 }
 
 /// A visitor the computes the conditioning data for a simulation point
-/// the closest n_cond points are retained in each octant
+/// the closest n_cond points are retained in each sector (octant_* members are indexed by sector)
+/// closeness is measured by the ranking key, distances stored in the octants are ranking keys
 pub struct ConditioningDataCollector<'a, 'b, T> {
     pub n_cond: usize,
     pub point: Point3<f32>,
@@ -35,6 +42,12 @@ pub struct ConditioningDataCollector<'a, 'b, T> {
     pub octant_max_inds: Vec<usize>,
     pub octant_max_distances: Vec<f32>,
     pub full_octants: u8,
+    pub outlier_restriction: Option<(f32, Ellipsoid)>,
+    pub domain_constraint: Option<(u32, &'b DomainBoundaries)>,
+    pub ranking: DistanceRanking,
+    pub sectors: Sectors,
+    pub max_per_hole: Option<usize>,
+    pub candidates: Vec<(f32, u32)>,
 }
 
 impl<'a, 'b, T> ConditioningDataCollector<'a, 'b, T> {
@@ -62,9 +75,92 @@ impl<'a, 'b, T> ConditioningDataCollector<'a, 'b, T> {
             octant_max_inds: vec![0; 8],
             octant_max_distances: vec![f32::MAX; 8],
             full_octants: 0,
+            outlier_restriction: None,
+            domain_constraint: None,
+            ranking: DistanceRanking::default(),
+            sectors: Sectors::default(),
+            max_per_hole: None,
+            candidates: Vec::new(),
         }
     }
 
+    /// Apply the sectors and drillhole limit of a search specification
+    /// the drillhole limit is ignored if the point set has no hole ids
+    pub fn set_search(&mut self, search: &SearchSpecification) {
+        self.sectors = search.sectors;
+        self.max_per_hole = search
+            .max_per_hole
+            .filter(|_| self.point_set.hole_ids.is_some());
+    }
+
+    /// Select the closest candidates while honouring the drillhole limit
+    /// candidates are only gathered during traversal when a drillhole limit is set
+    pub fn select_candidates(&mut self) {
+        let (Some(max_per_hole), Some(hole_ids)) =
+            (self.max_per_hole, self.point_set.hole_ids.as_ref())
+        else {
+            return;
+        };
+
+        let mut candidates = std::mem::take(&mut self.candidates)
+            .into_iter()
+            .map(|(dist, ind)| {
+                let point = self.point_set.points[ind as usize];
+                let key = self.ranking.key(&point, &self.point, dist, self.ellipsoid);
+                (key, dist, ind)
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        let mut hole_counts = HashMap::new();
+        for (_, dist, ind) in candidates {
+            let count = hole_counts.entry(hole_ids[ind as usize]).or_insert(0);
+            if *count >= max_per_hole {
+                continue;
+            }
+
+            let point = self.point_set.points[ind as usize];
+            match self.insert_octant_point(point, dist, ind) {
+                InsertionResult::InsertedNotFull | InsertionResult::InsertedFull => *count += 1,
+                _ => {}
+            }
+        }
+    }
+
+    /// Set the ordering used to retain the closest points
+    pub fn set_ranking(&mut self, ranking: DistanceRanking) {
+        self.ranking = ranking;
+    }
+
+    /// Euclidean distance beyond which no point can replace an accepted point
+    #[inline(always)]
+    pub fn traversal_bound(&self) -> f32 {
+        self.ranking
+            .euclidean_bound(self.max_accepted_dist, self.ellipsoid)
+    }
+
+    /// Only accept values above the threshold within the given (already translated) ellipsoid
+    pub fn set_outlier_restriction(&mut self, threshold: f32, ellipsoid: Ellipsoid) {
+        self.outlier_restriction = Some((threshold, ellipsoid));
+    }
+
+    /// Only accept points whose domain may inform a target in `target_domain`
+    pub fn set_domain_constraint(&mut self, target_domain: u32, boundaries: &'b DomainBoundaries) {
+        self.domain_constraint = Some((target_domain, boundaries));
+    }
+
+    /// Checks if the point belongs to a domain which may not inform the target
+    #[inline(always)]
+    pub fn is_excluded_domain(&self, ind: u32, dist: f32) -> bool {
+        let (Some((target_domain, boundaries)), Some(domains)) =
+            (self.domain_constraint, self.point_set.domains.as_ref())
+        else {
+            return false;
+        };
+
+        !boundaries.accepts(target_domain, domains[ind as usize], dist)
+    }
+
     #[inline(always)]
     fn update_max_octant_ind(&mut self, octant: usize) {
         self.octant_max_inds[octant] = self.octant_distances[octant]
@@ -83,7 +179,7 @@ impl<'a, 'b, T> ConditioningDataCollector<'a, 'b, T> {
 
     #[inline(always)]
     pub fn all_octants_full(&self) -> bool {
-        self.full_octants == 8
+        self.full_octants as usize == self.sectors.count()
     }
 
     #[inline(always)]
@@ -98,6 +194,11 @@ impl<'a, 'b, T> ConditioningDataCollector<'a, 'b, T> {
         }
     }
 
+    /// Insert a point into its octant if it is among the closest points
+    /// # Arguments
+    /// * `point` - The candidate point
+    /// * `dist` - Euclidean distance from the candidate point to the query point
+    /// * `ind` - Index of the candidate point in the point set
     #[inline(always)]
     pub fn insert_octant_point(
         &mut self,
@@ -105,9 +206,9 @@ impl<'a, 'b, T> ConditioningDataCollector<'a, 'b, T> {
         dist: f32,
         ind: u32,
     ) -> InsertionResult {
-        // if point is further away the primary ellipsoid axis then it cannot be in the ellipsoid
+        // if point is further away the longest ellipsoid axis then it cannot be in the ellipsoid
         // and no further points can be in the ellipsoid
-        if self.ellipsoid.a < dist {
+        if self.ellipsoid.max_axis() < dist {
             return InsertionResult::NotInserted;
         }
         //check if point in ellipsoid
@@ -115,9 +216,12 @@ impl<'a, 'b, T> ConditioningDataCollector<'a, 'b, T> {
             return InsertionResult::NotInserted;
         }
 
+        //rank point by the configured ordering
+        let dist = self.ranking.key(&point, &self.point, dist, self.ellipsoid);
+
         //determine octant of point in ellispoid coordinate system
         let local_point = self.ellipsoid.coordinate_system.global_to_local(&point);
-        let octant = octant(&local_point);
+        let octant = self.sectors.sector(&local_point);
 
         //get octant points and distances
         let points = &mut self.octant_points[octant as usize];
@@ -173,8 +277,29 @@ impl<'a, 'b, T> ConditioningDataCollector<'a, 'b, T> {
     }
 }
 
+impl<'a, 'b, T> ConditioningDataCollector<'a, 'b, T>
+where
+    T: ToPrimitive,
+{
+    /// Checks if the point is an outlier lying outside of the restricted ellipsoid
+    #[inline(always)]
+    pub fn is_restricted_outlier(&self, point: &Point3<f32>, ind: u32) -> bool {
+        let Some((threshold, ellipsoid)) = &self.outlier_restriction else {
+            return false;
+        };
+
+        let is_outlier = self.point_set.data[ind as usize]
+            .to_f32()
+            .map_or(false, |v| v > *threshold);
+
+        is_outlier && !ellipsoid.contains(point)
+    }
+}
+
 impl<'a, 'b, LeafData, T> SimdNBestFirstVisitor<LeafData, SimdAabb>
     for ConditioningDataCollector<'a, 'b, T>
+where
+    T: ToPrimitive,
 {
     type Result = ();
 
@@ -185,6 +310,9 @@ impl<'a, 'b, LeafData, T> SimdNBestFirstVisitor<LeafData, SimdAabb>
         data: Option<[Option<&u32>; SIMD_WIDTH]>,
     ) -> SimdBestFirstVisitStatus<Self::Result> {
         //mask to select only element with dist less than current furthest point
+        if self.max_per_hole.is_some() {
+            *threshold = threshold.min(self.ellipsoid.max_axis());
+        }
         let dists = bv.distance_to_local_point(&Point3::splat(self.point));
         let mask = dists.simd_lt(SimdReal::splat(*threshold));
 
@@ -203,6 +331,24 @@ impl<'a, 'b, LeafData, T> SimdNBestFirstVisitor<LeafData, SimdAabb>
                     // get distance from query point to point
                     let dist = distance(&point, &self.point);
 
+                    // outliers beyond the restricted ellipsoid and samples across hard
+                    // boundaries are never accepted for this target
+                    if self.is_restricted_outlier(&point, part_id)
+                        || self.is_excluded_domain(part_id, dist)
+                    {
+                        continue;
+                    }
+
+                    //with a drillhole limit the closest points are selected after traversal
+                    //the search is then only bounded by the ellipsoid
+                    if self.max_per_hole.is_some() {
+                        if dist <= self.ellipsoid.max_axis() {
+                            self.candidates.push((dist, part_id));
+                        }
+                        weights[ii] = dist;
+                        continue;
+                    }
+
                     //insert point if distance is less than current furthest point
                     match self.insert_octant_point(point, dist, part_id) {
                         InsertionResult::InsertedNotFull => {
@@ -211,7 +357,9 @@ impl<'a, 'b, LeafData, T> SimdNBestFirstVisitor<LeafData, SimdAabb>
                         }
                         InsertionResult::InsertedFull => {
                             //Conditioning set full -> must updarte threshold
-                            *threshold = self.max_accepted_dist;
+                            if self.all_octants_full() {
+                                *threshold = self.traversal_bound();
+                            }
                             //If inserted dist <= threshold
                             mask[ii] = true;
                         }
@@ -245,10 +393,14 @@ mod tests {
     use std::hint::black_box;
 
     use crate::spatial_database::coordinate_system::CoordinateSystem;
+    use crate::spatial_database::domain_boundaries::SoftBoundary;
     use crate::spatial_database::qbvh::n_best_first::NBestFirst;
+    use crate::spatial_database::qbvh::point_set::{ConditioningParams, OutlierRestriction};
+    use crate::spatial_database::ConditioningProvider;
+    use crate::variography::model_variograms::spherical::SphericalVariogram;
 
     use super::*;
-    use nalgebra::distance;
+    use nalgebra::{distance, Vector3};
     use parry3d::bounding_volume::Aabb;
     use rand;
     use rand::rngs::ThreadRng;
@@ -438,6 +590,123 @@ mod tests {
         // println!("walker points: {:?}", cond_points.closest_points);
     }
 
+    #[test]
+    fn outlier_restriction() {
+        let points = vec![
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(5.0, 0.0, 0.0),
+            Point3::new(-10.0, 0.0, 0.0),
+            Point3::new(0.0, 1.5, 0.0),
+        ];
+        let data = vec![0.0f32, 10.0, 0.0, 12.0];
+        let point_set = PointSet::new(points, data);
+        let query_point = Point3::new(0.0, 0.0, 0.0);
+
+        let quat = nalgebra::UnitQuaternion::identity();
+        let cs = CoordinateSystem::new(query_point.coords.into(), quat);
+        let ellipsoid = Ellipsoid::new(20f32, 20f32, 20f32, cs);
+        let restricted_ellipsoid = Ellipsoid::new(2f32, 2f32, 2f32, cs);
+
+        let params = ConditioningParams::new(4)
+            .with_outlier_restriction(OutlierRestriction::new(5.0, restricted_ellipsoid));
+        let (mut inds, _, _) = point_set.query(&query_point, &ellipsoid, &params);
+        inds.sort();
+
+        //the outlier at 5 lies outside of the restricted ellipsoid
+        assert_eq!(inds, vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn anisotropic_ranking() {
+        let points = vec![Point3::new(0.5, 0.5, 0.0), Point3::new(3.0, 0.0, 0.0)];
+        let data = vec![0.0f32; 2];
+        let point_set = PointSet::new(points, data);
+        let query_point = Point3::new(0.0, 0.0, 0.0);
+
+        let quat = nalgebra::UnitQuaternion::identity();
+        let cs = CoordinateSystem::new(query_point.coords.into(), quat);
+        let ellipsoid = Ellipsoid::new(10f32, 1f32, 1f32, cs);
+
+        //the sample along the major axis is closer in anisotropic distance
+        let params = ConditioningParams::new(1);
+        let (inds, _, _) = point_set.query(&query_point, &ellipsoid, &params);
+        assert_eq!(inds, vec![1]);
+
+        let params = ConditioningParams::new(1).with_ranking(DistanceRanking::Euclidean);
+        let (inds, _, _) = point_set.query(&query_point, &ellipsoid, &params);
+        assert_eq!(inds, vec![0]);
+
+        //variogram continuity along y favours the first sample
+        let vgram = SphericalVariogram::new(Vector3::new(1.0, 10.0, 1.0), 1.0, 0.0, cs);
+        let params = ConditioningParams::new(1)
+            .with_ranking(DistanceRanking::Covariance(std::sync::Arc::new(vgram)));
+        let (inds, _, _) = point_set.query(&query_point, &ellipsoid, &params);
+        assert_eq!(inds, vec![0]);
+    }
+
+    #[test]
+    fn sectors_and_max_per_hole() {
+        let points = vec![
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(2.0, 0.0, 0.0),
+            Point3::new(3.0, 0.0, 0.0),
+            Point3::new(-4.0, 0.0, 0.0),
+            Point3::new(-5.0, 0.0, 0.0),
+        ];
+        let data = vec![0.0f32; 5];
+        let point_set = PointSet::new(points, data).with_hole_ids(vec![1, 1, 1, 2, 2]);
+        let query_point = Point3::new(0.0, 0.0, 0.0);
+
+        let quat = nalgebra::UnitQuaternion::identity();
+        let cs = CoordinateSystem::new(query_point.coords.into(), quat);
+        let ellipsoid = Ellipsoid::new(20f32, 20f32, 20f32, cs);
+
+        //a single sector keeps the closest points regardless of direction
+        let params = ConditioningParams::new(3).with_search(SearchSpecification::new(Sectors::One));
+        let (mut inds, _, _) = point_set.query(&query_point, &ellipsoid, &params);
+        inds.sort();
+        assert_eq!(inds, vec![0, 1, 2]);
+
+        let params = ConditioningParams::new(3)
+            .with_search(SearchSpecification::new(Sectors::One).with_max_per_hole(2));
+        let (mut inds, _, _) = point_set.query(&query_point, &ellipsoid, &params);
+        inds.sort();
+        assert_eq!(inds, vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn domain_constraint() {
+        let points = vec![
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(2.0, 0.0, 0.0),
+            Point3::new(-1.0, 0.0, 0.0),
+            Point3::new(0.0, 8.0, 0.0),
+        ];
+        let data = vec![0.0f32; 4];
+        let point_set = PointSet::new(points, data).with_domains(vec![1, 2, 3, 2]);
+        let query_point = Point3::new(0.0, 0.0, 0.0);
+
+        let quat = nalgebra::UnitQuaternion::identity();
+        let cs = CoordinateSystem::new(query_point.coords.into(), quat);
+        let ellipsoid = Ellipsoid::new(20f32, 20f32, 20f32, cs);
+
+        let hard = ConditioningParams::new(4).with_domain_boundaries(DomainBoundaries::Hard);
+        let (inds, _, _) = point_set.query_in_domain(&query_point, 1, &ellipsoid, &hard);
+        assert_eq!(inds, vec![0]);
+
+        //domain 2 is shared with domain 1 within a distance of 5
+        let soft = ConditioningParams::new(4).with_domain_boundaries(DomainBoundaries::Soft(vec![
+            SoftBoundary::new(1, 2, Some(5.0)),
+        ]));
+        let (mut inds, _, _) = point_set.query_in_domain(&query_point, 1, &ellipsoid, &soft);
+        inds.sort();
+        assert_eq!(inds, vec![0, 1]);
+
+        //domains are ignored by plain queries
+        let (inds, _, _) = point_set.query(&query_point, &ellipsoid, &hard);
+        assert_eq!(inds.len(), 4);
+    }
+
     #[test]
     fn speed_test() {
         let n_points = 1_000_000;
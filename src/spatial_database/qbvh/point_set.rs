@@ -6,10 +6,18 @@ use rstar::Point;
 use std::collections::HashMap;
 use std::error;
 use std::str::FromStr;
+use std::sync::Arc;
+
+use num_traits::ToPrimitive;
 
 use crate::{
-    geometry::ellipsoid::Ellipsoid,
-    spatial_database::{ConditioningProvider, SpatialDataBase},
+    geometry::{ellipsoid::Ellipsoid, Geometry},
+    spatial_database::{
+        domain_boundaries::DomainBoundaries,
+        search::{SearchSpecification, SearchStatus},
+        ConditioningProvider, SpatialDataBase,
+    },
+    variography::model_variograms::VariogramModel,
 };
 
 use super::conditioning_data_collector::ConditioningDataCollector;
@@ -20,6 +28,9 @@ pub struct PointSet<T> {
     pub points: Vec<Point3<f32>>,
     pub data: Vec<T>,
     pub tree: Qbvh<u32>,
+    pub hole_ids: Option<Vec<u32>>,
+    pub lengths: Option<Vec<f32>>,
+    pub domains: Option<Vec<u32>>,
 }
 
 impl<T> PointSet<T> {
@@ -33,7 +44,35 @@ impl<T> PointSet<T> {
                 .map(|(i, point)| (i as u32, Aabb::new(point.clone(), point.clone()))),
             0.0,
         );
-        PointSet { points, data, tree }
+        PointSet {
+            points,
+            data,
+            tree,
+            hole_ids: None,
+            lengths: None,
+            domains: None,
+        }
+    }
+
+    /// Attach drillhole ids to the points
+    pub fn with_hole_ids(mut self, hole_ids: Vec<u32>) -> Self {
+        assert_eq!(hole_ids.len(), self.points.len());
+        self.hole_ids = Some(hole_ids);
+        self
+    }
+
+    /// Attach sample lengths to the points
+    pub fn with_lengths(mut self, lengths: Vec<f32>) -> Self {
+        assert_eq!(lengths.len(), self.points.len());
+        self.lengths = Some(lengths);
+        self
+    }
+
+    /// Attach domain codes to the points
+    pub fn with_domains(mut self, domains: Vec<u32>) -> Self {
+        assert_eq!(domains.len(), self.points.len());
+        self.domains = Some(domains);
+        self
     }
 }
 
@@ -105,30 +144,155 @@ where
     }
 }
 
+/// Restricts the use of high values to a reduced search ellipsoid
+/// # Members
+/// * `threshold` - Values above the threshold are considered outliers
+/// * `ellipsoid` - Reduced search ellipsoid (translated to each target) within which outliers are used
+#[derive(Clone, Debug)]
+pub struct OutlierRestriction {
+    pub threshold: f32,
+    pub ellipsoid: Ellipsoid,
+}
+
+impl OutlierRestriction {
+    pub fn new(threshold: f32, ellipsoid: Ellipsoid) -> Self {
+        Self {
+            threshold,
+            ellipsoid,
+        }
+    }
+}
+
+/// Ordering used to retain the closest conditioning data in each octant
+#[derive(Clone, Default)]
+pub enum DistanceRanking {
+    /// Euclidean distance to the target
+    Euclidean,
+    /// Distance to the target normalized by the axes of the search ellipsoid
+    #[default]
+    Anisotropic,
+    /// Decreasing covariance with the target
+    Covariance(Arc<dyn VariogramModel + Send + Sync>),
+}
+
+impl DistanceRanking {
+    /// Ranking key of a point (smaller is better)
+    /// # Arguments
+    /// * `point` - The candidate point
+    /// * `target` - The target point
+    /// * `dist` - Euclidean distance between the point and the target
+    /// * `ellipsoid` - The search ellipsoid (centered on the target)
+    #[inline(always)]
+    pub fn key(
+        &self,
+        point: &Point3<f32>,
+        target: &Point3<f32>,
+        dist: f32,
+        ellipsoid: &Ellipsoid,
+    ) -> f32 {
+        match self {
+            DistanceRanking::Euclidean => dist,
+            DistanceRanking::Anisotropic => ellipsoid.normalized_distance(point),
+            DistanceRanking::Covariance(variogram) => variogram.variogram(point - target),
+        }
+    }
+
+    /// Euclidean distance beyond which no point can have a key below `key`
+    /// used to keep the traversal pruning of the Qbvh correct
+    #[inline(always)]
+    pub fn euclidean_bound(&self, key: f32, ellipsoid: &Ellipsoid) -> f32 {
+        match self {
+            DistanceRanking::Euclidean => key,
+            //the normalized distance is at least the euclidean distance over the longest axis
+            DistanceRanking::Anisotropic => key * ellipsoid.max_axis(),
+            //the covariance is not bounded by distance, only the ellipsoid limits the search
+            DistanceRanking::Covariance(_) => ellipsoid.max_axis(),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct ConditioningParams {
     pub max_n_cond: usize,
+    pub ranking: DistanceRanking,
+    pub search: SearchSpecification,
+    pub outlier_restriction: Option<OutlierRestriction>,
+    pub domain_boundaries: DomainBoundaries,
 }
 
 impl ConditioningParams {
     pub fn new(max_n_cond: usize) -> Self {
-        Self { max_n_cond }
+        Self {
+            max_n_cond,
+            ranking: DistanceRanking::default(),
+            search: SearchSpecification::default(),
+            outlier_restriction: None,
+            domain_boundaries: DomainBoundaries::default(),
+        }
+    }
+
+    /// Ordering used to retain the closest conditioning data
+    pub fn with_ranking(mut self, ranking: DistanceRanking) -> Self {
+        self.ranking = ranking;
+        self
+    }
+
+    /// Sectors, minimums and drillhole limits of the search
+    /// `max_n_cond` remains the maximum number of samples per sector
+    pub fn with_search(mut self, search: SearchSpecification) -> Self {
+        self.search = search;
+        self
+    }
+
+    /// Boundary conditions applied to domain queries
+    pub fn with_domain_boundaries(mut self, domain_boundaries: DomainBoundaries) -> Self {
+        self.domain_boundaries = domain_boundaries;
+        self
+    }
+
+    /// Only use values above the threshold within the reduced ellipsoid
+    pub fn with_outlier_restriction(mut self, outlier_restriction: OutlierRestriction) -> Self {
+        self.outlier_restriction = Some(outlier_restriction);
+        self
     }
 }
 
-impl<T> ConditioningProvider<Ellipsoid, T, ConditioningParams> for PointSet<T>
+impl<T> PointSet<T>
 where
-    T: Clone,
+    T: Clone + ToPrimitive,
 {
-    fn query(
+    /// Collect conditioning data around a point
+    /// # Arguments
+    /// * `point` - The target point
+    /// * `domain` - Domain of the target (None for no domain constraint)
+    /// * `ellipsoid` - The search ellipsoid (centered on the target)
+    /// * `params` - The conditioning parameters
+    fn collect_conditioning_data(
         &self,
         point: &Point3<f32>,
+        domain: Option<u32>,
         ellipsoid: &Ellipsoid,
         params: &ConditioningParams,
     ) -> (Vec<usize>, Vec<T>, Vec<Point3<f32>>) {
         let mut cond_points =
             ConditioningDataCollector::new(*point, ellipsoid, params.max_n_cond, &self);
+        cond_points.set_ranking(params.ranking.clone());
+        cond_points.set_search(&params.search);
+
+        //outliers are only accepted within the reduced ellipsoid centered on the target
+        if let Some(restriction) = &params.outlier_restriction {
+            let mut restricted_ellipsoid = restriction.ellipsoid.clone();
+            restricted_ellipsoid.translate_to(point);
+            cond_points.set_outlier_restriction(restriction.threshold, restricted_ellipsoid);
+        }
+
+        //samples from other domains are only accepted across permitted boundaries
+        if let Some(domain) = domain {
+            cond_points.set_domain_constraint(domain, &params.domain_boundaries);
+        }
 
         let _ = self.tree.traverse_n_best_first(&mut cond_points);
+        cond_points.select_candidates();
 
         let inds = cond_points
             .octant_inds
@@ -142,3 +306,47 @@ where
         (inds, data, points)
     }
 }
+
+impl<T> ConditioningProvider<Ellipsoid, T, ConditioningParams> for PointSet<T>
+where
+    T: Clone + ToPrimitive,
+{
+    fn query(
+        &self,
+        point: &Point3<f32>,
+        ellipsoid: &Ellipsoid,
+        params: &ConditioningParams,
+    ) -> (Vec<usize>, Vec<T>, Vec<Point3<f32>>) {
+        self.collect_conditioning_data(point, None, ellipsoid, params)
+    }
+
+    fn query_in_domain(
+        &self,
+        point: &Point3<f32>,
+        domain: u32,
+        ellipsoid: &Ellipsoid,
+        params: &ConditioningParams,
+    ) -> (Vec<usize>, Vec<T>, Vec<Point3<f32>>) {
+        assert!(
+            self.has_domains(),
+            "conditioning data hold no domain information"
+        );
+        self.collect_conditioning_data(point, Some(domain), ellipsoid, params)
+    }
+
+    fn has_domains(&self) -> bool {
+        self.domains.is_some()
+    }
+
+    fn search_status(
+        &self,
+        point: &Point3<f32>,
+        points: &[Point3<f32>],
+        ellipsoid: &Ellipsoid,
+        params: &ConditioningParams,
+    ) -> SearchStatus {
+        params
+            .search
+            .status_of_points(point, points, &ellipsoid.coordinate_system)
+    }
+}
@@ -0,0 +1,227 @@
+use nalgebra::Point3;
+
+use super::coordinate_system::{octant, CoordinateSystem};
+
+/// Number of angular sectors conditioning data is spread over
+/// sectors are defined in the local coordinate system of the search geometry
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Sectors {
+    /// No sectors, the closest data are used
+    One,
+    /// Quadrants of the local xy plane
+    Four,
+    /// Octants
+    #[default]
+    Eight,
+}
+
+impl Sectors {
+    /// Number of sectors
+    #[inline(always)]
+    pub fn count(&self) -> usize {
+        match self {
+            Sectors::One => 1,
+            Sectors::Four => 4,
+            Sectors::Eight => 8,
+        }
+    }
+
+    /// Sector of a point in the local coordinates of the search geometry
+    #[inline(always)]
+    pub fn sector(&self, local_point: &Point3<f32>) -> u8 {
+        match self {
+            Sectors::One => 0,
+            //octants 0-3 and 4-7 share the same quadrant above and below the xy plane
+            Sectors::Four => octant(local_point) % 4,
+            Sectors::Eight => octant(local_point),
+        }
+    }
+}
+
+/// Outcome for targets whose search does not meet the minimums
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InsufficientDataRule {
+    /// The target is not estimated
+    Skip,
+    /// The target is estimated with the available data and flagged
+    #[default]
+    Flag,
+    /// The target is assigned the given value and flagged
+    Fallback(f32),
+}
+
+/// Result of a search
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchStatus {
+    Satisfied,
+    Insufficient(InsufficientDataRule),
+}
+
+impl SearchStatus {
+    /// Value assigned to a target given the status of its search
+    /// # Arguments
+    /// * `estimate` - Computes the estimate from the available data (only called if required)
+    /// # Returns
+    /// None if the target is skipped
+    #[inline(always)]
+    pub fn resolve<F>(&self, estimate: F) -> Option<f32>
+    where
+        F: FnOnce() -> f32,
+    {
+        match self {
+            SearchStatus::Satisfied | SearchStatus::Insufficient(InsufficientDataRule::Flag) => {
+                Some(estimate())
+            }
+            SearchStatus::Insufficient(InsufficientDataRule::Skip) => None,
+            SearchStatus::Insufficient(InsufficientDataRule::Fallback(value)) => Some(*value),
+        }
+    }
+}
+
+/// Search specification shared by the conditioning data providers
+/// the maximum per sector is set by the provider (`max_n_cond` or `max_octant_size`)
+/// # Members
+/// * `sectors` - Number of sectors
+/// * `min_per_sector` - Minimum number of samples for a sector to count as informed
+/// * `min_total` - Minimum total number of samples
+/// * `min_informed_sectors` - Minimum number of informed sectors
+/// * `max_per_hole` - Maximum number of samples from a single drillhole
+/// * `insufficient_data` - Outcome when the minimums are not met
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchSpecification {
+    pub sectors: Sectors,
+    pub min_per_sector: usize,
+    pub min_total: usize,
+    pub min_informed_sectors: usize,
+    pub max_per_hole: Option<usize>,
+    pub insufficient_data: InsufficientDataRule,
+}
+
+impl Default for SearchSpecification {
+    fn default() -> Self {
+        Self::new(Sectors::default())
+    }
+}
+
+impl SearchSpecification {
+    /// Create a search specification without minimums
+    pub fn new(sectors: Sectors) -> Self {
+        Self {
+            sectors,
+            min_per_sector: 1,
+            min_total: 0,
+            min_informed_sectors: 0,
+            max_per_hole: None,
+            insufficient_data: InsufficientDataRule::default(),
+        }
+    }
+
+    /// Set the minimums of the search
+    /// # Arguments
+    /// * `min_total` - Minimum total number of samples
+    /// * `min_per_sector` - Minimum number of samples for a sector to count as informed
+    /// * `min_informed_sectors` - Minimum number of informed sectors
+    pub fn with_minimums(
+        mut self,
+        min_total: usize,
+        min_per_sector: usize,
+        min_informed_sectors: usize,
+    ) -> Self {
+        self.min_total = min_total;
+        self.min_per_sector = min_per_sector;
+        self.min_informed_sectors = min_informed_sectors;
+        self
+    }
+
+    /// Limit the number of samples taken from a single drillhole
+    pub fn with_max_per_hole(mut self, max_per_hole: usize) -> Self {
+        self.max_per_hole = Some(max_per_hole);
+        self
+    }
+
+    /// Set the outcome when the minimums are not met
+    pub fn with_insufficient_data_rule(mut self, rule: InsufficientDataRule) -> Self {
+        self.insufficient_data = rule;
+        self
+    }
+
+    /// Status of a search given the number of samples found in each sector
+    pub fn status(&self, sector_counts: &[usize]) -> SearchStatus {
+        let total = sector_counts.iter().sum::<usize>();
+        let informed = sector_counts
+            .iter()
+            .filter(|&&count| count > 0 && count >= self.min_per_sector)
+            .count();
+
+        if total >= self.min_total && informed >= self.min_informed_sectors {
+            SearchStatus::Satisfied
+        } else {
+            SearchStatus::Insufficient(self.insufficient_data)
+        }
+    }
+
+    /// Status of a search given the points found around a target
+    /// # Arguments
+    /// * `target` - The target point
+    /// * `points` - The points found by the search
+    /// * `coordinate_system` - Coordinate system of the search geometry (defines sector orientation)
+    pub fn status_of_points(
+        &self,
+        target: &Point3<f32>,
+        points: &[Point3<f32>],
+        coordinate_system: &CoordinateSystem,
+    ) -> SearchStatus {
+        let mut sector_counts = vec![0; self.sectors.count()];
+        for point in points {
+            let local = coordinate_system
+                .world_to_local
+                .rotation
+                .transform_vector(&(point - target));
+            sector_counts[self.sectors.sector(&local.into()) as usize] += 1;
+        }
+
+        self.status(&sector_counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion};
+
+    use super::*;
+
+    #[test]
+    fn search_minimums() {
+        let cs = CoordinateSystem::new(Translation3::identity(), UnitQuaternion::identity());
+        let target = Point3::new(0.0, 0.0, 0.0);
+        let points = vec![
+            Point3::new(1.0, 1.0, 1.0),
+            Point3::new(2.0, 1.0, 1.0),
+            Point3::new(1.0, 1.0, -1.0),
+            Point3::new(-1.0, 1.0, 1.0),
+        ];
+
+        let search = SearchSpecification::new(Sectors::Eight)
+            .with_minimums(4, 1, 3)
+            .with_insufficient_data_rule(InsufficientDataRule::Skip);
+        assert_eq!(
+            search.status_of_points(&target, &points, &cs),
+            SearchStatus::Satisfied
+        );
+
+        //only the first quadrant holds at least two samples
+        let search = SearchSpecification::new(Sectors::Four)
+            .with_minimums(4, 2, 2)
+            .with_insufficient_data_rule(InsufficientDataRule::Skip);
+        assert_eq!(
+            search.status_of_points(&target, &points, &cs),
+            SearchStatus::Insufficient(InsufficientDataRule::Skip)
+        );
+
+        let search = SearchSpecification::new(Sectors::One).with_minimums(5, 1, 1);
+        assert_eq!(
+            search.status_of_points(&target, &points, &cs),
+            SearchStatus::Insufficient(InsufficientDataRule::Flag)
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use nalgebra::UnitQuaternion;
+use nalgebra::Vector3;
+
+use crate::spatial_database::coordinate_system::CoordinateSystem;
+
+use super::{NestedStructures, StructureShape, VariogramModel, VariogramStructure};
+use simba::simd::f32x16;
+use simba::simd::SimdPartialOrd;
+use simba::simd::SimdValue;
+
+/// Variogram of a single basic structure with a nugget effect
+/// exponential and gaussian ranges are practical ranges (95% of the sill)
+pub struct BasicVariogram {
+    shape: StructureShape,
+    range: Vector3<f32>,
+    sill: f32,
+    nugget: f32,
+    rotation: UnitQuaternion<f32>,
+    vec_rotation: UnitQuaternion<f32x16>,
+}
+
+impl BasicVariogram {
+    pub fn new(
+        shape: StructureShape,
+        range: Vector3<f32>,
+        sill: f32,
+        nugget: f32,
+        coordinate_system: CoordinateSystem,
+    ) -> Self {
+        let vec_cs = coordinate_system.vectorized_global_to_local_isomety();
+        Self {
+            shape,
+            range,
+            sill,
+            nugget,
+            rotation: coordinate_system.world_to_local.rotation,
+            vec_rotation: vec_cs.rotation,
+        }
+    }
+
+    #[inline(always)]
+    pub fn variogram(&self, h: Vector3<f32>) -> f32 {
+        let mut h = self.rotation.transform_vector(&h);
+
+        h.component_div_assign(&self.range);
+        let iso_h = h.norm();
+
+        if iso_h == 0f32 {
+            0f32
+        } else {
+            self.nugget + (self.sill - self.nugget) * (1.0 - self.shape.correlation(iso_h))
+        }
+    }
+
+    #[inline(always)]
+    pub fn covariogram(&self, h: Vector3<f32>) -> f32 {
+        self.sill - self.variogram(h)
+    }
+
+    #[inline(always)]
+    pub fn vectorized_variogram(&self, h: Vector3<f32x16>) -> f32x16 {
+        let mut h = self.vec_rotation.transform_vector(&h);
+
+        let rx = f32x16::splat(self.range.x);
+        let ry = f32x16::splat(self.range.y);
+        let rz = f32x16::splat(self.range.z);
+        let simd_range = Vector3::new(rx, ry, rz);
+        h.component_div_assign(&simd_range);
+        let iso_h = h.norm();
+
+        let mask = !iso_h.simd_eq(f32x16::splat(0.0));
+
+        let simd_nugget = f32x16::splat(self.nugget);
+        let simd_sill = f32x16::splat(self.sill);
+
+        //create simd variance
+        let simd_v = simd_nugget
+            + (simd_sill - simd_nugget)
+                * (f32x16::splat(1.0) - self.shape.vectorized_correlation(iso_h));
+
+        //set lanes of simd variance to 0 where lanes of iso_h == 0.0
+        simd_v.select(mask, f32x16::splat(0.0))
+    }
+
+    #[inline(always)]
+    pub fn vectorized_covariogram(&self, h: Vector3<f32x16>) -> f32x16 {
+        let simd_sill = f32x16::splat(self.sill);
+        simd_sill - self.vectorized_variogram(h)
+    }
+}
+
+impl VariogramModel for BasicVariogram {
+    fn variogram(&self, h: Vector3<f32>) -> f32 {
+        self.variogram(h)
+    }
+
+    fn covariogram(&self, h: Vector3<f32>) -> f32 {
+        self.covariogram(h)
+    }
+
+    #[inline(always)]
+    fn c_0(&self) -> f32 {
+        self.sill
+    }
+
+    #[inline(always)]
+    fn vectorized_variogram(&self, h: Vector3<f32x16>) -> f32x16 {
+        self.vectorized_variogram(h)
+    }
+
+    #[inline(always)]
+    fn vectorized_covariogram(&self, h: Vector3<f32x16>) -> f32x16 {
+        self.vectorized_covariogram(h)
+    }
+}
+
+impl NestedStructures for BasicVariogram {
+    fn nugget(&self) -> f32 {
+        self.nugget
+    }
+
+    fn structures(&self) -> Vec<VariogramStructure> {
+        vec![VariogramStructure {
+            shape: self.shape,
+            contribution: self.sill - self.nugget,
+            range: self.range,
+            rotation: self.rotation,
+        }]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use nalgebra::Translation3;
+
+    use crate::variography::model_variograms::spherical::SphericalVariogram;
+
+    use super::*;
+
+    #[test]
+    fn basic_vgram_var() {
+        let cs =
+            CoordinateSystem::new(Translation3::new(0.0, 0.0, 0.0), UnitQuaternion::identity());
+        let range = Vector3::new(100.0, 50.0, 10.0);
+        let lags = [
+            0.0, 5.0, 20.0, 70.0, 150.0, 300.0, 1.0, 2.0, 3.0, 4.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0,
+        ];
+        let simd_h = Vector3::new(f32x16::from(lags), f32x16::splat(0.0), f32x16::splat(0.0));
+
+        for shape in [StructureShape::Exponential, StructureShape::Gaussian] {
+            let vgram = BasicVariogram::new(shape, range, 1.0, 0.1, cs);
+
+            assert_eq!(vgram.variogram(Vector3::zeros()), 0.0);
+            //practical range in every direction
+            for h in [
+                Vector3::new(100.0, 0.0, 0.0),
+                Vector3::new(0.0, 50.0, 0.0),
+                Vector3::new(0.0, 0.0, 10.0),
+            ] {
+                assert!((vgram.variogram(h) - (0.1 + 0.9 * 0.95)).abs() < 1e-3);
+            }
+
+            let simd_cov: [f32; 16] = vgram.vectorized_covariogram(simd_h).into();
+            for (lag, cov) in lags.iter().zip(simd_cov) {
+                assert!((vgram.covariogram(Vector3::new(*lag, 0.0, 0.0)) - cov).abs() < 1e-5);
+            }
+        }
+
+        //a spherical shape matches the spherical variogram
+        let vgram = BasicVariogram::new(StructureShape::Spherical, range, 1.0, 0.1, cs);
+        let spherical = SphericalVariogram::new(range, 1.0, 0.1, cs);
+        let simd_v: [f32; 16] = vgram.vectorized_variogram(simd_h).into();
+        let simd_spherical: [f32; 16] = spherical.vectorized_variogram(simd_h).into();
+        for ((lag, v), spherical_v) in lags.iter().zip(simd_v).zip(simd_spherical) {
+            let h = Vector3::new(*lag, 0.0, 0.0);
+            assert!((vgram.variogram(h) - spherical.variogram(h)).abs() < 1e-6);
+            assert!((v - spherical_v).abs() < 1e-6);
+        }
+
+        //scalar and vectorized lags are rotated into the axes of the variogram alike
+        let rotated_cs = CoordinateSystem::new(
+            Translation3::new(0.0, 0.0, 0.0),
+            UnitQuaternion::from_euler_angles(0.2, -0.4, 0.9),
+        );
+        let vgram = BasicVariogram::new(StructureShape::Gaussian, range, 1.0, 0.1, rotated_cs);
+        let major_axis = rotated_cs
+            .rotation
+            .transform_vector(&Vector3::new(100.0, 0.0, 0.0));
+        assert!((vgram.variogram(major_axis) - (0.1 + 0.9 * 0.95)).abs() < 1e-3);
+        assert!(
+            (vgram.structures()[0].covariogram(major_axis) - vgram.covariogram(major_axis)).abs()
+                < 1e-5
+        );
+
+        let simd_h = Vector3::new(
+            f32x16::from(lags),
+            f32x16::from(lags) * f32x16::splat(0.5),
+            f32x16::splat(3.0),
+        );
+        let simd_cov: [f32; 16] = vgram.vectorized_covariogram(simd_h).into();
+        for (lag, cov) in lags.iter().zip(simd_cov) {
+            let h = Vector3::new(*lag, 0.5 * lag, 3.0);
+            assert!((vgram.covariogram(h) - cov).abs() < 1e-5);
+        }
+    }
+}
@@ -1,7 +1,9 @@
-use nalgebra::Vector3;
+use nalgebra::{UnitQuaternion, Vector3};
 
-use simba::simd::f32x16;
+use simba::simd::{f32x16, SimdComplexField, SimdPartialOrd, SimdValue};
 
+pub mod basic;
+pub mod nested;
 pub mod spherical;
 
 pub trait VariogramModel {
@@ -12,3 +14,148 @@ pub trait VariogramModel {
     fn vectorized_variogram(&self, h: Vector3<f32x16>) -> f32x16;
     fn vectorized_covariogram(&self, h: Vector3<f32x16>) -> f32x16;
 }
+
+impl<V> VariogramModel for &V
+where
+    V: VariogramModel,
+{
+    #[inline(always)]
+    fn variogram(&self, h: Vector3<f32>) -> f32 {
+        (*self).variogram(h)
+    }
+
+    #[inline(always)]
+    fn covariogram(&self, h: Vector3<f32>) -> f32 {
+        (*self).covariogram(h)
+    }
+
+    #[inline(always)]
+    fn c_0(&self) -> f32 {
+        (*self).c_0()
+    }
+
+    #[inline(always)]
+    fn vectorized_variogram(&self, h: Vector3<f32x16>) -> f32x16 {
+        (*self).vectorized_variogram(h)
+    }
+
+    #[inline(always)]
+    fn vectorized_covariogram(&self, h: Vector3<f32x16>) -> f32x16 {
+        (*self).vectorized_covariogram(h)
+    }
+}
+
+/// Shape of a basic variogram structure
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StructureShape {
+    Spherical,
+    /// Exponential with a practical range
+    Exponential,
+    /// Gaussian with a practical range
+    Gaussian,
+}
+
+impl StructureShape {
+    /// Correlation of the structure at an isotropic lag (lag divided by the range)
+    #[inline(always)]
+    pub fn correlation(&self, iso_h: f32) -> f32 {
+        match self {
+            StructureShape::Spherical => {
+                if iso_h <= 1.0 {
+                    1.0 - 1.5 * iso_h + 0.5 * iso_h.powi(3)
+                } else {
+                    0.0
+                }
+            }
+            StructureShape::Exponential => (-3.0 * iso_h).exp(),
+            StructureShape::Gaussian => (-3.0 * iso_h * iso_h).exp(),
+        }
+    }
+
+    /// Vectorized correlation of the structure at isotropic lags
+    #[inline(always)]
+    pub fn vectorized_correlation(&self, iso_h: f32x16) -> f32x16 {
+        match self {
+            StructureShape::Spherical => {
+                let correlation = f32x16::splat(1.0) - f32x16::splat(1.5) * iso_h
+                    + f32x16::splat(0.5) * iso_h * iso_h * iso_h;
+                correlation.select(iso_h.simd_le(f32x16::splat(1.0)), f32x16::splat(0.0))
+            }
+            StructureShape::Exponential => (f32x16::splat(-3.0) * iso_h).simd_exp(),
+            StructureShape::Gaussian => (f32x16::splat(-3.0) * iso_h * iso_h).simd_exp(),
+        }
+    }
+
+    /// Isotropic lag beyond which the correlation is negligible (below 0.003)
+    #[inline(always)]
+    pub fn support(&self) -> f32 {
+        match self {
+            StructureShape::Spherical => 1.0,
+            StructureShape::Exponential => 2.0,
+            StructureShape::Gaussian => 1.5,
+        }
+    }
+}
+
+/// Basic structure of a variogram model
+/// the structure is isotropic with a unit range once lags are rotated and divided by the range
+/// # Members
+/// * `shape` - Shape of the structure
+/// * `contribution` - Sill contribution of the structure
+/// * `range` - Ranges along the axes of the structure
+/// * `rotation` - Rotation of global lags into the axes of the structure
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VariogramStructure {
+    pub shape: StructureShape,
+    pub contribution: f32,
+    pub range: Vector3<f32>,
+    pub rotation: UnitQuaternion<f32>,
+}
+
+impl VariogramStructure {
+    /// Lag in the isotropic space of the structure
+    #[inline(always)]
+    pub fn isotropic_lag(&self, h: &Vector3<f32>) -> Vector3<f32> {
+        let mut h = self.rotation.transform_vector(h);
+        h.component_div_assign(&self.range);
+        h
+    }
+
+    /// Covariance of the structure at a lag
+    #[inline(always)]
+    pub fn covariogram(&self, h: Vector3<f32>) -> f32 {
+        self.contribution * self.shape.correlation(self.isotropic_lag(&h).norm())
+    }
+
+    /// Vectorized covariance of the structure at lags
+    #[inline(always)]
+    pub fn vectorized_covariogram(&self, h: Vector3<f32x16>) -> f32x16 {
+        let rotation: UnitQuaternion<f32x16> = UnitQuaternion::new_unchecked(
+            self.rotation.quaternion().coords.cast::<f32x16>().into(),
+        );
+        let mut h = rotation.transform_vector(&h);
+        h.component_div_assign(&self.range.cast::<f32x16>());
+        f32x16::splat(self.contribution) * self.shape.vectorized_correlation(h.norm())
+    }
+}
+
+/// Variogram models made of a nugget effect and basic structures
+pub trait NestedStructures {
+    fn nugget(&self) -> f32;
+    fn structures(&self) -> Vec<VariogramStructure>;
+}
+
+impl<V> NestedStructures for &V
+where
+    V: NestedStructures,
+{
+    #[inline(always)]
+    fn nugget(&self) -> f32 {
+        (*self).nugget()
+    }
+
+    #[inline(always)]
+    fn structures(&self) -> Vec<VariogramStructure> {
+        (*self).structures()
+    }
+}
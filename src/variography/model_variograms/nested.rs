@@ -0,0 +1,181 @@
+use nalgebra::Vector3;
+use simba::simd::{f32x16, SimdPartialOrd, SimdValue};
+
+use super::{NestedStructures, VariogramModel, VariogramStructure};
+
+/// Nugget effect and a sum of basic structures with their own ranges and orientations
+pub struct NestedVariogram {
+    nugget: f32,
+    structures: Vec<VariogramStructure>,
+}
+
+impl NestedVariogram {
+    /// Create a nested variogram
+    /// # Arguments
+    /// * `nugget` - Nugget effect
+    /// * `structures` - Basic structures
+    pub fn new(nugget: f32, structures: Vec<VariogramStructure>) -> Self {
+        Self { nugget, structures }
+    }
+
+    #[inline(always)]
+    pub fn variogram(&self, h: Vector3<f32>) -> f32 {
+        if h == Vector3::zeros() {
+            return 0f32;
+        }
+
+        self.c_0() - self.covariogram(h)
+    }
+
+    #[inline(always)]
+    pub fn covariogram(&self, h: Vector3<f32>) -> f32 {
+        let structures = self
+            .structures
+            .iter()
+            .map(|structure| structure.covariogram(h))
+            .sum::<f32>();
+
+        //nugget only contributes at the origin
+        if h == Vector3::zeros() {
+            structures + self.nugget
+        } else {
+            structures
+        }
+    }
+
+    #[inline(always)]
+    pub fn c_0(&self) -> f32 {
+        self.nugget
+            + self
+                .structures
+                .iter()
+                .map(|structure| structure.contribution)
+                .sum::<f32>()
+    }
+
+    #[inline(always)]
+    pub fn vectorized_variogram(&self, h: Vector3<f32x16>) -> f32x16 {
+        let origin = h.norm_squared().simd_eq(f32x16::splat(0.0));
+        (f32x16::splat(self.c_0()) - self.vectorized_covariogram(h))
+            .select(origin, f32x16::splat(0.0))
+    }
+
+    #[inline(always)]
+    pub fn vectorized_covariogram(&self, h: Vector3<f32x16>) -> f32x16 {
+        let structures = self
+            .structures
+            .iter()
+            .fold(f32x16::splat(0.0), |sum, structure| {
+                sum + structure.vectorized_covariogram(h)
+            });
+
+        //nugget only contributes at the origin
+        let origin = h.norm_squared().simd_eq(f32x16::splat(0.0));
+        (structures + f32x16::splat(self.nugget)).select(origin, structures)
+    }
+}
+
+impl VariogramModel for NestedVariogram {
+    fn variogram(&self, h: Vector3<f32>) -> f32 {
+        self.variogram(h)
+    }
+
+    fn covariogram(&self, h: Vector3<f32>) -> f32 {
+        self.covariogram(h)
+    }
+
+    #[inline(always)]
+    fn c_0(&self) -> f32 {
+        self.c_0()
+    }
+
+    #[inline(always)]
+    fn vectorized_variogram(&self, h: Vector3<f32x16>) -> f32x16 {
+        self.vectorized_variogram(h)
+    }
+
+    #[inline(always)]
+    fn vectorized_covariogram(&self, h: Vector3<f32x16>) -> f32x16 {
+        self.vectorized_covariogram(h)
+    }
+}
+
+impl NestedStructures for NestedVariogram {
+    fn nugget(&self) -> f32 {
+        self.nugget
+    }
+
+    fn structures(&self) -> Vec<VariogramStructure> {
+        self.structures.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use nalgebra::{Translation3, UnitQuaternion};
+
+    use crate::{
+        spatial_database::coordinate_system::CoordinateSystem,
+        variography::model_variograms::{
+            basic::BasicVariogram, spherical::SphericalVariogram, StructureShape,
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn nested_vgram_matches_structures() {
+        let cs =
+            CoordinateSystem::new(Translation3::new(0.0, 0.0, 0.0), UnitQuaternion::identity());
+        let rotated_cs = CoordinateSystem::new(
+            Translation3::new(0.0, 0.0, 0.0),
+            UnitQuaternion::from_euler_angles(0.0, 0.3, 0.7),
+        );
+        let spherical = SphericalVariogram::new(Vector3::new(50.0, 30.0, 10.0), 0.6, 0.1, cs);
+        let exponential = BasicVariogram::new(
+            StructureShape::Exponential,
+            Vector3::new(200.0, 80.0, 40.0),
+            0.4,
+            0.0,
+            rotated_cs,
+        );
+
+        let mut structures = spherical.structures();
+        structures.extend(exponential.structures());
+        let nested = NestedVariogram::new(0.1, structures);
+        assert!((nested.c_0() - 1.0).abs() < 1e-6);
+        assert_eq!(nested.structures()[0].shape, StructureShape::Spherical);
+
+        for h in [
+            Vector3::new(10.0, 0.0, 0.0),
+            Vector3::new(0.0, 25.0, 3.0),
+            Vector3::new(60.0, 40.0, 0.0),
+        ] {
+            let expected = spherical.variogram(h) + exponential.variogram(h);
+            assert!((nested.variogram(h) - expected).abs() < 1e-5);
+        }
+        assert_eq!(nested.variogram(Vector3::zeros()), 0.0);
+        assert!((nested.covariogram(Vector3::zeros()) - 1.0).abs() < 1e-6);
+
+        let lags = [
+            0.0, 5.0, 12.0, 20.0, 35.0, 50.0, 80.0, 120.0, 1.0, 2.0, 3.0, 4.0, 6.0, 7.0, 8.0, 300.0,
+        ];
+        let simd_h = Vector3::new(f32x16::from(lags), f32x16::splat(3.0), f32x16::splat(0.0));
+        let simd_h_0 = Vector3::new(f32x16::from(lags), f32x16::splat(0.0), f32x16::splat(0.0));
+        for simd_h in [simd_h, simd_h_0] {
+            let simd_v: [f32; 16] = nested.vectorized_variogram(simd_h).into();
+            let simd_cov: [f32; 16] = nested.vectorized_covariogram(simd_h).into();
+            let y: [f32; 16] = simd_h.y.into();
+            //the vectorized paths of the structures agree with the scalar paths
+            let simd_expected: [f32; 16] = (spherical.vectorized_variogram(simd_h)
+                + exponential.vectorized_variogram(simd_h))
+            .into();
+            for (i, lag) in lags.iter().enumerate() {
+                let h = Vector3::new(*lag, y[i], 0.0);
+                assert!((simd_v[i] - nested.variogram(h)).abs() < 1e-5);
+                assert!((simd_cov[i] - nested.covariogram(h)).abs() < 1e-5);
+                assert!((simd_expected[i] - nested.variogram(h)).abs() < 1e-5);
+            }
+        }
+    }
+}
@@ -3,7 +3,7 @@ use nalgebra::Vector3;
 
 use crate::spatial_database::coordinate_system::CoordinateSystem;
 
-use super::VariogramModel;
+use super::{NestedStructures, StructureShape, VariogramModel, VariogramStructure};
 use simba::simd::f32x16;
 use simba::simd::SimdPartialOrd;
 use simba::simd::SimdValue;
@@ -28,7 +28,7 @@ impl SphericalVariogram {
             range,
             sill,
             nugget,
-            rotation: coordinate_system.rotation,
+            rotation: coordinate_system.world_to_local.rotation,
             vec_rotation: vec_cs.rotation,
         }
     }
@@ -117,6 +117,21 @@ impl VariogramModel for SphericalVariogram {
     }
 }
 
+impl NestedStructures for SphericalVariogram {
+    fn nugget(&self) -> f32 {
+        self.nugget
+    }
+
+    fn structures(&self) -> Vec<VariogramStructure> {
+        vec![VariogramStructure {
+            shape: StructureShape::Spherical,
+            contribution: self.sill - self.nugget,
+            range: self.range,
+            rotation: self.rotation,
+        }]
+    }
+}
+
 #[cfg(test)]
 mod test {
     use nalgebra::Translation3;